@@ -0,0 +1,57 @@
+//! Embeds information about the build into the binary, see `src/build_info.rs`.
+
+use std::{
+    env,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-env-changed=YARA_VERSION");
+
+    // container builds don't have the repository, so the commit can be passed in instead
+    let git_commit = env::var("GIT_COMMIT")
+        .ok()
+        .or_else(|| {
+            let output = Command::new("git")
+                .args(["rev-parse", "--short=12", "HEAD"])
+                .output()
+                .ok()?;
+            output
+                .status
+                .success()
+                .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        })
+        .unwrap_or_else(|| String::from("unknown"));
+
+    // honor SOURCE_DATE_EPOCH for reproducible builds
+    let build_timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default()
+        });
+
+    // the version of the libyara installation being linked against, as set by the Dockerfile
+    let yara_version = env::var("YARA_VERSION").unwrap_or_else(|_| String::from("unknown"));
+
+    let mut features = env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_ascii_lowercase().replace('_', "-"))
+        })
+        .collect::<Vec<_>>();
+    features.sort_unstable();
+
+    println!("cargo:rustc-env=DRAGONFLY_GIT_COMMIT={git_commit}");
+    println!("cargo:rustc-env=DRAGONFLY_BUILD_TIMESTAMP={build_timestamp}");
+    println!("cargo:rustc-env=DRAGONFLY_YARA_VERSION={yara_version}");
+    println!("cargo:rustc-env=DRAGONFLY_FEATURES={}", features.join(","));
+}
@@ -0,0 +1,42 @@
+//! Feeds arbitrary bytes to every extraction backend through the safety layer. The first byte
+//! picks the backend.
+
+#![no_main]
+
+#[allow(dead_code)]
+#[path = "../../src/extract.rs"]
+mod extract;
+
+use std::{
+    io::{self, Cursor},
+    path::Component,
+};
+
+use extract::{ArchiveKind, Limits};
+use libfuzzer_sys::fuzz_target;
+
+const LIMITS: Limits = Limits {
+    max_entries: 64,
+    max_total_size: 1024 * 1024,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((kind, archive)) = data.split_first() else {
+        return;
+    };
+    let kind = match kind % 3 {
+        0 => ArchiveKind::TarGz,
+        1 => ArchiveKind::TarZst,
+        _ => ArchiveKind::Zip,
+    };
+
+    let mut total = 0;
+    let _ = extract::for_each_file_in(Cursor::new(archive), kind, LIMITS, |path, _, reader| {
+        assert!(path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_))));
+        total += io::copy(reader, &mut io::sink())?;
+        assert!(total <= LIMITS.max_total_size);
+        Ok::<_, io::Error>(())
+    });
+});
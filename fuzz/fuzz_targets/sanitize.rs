@@ -0,0 +1,25 @@
+//! Checks that sanitized paths can never leave the directory they're joined onto.
+
+#![no_main]
+
+#[allow(dead_code)]
+#[path = "../../src/extract.rs"]
+mod extract;
+
+use std::{
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path},
+};
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let path = Path::new(OsStr::from_bytes(data));
+    if let Some(sanitized) = extract::sanitize(path) {
+        assert!(sanitized
+            .components()
+            .all(|component| matches!(component, Component::Normal(_))));
+        assert!(Path::new("/base").join(&sanitized).starts_with("/base"));
+    }
+});
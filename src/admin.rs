@@ -0,0 +1,179 @@
+//! An admin endpoint exposing the internal state of the client, for debugging stuck clients.
+//!
+//! `/state` returns the current ruleset, when the access token expires, the jobs being scanned,
+//! the depth of the submission queue, the last errors and the uptime as JSON. The state is
+//! published by the job loop as it goes, so it's never more than an iteration old.
+//!
+//! The endpoint is only served on a loopback address, unless `admin_token` is set, in which case
+//! every request must carry it as an `Authorization: Bearer <token>` header.
+
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, SocketAddr},
+    time::Instant,
+};
+
+use chrono::{DateTime, Utc};
+use color_eyre::{eyre::eyre, Result};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::server::{self, Response};
+
+/// How many of the last errors are kept
+const MAX_ERRORS: usize = 20;
+
+/// A job being scanned
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JobInProgress {
+    pub name: String,
+    pub version: String,
+    pub started_at: DateTime<Utc>,
+}
+
+/// An error logged by the job loop
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RecentError {
+    pub at: DateTime<Utc>,
+    pub message: String,
+}
+
+/// What `/state` returns
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Snapshot {
+    pub uptime_secs: u64,
+    pub rules_hash: Option<String>,
+    pub auth_expires_at: Option<DateTime<Utc>>,
+    pub jobs_in_progress: Vec<JobInProgress>,
+    pub queue_depth: usize,
+
+    /// Oldest first
+    pub last_errors: VecDeque<RecentError>,
+}
+
+/// The state of the client, updated by the job loop and read by the admin endpoint
+pub struct State {
+    started: Instant,
+    snapshot: Mutex<Snapshot>,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            snapshot: Mutex::new(Snapshot::default()),
+        }
+    }
+
+    /// Record the ruleset being scanned with, and when the access token expires
+    pub fn set_client(&self, rules_hash: &str, auth_expires_at: DateTime<Utc>) {
+        let mut snapshot = self.snapshot.lock();
+        snapshot.rules_hash = Some(rules_hash.to_owned());
+        snapshot.auth_expires_at = Some(auth_expires_at);
+    }
+
+    pub fn set_queue_depth(&self, queue_depth: usize) {
+        self.snapshot.lock().queue_depth = queue_depth;
+    }
+
+    pub fn job_started(&self, name: &str, version: &str) {
+        self.snapshot.lock().jobs_in_progress.push(JobInProgress {
+            name: name.to_owned(),
+            version: version.to_owned(),
+            started_at: Utc::now(),
+        });
+    }
+
+    pub fn job_finished(&self, name: &str, version: &str) {
+        self.snapshot
+            .lock()
+            .jobs_in_progress
+            .retain(|job| (job.name.as_str(), job.version.as_str()) != (name, version));
+    }
+
+    /// Record an error, forgetting the oldest one past [`MAX_ERRORS`]
+    pub fn record_error(&self, message: impl Into<String>) {
+        let mut snapshot = self.snapshot.lock();
+        if snapshot.last_errors.len() >= MAX_ERRORS {
+            snapshot.last_errors.pop_front();
+        }
+        snapshot.last_errors.push_back(RecentError {
+            at: Utc::now(),
+            message: message.into(),
+        });
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let mut snapshot = self.snapshot.lock().clone();
+        snapshot.uptime_secs = self.started.elapsed().as_secs();
+        snapshot
+    }
+}
+
+/// The global state of the client
+pub static STATE: Lazy<State> = Lazy::new(State::new);
+
+/// Serve `/state` on `bind:port` in the background. Binding to anything but a loopback address
+/// requires a `token`.
+pub fn serve(bind: IpAddr, port: u16, token: Option<String>) -> Result<SocketAddr> {
+    if !bind.is_loopback() && token.is_none() {
+        return Err(eyre!(
+            "admin_token must be set to serve the admin endpoint on {bind}"
+        ));
+    }
+
+    Ok(server::serve((bind, port), move |request| {
+        if !server::authorized(request, token.as_deref()) {
+            return Response::text(401, "unauthorized");
+        }
+        match request.path.as_str() {
+            "/state" => match serde_json::to_string(&STATE.snapshot()) {
+                Ok(body) => Response::json(200, body),
+                Err(err) => Response::text(500, err.to_string()),
+            },
+            _ => Response::not_found(),
+        }
+    })?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serve, State, MAX_ERRORS};
+    use crate::server::{authorized, Request};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn tracks_jobs_and_recent_errors() {
+        let state = State::new();
+        state.job_started("a", "1.0");
+        state.job_started("b", "2.0");
+        state.job_finished("a", "1.0");
+        for i in 0..=MAX_ERRORS {
+            state.record_error(format!("error {i}"));
+        }
+
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.jobs_in_progress.len(), 1);
+        assert_eq!(snapshot.jobs_in_progress[0].name, "b");
+        assert_eq!(snapshot.last_errors.len(), MAX_ERRORS);
+        assert_eq!(snapshot.last_errors[0].message, "error 1");
+    }
+
+    #[test]
+    fn requires_the_token_if_set() {
+        let request = |authorization: Option<&str>| Request {
+            method: String::from("GET"),
+            path: String::from("/state"),
+            authorization: authorization.map(ToOwned::to_owned),
+            body: Vec::new(),
+        };
+
+        assert!(authorized(&request(None), None));
+        assert!(!authorized(&request(None), Some("secret")));
+        assert!(!authorized(&request(Some("Bearer nope")), Some("secret")));
+        assert!(authorized(&request(Some("Bearer secret")), Some("secret")));
+
+        assert!(serve(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0, None).is_err());
+    }
+}
@@ -0,0 +1,234 @@
+//! Heuristic analyzers that complement the YARA rules with structured findings.
+//!
+//! Analyzers look at things that are awkward or brittle to express as string rules, and report
+//! what they found as [`Finding`]s which are submitted alongside the matched rules. Some findings
+//! carry a weight, which is added to the score of the distribution like a rule's.
+
+mod ast;
+pub mod binaries;
+mod entropy;
+mod install_hooks;
+mod nesting;
+mod packers;
+mod syntax;
+
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::APP_CONFIG;
+
+/// What an analyzer found
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FindingKind {
+    /// The file looks like the output of a known Python packer or obfuscator
+    PackedPython {
+        /// The name of the packer, e.g. `pyarmor`
+        packer: &'static str,
+
+        /// What gave the packer away
+        evidence: String,
+    },
+
+    /// A file with a Python extension that no interpreter would accept. These are often
+    /// encrypted or encoded payloads, decoded at runtime by another module.
+    InvalidPythonSyntax {
+        /// Why the file was rejected
+        reason: String,
+    },
+
+    /// A Python module of a wheel that isn't in the sdist, see [`crate::scanner`]'s correlation
+    /// of distributions
+    OnlyInWheel {
+        /// The file name of the sdist the wheel was compared against
+        sdist: String,
+    },
+
+    /// A Python module of a wheel whose contents differ from the same module in the sdist
+    DiffersFromSdist {
+        /// The file name of the sdist the wheel was compared against
+        sdist: String,
+    },
+
+    /// A Python module of the sdist, inside a package the wheel installs, that's missing from the
+    /// wheel
+    OnlyInSdist {
+        /// The file name of the wheel the sdist was compared against
+        wheel: String,
+    },
+
+    /// A summary of how a wheel differs from the sdist it was compared against, over all of its
+    /// files rather than only Python modules. The path of the finding is empty, it's about the
+    /// whole wheel.
+    DistributionMismatch {
+        /// The file name of the sdist the wheel was compared against
+        sdist: String,
+
+        /// The files of the wheel that aren't in the sdist
+        only_in_wheel: Vec<String>,
+
+        /// The files of the wheel whose contents differ from the same file in the sdist
+        differs_from_sdist: Vec<String>,
+    },
+
+    /// Data that is close to random, such as a base64 encoded, marshalled, or compressed payload
+    HighEntropyBlob {
+        /// The Shannon entropy of the data, in bits per byte
+        entropy: f64,
+
+        /// The byte offset of the data in the file, `None` if it's the whole file
+        #[serde(skip_serializing_if = "Option::is_none")]
+        offset: Option<usize>,
+
+        /// The length of the data, in bytes
+        length: usize,
+    },
+
+    /// A packaging file of an sdist that runs or pulls in code on install, such as a custom
+    /// `cmdclass` in `setup.py`
+    InstallHook {
+        /// The kind of hook, e.g. `custom-cmdclass`, `url-requirement` or `top-level-code`
+        hook: &'static str,
+
+        /// The code or configuration that defines the hook
+        evidence: String,
+
+        /// How much the finding adds to the score of the distribution
+        weight: i64,
+    },
+
+    /// Python code doing something suspicious, found in its syntax tree, such as `exec` of a
+    /// decoded payload
+    SuspiciousCode {
+        /// What the code does, e.g. `exec-decoded`, `dynamic-network-import` or `pipe-to-shell`
+        pattern: &'static str,
+
+        /// The line the code starts on, counting from 1
+        line: usize,
+
+        /// The code itself
+        evidence: String,
+    },
+
+    /// A file of a wheel that doesn't match the wheel's own `RECORD`, `METADATA` or
+    /// `entry_points.txt`, see [`crate::scanner`]'s checks of wheels
+    WheelInconsistency {
+        /// The kind of inconsistency, e.g. `missing-from-record` or `hash-mismatch`
+        issue: &'static str,
+
+        /// What doesn't match
+        detail: String,
+
+        /// How much the finding adds to the score of the distribution
+        weight: i64,
+    },
+
+    /// An ELF, PE or Mach-O binary, see [`binaries`]
+    NativeBinary {
+        /// The format of the binary: `elf`, `pe` or `mach-o`
+        format: &'static str,
+
+        /// The architecture it's built for, e.g. `x86_64`
+        arch: &'static str,
+
+        /// The SHA-256 of the names of the shared libraries it references, if any
+        #[serde(skip_serializing_if = "Option::is_none")]
+        imports_hash: Option<String>,
+
+        /// How much the finding adds to the score of the distribution
+        weight: i64,
+    },
+
+    /// A file that took too long to match against the rules, so it wasn't, see
+    /// [`crate::scanner`]'s matching of files
+    ScanTimeout {
+        /// How many seconds matching a file may take, `yara_timeout`
+        timeout_secs: u32,
+    },
+
+    /// A script with a shebang for anything but Python, somewhere scripts aren't expected
+    UnusualShebang {
+        /// The interpreter line, without the `#!`
+        shebang: String,
+
+        /// How much the finding adds to the score of the distribution
+        weight: i64,
+    },
+}
+
+impl FindingKind {
+    /// How much the finding adds to the score of the distribution it was made in
+    pub fn weight(&self) -> i64 {
+        match self {
+            Self::InstallHook { weight, .. }
+            | Self::WheelInconsistency { weight, .. }
+            | Self::NativeBinary { weight, .. }
+            | Self::UnusualShebang { weight, .. } => *weight,
+            _ => 0,
+        }
+    }
+}
+
+/// A structured finding about a single file of a distribution
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Finding {
+    /// The file name of the distribution the finding was made in. Only known once the results of
+    /// all distributions are collected into a package.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distribution: Option<String>,
+
+    /// The path of the file, relative to the distribution's archive root
+    pub path: String,
+
+    #[serde(flatten)]
+    pub kind: FindingKind,
+}
+
+impl Finding {
+    pub fn new(path: &Path, kind: FindingKind) -> Self {
+        Self {
+            distribution: None,
+            path: path.to_string_lossy().into_owned(),
+            kind,
+        }
+    }
+}
+
+/// Run every file analyzer against a file.
+///
+/// # Arguments
+/// * `path` - The path of the file, relative to the archive root
+/// * `contents` - The raw contents of the file
+pub fn analyze_file(path: &Path, contents: &[u8]) -> Vec<Finding> {
+    let mut kinds = packers::detect(path, contents);
+    kinds.extend(install_hooks::detect(path, contents));
+    kinds.extend(entropy::detect(
+        path,
+        contents,
+        entropy::Thresholds {
+            file: APP_CONFIG.load().entropy_file_threshold,
+            string: APP_CONFIG.load().entropy_string_threshold,
+            min_string_length: APP_CONFIG.load().entropy_min_string_length,
+        },
+    ));
+
+    if APP_CONFIG.load().binary_analysis {
+        kinds.extend(binaries::detect(path, contents));
+    }
+
+    if APP_CONFIG.load().ast_analysis && syntax::is_python_source(path) {
+        kinds.extend(ast::detect(path, contents));
+    }
+
+    if APP_CONFIG.load().python_syntax_check && syntax::is_python_source(path) {
+        if let Some(reason) = syntax::check(contents) {
+            kinds.push(FindingKind::InvalidPythonSyntax { reason });
+        }
+    }
+
+    kinds
+        .into_iter()
+        .map(|kind| Finding::new(path, kind))
+        .collect()
+}
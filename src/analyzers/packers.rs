@@ -0,0 +1,130 @@
+//! Detection of common Python packers and obfuscators.
+
+use std::path::Path;
+
+use memchr::memmem;
+
+use super::{syntax::is_python_source, FindingKind};
+
+/// Lines longer than this (in bytes) in a Python source file are reported as a packed payload
+const LONG_LINE_THRESHOLD: usize = 10_000;
+
+/// File name prefixes of the runtime `PyArmor` ships with every obfuscated package
+const PYARMOR_RUNTIME_PREFIXES: &[&str] = &["pytransform", "_pytransform", "pyarmor_runtime"];
+
+/// Code snippets left behind by packers, matched after stripping all whitespace.
+const PACKER_SIGNATURES: &[(&str, &str)] = &[
+    ("pyarmor", "__pyarmor__("),
+    ("pyarmor", "frompytransformimportpyarmor"),
+    ("marshal-zlib", "marshal.loads(zlib.decompress("),
+    ("marshal", "exec(marshal.loads("),
+    ("zlib-base64", "zlib.decompress(base64.b64decode("),
+    ("reversed-base64", "base64.b64decode(__[::-1])"),
+];
+
+/// Detect packers in the file at `path` (relative to the archive root) with the given `contents`.
+///
+/// At most one finding is reported per packer.
+pub fn detect(path: &Path, contents: &[u8]) -> Vec<FindingKind> {
+    let mut findings: Vec<FindingKind> = Vec::new();
+    let mut push = |packer: &'static str, evidence: String| {
+        let seen = findings
+            .iter()
+            .any(|finding| matches!(finding, FindingKind::PackedPython { packer: p, .. } if *p == packer));
+        if !seen {
+            findings.push(FindingKind::PackedPython { packer, evidence });
+        }
+    };
+
+    if let Some(component) = path.iter().find_map(|component| {
+        let component = component.to_str()?;
+        PYARMOR_RUNTIME_PREFIXES
+            .iter()
+            .any(|prefix| component.starts_with(prefix))
+            .then_some(component)
+    }) {
+        push("pyarmor", format!("pyarmor runtime file `{component}`"));
+    }
+
+    let stripped: Vec<u8> = contents
+        .iter()
+        .copied()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .collect();
+    for (packer, signature) in PACKER_SIGNATURES {
+        if memmem::find(&stripped, signature.as_bytes()).is_some() {
+            push(packer, format!("contains `{signature}`"));
+        }
+    }
+
+    if is_python_source(path) {
+        if let Some((line_number, line)) = contents
+            .split(|byte| *byte == b'\n')
+            .enumerate()
+            .find(|(_, line)| line.len() > LONG_LINE_THRESHOLD)
+        {
+            push(
+                "long-line",
+                format!("line {} is {} bytes long", line_number + 1, line.len()),
+            );
+        }
+    }
+
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{detect, LONG_LINE_THRESHOLD};
+    use crate::analyzers::FindingKind;
+    use std::path::Path;
+
+    fn packers(findings: &[FindingKind]) -> Vec<&'static str> {
+        findings
+            .iter()
+            .filter_map(|finding| {
+                if let FindingKind::PackedPython { packer, .. } = finding {
+                    Some(*packer)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn detects_pyarmor_runtime() {
+        let findings = detect(
+            Path::new("pkg/pyarmor_runtime_000000/__init__.py"),
+            b"from .pyarmor_runtime import __pyarmor__",
+        );
+
+        assert_eq!(packers(&findings), vec!["pyarmor"]);
+    }
+
+    #[test]
+    fn detects_marshal_zlib_with_whitespace() {
+        let findings = detect(
+            Path::new("pkg/__init__.py"),
+            b"import marshal, zlib\nexec(marshal.loads( zlib.decompress( b'...' )))",
+        );
+
+        assert_eq!(packers(&findings), vec!["marshal-zlib", "marshal"]);
+    }
+
+    #[test]
+    fn detects_long_lines_in_python_only() {
+        let contents = "a".repeat(LONG_LINE_THRESHOLD + 1);
+
+        assert_eq!(
+            packers(&detect(Path::new("pkg/x.py"), contents.as_bytes())),
+            vec!["long-line"]
+        );
+        assert!(detect(Path::new("pkg/x.min.js"), contents.as_bytes()).is_empty());
+    }
+
+    #[test]
+    fn clean_file_has_no_findings() {
+        assert!(detect(Path::new("pkg/__init__.py"), b"import os\nprint(os.name)\n").is_empty());
+    }
+}
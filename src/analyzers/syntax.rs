@@ -0,0 +1,148 @@
+//! A syntax check of Python source files.
+//!
+//! Files are dry-parsed to catch the ones that no Python interpreter would accept: binary or
+//! encrypted blobs with a `.py` extension which are decoded and loaded at runtime by some other
+//! module.
+//!
+//! Files nested too deeply to be parsed are rejected before they are, see [`nesting`].
+
+use std::path::Path;
+
+use rustpython_parser::{parse, Mode, ParseErrorType};
+
+use super::nesting;
+
+/// Whether the file at `path` is Python source that should be syntax checked
+pub fn is_python_source(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("py") || ext.eq_ignore_ascii_case("pyw"))
+}
+
+/// Check that `contents` looks like syntactically valid Python.
+///
+/// Returns the reason the file was rejected, or `None` if it looks valid. Files declaring a source
+/// encoding other than UTF-8 are not checked.
+pub fn check(contents: &[u8]) -> Option<String> {
+    if let Some(offset) = memchr::memchr(0, contents) {
+        return Some(format!("null byte at offset {offset}"));
+    }
+
+    if declares_foreign_encoding(contents) {
+        return None;
+    }
+
+    let source = match std::str::from_utf8(contents) {
+        Ok(source) => source,
+        Err(err) => return Some(format!("invalid UTF-8 at offset {}", err.valid_up_to())),
+    };
+    if let Some((offset, too_deep)) = nesting::too_deep(source) {
+        return Some(format!(
+            "{} on line {}",
+            too_deep.message(),
+            line(source, offset)
+        ));
+    }
+    let err = parse(source, Mode::Module, "<file>").err()?;
+    let mut offset = usize::from(err.offset).min(source.len());
+    // the lexer fails on a string left open at the end of its line after reading the newline
+    if matches!(err.error, ParseErrorType::Lexical(_)) && source[..offset].ends_with('\n') {
+        offset -= 1;
+    }
+
+    Some(format!("{} on line {}", err.error, line(source, offset)))
+}
+
+/// The 1-based line of the byte at `offset` in `source`
+fn line(source: &str, offset: usize) -> usize {
+    source.as_bytes()[..offset]
+        .iter()
+        .filter(|byte| **byte == b'\n')
+        .count()
+        + 1
+}
+
+/// Whether one of the first two lines carries a PEP 263 coding declaration for a non UTF-8
+/// encoding
+fn declares_foreign_encoding(contents: &[u8]) -> bool {
+    contents
+        .split(|byte| *byte == b'\n')
+        .take(2)
+        .filter_map(|line| std::str::from_utf8(line).ok())
+        .filter(|line| line.trim_start().starts_with('#'))
+        .filter_map(|line| {
+            let (_, rest) = line.split_once("coding")?;
+            let rest = rest.strip_prefix([':', '='])?.trim_start();
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            Some(rest[..end].to_ascii_lowercase())
+        })
+        .any(|encoding| !matches!(encoding.as_str(), "utf-8" | "utf8" | "utf_8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+    use crate::analyzers::nesting::MAX_BRACKET_DEPTH;
+
+    #[test]
+    fn accepts_valid_python() {
+        let source = br#"
+# -*- coding: utf-8 -*-
+import os
+
+def f(x: dict[str, int]) -> str:
+    """A docstring with 'quotes' and (brackets"""
+    s = r"C:\path" + 'it\'s' + f"{x['a']}"
+    return (s,
+            b'\x00 bytes')
+"#;
+
+        assert_eq!(check(source), None);
+    }
+
+    #[test]
+    fn rejects_binary_blobs() {
+        assert!(check(b"\x89PNG\r\n\x1a\n\0\0").is_some());
+        assert!(check(b"\xff\xfe garbage").is_some());
+    }
+
+    #[test]
+    fn rejects_unbalanced_brackets() {
+        assert!(check(b"print(1]\n").is_some_and(|reason| reason.ends_with("on line 1")));
+        assert!(check(b"x = [1,\n2,\n").is_some());
+    }
+
+    #[test]
+    fn rejects_deeply_nested_brackets() {
+        let nested = |depth| format!("x = {}{}\n", "[".repeat(depth), "]".repeat(depth));
+
+        assert_eq!(check(nested(MAX_BRACKET_DEPTH).as_bytes()), None);
+        assert_eq!(
+            check(format!("y = 1\n{}", nested(20_000)).as_bytes()),
+            Some(String::from("too many nested parentheses on line 2"))
+        );
+        assert_eq!(
+            check(format!("x = {}1\n", "1 + ".repeat(20_000)).as_bytes()),
+            Some(String::from("too deeply nested code on line 1"))
+        );
+    }
+
+    #[test]
+    fn rejects_unterminated_strings() {
+        assert!(check(b"x = 'abc\ny = 1\n").is_some_and(|reason| reason.ends_with("on line 1")));
+        assert!(check(b"x = \"\"\"abc\n").is_some());
+    }
+
+    #[test]
+    fn accepts_backslash_continuations() {
+        assert_eq!(check(b"x = 1 + \\\n    2\ny = 'a\\\r\nb'\n"), None);
+        assert!(check(b"x = 1 + \\\n    2\nprint(1]\n")
+            .is_some_and(|reason| reason.ends_with("on line 3")));
+    }
+
+    #[test]
+    fn skips_foreign_encodings() {
+        assert_eq!(check(b"# coding: latin-1\nx = '\xe9'\n"), None);
+    }
+}
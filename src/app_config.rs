@@ -1,27 +1,434 @@
+use arc_swap::{ArcSwap, Guard};
 use figment::{
     providers::{Env, Format, Serialized, Toml},
+    value::Dict,
     Figment,
 };
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use crate::extract::Limits;
+
+/// The output format of the logs
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable, colored output
+    Pretty,
+
+    /// One JSON object per event, with the fields of the current span attached
+    Json,
+}
+
+/// How the scores of the distributions of a package are combined into the package's score
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScoringStrategy {
+    /// The score of the highest scoring distribution
+    Max,
+
+    /// The sum of the weights of every rule matched in any distribution, each counted once
+    SumUnique,
+
+    /// The mean score of the distributions, rounded down
+    Mean,
+
+    /// Like `max`, with the weight of every match multiplied by the `filetype_weights` entry of
+    /// the extension of the file it matched in
+    WeightedByFiletype,
+}
+
+/// Where the job loop gets its jobs from, see [`crate::job_source`]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum JobSourceKind {
+    /// The Dragonfly API
+    Api,
+
+    /// A directory of job JSON files at `job_source_path`
+    Directory,
+
+    /// A JSON Lines file of jobs at `job_source_path`
+    QueueFile,
+
+    /// A directory at `job_source_path` that distribution archives are dropped into
+    Archives,
+
+    /// Jobs pushed over HTTP to `listener_port`
+    Listener,
+}
+
+/// A destination for scan results, see [`crate::result_sink`]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResultSinkKind {
+    /// The Dragonfly API
+    Http,
+
+    /// A JSON Lines file at `results_file_path`
+    File,
+
+    /// An S3 compatible bucket
+    S3,
+
+    /// `POST` requests to `webhook_url`
+    Webhook,
+}
+
+/// Where events are written to, see [`crate::events`]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventStream {
+    /// Nowhere, there's no event stream
+    None,
+
+    /// One line per event on stdout
+    Stdout,
+
+    /// One line per event to every client of the UNIX socket at `event_socket_path`
+    Socket,
+}
+
+/// Where files matched by `quarantine` rules are uploaded to, see [`crate::quarantine`]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuarantineTarget {
+    /// Nowhere, matched files aren't kept
+    None,
+
+    /// The S3 compatible bucket configured with the `s3_*` settings
+    S3,
+
+    /// `PUT` requests below `quarantine_url`
+    Http,
+}
+
+/// A ruleset compiled into its own namespace next to the Dragonfly rules, see
+/// [`crate::client::bundles`]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RuleBundle {
+    pub namespace: String,
+
+    /// A rule file, or a directory of them
+    #[serde(default)]
+    pub path: Option<String>,
+
+    /// The URL of a rule file
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+/// A Dragonfly API the client takes jobs from, see `backends`. The settings that aren't given are
+/// the top level ones.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Backend {
+    /// Tags the results and statistics of the jobs taken from this backend
+    pub name: String,
+
+    pub base_url: String,
+
+    #[serde(default)]
+    pub auth0_domain: Option<String>,
+
+    #[serde(default)]
+    pub audience: Option<String>,
+
+    #[serde(default)]
+    pub client_id: Option<String>,
+
+    #[serde(default)]
+    pub client_secret: Option<String>,
+
+    /// How often this backend is polled, relative to the other ones
+    #[serde(default = "Backend::default_weight")]
+    pub weight: u32,
+}
+
+impl Backend {
+    fn default_weight() -> u32 {
+        1
+    }
+
+    /// The backend made of the top level settings, used when no `backends` are configured
+    fn from_config(config: &AppConfig) -> Self {
+        Self {
+            name: String::from("default"),
+            base_url: config.base_url.clone(),
+            auth0_domain: None,
+            audience: None,
+            client_id: None,
+            client_secret: None,
+            weight: Self::default_weight(),
+        }
+    }
+}
+
+/// How large result bodies are compressed before they're sent to the API
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResultCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+/// How the connections of an HTTP client are managed, see `api_http` and `download_http`. All
+/// durations are in seconds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct HttpTuning {
+    /// How many idle connections are kept open to each host, unlimited if unset
+    pub pool_max_idle_per_host: Option<usize>,
+
+    /// How long idle connections are kept open, forever if 0
+    pub pool_idle_timeout: u64,
+
+    /// Talk HTTP/2 right away instead of negotiating it, for hosts known to support it
+    pub http2_prior_knowledge: bool,
+
+    /// How often TCP keepalives are sent on open connections, never if 0
+    pub tcp_keepalive: u64,
+
+    /// How long connecting may take, as long as the read timeout allows if 0
+    pub connect_timeout: u64,
+
+    /// How long to wait for a response or the next part of its body, forever if 0
+    pub read_timeout: u64,
+
+    /// How many redirects a request follows before it fails
+    pub max_redirects: usize,
+}
+
+impl Default for HttpTuning {
+    fn default() -> Self {
+        Self {
+            pool_max_idle_per_host: None,
+            pool_idle_timeout: 90,
+            http2_prior_knowledge: false,
+            tcp_keepalive: 0,
+            connect_timeout: 0,
+            read_timeout: 30,
+            max_redirects: 10,
+        }
+    }
+}
+
+/// When the files that aren't of any of the rules' types are matched against the rules, with
+/// `two_pass_scan`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TwoPassFallback {
+    /// Only if none of the files of the rules' types matched
+    NoMatches,
+
+    /// Always, the files of the rules' types are only scanned first
+    Always,
+}
+
+/// What to do with files larger than `max_file_size`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OversizedFilePolicy {
+    /// Don't scan the file at all
+    Skip,
+
+    /// Only scan the first `max_file_size` bytes of the file
+    Truncate,
+}
 
 #[derive(Serialize, Deserialize)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct AppConfig {
     pub base_url: String,
+    pub backends: Vec<Backend>,
+    pub pypi_url: String,
     pub threads: usize,
+    pub scan_threads: usize,
+    pub low_resource: bool,
     pub load_duration: u64,
+    pub poll_min_interval: u64,
+    pub poll_jitter: f64,
+    pub breaker_threshold: u32,
+    pub breaker_max_interval: u64,
+    pub iteration_timeout: u64,
     pub bulk_size: usize,
+    pub priority_poll_interval: u64,
     pub auth0_domain: String,
     pub client_id: String,
     pub client_secret: String,
+    pub client_id_file: Option<PathBuf>,
+    pub client_secret_file: Option<PathBuf>,
+    pub vault_addr: Option<String>,
+    pub vault_token: Option<String>,
+    pub vault_path: String,
+    pub auth_refresh_margin: u64,
     pub audience: String,
     pub grant_type: String,
     pub username: String,
     pub password: String,
     pub max_scan_size: u64,
+    pub log_format: LogFormat,
+    pub python_syntax_check: bool,
+    pub ast_analysis: bool,
+    pub binary_analysis: bool,
+    pub rules_cache_dir: PathBuf,
+    pub rules_cache_size: usize,
+    pub max_rules_age: u64,
+    pub submit_queue_path: PathBuf,
+    pub submit_queue_capacity: usize,
+    pub stream_file_results: bool,
+    pub stream_chunk_size: usize,
+    pub result_compression: ResultCompression,
+    pub result_compression_threshold: u64,
+    pub health_port: Option<u16>,
+    pub admin_port: Option<u16>,
+    pub admin_bind: IpAddr,
+    pub admin_token: Option<String>,
+    pub readiness_max_poll_age: u64,
+    pub retry_max_attempts: u32,
+    pub retry_base_delay_ms: u64,
+    pub retry_max_delay_ms: u64,
+    pub retry_jitter: f64,
+    pub confidence_curve: Vec<(i64, u8)>,
+    pub confidence_suspicious: u8,
+    pub confidence_malicious: u8,
+    pub skip_extensions: Vec<String>,
+    pub skip_media_by_content: bool,
+    pub ignore_paths: Vec<String>,
+    pub max_file_size: u64,
+    pub yara_timeout: u32,
+    pub yara_fast_mode: bool,
+    pub max_matches_per_rule: usize,
+    pub oversized_file_policy: OversizedFilePolicy,
+    pub host_fingerprint: bool,
+    pub host_fingerprint_key: Option<String>,
+    pub region: Option<String>,
+    pub worker_id: Option<String>,
+    pub register_client: bool,
+    pub keepalive_interval: u64,
+    pub user_agent: Option<String>,
+    pub rules_path: Option<PathBuf>,
+    pub offline_jobs_path: PathBuf,
+    pub offline_results_dir: PathBuf,
+    pub max_archive_entries: usize,
+    pub max_files_per_distribution: usize,
+    pub max_extracted_size: u64,
+    pub max_archive_depth: usize,
+    pub max_nested_extracted_size: u64,
+    pub proxy_url: Option<String>,
+    pub no_proxy: Option<String>,
+    pub proxy_username: Option<String>,
+    pub proxy_password: Option<String>,
+    pub ca_bundle_path: Option<PathBuf>,
+    pub client_cert_path: Option<PathBuf>,
+    pub client_key_path: Option<PathBuf>,
+    pub danger_accept_invalid_certs: bool,
+    pub api_http: HttpTuning,
+    pub download_http: HttpTuning,
+    pub log_throttle_window: u64,
+    pub default_rule_weight: i64,
+    pub required_rule_metadata: Vec<String>,
+    pub canary_rule: String,
+    pub rules_include: Vec<String>,
+    pub rules_exclude: Vec<String>,
+    pub stats_path: PathBuf,
+    pub stats_retention_days: u32,
+    pub audit_log: bool,
+    pub audit_log_path: PathBuf,
+    pub audit_log_max_size: u64,
+    pub audit_log_max_files: usize,
+    pub stats_upload_interval: u64,
+    pub result_history_size: usize,
+    pub scoring_strategy: ScoringStrategy,
+    pub filetype_weights: HashMap<String, f64>,
+    pub two_pass_scan: bool,
+    pub deduplicate_files: bool,
+    pub shadow_scan: bool,
+    pub two_pass_fallback: TwoPassFallback,
+    pub entropy_file_threshold: f64,
+    pub entropy_string_threshold: f64,
+    pub entropy_min_string_length: usize,
+    pub job_source: JobSourceKind,
+    pub job_source_path: PathBuf,
+    pub listener_bind: IpAddr,
+    pub listener_port: u16,
+    pub listener_token: Option<String>,
+    pub listener_queue_size: usize,
+    pub result_sinks: Vec<ResultSinkKind>,
+    pub results_file_path: PathBuf,
+    pub s3_endpoint: Option<String>,
+    pub s3_region: String,
+    pub s3_bucket: Option<String>,
+    pub s3_prefix: String,
+    pub s3_access_key_id: Option<String>,
+    pub s3_secret_access_key: Option<String>,
+    pub webhook_url: Option<String>,
+    pub webhook_secret: Option<String>,
+    pub typosquat_score: i64,
+    pub top_packages_url: Option<String>,
+    pub top_packages_refresh_interval: u64,
+    pub top_packages_count: usize,
+    pub max_iocs: usize,
+    pub fuzzy_hashes: bool,
+    pub match_context: usize,
+    pub max_match_snippets: usize,
+    pub max_match_data: usize,
+    pub match_data_budget: usize,
+    pub redact_match_data: bool,
+    pub event_stream: EventStream,
+    pub event_socket_path: PathBuf,
+    pub dry_run: bool,
+    pub dry_run_skip_jobs: bool,
+    pub report_telemetry: bool,
+    pub download_rate_limit: f64,
+    pub max_concurrent_downloads: usize,
+    pub download_resume_attempts: u32,
+    pub verify_digests: bool,
+    pub fetch_pypi_digests: bool,
+    pub pypi_enrichment: bool,
+    pub delta_rules: bool,
+    pub rule_profiling_sample_rate: f64,
+    pub rule_profiling_top: usize,
+    pub rule_profiling_report_interval: u64,
+    pub quarantine_target: QuarantineTarget,
+    pub quarantine_url: Option<String>,
+    pub quarantine_prefix: String,
+    pub quarantine_max_size: u64,
+    pub quarantine_max_files: usize,
+    pub scan_cache_size: usize,
+    pub scan_cache_ttl: u64,
+    pub rule_bundles: Vec<RuleBundle>,
+    pub memory_budget: u64,
+    pub scratch_dir: Option<PathBuf>,
+    pub min_free_disk: u64,
+    pub scratch_orphan_age: u64,
+
+    /// The settings that were read from files, see [`read_setting_files`]
+    #[serde(skip)]
+    pub read_from_files: Vec<String>,
+}
+
+/// The private cache directory of the client: `dragonfly` in `$XDG_CACHE_HOME`, or in `~/.cache`
+fn cache_dir() -> PathBuf {
+    std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir)
+        .join("dragonfly")
 }
 
 impl Default for AppConfig {
+    #[allow(clippy::too_many_lines)]
     fn default() -> Self {
         let available_parallelism = std::thread::available_parallelism()
             .map(usize::from)
@@ -30,30 +437,618 @@ impl Default for AppConfig {
         #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
         AppConfig {
             base_url: String::from("https://dragonfly.vipyrsec.com"),
+            backends: Vec::new(),
+            pypi_url: String::from("https://pypi.org"),
             auth0_domain: String::from("vipyrsec.us.auth0.com"),
             audience: String::from("https://dragonfly.vipyrsec.com"),
             grant_type: String::from("password"),
             client_id: String::new(),
             client_secret: String::new(),
+            client_id_file: None,
+            client_secret_file: None,
+            vault_addr: None,
+            vault_token: None,
+            vault_path: String::from("secret/data/dragonfly"),
+            auth_refresh_margin: 300,
             username: String::new(),
             password: String::new(),
             threads: available_parallelism,
+            scan_threads: 1,
+            low_resource: false,
             bulk_size: 20,
+            priority_poll_interval: 0,
             load_duration: 60,
+            poll_min_interval: 5,
+            poll_jitter: 0.2,
+            breaker_threshold: 5,
+            breaker_max_interval: 900,
+            iteration_timeout: 1800,
             max_scan_size: 1.28e+8 as u64, // 128 MB
+            log_format: LogFormat::Pretty,
+            python_syntax_check: false,
+            ast_analysis: false,
+            binary_analysis: false,
+            rules_cache_dir: cache_dir().join("rules"),
+            rules_cache_size: 4,
+            max_rules_age: 48 * 60 * 60,
+            submit_queue_path: std::env::temp_dir().join("dragonfly-submit-queue.json"),
+            submit_queue_capacity: 64,
+            stream_file_results: false,
+            stream_chunk_size: 500,
+            result_compression: ResultCompression::None,
+            result_compression_threshold: 1024 * 1024,
+            health_port: None,
+            admin_port: None,
+            admin_bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            admin_token: None,
+            readiness_max_poll_age: 600,
+            retry_max_attempts: 4,
+            retry_base_delay_ms: 500,
+            retry_max_delay_ms: 30_000,
+            retry_jitter: 0.5,
+            confidence_curve: vec![(0, 0), (5, 40), (15, 80), (30, 100)],
+            confidence_suspicious: 40,
+            confidence_malicious: 80,
+            skip_extensions: [
+                "so", "pyd", "dylib", "png", "jpg", "jpeg", "gif", "bmp", "ico", "webp", "ttf",
+                "otf", "woff", "woff2", "eot", "mp3", "wav", "ogg", "mp4",
+            ]
+            .map(String::from)
+            .to_vec(),
+            skip_media_by_content: false,
+            ignore_paths: Vec::new(),
+            max_file_size: 64 * 1024 * 1024,
+            yara_timeout: 10,
+            yara_fast_mode: false,
+            max_matches_per_rule: 0,
+            oversized_file_policy: OversizedFilePolicy::Truncate,
+            host_fingerprint: false,
+            host_fingerprint_key: None,
+            region: None,
+            worker_id: None,
+            register_client: false,
+            keepalive_interval: 60,
+            user_agent: None,
+            rules_path: None,
+            offline_jobs_path: PathBuf::from("jobs"),
+            offline_results_dir: PathBuf::from("results"),
+            max_archive_entries: 100_000,
+            max_files_per_distribution: 20_000,
+            max_extracted_size: 2 * 1024 * 1024 * 1024,
+            max_archive_depth: 3,
+            max_nested_extracted_size: 256 * 1024 * 1024,
+            proxy_url: None,
+            no_proxy: None,
+            proxy_username: None,
+            proxy_password: None,
+            ca_bundle_path: None,
+            client_cert_path: None,
+            client_key_path: None,
+            danger_accept_invalid_certs: false,
+            api_http: HttpTuning::default(),
+            download_http: HttpTuning::default(),
+            log_throttle_window: 300,
+            default_rule_weight: 0,
+            required_rule_metadata: vec![String::from("weight")],
+            canary_rule: String::from("canary"),
+            rules_include: Vec::new(),
+            rules_exclude: Vec::new(),
+            stats_path: std::env::temp_dir().join("dragonfly-stats.json"),
+            stats_retention_days: 90,
+            audit_log: true,
+            audit_log_path: std::env::temp_dir().join("dragonfly-audit.jsonl"),
+            audit_log_max_size: 10 * 1024 * 1024,
+            audit_log_max_files: 5,
+            stats_upload_interval: 0,
+            result_history_size: 50,
+            scoring_strategy: ScoringStrategy::Max,
+            filetype_weights: HashMap::new(),
+            two_pass_scan: false,
+            deduplicate_files: true,
+            shadow_scan: false,
+            two_pass_fallback: TwoPassFallback::NoMatches,
+            entropy_file_threshold: 6.0,
+            entropy_string_threshold: 5.2,
+            entropy_min_string_length: 256,
+            job_source: JobSourceKind::Api,
+            job_source_path: PathBuf::from("jobs"),
+            listener_bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            listener_port: 8090,
+            listener_token: None,
+            listener_queue_size: 100,
+            result_sinks: vec![ResultSinkKind::Http],
+            results_file_path: PathBuf::from("results.jsonl"),
+            s3_endpoint: None,
+            s3_region: String::from("us-east-1"),
+            s3_bucket: None,
+            s3_prefix: String::new(),
+            s3_access_key_id: None,
+            s3_secret_access_key: None,
+            webhook_url: None,
+            webhook_secret: None,
+            typosquat_score: 5,
+            top_packages_url: Some(String::from(
+                "https://hugovk.github.io/top-pypi-packages/top-pypi-packages-30-days.min.json",
+            )),
+            top_packages_refresh_interval: 86400,
+            top_packages_count: 1000,
+            max_iocs: 500,
+            fuzzy_hashes: false,
+            match_context: 40,
+            max_match_snippets: 5,
+            max_match_data: 256,
+            match_data_budget: 64 * 1024,
+            redact_match_data: false,
+            event_stream: EventStream::None,
+            event_socket_path: std::env::temp_dir().join("dragonfly-events.sock"),
+            dry_run: false,
+            dry_run_skip_jobs: false,
+            report_telemetry: false,
+            download_rate_limit: 0.0,
+            max_concurrent_downloads: 0,
+            download_resume_attempts: 3,
+            verify_digests: true,
+            fetch_pypi_digests: false,
+            pypi_enrichment: false,
+            delta_rules: true,
+            rule_profiling_sample_rate: 0.0,
+            rule_profiling_top: 10,
+            rule_profiling_report_interval: 3600,
+            quarantine_target: QuarantineTarget::None,
+            quarantine_url: None,
+            quarantine_prefix: String::from("quarantine/"),
+            quarantine_max_size: 1024 * 1024,
+            quarantine_max_files: 10,
+            scan_cache_size: 256,
+            scan_cache_ttl: 900,
+            rule_bundles: Vec::new(),
+            memory_budget: 0,
+            scratch_dir: None,
+            min_free_disk: 0,
+            scratch_orphan_age: 3600,
+            read_from_files: Vec::new(),
         }
     }
 }
 
+/// The files the configuration is read from, the later ones overriding the earlier ones
+const CONFIG_FILES: [&str; 2] = ["Config.toml", "Config-dev.toml"];
+
+/// Settings that are only read on startup, so changing them takes a restart
+const STARTUP_ONLY: &[&str] = &[
+    "base_url",
+    "backends",
+    "client_id",
+    "client_secret",
+    "client_id_file",
+    "client_secret_file",
+    "vault_addr",
+    "vault_token",
+    "vault_path",
+    "auth_refresh_margin",
+    "audience",
+    "grant_type",
+    "username",
+    "password",
+    "threads",
+    "download_rate_limit",
+    "max_concurrent_downloads",
+    "rule_profiling_sample_rate",
+    "scan_cache_size",
+    "scan_cache_ttl",
+    "memory_budget",
+    "scratch_orphan_age",
+    "log_format",
+    "log_throttle_window",
+    "health_port",
+    "admin_port",
+    "admin_bind",
+    "admin_token",
+    "rules_cache_dir",
+    "rules_cache_size",
+    "submit_queue_path",
+    "submit_queue_capacity",
+    "result_history_size",
+    "stats_path",
+    "stats_retention_days",
+    "audit_log",
+    "audit_log_path",
+    "audit_log_max_size",
+    "audit_log_max_files",
+    "host_fingerprint",
+    "host_fingerprint_key",
+    "region",
+    "worker_id",
+    "register_client",
+    "user_agent",
+    "proxy_url",
+    "no_proxy",
+    "proxy_username",
+    "proxy_password",
+    "ca_bundle_path",
+    "client_cert_path",
+    "client_key_path",
+    "danger_accept_invalid_certs",
+    "api_http",
+    "download_http",
+    "job_source",
+    "job_source_path",
+    "listener_bind",
+    "listener_port",
+    "listener_token",
+    "listener_queue_size",
+    "result_sinks",
+    "results_file_path",
+    "s3_endpoint",
+    "s3_region",
+    "s3_bucket",
+    "s3_prefix",
+    "s3_access_key_id",
+    "s3_secret_access_key",
+    "webhook_url",
+    "webhook_secret",
+    "event_stream",
+    "event_socket_path",
+];
+
+/// Settings that hold credentials, and are never printed
+const SECRETS: &[&str] = &[
+    "client_secret",
+    "password",
+    "proxy_password",
+    "s3_secret_access_key",
+    "webhook_secret",
+    "host_fingerprint_key",
+    "admin_token",
+    "listener_token",
+    "vault_token",
+];
+
 impl AppConfig {
     pub fn build() -> Result<AppConfig, figment::Error> {
-        Figment::from(Serialized::defaults(AppConfig::default()))
-            .merge(Toml::file("Config.toml"))
-            .merge(Toml::file("Config-dev.toml"))
-            .merge(Env::prefixed("DRAGONFLY_"))
-            .extract()
+        let [config, dev_config] = CONFIG_FILES;
+        let figment = Figment::from(Serialized::defaults(AppConfig::default()))
+            .merge(Toml::file(config))
+            .merge(Toml::file(dev_config))
+            .merge(Env::prefixed("DRAGONFLY_"));
+        let (figment, read_from_files) = read_setting_files(figment)?;
+
+        let mut config: AppConfig = figment.extract()?;
+        config.read_from_files = read_from_files;
+        Ok(config.with_profile())
+    }
+
+    /// Apply the `low_resource` profile, if it's selected: scan the files of a distribution on a
+    /// single thread, poll at most every 5 minutes (even right after finding jobs), scan at most
+    /// 16 MiB of every file, and don't hold nested archives in memory. Values that are already more
+    /// conservative are kept.
+    fn with_profile(mut self) -> Self {
+        if self.low_resource {
+            self.scan_threads = 1;
+            self.load_duration = self.load_duration.max(300);
+            self.poll_min_interval = self.poll_min_interval.max(300);
+            self.max_file_size = self.max_file_size.min(16 * 1024 * 1024);
+            self.max_archive_depth = 0;
+        }
+        self
     }
+
+    /// The configuration as TOML, with the values of the [`SECRETS`] and of the settings read from
+    /// files that are set replaced
+    pub fn redacted(&self) -> Result<String, toml::ser::Error> {
+        let mut table = toml::Table::try_from(self)?;
+        let secrets = SECRETS
+            .iter()
+            .copied()
+            .chain(self.read_from_files.iter().map(String::as_str));
+        for secret in secrets {
+            redact(&mut table, secret);
+        }
+        if let Some(toml::Value::Array(backends)) = table.get_mut("backends") {
+            for backend in backends.iter_mut().filter_map(toml::Value::as_table_mut) {
+                redact(backend, "client_secret");
+            }
+        }
+
+        toml::to_string(&table)
+    }
+
+    /// The backends to take jobs from: the configured `backends`, or a single `default` one made of
+    /// the top level settings
+    pub fn api_backends(&self) -> Result<Vec<Backend>, figment::Error> {
+        if self.backends.is_empty() {
+            return Ok(vec![Backend::from_config(self)]);
+        }
+
+        for (index, backend) in self.backends.iter().enumerate() {
+            if backend.weight == 0 {
+                return Err(format!("backend {} has a weight of 0", backend.name).into());
+            }
+            if self.backends[..index]
+                .iter()
+                .any(|other| other.name == backend.name)
+            {
+                return Err(format!("backend {} is configured twice", backend.name).into());
+            }
+        }
+        if self.backends.len() > 1 && self.job_source != JobSourceKind::Api {
+            return Err("several backends can only be served with the api job source".into());
+        }
+
+        Ok(self.backends.clone())
+    }
+
+    /// `path`, suffixed with the name of `backend` if `backends` are configured, so that every
+    /// backend keeps its own file
+    pub fn backend_path(&self, path: &Path, backend: &Backend) -> PathBuf {
+        if self.backends.is_empty() {
+            return path.to_owned();
+        }
+        let mut path = OsString::from(path.as_os_str());
+        path.push(format!(".{}", backend.name));
+        PathBuf::from(path)
+    }
+
+    /// The limits every distribution archive is extracted under
+    pub fn extraction_limits(&self) -> Limits {
+        Limits {
+            max_entries: self.max_archive_entries,
+            max_total_size: self.max_extracted_size,
+        }
+    }
+}
+
+/// Replace the `key` of `table` if it's a non-empty string
+fn redact(table: &mut toml::Table, key: &str) {
+    if let Some(value) = table.get_mut(key) {
+        if value.as_str().is_some_and(|value| !value.is_empty()) {
+            *value = toml::Value::from("<redacted>");
+        }
+    }
+}
+
+/// Set every setting `name` for which a `name_file` is given to the contents of that file, without
+/// its trailing newline, the way Kubernetes and Docker mount secrets. Only string settings can be
+/// read from files.
+///
+/// Returns the names of the settings that were read from files.
+fn read_setting_files(figment: Figment) -> Result<(Figment, Vec<String>), figment::Error> {
+    let settings: Dict = figment.extract()?;
+    let mut figment = figment;
+    let mut read_from_files = Vec::new();
+
+    for (key, value) in &settings {
+        let Some(name) = key.strip_suffix("_file") else {
+            continue;
+        };
+        let Some(path) = value.as_str().filter(|_| settings.contains_key(name)) else {
+            continue;
+        };
+
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {key} at {path}: {err}"))?;
+        figment = figment.merge((name, contents.trim_end_matches(['\r', '\n'])));
+        read_from_files.push(name.to_owned());
+    }
+
+    Ok((figment, read_from_files))
+}
+
+/// The names of the settings that differ between `old` and `new`
+fn changed_settings(old: &AppConfig, new: &AppConfig) -> Vec<String> {
+    let (Ok(serde_json::Value::Object(old)), Ok(serde_json::Value::Object(new))) =
+        (serde_json::to_value(old), serde_json::to_value(new))
+    else {
+        return Vec::new();
+    };
+
+    new.into_iter()
+        .filter(|(key, value)| old.get(key) != Some(value))
+        .map(|(key, _)| key)
+        .collect()
 }
 
-/// The global, immutable application configuration.
-pub static APP_CONFIG: Lazy<AppConfig> = Lazy::new(|| AppConfig::build().unwrap());
+/// A handle to the current configuration, which can be replaced while the client is running.
+///
+/// Settings are read from [`ConfigHandle::load`], which sees the configuration as it is when
+/// called. Anything that needs the same configuration for longer (like the skipped extensions of
+/// a [`crate::scanner`] filter) copies it, or keeps a [`ConfigHandle::current`] snapshot. A
+/// replaced configuration is dropped once the last snapshot of it is.
+pub struct ConfigHandle(ArcSwap<AppConfig>);
+
+impl ConfigHandle {
+    fn new(config: AppConfig) -> Self {
+        Self(ArcSwap::from_pointee(config))
+    }
+
+    /// The current configuration, to read settings from. It's only held briefly, see
+    /// [`ConfigHandle::current`] to keep it.
+    pub fn load(&self) -> Guard<Arc<AppConfig>> {
+        self.0.load()
+    }
+
+    /// Get a snapshot of the current configuration. Replacing the configuration doesn't affect it.
+    pub fn current(&self) -> Arc<AppConfig> {
+        self.0.load_full()
+    }
+
+    /// Replace the configuration by `config`, returning the names of the settings that changed
+    fn replace(&self, config: AppConfig) -> Vec<String> {
+        let changed = changed_settings(&self.load(), &config);
+        if !changed.is_empty() {
+            self.0.store(Arc::new(config));
+        }
+        changed
+    }
+
+    /// Read the configuration again, and replace the current one by it.
+    ///
+    /// Returns the names of the settings that changed, and those of them that only take effect
+    /// after a restart. The current configuration is kept if the new one can't be read.
+    pub fn reload(&self) -> Result<(Vec<String>, Vec<String>), figment::Error> {
+        let changed = self.replace(AppConfig::build()?);
+        let startup_only = changed
+            .iter()
+            .filter(|setting| STARTUP_ONLY.contains(&setting.as_str()))
+            .cloned()
+            .collect();
+        Ok((changed, startup_only))
+    }
+}
+
+/// Tells when the configuration files have changed since it was last asked, by their modification
+/// times. The environment of a process can't change, so only the files are watched.
+pub struct ConfigWatcher {
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl ConfigWatcher {
+    pub fn new() -> Self {
+        Self {
+            modified: Self::modification_times(),
+        }
+    }
+
+    fn modification_times() -> Vec<Option<SystemTime>> {
+        CONFIG_FILES
+            .iter()
+            .map(|file| Path::new(file).metadata().and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    /// Whether a configuration file was created, changed or removed since the last call
+    pub fn changed(&mut self) -> bool {
+        let modified = Self::modification_times();
+        let changed = modified != self.modified;
+        self.modified = modified;
+        changed
+    }
+}
+
+/// The global application configuration, reloaded at the top of an iteration of the job loop
+/// whenever one of the configuration files changes.
+pub static APP_CONFIG: Lazy<ConfigHandle> =
+    Lazy::new(|| ConfigHandle::new(AppConfig::build().unwrap()));
+
+#[cfg(test)]
+mod tests {
+    use super::{read_setting_files, AppConfig, Backend, ConfigHandle};
+    use figment::{providers::Serialized, Figment};
+
+    #[test]
+    fn low_resource_profile_keeps_conservative_values() {
+        let config = AppConfig {
+            low_resource: true,
+            scan_threads: 4,
+            load_duration: 600,
+            ..AppConfig::default()
+        }
+        .with_profile();
+
+        assert_eq!(config.scan_threads, 1);
+        assert_eq!(config.load_duration, 600);
+        assert_eq!(config.max_file_size, 16 * 1024 * 1024);
+        assert_eq!(config.max_archive_depth, 0);
+
+        let config = AppConfig::default().with_profile();
+        assert_eq!(
+            config.max_archive_depth,
+            AppConfig::default().max_archive_depth
+        );
+    }
+
+    #[test]
+    fn redacts_secrets() {
+        let config = AppConfig {
+            client_secret: String::from("hunter2"),
+            proxy_password: Some(String::from("hunter3")),
+            username: String::from("scanner"),
+            ..AppConfig::default()
+        };
+        let redacted = config.redacted().unwrap();
+
+        assert!(!redacted.contains("hunter"));
+        assert!(redacted.contains(r#"client_secret = "<redacted>""#));
+        assert!(redacted.contains(r#"username = "scanner""#));
+        // unset secrets aren't made to look set
+        assert!(redacted.contains(r#"password = """#));
+    }
+
+    #[test]
+    fn validates_backends() {
+        let backend = |name: &str, weight| Backend {
+            name: name.to_owned(),
+            base_url: format!("https://{name}.example.com"),
+            auth0_domain: None,
+            audience: None,
+            client_id: Some(String::from("id")),
+            client_secret: Some(String::from("hunter2")),
+            weight,
+        };
+
+        let backends = AppConfig::default().api_backends().unwrap();
+        assert_eq!(backends.len(), 1);
+        assert_eq!(backends[0].name, "default");
+        assert_eq!(backends[0].base_url, AppConfig::default().base_url);
+
+        let config = AppConfig {
+            backends: vec![backend("prod", 3), backend("staging", 1)],
+            ..AppConfig::default()
+        };
+        assert_eq!(config.api_backends().unwrap(), config.backends);
+        assert!(!config.redacted().unwrap().contains("hunter2"));
+
+        for backends in [
+            vec![backend("prod", 0)],
+            vec![backend("prod", 1), backend("prod", 2)],
+        ] {
+            let config = AppConfig {
+                backends,
+                ..AppConfig::default()
+            };
+            assert!(config.api_backends().is_err());
+        }
+    }
+
+    #[test]
+    fn reads_settings_from_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("client_secret");
+        std::fs::write(&secret, "hunter2\n").unwrap();
+
+        let figment = Figment::from(Serialized::defaults(AppConfig::default()))
+            .merge(("client_secret_file", &secret))
+            .merge(("username_file", dir.path().join("username")));
+        assert!(read_setting_files(figment).is_err());
+
+        let figment = Figment::from(Serialized::defaults(AppConfig::default()))
+            .merge(("client_secret_file", &secret))
+            .merge(("client_id", "dragonfly"));
+        let (figment, read_from_files) = read_setting_files(figment).unwrap();
+        let mut config: AppConfig = figment.extract().unwrap();
+        config.read_from_files = read_from_files;
+
+        assert_eq!(config.client_secret, "hunter2");
+        assert_eq!(config.client_id, "dragonfly");
+        assert_eq!(config.read_from_files, ["client_secret"]);
+        assert!(!config.redacted().unwrap().contains("hunter2"));
+    }
+
+    #[test]
+    fn replaces_the_configuration() {
+        let handle = ConfigHandle::new(AppConfig::default());
+        let before = handle.current();
+
+        assert!(handle.replace(AppConfig::default()).is_empty());
+        let changed = handle.replace(AppConfig {
+            load_duration: 5,
+            rules_exclude: vec![String::from("noisy")],
+            ..AppConfig::default()
+        });
+
+        assert_eq!(changed, ["load_duration", "rules_exclude"]);
+        assert_eq!(handle.load().load_duration, 5);
+        // the snapshot taken before is unaffected
+        assert_eq!(before.load_duration, AppConfig::default().load_duration);
+    }
+}
@@ -0,0 +1,235 @@
+//! A local audit trail of the jobs this client completed.
+//!
+//! Every scanned job is appended to `audit_log_path` as one JSON object per line, whether or not
+//! its results ever make it to the API, so operators can reconstruct what a client did. Once the
+//! log would grow past `audit_log_max_size` bytes it's rotated: the current file becomes `.1`, the
+//! previous `.1` becomes `.2`, and so on, keeping at most `audit_log_max_files` rotated files.
+
+use std::{
+    ffi::OsString,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use color_eyre::Result;
+use serde::Serialize;
+
+use crate::{
+    app_config::AppConfig,
+    client::{Job, ScanResult},
+    scanner::Verdict,
+};
+
+/// How a job ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Scanned,
+    Failed,
+}
+
+/// A line of the audit log
+#[derive(Debug, Serialize)]
+pub struct Entry<'a> {
+    pub timestamp: DateTime<Utc>,
+
+    /// The backend the job was taken from
+    pub backend: &'a str,
+
+    pub name: &'a str,
+    pub version: &'a str,
+    pub rules_hash: &'a str,
+    pub outcome: Outcome,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verdict: Option<Verdict>,
+
+    /// The rules matched by the package
+    pub rules: &'a [String],
+
+    pub duration_ms: u128,
+
+    /// Why the job failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'a str>,
+}
+
+impl<'a> Entry<'a> {
+    /// The entry of `job` from `backend`, scanned with the ruleset `rules_hash` in `duration`
+    pub fn new(
+        job: &'a Job,
+        backend: &'a str,
+        rules_hash: &'a str,
+        scan_result: &'a ScanResult,
+        duration: Duration,
+    ) -> Self {
+        let mut entry = Self {
+            timestamp: Utc::now(),
+            backend,
+            name: &job.name,
+            version: &job.version,
+            rules_hash,
+            outcome: Outcome::Scanned,
+            score: None,
+            verdict: None,
+            rules: &[],
+            duration_ms: duration.as_millis(),
+            reason: None,
+        };
+        match scan_result {
+            Ok(body) => {
+                entry.score = Some(body.score);
+                entry.verdict = Some(body.verdict);
+                entry.rules = &body.rules_matched;
+            }
+            Err(err) => {
+                entry.outcome = Outcome::Failed;
+                entry.reason = Some(&err.reason);
+            }
+        }
+        entry
+    }
+}
+
+/// A JSON Lines file rotated by size
+pub struct AuditLog {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl AuditLog {
+    pub fn new(path: impl Into<PathBuf>, max_size: u64, max_files: usize) -> Self {
+        Self {
+            path: path.into(),
+            max_size,
+            max_files,
+        }
+    }
+
+    /// The audit log configured in `config`, `None` if it's disabled
+    pub fn from_config(config: &AppConfig) -> Option<Self> {
+        config.audit_log.then(|| {
+            Self::new(
+                &config.audit_log_path,
+                config.audit_log_max_size,
+                config.audit_log_max_files,
+            )
+        })
+    }
+
+    /// Append `entry` to the log, rotating it first if it would grow too large
+    pub fn append(&self, entry: &Entry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let size = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == ErrorKind::NotFound => 0,
+            Err(err) => return Err(err.into()),
+        };
+        if size > 0 && size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // a single write, so concurrent readers never see half a line
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&line)?;
+
+        Ok(())
+    }
+
+    /// The path of the `index`th rotated file, 0 being the current one
+    fn rotated(&self, index: usize) -> PathBuf {
+        if index == 0 {
+            return self.path.clone();
+        }
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(format!(".{index}"));
+        PathBuf::from(path)
+    }
+
+    /// Shift every file to the next index, dropping the oldest one past `max_files`
+    fn rotate(&self) -> Result<()> {
+        remove_if_exists(&self.rotated(self.max_files))?;
+        for index in (0..self.max_files).rev() {
+            let from = self.rotated(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated(index + 1))?;
+            }
+        }
+        // without any rotated files, the current one is dropped
+        remove_if_exists(&self.path)
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuditLog, Entry, Outcome};
+    use chrono::{TimeZone, Utc};
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn entry(name: &str) -> Entry<'_> {
+        Entry {
+            timestamp: Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap(),
+            backend: "default",
+            name,
+            version: "1.0",
+            rules_hash: "abc",
+            outcome: Outcome::Failed,
+            score: None,
+            verdict: None,
+            rules: &[],
+            duration_ms: 12,
+            reason: Some("no distributions"),
+        }
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let line_size = serde_json::to_vec(&entry("a")).unwrap().len() as u64 + 1;
+        let log = AuditLog::new(&path, line_size * 2, 1);
+
+        for name in ["a", "b", "c", "d", "e"] {
+            log.append(&entry(name)).unwrap();
+        }
+
+        let names = |path: std::path::PathBuf| {
+            fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+                .map(|line| line["name"].as_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(path.clone()), ["e"]);
+        assert_eq!(names(dir.path().join("audit.jsonl.1")), ["c", "d"]);
+        assert!(!dir.path().join("audit.jsonl.2").exists());
+
+        let line = fs::read_to_string(&path).unwrap();
+        assert!(line.contains(r#""outcome":"failed""#));
+        assert!(line.contains(r#""reason":"no distributions""#));
+    }
+}
@@ -0,0 +1,132 @@
+//! A circuit breaker around the requests to a backend, so a client doesn't hammer an API that's
+//! down and fill the logs with the same error.
+//!
+//! After `breaker_threshold` job fetches or result submissions in a row fail, the circuit opens:
+//! the backend isn't sent any requests for `load_duration`. The next request then tries the API
+//! again. If it fails too, the circuit opens again for twice as long, up to `breaker_max_interval`,
+//! and once a request succeeds the circuit closes. Only opening and closing the circuit are
+//! logged, not every failed request while it's open.
+
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::app_config::AppConfig;
+
+/// A change of the state of a [`Breaker`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum Transition {
+    /// No requests are made for `wait`, after `failures` failed in a row
+    Opened {
+        failures: u32,
+        #[serde(rename = "wait_secs", serialize_with = "as_secs")]
+        wait: Duration,
+    },
+
+    /// Requests succeed again
+    Closed,
+}
+
+fn as_secs<S: serde::Serializer>(wait: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(wait.as_secs())
+}
+
+/// The circuit breaker of a backend, see the module docs
+#[derive(Debug, Default)]
+pub struct Breaker {
+    /// How many requests in a row failed
+    failures: u32,
+
+    /// How many times in a row the circuit opened, 0 while it's closed
+    trips: u32,
+}
+
+impl Breaker {
+    /// Whether the circuit is open, or only trying the API again after being open
+    pub fn is_open(&self) -> bool {
+        self.trips > 0
+    }
+
+    /// Record a request that succeeded, closing the circuit if it was open
+    pub fn succeeded(&mut self) -> Option<Transition> {
+        let was_open = self.is_open();
+        self.failures = 0;
+        self.trips = 0;
+        was_open.then_some(Transition::Closed)
+    }
+
+    /// Record a request that failed, opening the circuit after `breaker_threshold` failures in a
+    /// row, or right away if it was already open. A threshold of 0 never opens it.
+    pub fn failed(&mut self, config: &AppConfig) -> Option<Transition> {
+        self.failures = self.failures.saturating_add(1);
+        if config.breaker_threshold == 0
+            || (!self.is_open() && self.failures < config.breaker_threshold)
+        {
+            return None;
+        }
+
+        self.trips = self.trips.saturating_add(1);
+        Some(Transition::Opened {
+            failures: self.failures,
+            wait: self.wait(config),
+        })
+    }
+
+    /// How long the circuit stays open this time, doubling from `load_duration`
+    fn wait(&self, config: &AppConfig) -> Duration {
+        let max = Duration::from_secs(config.breaker_max_interval);
+        Duration::from_secs(config.load_duration)
+            .saturating_mul(2_u32.saturating_pow(self.trips.saturating_sub(1)))
+            .min(max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Breaker, Transition};
+    use crate::app_config::AppConfig;
+    use std::time::Duration;
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let config = AppConfig {
+            breaker_threshold: 3,
+            breaker_max_interval: 300,
+            load_duration: 60,
+            ..AppConfig::default()
+        };
+        let mut breaker = Breaker::default();
+
+        assert_eq!(breaker.failed(&config), None);
+        assert_eq!(breaker.succeeded(), None);
+        assert_eq!(breaker.failed(&config), None);
+        assert_eq!(breaker.failed(&config), None);
+        assert!(!breaker.is_open());
+
+        let waits = (0..4)
+            .map(|_| match breaker.failed(&config) {
+                Some(Transition::Opened { wait, .. }) => wait.as_secs(),
+                other => panic!("expected the circuit to open, got {other:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(waits, [60, 120, 240, 300]);
+        assert!(breaker.is_open());
+
+        assert_eq!(breaker.succeeded(), Some(Transition::Closed));
+        assert_eq!(breaker.failed(&config), None);
+
+        let never = AppConfig {
+            breaker_threshold: 0,
+            ..config
+        };
+        assert!((0..100).all(|_| breaker.failed(&never).is_none()));
+
+        let event = serde_json::to_string(&Transition::Opened {
+            failures: 5,
+            wait: Duration::from_secs(60),
+        })
+        .unwrap();
+        assert_eq!(event, r#"{"state":"opened","failures":5,"wait_secs":60}"#);
+    }
+}
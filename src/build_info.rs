@@ -0,0 +1,82 @@
+//! Information about how this binary was built, embedded by `build.rs`.
+
+use std::fmt::{self, Display};
+
+use chrono::DateTime;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+/// Identifies the build of a running client
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    /// The version of the crate
+    pub version: &'static str,
+
+    /// The abbreviated hash of the commit the client was built from, or `unknown`
+    pub git_commit: &'static str,
+
+    /// When the client was built, as an RFC 3339 timestamp
+    pub build_timestamp: String,
+
+    /// The version of libyara the client was built against, or `unknown`
+    pub yara_version: &'static str,
+
+    /// The cargo features the client was built with
+    pub features: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    fn new() -> Self {
+        let build_timestamp = env!("DRAGONFLY_BUILD_TIMESTAMP")
+            .parse()
+            .ok()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0))
+            .map(|timestamp| timestamp.to_rfc3339())
+            .unwrap_or_default();
+
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: env!("DRAGONFLY_GIT_COMMIT"),
+            build_timestamp,
+            yara_version: env!("DRAGONFLY_YARA_VERSION"),
+            features: env!("DRAGONFLY_FEATURES")
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .collect(),
+        }
+    }
+
+    /// The version and commit of the client, like `0.1.0+abc1234`, sent along with results
+    pub fn client_version(&self) -> String {
+        format!("{}+{}", self.version, self.git_commit)
+    }
+
+    /// A multi-line description of the build, for `--version --verbose`
+    pub fn verbose(&self) -> String {
+        let features = if self.features.is_empty() {
+            String::from("none")
+        } else {
+            self.features.join(", ")
+        };
+
+        format!(
+            "{self}\ncommit: {}\nbuilt: {}\nyara: {}\nfeatures: {features}",
+            self.git_commit, self.build_timestamp, self.yara_version
+        )
+    }
+}
+
+impl Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({})",
+            env!("CARGO_PKG_NAME"),
+            self.version,
+            self.git_commit
+        )
+    }
+}
+
+/// The build information of the running binary
+pub static BUILD_INFO: Lazy<BuildInfo> = Lazy::new(BuildInfo::new);
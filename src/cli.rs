@@ -0,0 +1,169 @@
+//! The command line interface.
+//!
+//! Without a command, the client runs the job loop, like with `run`.
+
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::extract::ArchiveKind;
+
+#[derive(Debug, Parser)]
+#[command(about, disable_version_flag = true)]
+pub struct Cli {
+    /// Print the version and exit
+    #[arg(short = 'V', long)]
+    pub version: bool,
+
+    /// With --version, also print how the binary was built
+    #[arg(short, long, requires = "version")]
+    pub verbose: bool,
+
+    /// Scan local jobs against local rules instead of running the job loop, see `run --offline`
+    #[arg(long, hide = true)]
+    pub offline: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Fetch, scan, and submit jobs forever
+    Run {
+        /// Scan the jobs at `offline_jobs_path` against the rules at `rules_path`, and write the
+        /// results to `offline_results_dir`, without the API
+        #[arg(long)]
+        offline: bool,
+
+        /// Fetch and scan jobs, but only log their results instead of submitting them, like
+        /// `dry_run`
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Scan a local distribution archive against the current ruleset and print the results
+    Scan {
+        /// The archive to scan, `-` for stdin
+        target: PathBuf,
+
+        /// The type of the archive, guessed from its file name by default (tar.gz for stdin)
+        #[arg(long = "type", value_enum)]
+        kind: Option<Kind>,
+    },
+
+    /// Scan a distribution archive piped into stdin, like `scan -`
+    #[command(hide = true)]
+    ScanStdin {
+        #[arg(value_enum, default_value = "tar.gz")]
+        kind: Kind,
+    },
+
+    /// Scan a release straight from the package index and print the results
+    FetchAndScan {
+        name: String,
+        version: Option<String>,
+
+        /// Also submit the results to the configured result sinks
+        #[arg(long)]
+        submit: bool,
+    },
+
+    /// Submit the most recently submitted results of every backend again
+    Resend {
+        /// How many of the last results to submit again
+        #[arg(long, default_value_t = 1)]
+        last: usize,
+    },
+
+    /// Print the per-day statistics of the scanned jobs of every backend
+    Stats,
+
+    /// Manage the ruleset
+    #[command(subcommand)]
+    Rules(RulesCommand),
+
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RulesCommand {
+    /// Fetch and compile the ruleset from the API, even if a compiled copy is cached
+    Pull,
+
+    /// List the rules of the current ruleset, with their weights
+    Show,
+
+    /// Check the current ruleset for malformed `weight` and `filetype` metadata, identifiers
+    /// defined in several rule files, missing metadata, and rules that `rules_include` and
+    /// `rules_exclude` list but it doesn't have
+    Lint,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Print the effective configuration, with secrets redacted
+    Validate,
+}
+
+/// The type of an archive given on the command line
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Kind {
+    #[value(name = "tar.gz")]
+    TarGz,
+    #[value(name = "tar.zst")]
+    TarZst,
+    Zip,
+}
+
+impl From<Kind> for ArchiveKind {
+    fn from(kind: Kind) -> Self {
+        match kind {
+            Kind::TarGz => Self::TarGz,
+            Kind::TarZst => Self::TarZst,
+            Kind::Zip => Self::Zip,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cli, Command, Kind, RulesCommand};
+    use clap::{CommandFactory, Parser};
+
+    #[test]
+    fn parses_commands() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::parse_from(["dragonfly-client-rs"]);
+        assert!(cli.command.is_none() && !cli.version);
+        let cli = Cli::parse_from(["dragonfly-client-rs", "-V", "-v"]);
+        assert!(cli.version && cli.verbose);
+
+        let cli = Cli::parse_from(["dragonfly-client-rs", "scan", "-", "--type", "tar.zst"]);
+        assert!(matches!(
+            cli.command,
+            Some(Command::Scan {
+                kind: Some(Kind::TarZst),
+                ..
+            })
+        ));
+        let cli = Cli::parse_from(["dragonfly-client-rs", "rules", "lint"]);
+        assert!(matches!(
+            cli.command,
+            Some(Command::Rules(RulesCommand::Lint))
+        ));
+        let cli = Cli::parse_from(["dragonfly-client-rs", "fetch-and-scan", "pkg", "--submit"]);
+        assert!(matches!(
+            cli.command,
+            Some(Command::FetchAndScan {
+                version: None,
+                submit: true,
+                ..
+            })
+        ));
+        assert!(Cli::try_parse_from(["dragonfly-client-rs", "resend", "--last", "x"]).is_err());
+    }
+}
@@ -1,16 +1,60 @@
+pub mod bundles;
+mod compression;
+mod http;
+mod integrity;
 mod methods;
 mod models;
-
+mod rate_limit;
+mod resume;
+mod retry;
+mod rules_cache;
+mod staleness;
+mod submit_queue;
+mod token_refresh;
+mod vault;
+
+use arc_swap::ArcSwap;
 use chrono::{DateTime, TimeDelta, Utc};
-use flate2::read::GzDecoder;
+use integrity::Hashing;
 pub use methods::*;
 pub use models::*;
-use tempfile::{tempdir, tempfile, TempDir};
-
-use color_eyre::Result;
-use reqwest::{blocking::Client, Url};
-use std::{io, time::Duration};
-use tracing::{error, info, trace, warn};
+use resume::Resumable;
+use rules_cache::RulesCache;
+use serde::Serialize;
+pub use staleness::Staleness;
+pub use submit_queue::{idempotency_key, FileResultsStream, Submission, SubmitQueue};
+use tempfile::TempDir;
+use token_refresh::TokenRefresher;
+
+use color_eyre::{eyre::eyre, Result};
+use reqwest::{
+    blocking::{Client, Response},
+    StatusCode, Url,
+};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, info, trace, warn};
+
+use crate::{
+    app_config::Backend,
+    deadline::Deadline,
+    disk::{self, DISK},
+    extract::{self, ArchiveKind, Extractor, Tar, Zip},
+    memory::{self, Reservation, MEMORY},
+    scanner::{
+        canary_self_test, profile_ruleset, report_missing_metadata, report_unknown_rules, Shadow,
+    },
+    APP_CONFIG,
+};
 
 pub struct AuthState {
     pub access_token: String,
@@ -20,45 +64,157 @@ pub struct AuthState {
 pub struct RulesState {
     pub rules: yara::Rules,
     pub hash: String,
+
+    /// The `ETag` the ruleset was served with, if it was fetched from the API in full
+    pub etag: Option<String>,
+
+    /// The source of each rule file, by name, to apply deltas to. Empty for rulesets that weren't
+    /// fetched from the API.
+    pub sources: Arc<HashMap<String, String>>,
+
+    /// The rule files compiled with yara-x, with `shadow_scan`, see [`Shadow`]
+    pub shadow: Option<Arc<Shadow>>,
+}
+
+impl RulesState {
+    /// Wrap a freshly loaded ruleset, logging the rules that are missing required metadata and
+    /// the included or excluded rules it doesn't have
+    pub fn new(rules: yara::Rules, hash: String) -> Self {
+        report_missing_metadata(&rules, &hash, &APP_CONFIG.load().required_rule_metadata);
+        report_unknown_rules(&rules, &hash);
+        Self {
+            rules,
+            hash,
+            etag: None,
+            sources: Arc::default(),
+            shadow: None,
+        }
+    }
 }
 
-#[warn(clippy::module_name_repetitions)]
+/// A copy-on-write handle to the current ruleset.
+///
+/// Scans take a snapshot of the ruleset with [`RulesHandle::current`] and keep using it until they
+/// finish, even if the ruleset is replaced in the meantime. Replacing the ruleset never blocks on,
+/// or waits for, in-flight scans; the old ruleset is dropped once the last snapshot of it is.
+///
+/// Only rulesets that compiled and passed the canary self-test are installed, so the current one
+/// is always the last good one to fall back on.
+pub struct RulesHandle(ArcSwap<RulesState>);
+
+impl RulesHandle {
+    pub fn new(state: RulesState) -> Self {
+        Self(ArcSwap::from_pointee(state))
+    }
+
+    /// Get a snapshot of the current ruleset
+    pub fn current(&self) -> Arc<RulesState> {
+        self.0.load_full()
+    }
+
+    /// Replace the current ruleset. Existing snapshots are unaffected.
+    pub fn replace(&self, state: RulesState) {
+        self.0.store(Arc::new(state));
+    }
+}
+
+/// The outcome of fetching the next batch of jobs in the background, see
+/// [`DragonflyClient::prefetch_jobs`]
+pub struct Prefetched {
+    pub jobs: reqwest::Result<Vec<Job>>,
+
+    /// The compiled ruleset the first job asks for, if it's different from the current one and
+    /// could be fetched and compiled
+    pub rules: Option<RulesState>,
+}
+
+#[allow(clippy::module_name_repetitions)]
 pub struct DragonflyClient {
+    /// Talks to the API, tuned with `api_http`
     pub client: Client,
+
+    /// Downloads distributions and talks to PyPI, tuned with `download_http`
+    pub download_client: Client,
+
+    /// The API jobs are taken from and results are sent to
+    pub backend: Backend,
+
     pub authentication_state: AuthState,
-    pub rules_state: RulesState,
+    pub rules_state: RulesHandle,
+    pub staleness: Staleness,
+
+    /// The worker ID the backend assigned to this client, once registered, see
+    /// [`DragonflyClient::register`]
+    pub worker_id: Option<String>,
+
+    /// Renews the access token ahead of its expiry, once started
+    token_refresher: Option<TokenRefresher>,
 }
 
 impl DragonflyClient {
+    /// A client of the first of the configured backends, see
+    /// [`crate::app_config::AppConfig::api_backends`]
     pub fn new() -> Result<Self> {
-        let client = Client::builder().gzip(true).build()?;
+        let backend = APP_CONFIG.load().api_backends()?.swap_remove(0);
+        Self::for_backend(backend)
+    }
+
+    /// A client of `backend`, authenticated and with its current ruleset
+    pub fn for_backend(backend: Backend) -> Result<Self> {
+        let client = http::build_client(&APP_CONFIG.load())?;
+        let download_client = http::build_download_client(&APP_CONFIG.load())?;
 
-        let auth_response = fetch_access_token(&client)?;
-        let rules_response = fetch_rules(&client, &auth_response.access_token)?;
+        let auth_response = fetch_access_token(&client, &backend)?;
+        let rules_state = prepare_rules(&client, &backend, &auth_response.access_token, true)?;
+        profile_ruleset(&rules_state);
 
         let authentication_state = AuthState {
             access_token: auth_response.access_token,
             expires_at: Utc::now() + TimeDelta::seconds(auth_response.expires_in.into()),
         };
 
-        let rules_state = RulesState {
-            rules: rules_response.compile()?,
-            hash: rules_response.hash,
-        };
-
-        Ok(Self {
+        let client = Self {
             client,
+            download_client,
+            backend,
             authentication_state,
-            rules_state,
-        })
+            rules_state: RulesHandle::new(rules_state),
+            staleness: Staleness::new(Duration::from_secs(APP_CONFIG.load().max_rules_age)),
+            worker_id: None,
+            token_refresher: None,
+        };
+        // there are no previous rules to fall back on
+        client.self_test(&client.rules())?;
+
+        Ok(client)
+    }
+
+    /// Renew the access token in the background from now on, `auth_refresh_margin` before it
+    /// expires, see [`token_refresh`]. Does nothing if the margin is 0.
+    pub fn start_token_refresh(&mut self) {
+        if APP_CONFIG.load().auth_refresh_margin == 0 || self.token_refresher.is_some() {
+            return;
+        }
+
+        self.token_refresher = Some(TokenRefresher::spawn(
+            self.client.clone(),
+            self.backend.clone(),
+            self.authentication_state.expires_at,
+            Duration::from_secs(APP_CONFIG.load().auth_refresh_margin),
+        ));
     }
 
     /// Update the state with a new access token, if it's expired.
     ///
-    /// If the token is not expired, then nothing is done.
+    /// A token renewed in the background (see [`DragonflyClient::start_token_refresh`]) is
+    /// installed first. If the token is not expired, then nothing else is done.
     /// If an error occurs while reauthenticating, the function retries with an exponential backoff
     /// described by the equation `min(10 * 60, 2^(x - 1))` where `x` is the number of failed tries.
     pub fn reauthenticate(&mut self) {
+        if let Some(renewed) = self.token_refresher.as_ref().and_then(TokenRefresher::take) {
+            trace!("Installing the access token renewed in the background");
+            self.authentication_state = renewed;
+        }
         if Utc::now() <= self.authentication_state.expires_at {
             return;
         }
@@ -68,7 +224,7 @@ impl DragonflyClient {
         let mut tries = 0;
 
         let authentication_response = loop {
-            let r = fetch_access_token(self.get_http_client());
+            let r = fetch_access_token_once(self.get_http_client(), &self.backend);
             match r {
                 Ok(authentication_response) => break authentication_response,
                 Err(e) => {
@@ -97,85 +253,640 @@ impl DragonflyClient {
         info!("Successfully reauthenticated.");
     }
 
-    /// Update the global ruleset. Waits for a write lock.
+    /// Update the global ruleset, if it changed. See [`prepare_rules_update`].
+    ///
+    /// Scans holding a snapshot of the previous ruleset (see [`RulesHandle::current`]) finish on
+    /// it, while new scans pick up the new one. If the new ruleset doesn't compile, it's reported
+    /// to the API and the last good one is kept, see [`DragonflyClient::update_failed`].
     pub fn update_rules(&mut self) -> Result<()> {
         self.reauthenticate();
 
-        let response = fetch_rules(
+        let current = self.rules();
+        let state = prepare_rules_update(
             self.get_http_client(),
+            &self.backend,
             &self.authentication_state.access_token,
-        )?;
-        self.rules_state.rules = response.compile()?;
-        self.rules_state.hash = response.hash;
+            &current.hash,
+            current.etag.as_deref(),
+            &current.sources,
+        )
+        .inspect_err(|err| self.update_failed(err))?;
+        if let Some(state) = state {
+            self.install_rules(state)?;
+        } else {
+            info!("Rules {} are still current", current.hash);
+            self.staleness.verified(Instant::now());
+        }
 
         Ok(())
     }
 
+    /// Fetch and recompile the global ruleset from source, even if its hash didn't change and
+    /// compiled rules for it are cached. Used for forced rescans.
+    pub fn refresh_rules(&mut self) -> Result<()> {
+        self.reauthenticate();
+
+        let state = prepare_rules(
+            self.get_http_client(),
+            &self.backend,
+            &self.authentication_state.access_token,
+            false,
+        )
+        .inspect_err(|err| self.update_failed(err))?;
+        self.install_rules(state)
+    }
+
+    /// Record that the rules couldn't be updated because of `err`. The current ruleset is the last
+    /// one that compiled and passed the canary self-test, and is kept. Rulesets that don't compile
+    /// are reported to the API, with where they fail.
+    fn update_failed(&mut self, err: &color_eyre::Report) {
+        self.staleness.update_failed();
+        let Some(failure) = err.downcast_ref::<CompileFailure>() else {
+            return;
+        };
+
+        error!(
+            "{failure}. Scanning with the last good rules {}",
+            self.rules().hash
+        );
+        self.report_broken_rules(&RulesHealthReport {
+            hash: &failure.hash,
+            error: failure.to_string(),
+            diagnostics: failure.diagnostics.clone(),
+        });
+    }
+
+    /// Replace the global ruleset with one that was just fetched, see [`RulesHandle::replace`].
+    ///
+    /// Rulesets that fail the canary self-test are reported to the API and never installed, so
+    /// the current ruleset is kept, see [`canary_self_test`].
+    pub fn install_rules(&mut self, state: RulesState) -> Result<()> {
+        if let Err(err) = self.self_test(&state) {
+            self.staleness.update_failed();
+            return Err(err.wrap_err(format!(
+                "Refusing to install rules {}, keeping rules {}",
+                state.hash,
+                self.rules().hash
+            )));
+        }
+
+        profile_ruleset(&state);
+        self.rules_state.replace(state);
+        self.staleness.verified(Instant::now());
+
+        Ok(())
+    }
+
+    /// Run the canary self-test on the ruleset `state`, reporting a failure to the API
+    fn self_test(&self, state: &RulesState) -> Result<()> {
+        let Err(err) = canary_self_test(&state.rules) else {
+            return Ok(());
+        };
+
+        error!("Rules {} failed the canary self-test: {err}", state.hash);
+        self.report_broken_rules(&RulesHealthReport {
+            hash: &state.hash,
+            error: err.to_string(),
+            diagnostics: Vec::new(),
+        });
+
+        Err(err)
+    }
+
+    /// Tell the API about a broken ruleset, see [`report_rules_health`]
+    fn report_broken_rules(&self, report: &RulesHealthReport) {
+        if let Err(err) = report_rules_health(
+            self.get_http_client(),
+            &self.backend,
+            &self.authentication_state.access_token,
+            report,
+        ) {
+            warn!("Failed to report the broken rules {}: {err}", report.hash);
+        }
+    }
+
+    /// Get a snapshot of the current ruleset
+    pub fn rules(&self) -> Arc<RulesState> {
+        self.rules_state.current()
+    }
+
     pub fn bulk_get_job(&mut self, n_jobs: usize) -> reqwest::Result<Vec<Job>> {
         self.reauthenticate();
 
         fetch_bulk_job(
             self.get_http_client(),
+            &self.backend,
             &self.authentication_state.access_token,
             n_jobs,
         )
     }
 
-    pub fn get_job(&mut self) -> reqwest::Result<Option<Job>> {
+    /// Fetch the next `n_jobs` jobs on a background thread, so they're ready as soon as the
+    /// current ones are done.
+    ///
+    /// If the first job asks for a different ruleset than the current one, that ruleset is fetched
+    /// and compiled in the background too. Installing it is up to the caller.
+    pub fn prefetch_jobs(&mut self, n_jobs: usize) -> JoinHandle<Prefetched> {
         self.reauthenticate();
 
-        // not `slice::first` because we want to own the Job
-        self.bulk_get_job(1).map(|jobs| jobs.into_iter().nth(0))
+        let http_client = self.client.clone();
+        let backend = self.backend.clone();
+        let access_token = self.authentication_state.access_token.clone();
+        let current = self.rules();
+        let (current_hash, current_etag) = (current.hash.clone(), current.etag.clone());
+        let current_sources = Arc::clone(&current.sources);
+        drop(current);
+
+        std::thread::spawn(move || {
+            let jobs = fetch_bulk_job(&http_client, &backend, &access_token, n_jobs);
+
+            let rules = match jobs.as_deref().map(<[Job]>::first) {
+                Ok(Some(job)) if job.hash != current_hash => prepare_rules_update(
+                    &http_client,
+                    &backend,
+                    &access_token,
+                    &current_hash,
+                    current_etag.as_deref(),
+                    &current_sources,
+                )
+                .inspect_err(|err| {
+                    warn!("Failed to prepare rules in the background: {err}");
+                })
+                .ok()
+                .flatten(),
+                _ => None,
+            };
+
+            Prefetched { jobs, rules }
+        })
     }
 
-    /// Send a [`crate::client::models::ScanResult`] to mainframe
-    pub fn send_result(&mut self, body: models::ScanResult) -> reqwest::Result<()> {
+    /// Send a serialized result body to mainframe, such as a
+    /// [`crate::client::models::ScanResultSerializer`]
+    pub fn send_result<T: Serialize + ?Sized>(
+        &mut self,
+        body: &T,
+        idempotency_key: &str,
+    ) -> Result<()> {
         self.reauthenticate();
 
         send_result(
             self.get_http_client(),
+            &self.backend,
+            &self.authentication_state.access_token,
+            body,
+            idempotency_key,
+        )
+    }
+
+    pub fn skip_job(&mut self, name: &str, version: &str) -> reqwest::Result<()> {
+        self.reauthenticate();
+
+        skip_job(
+            self.get_http_client(),
+            &self.backend,
+            &self.authentication_state.access_token,
+            name,
+            version,
+        )
+    }
+
+    /// Register with the backend if `register_client` is set, so it lists this client among the
+    /// live scanners, and remember the worker ID it assigned. Failing to register isn't fatal,
+    /// results are then sent without a worker ID.
+    pub fn register(&mut self) {
+        if !APP_CONFIG.load().register_client {
+            return;
+        }
+        self.reauthenticate();
+
+        let hostname = gethostname::gethostname().to_string_lossy().into_owned();
+        match register_client(
+            self.get_http_client(),
+            &self.backend,
+            &self.authentication_state.access_token,
+            &Registration::new(hostname, &APP_CONFIG.load()),
+        ) {
+            Ok(response) => {
+                info!("Registered as worker {}", response.worker_id);
+                self.worker_id = Some(response.worker_id);
+            }
+            Err(err) => warn!("Failed to register with {}: {err}", self.backend.name),
+        }
+    }
+
+    /// Tell the backend this client is still alive, registering again if it forgot about it
+    pub fn send_keepalive(&mut self) -> reqwest::Result<()> {
+        let Some(worker_id) = self.worker_id.clone() else {
+            return Ok(());
+        };
+        self.reauthenticate();
+
+        match send_keepalive(
+            self.get_http_client(),
+            &self.backend,
+            &self.authentication_state.access_token,
+            &worker_id,
+        ) {
+            Err(err) if err.status() == Some(StatusCode::NOT_FOUND) => {
+                warn!(
+                    "Worker {worker_id} is unknown to {}, registering again",
+                    self.backend.name
+                );
+                self.worker_id = None;
+                self.register();
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    pub fn send_stats<T: Serialize + ?Sized>(&mut self, body: &T) -> reqwest::Result<()> {
+        self.reauthenticate();
+
+        send_stats(
+            self.get_http_client(),
+            &self.backend,
             &self.authentication_state.access_token,
             body,
         )
     }
 
+    /// Send a chunk of the newline delimited file results of a package to mainframe, see
+    /// [`FileResultsStream`]
+    pub fn send_file_results(
+        &mut self,
+        name: &str,
+        version: &str,
+        chunk: usize,
+        body: &str,
+        idempotency_key: &str,
+    ) -> Result<()> {
+        self.reauthenticate();
+
+        trace!("Sending file results chunk {chunk} of {name} v{version}");
+        send_file_results_chunk(
+            self.get_http_client(),
+            &self.backend,
+            &self.authentication_state.access_token,
+            name,
+            version,
+            chunk,
+            body,
+            idempotency_key,
+        )
+    }
+
     /// Return a reference to the underlying HTTP Client
     pub fn get_http_client(&self) -> &Client {
         &self.client
     }
+
+    /// Return a reference to the HTTP Client for downloads, from PyPI or elsewhere
+    pub fn get_download_client(&self) -> &Client {
+        &self.download_client
+    }
 }
 
-/// Download and unpack a tarball, return the [`TempDir`] containing the contents.
-fn extract_tarball<R: io::Read>(response: R) -> Result<TempDir> {
-    let mut tarball = tar::Archive::new(GzDecoder::new(response));
-    let tmpdir = tempdir()?;
-    tarball.unpack(tmpdir.path())?;
-    Ok(tmpdir)
+/// Fetch and compile the current ruleset
+fn prepare_rules(
+    http_client: &Client,
+    backend: &Backend,
+    access_token: &str,
+    use_cache: bool,
+) -> Result<RulesState> {
+    let response = fetch_rules(http_client, backend, access_token, None)?
+        .ok_or_else(|| eyre!("The API answered an unconditional rules request with 304"))?;
+
+    rules_state(http_client, response, use_cache)
+}
+
+/// Fetch and compile the current ruleset, unless it's still the one with `current_hash`: the API
+/// reports the same hash, or answers `304 Not Modified` to `current_etag`. Doesn't download the
+/// whole ruleset just to find out that it didn't change, such as for jobs that are briefly handed
+/// out with an old hash.
+///
+/// With `delta_rules`, only the rule files that changed since `current_hash` are fetched and
+/// applied to `current_sources`, falling back to the whole ruleset if the API can't tell.
+fn prepare_rules_update(
+    http_client: &Client,
+    backend: &Backend,
+    access_token: &str,
+    current_hash: &str,
+    current_etag: Option<&str>,
+    current_sources: &HashMap<String, String>,
+) -> Result<Option<RulesState>> {
+    match fetch_rules_hash(http_client, backend, access_token) {
+        Ok(hash) if hash == current_hash => return Ok(None),
+        Ok(_) => {}
+        Err(err) => debug!("Failed to fetch the rules hash, fetching the rules instead: {err}"),
+    }
+
+    if APP_CONFIG.load().delta_rules && !current_sources.is_empty() {
+        match fetch_rules_delta(http_client, backend, access_token, current_hash) {
+            Ok(delta) if delta.hash == current_hash => return Ok(None),
+            Ok(delta) => {
+                info!(
+                    "Updating rules from {current_hash} to {} with {} added, {} modified and {} removed rule files",
+                    delta.hash,
+                    delta.added.len(),
+                    delta.modified.len(),
+                    delta.removed.len()
+                );
+                return Ok(Some(rules_state(
+                    http_client,
+                    delta.apply(current_sources),
+                    true,
+                )?));
+            }
+            Err(err) => {
+                debug!("Failed to fetch the rules delta, fetching all rules instead: {err}");
+            }
+        }
+    }
+
+    match fetch_rules(http_client, backend, access_token, current_etag)? {
+        Some(response) if response.hash != current_hash => {
+            Ok(Some(rules_state(http_client, response, true)?))
+        }
+        _ => Ok(None),
+    }
 }
 
-/// Download and extract a zip, return the [`TempDir`] containing the contents.
-fn extract_zipfile<R: io::Read>(mut response: R) -> Result<TempDir> {
-    let mut file = tempfile()?;
+/// Compile the rules of a [`RulesResponse`] and the `rule_bundles` into a [`RulesState`], see
+/// [`compile_rules`]
+fn rules_state(
+    http_client: &Client,
+    response: RulesResponse,
+    use_cache: bool,
+) -> Result<RulesState> {
+    let bundles = bundles::load_all(http_client, &APP_CONFIG.load().rule_bundles)?;
+    let rules = compile_rules(&response, &bundles, use_cache)?;
+    let mut state = RulesState::new(rules, response.hash);
+    state.etag = response.etag;
+    if APP_CONFIG.load().shadow_scan {
+        match Shadow::compile(&response.rules) {
+            Ok(shadow) => state.shadow = Some(Arc::new(shadow)),
+            Err(err) => warn!(
+                "Not shadow scanning with rules {}, they failed to compile with yara-x: {err}",
+                state.hash
+            ),
+        }
+    }
+    state.sources = Arc::new(response.rules);
 
-    // first write the archive to a file because `response` isn't Seek, which is needed by
-    // `zip::ZipArchive::new`
-    io::copy(&mut response, &mut file)?;
+    Ok(state)
+}
 
-    let mut zip = zip::ZipArchive::new(file)?;
-    let tmpdir = tempdir()?;
-    zip.extract(tmpdir.path())?;
+/// Compile the rules of a [`RulesResponse`], going through the on-disk cache of compiled rulesets
+/// unless it's disabled.
+///
+/// If `use_cache` is `false` the rules are always compiled from source, and the cached entry is
+/// replaced with the result. Rulesets with different `bundles` are cached separately.
+fn compile_rules(
+    response: &RulesResponse,
+    bundles: &[bundles::Bundle],
+    use_cache: bool,
+) -> Result<yara::Rules> {
+    if APP_CONFIG.load().rules_cache_size == 0 {
+        return response.compile_with(bundles);
+    }
 
+    let key = match bundles::digest(bundles) {
+        Some(digest) => format!("{}-{digest}", response.hash),
+        None => response.hash.clone(),
+    };
+    let cache = RulesCache::new(
+        &APP_CONFIG.load().rules_cache_dir,
+        APP_CONFIG.load().rules_cache_size,
+    );
+    if let Some(rules) = use_cache.then(|| cache.load(&key)).flatten() {
+        info!("Loaded compiled rules for {key} from the cache");
+        return Ok(rules);
+    }
+
+    let mut rules = response.compile_with(bundles)?;
+    if let Err(err) = cache.store(&key, &mut rules) {
+        warn!("Failed to cache compiled rules for {key}: {err}");
+    }
+
+    Ok(rules)
+}
+
+/// Extract an archive into a new [`TempDir`] in the scratch directory, with `disk` reserved for it
+fn extract_to_tempdir(mut extractor: impl Extractor, disk: &disk::Reservation) -> Result<TempDir> {
+    let tmpdir = disk::tempdir()?;
+    extract::unpack(
+        &mut extractor,
+        APP_CONFIG.load().extraction_limits(),
+        tmpdir.path(),
+    )
+    .map_err(|err| disk.exhausted(err.into()))?;
     Ok(tmpdir)
 }
 
-pub fn download_distribution(http_client: &Client, download_url: Url) -> Result<TempDir> {
+/// Counts the time spent waiting on reads from `inner`
+struct Timed<R> {
+    inner: R,
+    nanos: Arc<AtomicU64>,
+}
+
+impl<R> Timed<R> {
+    fn new(inner: R, nanos: &Arc<AtomicU64>) -> Self {
+        Self {
+            inner,
+            nanos: Arc::clone(nanos),
+        }
+    }
+}
+
+impl<R: Read> Read for Timed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        let read = self.inner.read(buf);
+        let elapsed = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        self.nanos.fetch_add(elapsed, Ordering::Relaxed);
+        read
+    }
+}
+
+impl<R: Seek> Seek for Timed<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// A distribution extracted by [`download_distribution`]
+pub struct Extracted {
+    /// The contents of the distribution
+    pub dir: TempDir,
+
+    /// The time spent waiting on the distribution to be read
+    pub download_time: Duration,
+
+    /// The rest of the time, spent extracting the distribution
+    pub extraction_time: Duration,
+
+    /// The memory reserved for scanning the distribution, to be held until it's scanned
+    pub memory: Reservation<'static>,
+
+    /// The disk space reserved for the contents of the distribution, to be held until `dir` is
+    /// removed
+    pub disk: disk::Reservation<'static>,
+}
+
+/// Download (or, for `file://` URLs, open) and extract a distribution into a [`TempDir`].
+///
+/// Reading the distribution fails once `deadline` has passed. With an `expected_sha256` digest,
+/// it fails with an [`integrity::DigestMismatch`] if the distribution doesn't match it. Fails with
+/// a [`crate::memory::ResourceLimit`] if scanning it is estimated to take more than the memory
+/// budget, see [`crate::memory`], or its contents don't fit on the disk, see [`crate::disk`].
+pub fn download_distribution(
+    http_client: &Client,
+    download_url: &Url,
+    expected_sha256: Option<&str>,
+    deadline: Deadline,
+) -> Result<Extracted> {
+    let start = Instant::now();
+    let read_nanos = Arc::new(AtomicU64::new(0));
+    let (dir, memory, disk) = download_and_extract(
+        http_client,
+        download_url,
+        expected_sha256,
+        deadline,
+        &read_nanos,
+    )?;
+
+    let download_time = Duration::from_nanos(read_nanos.load(Ordering::Relaxed));
+    Ok(Extracted {
+        dir,
+        download_time,
+        extraction_time: start.elapsed().saturating_sub(download_time),
+        memory,
+        disk,
+    })
+}
+
+/// Request a distribution once the download [`rate_limit::Limiter`] lets it through. `429 Too
+/// Many Requests` responses pause all downloads as long as their `Retry-After` asks, and are
+/// retried up to `retry_max_attempts` times.
+fn request_distribution(
+    http_client: &Client,
+    download_url: &Url,
+    deadline: Deadline,
+) -> Result<(rate_limit::Permit<'static>, Response)> {
+    let mut attempt = 1;
+    loop {
+        let permit = rate_limit::DOWNLOADS.acquire();
+        deadline.check()?;
+
+        let response = http_client.get(download_url.clone()).send()?;
+        if response.status() != StatusCode::TOO_MANY_REQUESTS
+            || attempt >= APP_CONFIG.load().retry_max_attempts
+        {
+            return Ok((permit, response.error_for_status()?));
+        }
+
+        let delay = rate_limit::retry_after(&response);
+        warn!(
+            "Downloading {download_url} was rate limited, pausing downloads for {:.3} seconds",
+            delay.as_secs_f64()
+        );
+        rate_limit::DOWNLOADS.back_off(delay);
+        attempt += 1;
+    }
+}
+
+/// See [`download_distribution`], adding the time spent reading the distribution to `read_nanos`
+fn download_and_extract(
+    http_client: &Client,
+    download_url: &Url,
+    expected_sha256: Option<&str>,
+    deadline: Deadline,
+    read_nanos: &Arc<AtomicU64>,
+) -> Result<(TempDir, Reservation<'static>, disk::Reservation<'static>)> {
+    deadline.check()?;
     // This conversion is fast as per the docs
-    let is_tarball = download_url.as_str().ends_with(".tar.gz");
-    let response = http_client.get(download_url).send()?;
+    let kind = ArchiveKind::from_file_name(download_url.as_str());
+
+    // distributions of jobs read from disk, see `crate::job_source`
+    if download_url.scheme() == "file" {
+        let path = download_url
+            .to_file_path()
+            .map_err(|()| eyre!("{download_url} isn't a local path"))?;
+        let size = File::open(&path)?.metadata()?.len();
+        let memory = MEMORY.reserve(memory::estimate(Some(size)), deadline)?;
+        let disk = DISK.reserve(disk::estimate(Some(size)))?;
+        if let Some(expected) = expected_sha256 {
+            let actual = integrity::digest(File::open(&path)?)?;
+            integrity::verify(download_url, expected, actual)?;
+        }
+        let file = Timed::new(File::open(&path)?, read_nanos);
+        let dir = match kind {
+            ArchiveKind::TarGz => extract_to_tempdir(Tar::gz(deadline.reader(file)), &disk)?,
+            ArchiveKind::TarZst => extract_to_tempdir(Tar::zst(deadline.reader(file))?, &disk)?,
+            ArchiveKind::Zip => extract_to_tempdir(Zip::new(file)?, &disk)?,
+        };
+        return Ok((dir, memory, disk));
+    }
+
+    // held until the distribution is fully read
+    let (_permit, response) = request_distribution(http_client, download_url, deadline)?;
+    let memory = MEMORY.reserve(memory::estimate(response.content_length()), deadline)?;
+    let disk = DISK.reserve(disk::estimate(response.content_length()))?;
+    let response = Resumable::new(http_client, download_url.clone(), response);
+    let mut response = Hashing::new(deadline.reader(Timed::new(response, read_nanos)));
+
+    let dir = match kind {
+        ArchiveKind::TarGz => extract_to_tempdir(Tar::gz(&mut response), &disk)?,
+        ArchiveKind::TarZst => extract_to_tempdir(Tar::zst(&mut response)?, &disk)?,
+        ArchiveKind::Zip => {
+            // first write the archive to a file because `response` isn't Seek, which is needed by
+            // `zip::ZipArchive::new`
+            let mut file = disk::tempfile()?;
+            io::copy(&mut response, &mut file).map_err(|err| disk.exhausted(err.into()))?;
+            extract_to_tempdir(Zip::new(file)?, &disk)?
+        }
+    };
+
+    if let Some(expected) = expected_sha256 {
+        // the archive may end before the response does
+        io::copy(&mut response, &mut io::sink())?;
+        integrity::verify(download_url, expected, response.finish())?;
+    }
+
+    Ok((dir, memory, disk))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RulesHandle, RulesState};
+    use std::sync::Arc;
+    use yara::Compiler;
+
+    fn state(hash: &str) -> RulesState {
+        RulesState {
+            rules: Compiler::new()
+                .unwrap()
+                .add_rules_str("rule a { condition: true }")
+                .unwrap()
+                .compile_rules()
+                .unwrap(),
+            hash: hash.into(),
+            etag: None,
+            sources: Arc::default(),
+            shadow: None,
+        }
+    }
+
+    #[test]
+    fn snapshots_survive_replacement() {
+        let handle = RulesHandle::new(state("old"));
+        let snapshot = handle.current();
+
+        handle.replace(state("new"));
 
-    if is_tarball {
-        extract_tarball(response)
-    } else {
-        extract_zipfile(response)
+        assert_eq!(snapshot.hash, "old");
+        assert_eq!(handle.current().hash, "new");
     }
 }
@@ -0,0 +1,139 @@
+//! Additional rulesets compiled alongside the Dragonfly rules, each into its own YARA namespace.
+//!
+//! Every entry of `rule_bundles` names a `namespace` and either a local `path` (a rule file, or a
+//! directory of `.yar` and `.yara` files) or a remote `url` serving a single rule file. Bundles
+//! are loaded again whenever the rules are compiled, so updates to them are picked up with the
+//! next rules update. Matches of their rules are reported as `namespace:rule`, see
+//! [`crate::scanner::RuleScore`].
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use color_eyre::{eyre::eyre, Result};
+use reqwest::blocking::Client;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    app_config::RuleBundle,
+    offline,
+    scanner::{canary_bundle, CANARY_NAMESPACE},
+};
+
+/// The namespace of the Dragonfly rules themselves, YARA's default
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// The rule files of a bundle, by name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bundle {
+    pub namespace: String,
+    pub sources: BTreeMap<String, String>,
+}
+
+impl Bundle {
+    fn load(http_client: &Client, source: &RuleBundle) -> Result<Self> {
+        let namespace = &source.namespace;
+        if namespace.is_empty()
+            || namespace == DEFAULT_NAMESPACE
+            || namespace == CANARY_NAMESPACE
+            || namespace.contains(':')
+        {
+            return Err(eyre!(
+                "{namespace:?} can't be the namespace of a rule bundle"
+            ));
+        }
+
+        let sources = match (&source.path, &source.url) {
+            (Some(path), None) => {
+                let path = Path::new(path);
+                if path.is_dir() {
+                    offline::read_rule_files(path)?.into_iter().collect()
+                } else {
+                    BTreeMap::from([(
+                        path.to_string_lossy().into_owned(),
+                        fs::read_to_string(path)?,
+                    )])
+                }
+            }
+            (None, Some(url)) => {
+                let source = http_client.get(url).send()?.error_for_status()?.text()?;
+                BTreeMap::from([(url.clone(), source)])
+            }
+            _ => {
+                return Err(eyre!(
+                    "The rule bundle {namespace} needs exactly one of a path and a url"
+                ))
+            }
+        };
+
+        Ok(Self {
+            namespace: namespace.clone(),
+            sources,
+        })
+    }
+}
+
+/// Load every bundle of `sources`, failing if any of them can't be loaded, followed by the
+/// built-in canary rule every ruleset is self-tested with
+pub fn load_all(http_client: &Client, sources: &[RuleBundle]) -> Result<Vec<Bundle>> {
+    let mut bundles = sources
+        .iter()
+        .map(|source| Bundle::load(http_client, source))
+        .collect::<Result<Vec<_>>>()?;
+    bundles.push(canary_bundle());
+
+    Ok(bundles)
+}
+
+/// A digest of the contents of `bundles`, to tell compiled rulesets with different bundles
+/// apart. `None` without bundles.
+pub fn digest(bundles: &[Bundle]) -> Option<String> {
+    if bundles.is_empty() {
+        return None;
+    }
+
+    let mut hasher = Sha256::new();
+    for bundle in bundles {
+        hasher.update(bundle.namespace.as_bytes());
+        hasher.update([0]);
+        for (name, source) in &bundle.sources {
+            hasher.update(name.as_bytes());
+            hasher.update([0]);
+            hasher.update(source.as_bytes());
+            hasher.update([0]);
+        }
+    }
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{digest, load_all, RuleBundle, CANARY_NAMESPACE};
+    use reqwest::blocking::Client;
+    use tempfile::tempdir;
+
+    #[test]
+    fn loads_local_bundles() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("a.yar"), "rule a { condition: true }").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "not a rule").unwrap();
+        let source = |namespace: &str| RuleBundle {
+            namespace: namespace.into(),
+            path: Some(dir.path().to_string_lossy().into_owned()),
+            url: None,
+        };
+
+        let bundles = load_all(&Client::new(), &[source("acme")]).unwrap();
+        assert_eq!(bundles[0].sources.len(), 1);
+        assert_eq!(bundles[0].sources["a.yar"], "rule a { condition: true }");
+        assert_eq!(bundles[1].namespace, CANARY_NAMESPACE);
+        assert!(digest(&bundles).is_some());
+        assert!(digest(&[]).is_none());
+
+        assert!(load_all(&Client::new(), &[source("default")]).is_err());
+        assert!(load_all(&Client::new(), &[source(CANARY_NAMESPACE)]).is_err());
+        let neither = RuleBundle {
+            path: None,
+            ..source("acme")
+        };
+        assert!(load_all(&Client::new(), &[neither]).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+//! Compressing large result bodies before they're sent.
+//!
+//! The per-file results of a big package can add up to megabytes of JSON. With a
+//! `result_compression`, result bodies of at least `result_compression_threshold` bytes are sent
+//! compressed, with a matching `Content-Encoding`. A backend that answers `415 Unsupported Media
+//! Type` is sent the body again as is, and isn't sent compressed bodies anymore.
+
+use std::{collections::HashSet, io::Write};
+
+use flate2::{write::GzEncoder, Compression};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use reqwest::{blocking::Response, StatusCode};
+use tracing::warn;
+
+use crate::app_config::ResultCompression;
+
+/// The `Content-Encoding` of bodies compressed with `encoding`
+fn header(encoding: ResultCompression) -> Option<&'static str> {
+    match encoding {
+        ResultCompression::None => None,
+        ResultCompression::Gzip => Some("gzip"),
+        ResultCompression::Zstd => Some("zstd"),
+    }
+}
+
+fn encode(body: &[u8], encoding: ResultCompression) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        ResultCompression::None => Ok(body.to_vec()),
+        ResultCompression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        ResultCompression::Zstd => zstd::encode_all(body, 0),
+    }
+}
+
+/// The base URLs of the backends that refused compressed bodies
+static REFUSED: Lazy<Mutex<HashSet<String>>> = Lazy::new(Mutex::default);
+
+/// `body` compressed with `encoding` along with its `Content-Encoding`, if it's at least
+/// `threshold` bytes
+pub fn compress(
+    body: &[u8],
+    encoding: ResultCompression,
+    threshold: u64,
+) -> Option<(Vec<u8>, &'static str)> {
+    let header = header(encoding)?;
+    if (body.len() as u64) < threshold {
+        return None;
+    }
+
+    match encode(body, encoding) {
+        Ok(compressed) => Some((compressed, header)),
+        Err(err) => {
+            warn!("Failed to compress a result body, sending it as is: {err}");
+            None
+        }
+    }
+}
+
+/// Whether the backend at `base_url` refused a compressed body with `response`, in which case it's
+/// remembered so it's only sent uncompressed bodies from now on
+pub fn refused(response: &Response, base_url: &str) -> bool {
+    if response.status() != StatusCode::UNSUPPORTED_MEDIA_TYPE {
+        return false;
+    }
+
+    warn!("{base_url} refused a compressed result body, sending results uncompressed from now on");
+    REFUSED.lock().insert(base_url.to_owned());
+    true
+}
+
+/// Whether compressed bodies should still be sent to `base_url`
+pub fn accepted(base_url: &str) -> bool {
+    !REFUSED.lock().contains(base_url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compress;
+    use crate::app_config::ResultCompression;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn compresses_large_bodies() {
+        let body = br#"{"name": "pkg", "files": []}"#.repeat(1000);
+        assert!(compress(&body, ResultCompression::None, 0).is_none());
+        assert!(compress(&body, ResultCompression::Gzip, 1 << 20).is_none());
+
+        let (gzip, header) = compress(&body, ResultCompression::Gzip, 1024).unwrap();
+        assert_eq!(header, "gzip");
+        assert!(gzip.len() < body.len());
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&gzip[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+
+        let (zstd, header) = compress(&body, ResultCompression::Zstd, 1024).unwrap();
+        assert_eq!(header, "zstd");
+        assert_eq!(zstd::decode_all(&zstd[..]).unwrap(), body);
+    }
+}
@@ -0,0 +1,270 @@
+use std::{fs, path::Path, time::Duration};
+
+use color_eyre::{eyre::eyre, Result};
+use reqwest::{
+    blocking::{Client, ClientBuilder},
+    redirect::Policy,
+    Certificate, Identity, NoProxy, Proxy,
+};
+use tracing::warn;
+
+use crate::{
+    app_config::{AppConfig, HttpTuning},
+    build_info::BUILD_INFO,
+};
+
+/// The proxy all outbound traffic goes through, if one is configured.
+///
+/// Without a `proxy_url`, reqwest falls back to the `HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY`
+/// and `NO_PROXY` environment variables.
+fn proxy(config: &AppConfig) -> Result<Option<Proxy>> {
+    let Some(url) = &config.proxy_url else {
+        return Ok(None);
+    };
+
+    let mut proxy =
+        Proxy::all(url)?.no_proxy(config.no_proxy.as_deref().and_then(NoProxy::from_string));
+    if let Some(username) = &config.proxy_username {
+        proxy = proxy.basic_auth(
+            username,
+            config.proxy_password.as_deref().unwrap_or_default(),
+        );
+    }
+
+    Ok(Some(proxy))
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|err| eyre!("Failed to read {}: {err}", path.display()))
+}
+
+/// Trust the configured CA bundle in addition to the system roots, and present the configured
+/// client certificate
+fn configure_tls(mut builder: ClientBuilder, config: &AppConfig) -> Result<ClientBuilder> {
+    if let Some(path) = &config.ca_bundle_path {
+        for certificate in Certificate::from_pem_bundle(&read(path)?)? {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    match (&config.client_cert_path, &config.client_key_path) {
+        (Some(cert), Some(key)) => {
+            let identity = Identity::from_pkcs8_pem(&read(cert)?, &read(key)?)?;
+            builder = builder.identity(identity);
+        }
+        (None, None) => {}
+        _ => {
+            return Err(eyre!(
+                "client_cert_path and client_key_path must be set together"
+            ))
+        }
+    }
+
+    if config.danger_accept_invalid_certs {
+        warn!("TLS certificate validation is disabled, do not use this in production");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    Ok(builder)
+}
+
+/// The `User-Agent` of every request: `user_agent` if it's set, otherwise the name, version and
+/// commit of the client and the worker it runs on (`worker_id`, or the host name), like
+/// `dragonfly-client-rs/0.1.0 (commit abc1234; worker scanner-3)`
+fn user_agent(config: &AppConfig) -> String {
+    if let Some(user_agent) = &config.user_agent {
+        return user_agent.clone();
+    }
+
+    let worker = config
+        .worker_id
+        .clone()
+        .unwrap_or_else(|| gethostname::gethostname().to_string_lossy().into_owned());
+    format!(
+        "{}/{} (commit {}; worker {worker})",
+        env!("CARGO_PKG_NAME"),
+        BUILD_INFO.version,
+        BUILD_INFO.git_commit
+    )
+}
+
+/// Manage the connections of the client as `tuning` says, 0 leaving a duration unbounded
+fn tune(mut builder: ClientBuilder, tuning: &HttpTuning) -> ClientBuilder {
+    let seconds = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+
+    if let Some(max) = tuning.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max);
+    }
+    if tuning.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+
+    builder
+        .redirect(redirect_policy(tuning.max_redirects))
+        .connect_timeout(seconds(tuning.connect_timeout))
+        .pool_idle_timeout(seconds(tuning.pool_idle_timeout))
+        .tcp_keepalive(seconds(tuning.tcp_keepalive))
+        .timeout(seconds(tuning.read_timeout))
+}
+
+/// Follow at most `max` redirects, and none from HTTPS to plain HTTP
+fn redirect_policy(max: usize) -> Policy {
+    Policy::custom(move |attempt| {
+        let downgrade = attempt.url().scheme() == "http"
+            && attempt
+                .previous()
+                .last()
+                .is_some_and(|previous| previous.scheme() == "https");
+        if downgrade {
+            attempt.error("refusing to follow a redirect from HTTPS to HTTP")
+        } else if attempt.previous().len() > max {
+            attempt.error(format!("too many redirects, at most {max} are followed"))
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// The builder of an HTTP client tuned with `tuning`
+fn builder(config: &AppConfig, tuning: &HttpTuning) -> Result<ClientBuilder> {
+    let mut builder = configure_tls(
+        tune(
+            Client::builder().gzip(true).user_agent(user_agent(config)),
+            tuning,
+        ),
+        config,
+    )?;
+    if let Some(proxy) = proxy(config)? {
+        builder = builder.proxy(proxy);
+    }
+
+    Ok(builder)
+}
+
+/// Build the HTTP client of the Dragonfly API, tuned with `api_http`
+pub fn build_client(config: &AppConfig) -> Result<Client> {
+    Ok(builder(config, &config.api_http)?.build()?)
+}
+
+/// Build the HTTP client distributions are downloaded with, tuned with `download_http`.
+///
+/// It's kept apart from the API's so that slow downloads never hold up API requests, and so that
+/// nothing of the API's reaches third party hosts: the URL a redirect came from isn't sent along
+/// as the `Referer`, and like the API's it never stores cookies.
+pub fn build_download_client(config: &AppConfig) -> Result<Client> {
+    Ok(builder(config, &config.download_http)?
+        .referer(false)
+        .build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_client, build_download_client, user_agent};
+    use crate::{
+        app_config::{AppConfig, HttpTuning},
+        server::{serve, Response},
+    };
+    use std::{
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/client/fixtures")
+            .join(name)
+    }
+
+    #[test]
+    fn loads_ca_bundles_and_client_certificates() {
+        let config = AppConfig {
+            ca_bundle_path: Some(fixture("ca.pem")),
+            client_cert_path: Some(fixture("client.pem")),
+            client_key_path: Some(fixture("client.key")),
+            ..AppConfig::default()
+        };
+        assert!(build_client(&config).is_ok());
+
+        let config = AppConfig {
+            client_cert_path: Some(fixture("client.pem")),
+            ..AppConfig::default()
+        };
+        assert!(build_client(&config).is_err());
+
+        let config = AppConfig {
+            ca_bundle_path: Some(fixture("missing.pem")),
+            ..AppConfig::default()
+        };
+        let err = build_client(&config).unwrap_err();
+        assert!(err.to_string().contains("missing.pem"));
+    }
+
+    #[test]
+    fn identifies_the_build_and_worker() {
+        let config = AppConfig {
+            worker_id: Some(String::from("scanner-3")),
+            ..AppConfig::default()
+        };
+        let agent = user_agent(&config);
+        assert!(agent.starts_with(concat!("dragonfly-client-rs/", env!("CARGO_PKG_VERSION"))));
+        assert!(agent.ends_with("; worker scanner-3)"));
+
+        let config = AppConfig {
+            user_agent: Some(String::from("custom/1.0")),
+            ..config
+        };
+        assert_eq!(user_agent(&config), "custom/1.0");
+    }
+
+    #[test]
+    fn sends_requests_through_the_proxy() {
+        // a plain HTTP proxy receives the absolute URL as the request target
+        let addr = serve("127.0.0.1:0", |request| {
+            Response::text(200, request.path.clone())
+        })
+        .unwrap();
+        let config = AppConfig {
+            proxy_url: Some(format!("http://{addr}")),
+            no_proxy: Some(String::from("localhost")),
+            proxy_username: Some(String::from("user")),
+            proxy_password: Some(String::from("hunter2")),
+            ..AppConfig::default()
+        };
+
+        let response = build_client(&config)
+            .unwrap()
+            .get("http://files.pythonhosted.invalid/pkg-1.0.tar.gz")
+            .send()
+            .unwrap();
+        assert_eq!(
+            response.text().unwrap(),
+            "http://files.pythonhosted.invalid/pkg-1.0.tar.gz"
+        );
+    }
+
+    #[test]
+    fn times_out_slow_responses() {
+        let addr = serve("127.0.0.1:0", |_| {
+            std::thread::sleep(Duration::from_millis(1500));
+            Response::text(200, String::from("late"))
+        })
+        .unwrap();
+        let config = AppConfig {
+            download_http: HttpTuning {
+                pool_max_idle_per_host: Some(2),
+                tcp_keepalive: 30,
+                connect_timeout: 5,
+                read_timeout: 1,
+                ..HttpTuning::default()
+            },
+            ..AppConfig::default()
+        };
+
+        let err = build_download_client(&config)
+            .unwrap()
+            .get(format!("http://{addr}/"))
+            .send()
+            .unwrap_err();
+        assert!(err.is_timeout());
+    }
+}
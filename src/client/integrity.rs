@@ -0,0 +1,100 @@
+//! Checking downloaded distributions against their published SHA-256 digests, so a corrupted
+//! download or a tampered mirror isn't scanned (and cleared) in place of the real distribution.
+//! Rulesets are checked the same way against the digest the API sends with them, so a corrupted
+//! download isn't compiled.
+
+use std::{
+    fmt::{self, Display},
+    io::{self, Read},
+};
+
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+
+/// A reader hashing everything read through it
+pub struct Hashing<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R> Hashing<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// The hex digest of everything read so far
+    pub fn finish(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// The hex SHA-256 digest of the rest of `reader`
+pub fn digest(reader: impl Read) -> io::Result<String> {
+    let mut hashing = Hashing::new(reader);
+    io::copy(&mut hashing, &mut io::sink())?;
+    Ok(hashing.finish())
+}
+
+/// A download that doesn't match the digest it was published with
+#[derive(Debug)]
+pub struct DigestMismatch {
+    pub url: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl Display for DigestMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "integrity check of {} failed: expected SHA-256 digest {}, got {}",
+            self.url, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for DigestMismatch {}
+
+/// Check the `actual` digest of the download from `url` against the `expected` one
+pub fn verify(url: &Url, expected: &str, actual: String) -> Result<(), DigestMismatch> {
+    if expected.trim().eq_ignore_ascii_case(&actual) {
+        return Ok(());
+    }
+
+    Err(DigestMismatch {
+        url: url.to_string(),
+        expected: expected.trim().to_ascii_lowercase(),
+        actual,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{digest, verify};
+    use reqwest::Url;
+
+    #[test]
+    fn verifies_digests() {
+        let url = Url::parse("https://files.pythonhosted.org/packages/pkg-1.0.tar.gz").unwrap();
+        let actual = digest(&b"abc"[..]).unwrap();
+        assert_eq!(
+            actual,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        assert!(verify(&url, &actual.to_ascii_uppercase(), actual.clone()).is_ok());
+        let err = verify(&url, &"0".repeat(64), actual).unwrap_err();
+        assert!(err.to_string().contains("integrity check of"));
+    }
+}
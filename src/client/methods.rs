@@ -1,65 +1,370 @@
-use super::{models, ScanResultSerializer};
+use super::{
+    compression,
+    integrity::{self, Hashing},
+    models,
+    resume::Resumable,
+    retry::{retry, retry_unsent},
+    vault::{self, Credentials},
+};
 
-use crate::APP_CONFIG;
-use reqwest::blocking::Client;
+use crate::{app_config::Backend, APP_CONFIG};
+use reqwest::{
+    blocking::{Client, Response},
+    header::{
+        HeaderMap, HeaderValue, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE,
+        ETAG, IF_NONE_MATCH,
+    },
+    StatusCode, Url,
+};
+use serde::Serialize;
+use std::io::Read;
+use tracing::{debug, warn};
 
-pub fn fetch_access_token(http_client: &Client) -> reqwest::Result<models::AuthResponse> {
-    let url = format!("https://{}/oauth/token", APP_CONFIG.auth0_domain);
+/// Fetch an access token for `backend` with its client credentials, see
+/// [`vault::client_credentials`]
+pub fn fetch_access_token(
+    http_client: &Client,
+    backend: &Backend,
+) -> color_eyre::Result<models::AuthResponse> {
+    let credentials = vault::client_credentials(http_client, backend)?;
+    Ok(retry("fetching an access token", || {
+        request_access_token(http_client, backend, &credentials)
+    })?)
+}
+
+/// Fetch an access token for `backend` in a single attempt, for callers that retry on their own,
+/// see [`fetch_access_token`]
+pub fn fetch_access_token_once(
+    http_client: &Client,
+    backend: &Backend,
+) -> color_eyre::Result<models::AuthResponse> {
+    let credentials = vault::client_credentials(http_client, backend)?;
+    Ok(request_access_token(http_client, backend, &credentials)?)
+}
+
+/// Request an access token for `backend` with `credentials`
+fn request_access_token(
+    http_client: &Client,
+    backend: &Backend,
+    credentials: &Credentials,
+) -> reqwest::Result<models::AuthResponse> {
+    let config = APP_CONFIG.load();
+    let auth0_domain = backend
+        .auth0_domain
+        .as_deref()
+        .unwrap_or(&config.auth0_domain);
+    let url = format!("https://{auth0_domain}/oauth/token");
     let json_body = models::AuthBody {
-        client_id: &APP_CONFIG.client_id,
-        client_secret: &APP_CONFIG.client_secret,
-        audience: &APP_CONFIG.audience,
-        grant_type: &APP_CONFIG.grant_type,
-        username: &APP_CONFIG.username,
-        password: &APP_CONFIG.password,
+        client_id: &credentials.client_id,
+        client_secret: &credentials.client_secret,
+        audience: backend.audience.as_deref().unwrap_or(&config.audience),
+        grant_type: &config.grant_type,
+        username: &config.username,
+        password: &config.password,
     };
 
     http_client
-        .post(url)
+        .post(&url)
         .json(&json_body)
         .send()?
         .error_for_status()?
         .json()
 }
 
+/// Lease up to `n_jobs` jobs. The API hands them out as it responds, so the request is only retried
+/// if it wasn't sent at all: resending it after a lost response would orphan the jobs leased by
+/// the first attempt.
 pub fn fetch_bulk_job(
     http_client: &Client,
+    backend: &Backend,
     access_token: &str,
     n_jobs: usize,
 ) -> reqwest::Result<Vec<models::Job>> {
-    http_client
-        .post(format!("{}/jobs", APP_CONFIG.base_url))
-        .header("Authorization", format!("Bearer {access_token}"))
-        .query(&[("batch", n_jobs)])
-        .send()?
-        .error_for_status()?
-        .json()
+    retry_unsent("fetching jobs", || {
+        http_client
+            .post(format!("{}/jobs", backend.base_url))
+            .header("Authorization", format!("Bearer {access_token}"))
+            .query(&[("batch", n_jobs)])
+            .send()?
+            .error_for_status()?
+            .json()
+    })
 }
 
+/// The header the API sends the hex SHA-256 digest of the ruleset's body in
+const RULES_DIGEST: &str = "x-rules-sha256";
+
+/// Fetch the ruleset, or `None` if it's still the one that was served with `etag`.
+///
+/// The body is downloaded with [`Resumable`], so a connection that fails halfway resumes instead
+/// of leaving the client on its current rules. It's checked against the digest the API sends in
+/// the `X-Rules-Sha256` header before it's parsed. Rules served without a digest can't be checked,
+/// which is warned about every time.
 pub fn fetch_rules(
     http_client: &Client,
+    backend: &Backend,
     access_token: &str,
-) -> reqwest::Result<models::RulesResponse> {
-    http_client
-        .get(format!("{}/rules", APP_CONFIG.base_url))
-        .header("Authorization", format!("Bearer {access_token}"))
-        .send()?
-        .error_for_status()?
-        .json()
+    etag: Option<&str>,
+) -> color_eyre::Result<Option<models::RulesResponse>> {
+    let url = Url::parse(&format!("{}/rules", backend.base_url))?;
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        AUTHORIZATION,
+        HeaderValue::from_str(&format!("Bearer {access_token}"))?,
+    );
+    // ranges of a compressed body don't line up with what was decompressed of it
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+
+    let response = retry("fetching rules", || {
+        let mut request = http_client.get(url.clone()).headers(headers.clone());
+        if let Some(etag) = etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        request.send()?.error_for_status()
+    })?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    let etag = response
+        .headers()
+        .get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(ToOwned::to_owned);
+    let expected = response
+        .headers()
+        .get(RULES_DIGEST)
+        .and_then(|digest| digest.to_str().ok())
+        .map(ToOwned::to_owned);
+
+    let mut body = Vec::new();
+    let mut reader =
+        Hashing::new(Resumable::new(http_client, url.clone(), response).with_headers(headers));
+    reader.read_to_end(&mut body)?;
+    match expected {
+        Some(expected) => integrity::verify(&url, &expected, reader.finish())?,
+        None => {
+            warn!("The API sent the rules without an {RULES_DIGEST} header, they can't be verified")
+        }
+    }
+    let mut rules: models::RulesResponse = serde_json::from_slice(&body)?;
+    rules.etag = etag;
+
+    Ok(Some(rules))
+}
+
+/// Fetch the changes to the rule files since the ruleset `from`
+pub fn fetch_rules_delta(
+    http_client: &Client,
+    backend: &Backend,
+    access_token: &str,
+    from: &str,
+) -> reqwest::Result<models::RulesDelta> {
+    retry("fetching a rules delta", || {
+        http_client
+            .get(format!("{}/rules/delta", backend.base_url))
+            .header("Authorization", format!("Bearer {access_token}"))
+            .query(&[("from", from)])
+            .send()?
+            .error_for_status()?
+            .json()
+    })
+}
+
+/// Fetch only the hash of the current ruleset, to check whether the rules need updating at all
+pub fn fetch_rules_hash(
+    http_client: &Client,
+    backend: &Backend,
+    access_token: &str,
+) -> reqwest::Result<String> {
+    retry("fetching the rules hash", || {
+        http_client
+            .get(format!("{}/rules/hash", backend.base_url))
+            .header("Authorization", format!("Bearer {access_token}"))
+            .send()?
+            .error_for_status()?
+            .json()
+    })
+    .map(|response: models::RulesHashResponse| response.hash)
 }
 
-pub fn send_result(
+/// Report a ruleset that doesn't compile or failed the canary self-test, see
+/// [`models::RulesHealthReport`]
+pub fn report_rules_health(
     http_client: &Client,
+    backend: &Backend,
     access_token: &str,
-    body: models::ScanResult,
+    body: &models::RulesHealthReport,
+) -> reqwest::Result<()> {
+    retry("reporting the rules health", || {
+        http_client
+            .post(format!("{}/rules/health", backend.base_url))
+            .header("Authorization", format!("Bearer {access_token}"))
+            .json(body)
+            .send()?
+            .error_for_status()?;
+
+        Ok(())
+    })
+}
+
+/// Send one chunk of a streamed file results submission. `body` holds newline delimited
+/// [`models::FileResultPart`]s, and `chunk` is the 0-based index of this chunk for the package.
+/// The API answers a chunk with an `idempotency_key` it already has with `409 Conflict`, which
+/// counts as sent.
+#[allow(clippy::too_many_arguments)]
+pub fn send_file_results_chunk(
+    http_client: &Client,
+    backend: &Backend,
+    access_token: &str,
+    name: &str,
+    version: &str,
+    chunk: usize,
+    body: &str,
+    idempotency_key: &str,
+) -> color_eyre::Result<()> {
+    Ok(retry("sending file results", || {
+        let response = http_client
+            .put(format!("{}/package/files", backend.base_url))
+            .header("Authorization", format!("Bearer {access_token}"))
+            .header(CONTENT_TYPE, "application/x-ndjson")
+            .header("Idempotency-Key", idempotency_key)
+            .query(&[("name", name), ("version", version)])
+            .query(&[("chunk", chunk)])
+            .body(body.to_owned())
+            .send()?;
+        if response.status() == StatusCode::CONFLICT {
+            debug!("File results {idempotency_key} were already submitted");
+            return Ok(());
+        }
+        response.error_for_status()?;
+
+        Ok(())
+    })?)
+}
+
+/// Send a result, compressed if it's large, see [`compression`]. The API answers a result with an
+/// `idempotency_key` it already has with `409 Conflict`, which counts as sent.
+pub fn send_result<T: Serialize + ?Sized>(
+    http_client: &Client,
+    backend: &Backend,
+    access_token: &str,
+    body: &T,
+    idempotency_key: &str,
+) -> color_eyre::Result<()> {
+    let url = format!("{}/package", backend.base_url);
+    let body = serde_json::to_vec(body)?;
+    let compressed = compression::compress(
+        &body,
+        APP_CONFIG.load().result_compression,
+        APP_CONFIG.load().result_compression_threshold,
+    );
+
+    Ok(retry("sending a result", || {
+        let request = || {
+            http_client
+                .put(&url)
+                .header("Authorization", format!("Bearer {access_token}"))
+                .header(CONTENT_TYPE, "application/json")
+                .header("Idempotency-Key", idempotency_key)
+        };
+        let acknowledged = |response: Response| {
+            if response.status() == StatusCode::CONFLICT {
+                debug!("Result {idempotency_key} was already submitted");
+                return Ok(());
+            }
+            response.error_for_status().map(drop)
+        };
+
+        if let Some((compressed, encoding)) = compressed
+            .as_ref()
+            .filter(|_| compression::accepted(&backend.base_url))
+        {
+            let response = request()
+                .header(CONTENT_ENCODING, *encoding)
+                .body(compressed.clone())
+                .send()?;
+            if !compression::refused(&response, &backend.base_url) {
+                return acknowledged(response);
+            }
+        }
+
+        acknowledged(request().body(body.clone()).send()?)
+    })?)
+}
+
+/// Mark the job for `name` `version` as skipped, without a result. Used in dry runs, so the job
+/// isn't handed out again.
+pub fn skip_job(
+    http_client: &Client,
+    backend: &Backend,
+    access_token: &str,
+    name: &str,
+    version: &str,
+) -> reqwest::Result<()> {
+    retry("skipping a job", || {
+        http_client
+            .put(format!("{}/package/skip", backend.base_url))
+            .header("Authorization", format!("Bearer {access_token}"))
+            .query(&[("name", name), ("version", version)])
+            .send()?
+            .error_for_status()?;
+
+        Ok(())
+    })
+}
+
+/// Register this client with `backend`, returning the worker ID it was assigned
+pub fn register_client(
+    http_client: &Client,
+    backend: &Backend,
+    access_token: &str,
+    body: &models::Registration,
+) -> reqwest::Result<models::RegistrationResponse> {
+    retry("registering the client", || {
+        http_client
+            .post(format!("{}/clients", backend.base_url))
+            .header("Authorization", format!("Bearer {access_token}"))
+            .json(body)
+            .send()?
+            .error_for_status()?
+            .json()
+    })
+}
+
+/// Tell `backend` the client registered as `worker_id` is still alive
+pub fn send_keepalive(
+    http_client: &Client,
+    backend: &Backend,
+    access_token: &str,
+    worker_id: &str,
 ) -> reqwest::Result<()> {
-    let body: ScanResultSerializer = body.into();
     http_client
-        .put(format!("{}/package", APP_CONFIG.base_url))
+        .post(format!(
+            "{}/clients/{worker_id}/keepalive",
+            backend.base_url
+        ))
         .header("Authorization", format!("Bearer {access_token}"))
-        .json(&body)
         .send()?
         .error_for_status()?;
 
     Ok(())
 }
+
+/// Upload the statistics of this client, see [`crate::stats`]
+pub fn send_stats<T: Serialize + ?Sized>(
+    http_client: &Client,
+    backend: &Backend,
+    access_token: &str,
+    body: &T,
+) -> reqwest::Result<()> {
+    retry("sending statistics", || {
+        http_client
+            .post(format!("{}/stats", backend.base_url))
+            .header("Authorization", format!("Bearer {access_token}"))
+            .json(body)
+            .send()?
+            .error_for_status()?;
+
+        Ok(())
+    })
+}
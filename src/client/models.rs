@@ -1,15 +1,29 @@
+use chrono::{DateTime, Utc};
 use color_eyre::Result;
 use serde::Serialize;
 use serde::{self, Deserialize};
-use std::collections::HashMap;
-use std::fmt::Display;
-use yara::{Compiler, Rules};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display};
+use yara::{errors::CompileErrorLevel, Compiler, Rules};
+
+use super::bundles::Bundle;
+use crate::{
+    analyzers::Finding,
+    app_config::AppConfig,
+    build_info::BUILD_INFO,
+    host,
+    memory::ResourceLimit,
+    pypi,
+    quarantine::Artifact,
+    scanner::{Ioc, OversizedFile, PartialScan, SkippedFile, Snippet, Telemetry, Verdict},
+};
 
 pub type ScanResult = Result<SubmitJobResultsSuccess, SubmitJobResultsError>;
 
 #[derive(Serialize, Debug)]
 #[serde(untagged)]
 #[serde(remote = "ScanResult")]
+#[allow(clippy::large_enum_variant)] // mirrors `ScanResult`, it's never constructed
 enum ScanResultDef {
     Ok(SubmitJobResultsSuccess),
     Err(SubmitJobResultsError),
@@ -29,6 +43,10 @@ pub struct SubmitJobResultsSuccess {
     pub name: String,
     pub version: String,
     pub score: i64,
+
+    /// The score mapped onto 0 to 100, so it can be compared across rulesets.
+    pub confidence: u8,
+
     pub inspector_url: Option<String>,
 
     /// Contains all rule identifiers matched for the entire release.
@@ -36,6 +54,100 @@ pub struct SubmitJobResultsSuccess {
 
     /// The commit hash of the ruleset used to produce these results.
     pub commit: String,
+
+    /// Structured findings of the heuristic analyzers, across all distributions.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub findings: Vec<Finding>,
+
+    /// A coarse classification of the release, derived from the confidence and the matched rules.
+    pub verdict: Verdict,
+
+    /// Files over the size limit, which were skipped or only partially scanned.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub oversized_files: Vec<OversizedFile>,
+
+    /// Files that couldn't be read or scanned, and were left out of the results.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub files_skipped: Vec<SkippedFile>,
+
+    /// Distributions with more than `max_files_per_distribution` files, which were only scanned
+    /// up to the limit.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub partial_scans: Vec<PartialScan>,
+
+    /// The hosts of distributions that aren't on the Python Package Index (such as
+    /// mirrors), so the inspector can't
+    /// show them. `inspector_url` never links to those.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mirror_hosts: Vec<String>,
+
+    /// The popular package the name of this one looks like a typo of, see [`crate::typosquat`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub typosquat_candidate: Option<String>,
+
+    /// URLs, IP addresses and domains found in the files of all distributions, see
+    /// [`crate::scanner::Ioc`].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub iocs: Vec<Ioc>,
+
+    /// How long downloading, extracting and scanning the distributions took, and how much was
+    /// scanned, if `report_telemetry` is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub telemetry: Option<Telemetry>,
+
+    /// The uploaded files that matched rules with `quarantine = true` metadata, see
+    /// [`crate::quarantine`].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub artifacts: Vec<Artifact>,
+
+    /// The scores of the matched rules by the namespace they come from, if any `rule_bundles` are
+    /// configured. See [`crate::client::bundles`].
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub namespace_scores: BTreeMap<String, i64>,
+
+    /// The anonymized fingerprint of the host that produced these results, if enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<host::Fingerprint>,
+
+    /// The build of the client that produced these results, see
+    /// [`crate::build_info::BuildInfo::client_version`].
+    pub client_version: String,
+
+    /// The worker ID the mainframe assigned to this client, if it registered with
+    /// `register_client`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub worker_id: Option<String>,
+
+    /// What the index knows about the release, with `pypi_enrichment`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pypi_metadata: Option<pypi::Metadata>,
+}
+
+/// The results of a single file, sent as one line of a streamed file results submission
+#[derive(Debug, Serialize, PartialEq)]
+pub struct FileResultPart<'a> {
+    /// The file name of the distribution containing the file
+    pub distribution: Option<&'a str>,
+
+    /// The path of the file, relative to the distribution's archive root
+    pub path: String,
+
+    /// The total score of the rules matched by this file
+    pub score: i64,
+
+    /// Identifiers of the rules matched by this file
+    pub rules_matched: Vec<&'a str>,
+
+    /// The hex encoded SHA-256 hash of the file
+    pub sha256: &'a str,
+
+    /// The ssdeep fuzzy hash of the file, with `fuzzy_hashes`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssdeep: Option<&'a str>,
+
+    /// The context of the strings matched in this file, see `match_context`
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub snippets: &'a [Snippet],
 }
 
 #[derive(Debug, Serialize)]
@@ -43,6 +155,20 @@ pub struct SubmitJobResultsError {
     pub name: String,
     pub version: String,
     pub reason: String,
+
+    /// The limit the job ran into, if it was rejected for needing too much of a resource, see
+    /// [`crate::memory`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resource_limit: Option<ResourceLimit>,
+
+    /// The build of the client that failed, see
+    /// [`crate::build_info::BuildInfo::client_version`]
+    pub client_version: String,
+
+    /// The worker ID the mainframe assigned to this client, if it registered with
+    /// `register_client`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub worker_id: Option<String>,
 }
 
 impl Display for SubmitJobResultsError {
@@ -55,44 +181,313 @@ impl Display for SubmitJobResultsError {
     }
 }
 
-#[derive(Debug, Deserialize)]
+// The deserialized API responses accept the field names the API is moving to, and ignore fields
+// they don't know about, so the client keeps working throughout a planned rename. The fields a scan
+// can't do without are required, so a response missing them fails instead of passing as a job
+// without distributions or an empty ruleset, which would report every package clean.
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Job {
     pub hash: String,
+
+    #[serde(alias = "project")]
     pub name: String,
+
     pub version: String,
+
     pub distributions: Vec<String>,
+
+    /// The SHA-256 digests (in hex) the distributions were published with, by URL. Distributions
+    /// without one are only verified with `fetch_pypi_digests`.
+    #[serde(default)]
+    pub digests: HashMap<String, String>,
+
+    /// Set for analyst-triggered rescans, which must be scanned afresh with freshly compiled rules
+    /// and submitted even if a result for the same release was already submitted.
+    #[serde(default)]
+    pub rescan: bool,
+
+    /// The ruleset a rescan must be done with. Implies `rescan`.
+    #[serde(default)]
+    pub force_rules_hash: Option<String>,
+
+    /// How urgently the job must be scanned, such as for packages that were reported. Jobs with a
+    /// higher priority are scanned first, 0 being the bulk of the jobs.
+    #[serde(default)]
+    pub priority: i32,
+
+    /// When the API queued the job, older jobs of the same priority are scanned first
+    #[serde(default)]
+    pub queued_at: Option<DateTime<Utc>>,
+}
+
+impl Job {
+    /// Whether this job must bypass the rules-hash check and the result caches
+    pub fn is_forced(&self) -> bool {
+        self.rescan || self.force_rules_hash.is_some()
+    }
+
+    /// The hash of the ruleset this job must be scanned with
+    pub fn rules_hash(&self) -> &str {
+        self.force_rules_hash.as_deref().unwrap_or(&self.hash)
+    }
+
+    /// Whether this job can be scanned with the ruleset `rules_hash`. Rescans forced with a ruleset
+    /// must be scanned with exactly that one, other jobs are scanned with the rules at hand when
+    /// they can't be updated.
+    pub fn accepts_rules(&self, rules_hash: &str) -> bool {
+        self.force_rules_hash
+            .as_deref()
+            .map_or(true, |forced| forced == rules_hash)
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct RulesResponse {
     pub hash: String,
+
     pub rules: HashMap<String, String>,
+
+    /// The `ETag` the ruleset was served with, to ask for it only if it changed next time
+    #[serde(skip)]
+    pub etag: Option<String>,
+}
+
+/// The changes to the rule files since a previous ruleset
+#[derive(Debug, Deserialize)]
+pub struct RulesDelta {
+    /// The hash of the current ruleset
+    pub hash: String,
+
+    #[serde(default)]
+    pub added: HashMap<String, String>,
+
+    #[serde(default)]
+    pub modified: HashMap<String, String>,
+
+    #[serde(default)]
+    pub removed: Vec<String>,
+}
+
+impl RulesDelta {
+    /// The current ruleset, from the rule files of the previous one
+    pub fn apply(self, sources: &HashMap<String, String>) -> RulesResponse {
+        let mut rules = sources.clone();
+        for name in &self.removed {
+            rules.remove(name);
+        }
+        rules.extend(self.added);
+        rules.extend(self.modified);
+
+        RulesResponse {
+            hash: self.hash,
+            rules,
+            etag: None,
+        }
+    }
+}
+
+/// The hash of the current ruleset, without the rules
+#[derive(Debug, Deserialize)]
+pub struct RulesHashResponse {
+    pub hash: String,
 }
 
 impl RulesResponse {
     /// Compile the rules from the response
     pub fn compile(&self) -> Result<Rules> {
-        let rules_str = self
-            .rules
-            .values()
-            .map(String::as_ref)
-            .collect::<Vec<&str>>()
-            .join("\n");
+        self.compile_with(&[])
+    }
+
+    /// Compile the rules from the response, and each of the `bundles` into its own namespace.
+    /// Fails with a [`CompileFailure`] pointing at the offending rule files if they don't compile.
+    pub fn compile_with(&self, bundles: &[Bundle]) -> Result<Rules> {
+        let mut compiler = add_sources(Compiler::new()?, &self.hash, &self.rules, None)?;
+        for bundle in bundles {
+            compiler = add_sources(
+                compiler,
+                &self.hash,
+                &bundle.sources,
+                Some(&bundle.namespace),
+            )?;
+        }
+
+        Ok(compiler.compile_rules()?)
+    }
+}
+
+/// Add the rule files `sources` of the ruleset `hash` to `compiler` as a single source, in
+/// `namespace` if there's one. Fails with a [`CompileFailure`] if they don't compile.
+fn add_sources<'a>(
+    compiler: Compiler,
+    hash: &str,
+    sources: impl IntoIterator<Item = (&'a String, &'a String)>,
+    namespace: Option<&str>,
+) -> Result<Compiler> {
+    let sources = sources.into_iter().collect::<Vec<_>>();
+    // the line of the joined source each file starts at, to point the errors back at the files
+    let mut starts = Vec::with_capacity(sources.len());
+    let mut line = 1;
+    for (file, source) in &sources {
+        starts.push((line, *file));
+        line += source.matches('\n').count() + 1;
+    }
+    let joined = sources
+        .iter()
+        .map(|(_, source)| source.as_str())
+        .collect::<Vec<&str>>()
+        .join("\n");
+
+    let added = match namespace {
+        Some(namespace) => compiler.add_rules_str_with_namespace(&joined, namespace),
+        None => compiler.add_rules_str(&joined),
+    };
+    let errors = match added {
+        Ok(compiler) => return Ok(compiler),
+        Err(yara::Error::Compile(errors)) => errors,
+        Err(err) => return Err(err.into()),
+    };
+    let diagnostics = errors
+        .iter()
+        .filter(|error| error.level == CompileErrorLevel::Error)
+        .map(|error| {
+            let (start, file) = starts
+                .iter()
+                .rev()
+                .find(|(start, _)| *start <= error.line)
+                .map_or((1, None), |(start, file)| (*start, Some(*file)));
+            CompileDiagnostic {
+                file: file.map(|file| match namespace {
+                    Some(namespace) => format!("{namespace}:{file}"),
+                    None => file.clone(),
+                }),
+                line: error.line + 1 - start,
+                message: error.message.clone(),
+            }
+        })
+        .collect();
+
+    Err(CompileFailure {
+        hash: hash.to_owned(),
+        diagnostics,
+    }
+    .into())
+}
+
+/// Where a ruleset fails to compile
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CompileDiagnostic {
+    /// The rule file, prefixed with the namespace of its bundle if it's from one
+    pub file: Option<String>,
+
+    /// The line of the rule file
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for CompileDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{file}:")?;
+        }
+        write!(f, "{}: {}", self.line, self.message)
+    }
+}
 
-        let compiled_rules = Compiler::new()?
-            .add_rules_str(&rules_str)?
-            .compile_rules()?;
+/// A ruleset that doesn't compile, see [`RulesResponse::compile_with`]
+#[derive(Debug)]
+pub struct CompileFailure {
+    /// The hash of the ruleset
+    pub hash: String,
+    pub diagnostics: Vec<CompileDiagnostic>,
+}
 
-        Ok(compiled_rules)
+impl Display for CompileFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the rules {} don't compile", self.hash)?;
+        for (index, diagnostic) in self.diagnostics.iter().enumerate() {
+            let separator = if index == 0 { ": " } else { "; " };
+            write!(f, "{separator}{diagnostic}")?;
+        }
+        Ok(())
     }
 }
 
+impl std::error::Error for CompileFailure {}
+
 #[derive(Debug, Deserialize)]
 pub struct AuthResponse {
     pub access_token: String,
     pub expires_in: u32,
 }
 
+/// A ruleset that doesn't compile or failed the canary self-test, see
+/// [`crate::scanner::canary_self_test`]
+#[derive(Debug, Serialize)]
+pub struct RulesHealthReport<'a> {
+    /// The hash of the broken ruleset
+    pub hash: &'a str,
+    pub error: String,
+
+    /// Where the ruleset fails to compile, if that's what's wrong with it
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<CompileDiagnostic>,
+}
+
+/// What this client tells the mainframe about itself when it registers, see `register_client`
+#[derive(Debug, Serialize)]
+pub struct Registration<'a> {
+    pub hostname: String,
+
+    /// The worker ID the client asks for, from `worker_id`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub worker_id: Option<&'a str>,
+
+    pub client_version: String,
+    pub capabilities: Capabilities,
+}
+
+/// What a client can scan, so the mainframe can tell its scanners apart
+#[derive(Debug, Serialize)]
+pub struct Capabilities {
+    /// The engines files are matched with, YARA and the enabled analyzers
+    pub engines: Vec<&'static str>,
+    pub yara_version: &'static str,
+    pub threads: usize,
+    pub max_scan_size: u64,
+    pub max_file_size: u64,
+    pub max_archive_depth: usize,
+}
+
+impl<'a> Registration<'a> {
+    /// The registration of a client running on `hostname` with `config`
+    pub fn new(hostname: String, config: &'a AppConfig) -> Self {
+        let mut engines = vec!["yara"];
+        if config.ast_analysis {
+            engines.push("ast");
+        }
+
+        Self {
+            hostname,
+            worker_id: config.worker_id.as_deref(),
+            client_version: BUILD_INFO.client_version(),
+            capabilities: Capabilities {
+                engines,
+                yara_version: BUILD_INFO.yara_version,
+                threads: config.threads,
+                max_scan_size: config.max_scan_size,
+                max_file_size: config.max_file_size,
+                max_archive_depth: config.max_archive_depth,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegistrationResponse {
+    pub worker_id: String,
+}
+
 #[derive(Debug, Serialize)]
 pub struct AuthBody<'a> {
     pub client_id: &'a str,
@@ -102,3 +497,210 @@ pub struct AuthBody<'a> {
     pub username: &'a str,
     pub password: &'a str,
 }
+
+#[cfg(test)]
+mod tests {
+    //! Contract tests against responses recorded from the mainframe and Auth0, in both their
+    //! current shape and the shape they're moving to.
+
+    use super::{
+        AuthResponse, CompileFailure, Job, Registration, RegistrationResponse, RulesDelta,
+        RulesHashResponse, RulesResponse,
+    };
+    use crate::app_config::AppConfig;
+    use std::collections::HashMap;
+
+    const HASH: &str = "3f8e1b2c9d4a7e6f5b0c1d2e3f4a5b6c7d8e9f0a";
+
+    #[test]
+    fn deserializes_jobs() {
+        let jobs: Vec<Job> = serde_json::from_str(include_str!("fixtures/jobs.json")).unwrap();
+
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].hash, HASH);
+        assert_eq!(jobs[0].name, "requests");
+        assert_eq!(jobs[0].version, "2.19.1");
+        assert_eq!(jobs[0].distributions.len(), 2);
+        assert!(!jobs[0].is_forced());
+        assert!(jobs[0].accepts_rules("c"));
+    }
+
+    #[test]
+    fn deserializes_rescan_jobs() {
+        let job: Job = serde_json::from_str(
+            r#"{"hash": "a", "name": "pkg", "version": "1.0", "distributions": [],
+                "force_rules_hash": "b"}"#,
+        )
+        .unwrap();
+
+        assert!(job.is_forced());
+        assert_eq!(job.rules_hash(), "b");
+        assert!(job.accepts_rules("b"));
+        assert!(!job.accepts_rules("a"));
+        assert!(!job.accepts_rules("c"));
+    }
+
+    #[test]
+    fn deserializes_prioritized_jobs() {
+        let job: Job = serde_json::from_str(
+            r#"{"hash": "a", "name": "pkg", "version": "1.0", "distributions": [], "priority": 10,
+                "queued_at": "2024-06-01T12:00:00.123456+00:00"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(job.priority, 10);
+        assert!(job.queued_at.is_some());
+    }
+
+    #[test]
+    fn deserializes_renamed_jobs() {
+        let jobs: Vec<Job> =
+            serde_json::from_str(include_str!("fixtures/jobs_renamed.json")).unwrap();
+
+        assert_eq!(jobs[0].hash, HASH);
+        assert_eq!(jobs[0].name, "requests");
+        assert_eq!(jobs[0].distributions.len(), 1);
+        assert_eq!(
+            jobs[0].queued_at.map(|queued_at| queued_at.to_rfc3339()),
+            Some(String::from("2024-06-01T12:00:00+00:00"))
+        );
+        assert_eq!(jobs[1].priority, 0);
+    }
+
+    #[test]
+    fn rejects_jobs_without_distributions() {
+        let job = serde_json::from_str::<Job>(r#"{"hash": "a", "name": "pkg", "version": "1.0"}"#);
+        assert!(job.is_err());
+
+        let rules = serde_json::from_str::<RulesResponse>(&format!(r#"{{"hash": "{HASH}"}}"#));
+        assert!(rules.is_err());
+    }
+
+    #[test]
+    fn deserializes_rules() {
+        for fixture in [
+            include_str!("fixtures/rules.json"),
+            include_str!("fixtures/rules_renamed.json"),
+        ] {
+            let response: RulesResponse = serde_json::from_str(fixture).unwrap();
+
+            assert_eq!(response.hash, HASH);
+            assert!(response.rules.contains_key("exec_base64"));
+            response.compile().unwrap();
+        }
+    }
+
+    #[test]
+    fn applies_rules_deltas() {
+        let sources = HashMap::from([
+            (
+                String::from("a"),
+                String::from("rule a { condition: true }"),
+            ),
+            (
+                String::from("b"),
+                String::from("rule b { condition: true }"),
+            ),
+            (
+                String::from("c"),
+                String::from("rule c { condition: true }"),
+            ),
+        ]);
+        let delta: RulesDelta = serde_json::from_str(&format!(
+            r#"{{
+                "hash": "{HASH}",
+                "added": {{"d": "rule d {{ condition: true }}"}},
+                "modified": {{"b": "rule b {{ condition: false }}"}},
+                "removed": ["c"]
+            }}"#
+        ))
+        .unwrap();
+
+        let response = delta.apply(&sources);
+        assert_eq!(response.hash, HASH);
+        let mut names = response
+            .rules
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, ["a", "b", "d"]);
+        assert_eq!(response.rules["b"], "rule b { condition: false }");
+        response.compile().unwrap();
+    }
+
+    #[test]
+    fn deserializes_rules_hashes() {
+        let body = format!(r#"{{"hash": "{HASH}"}}"#);
+        let response: RulesHashResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(response.hash, HASH);
+        assert!(serde_json::from_str::<RulesHashResponse>(r#"{"commit": "a"}"#).is_err());
+    }
+
+    #[test]
+    fn deserializes_tokens() {
+        let response: AuthResponse =
+            serde_json::from_str(include_str!("fixtures/token.json")).unwrap();
+        assert_eq!(response.expires_in, 86400);
+
+        let response = serde_json::from_str::<AuthResponse>(r#"{"access_token": "eyJ"}"#);
+        assert!(response.is_err());
+    }
+
+    #[test]
+    fn registers_with_capabilities() {
+        let config = AppConfig {
+            worker_id: Some(String::from("scanner-3")),
+            ast_analysis: true,
+            threads: 4,
+            ..AppConfig::default()
+        };
+        let body =
+            serde_json::to_value(Registration::new(String::from("host-1"), &config)).unwrap();
+        assert_eq!(body["hostname"], "host-1");
+        assert_eq!(body["worker_id"], "scanner-3");
+        assert_eq!(
+            body["capabilities"]["engines"],
+            serde_json::json!(["yara", "ast"])
+        );
+        assert_eq!(body["capabilities"]["threads"], 4);
+
+        let body = serde_json::to_value(Registration::new(
+            String::from("host-1"),
+            &AppConfig::default(),
+        ))
+        .unwrap();
+        assert!(body.get("worker_id").is_none());
+
+        let response: RegistrationResponse =
+            serde_json::from_str(r#"{"worker_id": "w-17", "status": "live"}"#).unwrap();
+        assert_eq!(response.worker_id, "w-17");
+    }
+
+    #[test]
+    fn points_compile_failures_at_the_rule_file() {
+        let response = RulesResponse {
+            hash: String::from(HASH),
+            rules: HashMap::from([
+                (
+                    String::from("good.yar"),
+                    String::from("rule good {\n    condition: true\n}"),
+                ),
+                (
+                    String::from("bad.yar"),
+                    String::from(
+                        "rule fine { condition: true }\n\nrule bad {\n    condition: $missing }",
+                    ),
+                ),
+            ]),
+            etag: None,
+        };
+
+        let err = response.compile().err().unwrap();
+        let failure = err.downcast_ref::<CompileFailure>().unwrap();
+        assert_eq!(failure.hash, HASH);
+        assert_eq!(failure.diagnostics.len(), 1);
+        assert_eq!(failure.diagnostics[0].file.as_deref(), Some("bad.yar"));
+        assert_eq!(failure.diagnostics[0].line, 4);
+    }
+}
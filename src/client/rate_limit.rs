@@ -0,0 +1,225 @@
+//! Client-side rate limiting of distribution downloads, so bursts of bulk jobs don't hammer the
+//! package index.
+//!
+//! Every download takes a token from a bucket refilled at `download_rate_limit` tokens per second
+//! and holds one of `max_concurrent_downloads` slots while it's read. When the index answers with
+//! `429 Too Many Requests`, all downloads pause for as long as its `Retry-After` header asks.
+
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use parking_lot::{Condvar, Mutex};
+use reqwest::{blocking::Response, header::RETRY_AFTER};
+
+use crate::APP_CONFIG;
+
+/// The longest pause a `Retry-After` header can ask for
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(600);
+
+/// The pause after a `429` without a `Retry-After` header
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+struct State {
+    /// The tokens left in the bucket, at most `burst`
+    tokens: f64,
+    refilled_at: Instant,
+
+    /// The amount of downloads currently holding a [`Permit`]
+    active: usize,
+
+    /// When the index asked to be left alone until, see [`Limiter::back_off`]
+    paused_until: Option<Instant>,
+}
+
+/// Limits how often (and how many at once) distributions are downloaded, see the module docs
+pub struct Limiter {
+    /// Tokens added per second, unlimited if 0
+    rate: f64,
+    burst: f64,
+
+    /// The most downloads at once, unlimited if 0
+    max_concurrent: usize,
+
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+/// A slot of a running download, given back when dropped
+pub struct Permit<'a> {
+    limiter: &'a Limiter,
+}
+
+impl Limiter {
+    pub fn new(rate: f64, max_concurrent: usize) -> Self {
+        let rate = rate.max(0.0);
+        let burst = rate.max(1.0);
+        Self {
+            rate,
+            burst,
+            max_concurrent,
+            state: Mutex::new(State {
+                tokens: burst,
+                refilled_at: Instant::now(),
+                active: 0,
+                paused_until: None,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Block until a download may start, and hold a slot until the returned [`Permit`] is dropped
+    pub fn acquire(&self) -> Permit<'_> {
+        let mut state = self.state.lock();
+        while self.max_concurrent > 0 && state.active >= self.max_concurrent {
+            self.released.wait(&mut state);
+        }
+        state.active += 1;
+
+        loop {
+            let wait = self.wait_time(&mut state, Instant::now());
+            if wait.is_zero() {
+                break;
+            }
+            // other downloads may release their slots or back off in the meantime
+            self.released.wait_for(&mut state, wait);
+        }
+
+        Permit { limiter: self }
+    }
+
+    /// How long until a token can be taken, taking it right away if one is available
+    fn wait_time(&self, state: &mut State, now: Instant) -> Duration {
+        if let Some(until) = state.paused_until {
+            if until > now {
+                return until - now;
+            }
+            state.paused_until = None;
+        }
+        if self.rate == 0.0 {
+            return Duration::ZERO;
+        }
+
+        let elapsed = now.saturating_duration_since(state.refilled_at);
+        state.tokens = (state.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        state.refilled_at = now;
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - state.tokens) / self.rate)
+        }
+    }
+
+    /// Pause all downloads for `delay`, unless they're already paused for longer
+    pub fn back_off(&self, delay: Duration) {
+        let until = Instant::now() + delay.min(MAX_RETRY_AFTER);
+        let mut state = self.state.lock();
+        state.paused_until = state.paused_until.max(Some(until));
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.limiter.state.lock().active -= 1;
+        self.limiter.released.notify_all();
+    }
+}
+
+/// The limiter shared by all downloads, configured on startup
+pub static DOWNLOADS: Lazy<Limiter> = Lazy::new(|| {
+    Limiter::new(
+        APP_CONFIG.load().download_rate_limit,
+        APP_CONFIG.load().max_concurrent_downloads,
+    )
+});
+
+/// How long a `429` response asks to wait before trying again. `Retry-After` is either an amount
+/// of seconds or an HTTP date.
+pub fn retry_after(response: &Response) -> Duration {
+    response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_retry_after(value, Utc::now()))
+        .unwrap_or(DEFAULT_RETRY_AFTER)
+}
+
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_retry_after, Limiter};
+    use chrono::{TimeZone, Utc};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn parses_retry_after() {
+        let now = Utc.with_ymd_and_hms(2015, 10, 21, 7, 28, 0).unwrap();
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn refills_tokens_at_the_rate() {
+        let limiter = Limiter::new(2.0, 0);
+        let mut state = limiter.state.lock();
+        let now = state.refilled_at;
+
+        assert_eq!(limiter.wait_time(&mut state, now), Duration::ZERO);
+        assert_eq!(limiter.wait_time(&mut state, now), Duration::ZERO);
+        assert_eq!(
+            limiter.wait_time(&mut state, now),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            limiter.wait_time(&mut state, now + Duration::from_millis(500)),
+            Duration::ZERO
+        );
+
+        state.paused_until = Some(now + Duration::from_secs(3));
+        assert_eq!(
+            limiter.wait_time(&mut state, now + Duration::from_secs(1)),
+            Duration::from_secs(2)
+        );
+    }
+
+    #[test]
+    fn limits_concurrent_downloads() {
+        let limiter = Limiter::new(0.0, 1);
+        let first = limiter.acquire();
+        let start = Instant::now();
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(50));
+                drop(first);
+            });
+            let _second = limiter.acquire();
+            assert!(start.elapsed() >= Duration::from_millis(50));
+        });
+    }
+}
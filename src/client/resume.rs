@@ -0,0 +1,291 @@
+//! Resuming downloads that are cut off halfway.
+//!
+//! Large wheels occasionally fail mid-download, which used to fail the whole job. A [`Resumable`]
+//! download picks up where it left off with a `Range` request instead. Servers that ignore the
+//! range send the whole file again, of which the part that was already read is skipped. Either
+//! way, a download only ends once it has the size the first response announced. Range requests
+//! carry the `ETag` (or `Last-Modified` date) of the first response in `If-Range`, so a file that
+//! changed in the meantime fails the download instead of being spliced from two versions.
+//!
+//! Rulesets are downloaded the same way, with the headers of the first request sent again with
+//! every range request, see [`Resumable::with_headers`].
+
+use std::io::{self, Read};
+
+use reqwest::{
+    blocking::{Client, Response},
+    header::{HeaderMap, HeaderValue, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE},
+    StatusCode, Url,
+};
+use tracing::warn;
+
+use super::retry::Policy;
+use crate::APP_CONFIG;
+
+/// A download that resumes after its connection fails, see the module docs
+pub struct Resumable<'a> {
+    http_client: &'a Client,
+    url: Url,
+    response: Response,
+
+    /// Sent with every range request
+    headers: HeaderMap,
+
+    /// The amount of bytes read so far
+    offset: u64,
+
+    /// The size announced by the first response, if any
+    expected: Option<u64>,
+
+    /// The version of the file the first response sent, see [`validator`]
+    validator: Option<HeaderValue>,
+
+    /// How often the download was resumed
+    attempt: u32,
+    max_attempts: u32,
+    policy: Policy,
+}
+
+/// What identifies the version of the file `response` sends, for `If-Range`: its strong `ETag`, or
+/// its `Last-Modified` date
+fn validator(response: &Response) -> Option<HeaderValue> {
+    let headers = response.headers();
+    headers
+        .get(ETAG)
+        .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+        .or_else(|| headers.get(LAST_MODIFIED))
+        .cloned()
+}
+
+/// Where the body of a `206 Partial Content` response starts, from `bytes <start>-<end>/<size>`
+fn range_start(response: &Response) -> Option<u64> {
+    let value = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    let range = value.strip_prefix("bytes ")?;
+    range.split_once('-')?.0.trim().parse().ok()
+}
+
+impl<'a> Resumable<'a> {
+    /// Continue the download of `url` that `response` started, resuming it at most
+    /// `download_resume_attempts` times
+    pub fn new(http_client: &'a Client, url: Url, response: Response) -> Self {
+        Self {
+            http_client,
+            url,
+            headers: HeaderMap::new(),
+            expected: response.content_length(),
+            validator: validator(&response),
+            response,
+            offset: 0,
+            attempt: 0,
+            max_attempts: APP_CONFIG.load().download_resume_attempts,
+            policy: Policy::from(&**APP_CONFIG.load()),
+        }
+    }
+
+    /// Send `headers` with the range requests too, such as the credentials the download needs
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Replace the failed response with one that continues at `offset`, trying again while the
+    /// request itself fails
+    fn resume(&mut self, mut err: io::Error) -> io::Result<()> {
+        loop {
+            if self.attempt >= self.max_attempts {
+                return Err(err);
+            }
+            self.attempt += 1;
+
+            let delay = self.policy.delay(self.attempt);
+            warn!(
+                "Download of {} failed after {} bytes (attempt {}/{}): {err}. Resuming in {:.3} seconds",
+                self.url,
+                self.offset,
+                self.attempt,
+                self.max_attempts,
+                delay.as_secs_f64()
+            );
+            std::thread::sleep(delay);
+
+            let mut request = self
+                .http_client
+                .get(self.url.clone())
+                .headers(self.headers.clone())
+                .header(RANGE, format!("bytes={}-", self.offset));
+            if let Some(validator) = &self.validator {
+                request = request.header(IF_RANGE, validator.clone());
+            }
+            match request.send().and_then(Response::error_for_status) {
+                Ok(response) => return self.continue_with(response),
+                Err(request_err) => err = io::Error::other(request_err),
+            }
+        }
+    }
+
+    /// Continue reading from `response`, if it continues at `offset`
+    fn continue_with(&mut self, mut response: Response) -> io::Result<()> {
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT if range_start(&response) == Some(self.offset) => {}
+            // the server sends the whole file when it changed since the `If-Range` validator
+            StatusCode::OK
+                if self.validator.is_some() && validator(&response) != self.validator =>
+            {
+                return Err(io::Error::other(format!(
+                    "{} changed while resuming its download",
+                    self.url
+                )));
+            }
+            StatusCode::OK if response.content_length() == self.expected => {
+                warn!(
+                    "{} doesn't support range requests, downloading it again",
+                    self.url
+                );
+                let skipped = io::copy(&mut (&mut response).take(self.offset), &mut io::sink())?;
+                if skipped != self.offset {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("{} got shorter while resuming", self.url),
+                    ));
+                }
+            }
+            status => {
+                return Err(io::Error::other(format!(
+                    "can't resume the download of {} at {} bytes ({status})",
+                    self.url, self.offset
+                )))
+            }
+        }
+
+        self.response = response;
+        Ok(())
+    }
+}
+
+impl Read for Resumable<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let err = match self.response.read(buf) {
+                Ok(0)
+                    if !buf.is_empty() && self.expected.is_some_and(|size| self.offset < size) =>
+                {
+                    io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!(
+                            "the download ended after {} of {} bytes",
+                            self.offset,
+                            self.expected.unwrap_or_default()
+                        ),
+                    )
+                }
+                Ok(read) => {
+                    self.offset += read as u64;
+                    return Ok(read);
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => err,
+            };
+            self.resume(err)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Resumable;
+    use parking_lot::Mutex;
+    use reqwest::{
+        blocking::Client,
+        header::{HeaderMap, HeaderValue, AUTHORIZATION},
+        Url,
+    };
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::Arc,
+    };
+
+    /// Answer each connection with the next of `responses`, returning the URL and the heads of
+    /// the requests
+    fn serve(responses: Vec<String>) -> (Url, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/pkg.whl",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let heads = Arc::new(Mutex::new(Vec::new()));
+
+        let seen = Arc::clone(&heads);
+        std::thread::spawn(move || {
+            for (stream, response) in listener.incoming().zip(responses) {
+                let mut stream = stream.unwrap();
+                let mut head = String::new();
+                let mut reader = BufReader::new(&stream);
+                while reader.read_line(&mut head).unwrap() > 2 {}
+                seen.lock().push(head);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        (url, heads)
+    }
+
+    fn download(responses: Vec<String>) -> (std::io::Result<String>, Vec<String>) {
+        let (url, heads) = serve(responses);
+        let client = Client::new();
+        let response = client.get(url.clone()).send().unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer token"));
+        let mut body = String::new();
+        let result = Resumable::new(&client, url, response)
+            .with_headers(headers)
+            .read_to_string(&mut body)
+            .map(|_| body);
+        let heads = heads.lock().clone();
+        (result, heads)
+    }
+
+    const CUT_OFF: &str = "HTTP/1.1 200 OK\r\nContent-Length: 10\r\nConnection: close\r\n\r\n0123";
+
+    #[test]
+    fn resumes_with_range_requests() {
+        let (body, heads) = download(vec![
+            CUT_OFF.into(),
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 4-9/10\r\nContent-Length: 6\r\nConnection: close\r\n\r\n456789".into(),
+        ]);
+
+        assert_eq!(body.unwrap(), "0123456789");
+        let resumed = heads[1].to_ascii_lowercase();
+        assert!(resumed.contains("range: bytes=4-"));
+        assert!(resumed.contains("authorization: bearer token"));
+    }
+
+    #[test]
+    fn downloads_again_without_range_support() {
+        let (body, _) = download(vec![
+            CUT_OFF.into(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 10\r\nConnection: close\r\n\r\n0123456789".into(),
+        ]);
+        assert_eq!(body.unwrap(), "0123456789");
+
+        // the file changed in the meantime
+        let (body, _) = download(vec![
+            CUT_OFF.into(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nabc".into(),
+        ]);
+        assert!(body.is_err());
+    }
+
+    #[test]
+    fn does_not_splice_files_that_changed() {
+        let (body, heads) = download(vec![
+            "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 10\r\nConnection: close\r\n\r\n0123".into(),
+            "HTTP/1.1 200 OK\r\nETag: \"v2\"\r\nContent-Length: 10\r\nConnection: close\r\n\r\nabcdefghij".into(),
+        ]);
+
+        assert!(body.is_err());
+        assert!(heads[1].to_ascii_lowercase().contains("if-range: \"v1\""));
+    }
+}
@@ -0,0 +1,228 @@
+use std::time::Duration;
+
+use rand::Rng;
+use reqwest::StatusCode;
+use tracing::warn;
+
+use crate::{app_config::AppConfig, APP_CONFIG};
+
+/// How failed API requests are retried
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    /// The total amount of attempts, including the first one
+    pub max_attempts: u32,
+
+    /// The delay before the first retry, doubled for every retry after that
+    pub base_delay: Duration,
+
+    /// The upper bound of the delay between two attempts, before jitter is applied
+    pub max_delay: Duration,
+
+    /// The fraction (between 0 and 1) of each delay that is randomized, so that clients which
+    /// failed at the same time don't all retry at the same time
+    pub jitter: f64,
+}
+
+impl From<&AppConfig> for Policy {
+    fn from(config: &AppConfig) -> Self {
+        Self {
+            max_attempts: config.retry_max_attempts.max(1),
+            base_delay: Duration::from_millis(config.retry_base_delay_ms),
+            max_delay: Duration::from_millis(config.retry_max_delay_ms),
+            jitter: config.retry_jitter.clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl Policy {
+    /// The delay to wait after the given (1-based) failed attempt
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)));
+        let delay = exponential.min(self.max_delay);
+
+        let jitter = rand::thread_rng().gen_range(0.0..=self.jitter);
+        delay.mul_f64(1.0 - jitter)
+    }
+
+    /// Run `request` until it succeeds, fails with an error that isn't worth retrying, or the
+    /// attempts run out. `what` describes the request in the logs.
+    pub fn run<T, F>(&self, what: &str, request: F) -> reqwest::Result<T>
+    where
+        F: FnMut() -> reqwest::Result<T>,
+    {
+        self.run_while(what, is_retryable, request)
+    }
+
+    /// Run `request` until it succeeds, fails with an error that isn't `retryable`, or the attempts
+    /// run out, see [`Policy::run`]
+    fn run_while<T, F>(
+        &self,
+        what: &str,
+        retryable: fn(&reqwest::Error) -> bool,
+        mut request: F,
+    ) -> reqwest::Result<T>
+    where
+        F: FnMut() -> reqwest::Result<T>,
+    {
+        let mut attempt = 1;
+        loop {
+            match request() {
+                Err(err) if attempt < self.max_attempts && retryable(&err) => {
+                    let delay = self.delay(attempt);
+                    warn!(
+                        "Failed {what} (attempt {attempt}/{}): {err}. Retrying in {:.3} seconds",
+                        self.max_attempts,
+                        delay.as_secs_f64()
+                    );
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Run `request` with the retry policy from the global configuration. See [`Policy::run`].
+pub fn retry<T, F>(what: &str, request: F) -> reqwest::Result<T>
+where
+    F: FnMut() -> reqwest::Result<T>,
+{
+    Policy::from(&**APP_CONFIG.load()).run(what, request)
+}
+
+/// Run `request`, which mustn't be sent twice, with the retry policy from the global configuration.
+/// It's only retried if it couldn't connect, so it was never sent: a request that timed out or
+/// failed on the server may well have had its effect already.
+pub fn retry_unsent<T, F>(what: &str, request: F) -> reqwest::Result<T>
+where
+    F: FnMut() -> reqwest::Result<T>,
+{
+    Policy::from(&**APP_CONFIG.load()).run_while(what, reqwest::Error::is_connect, request)
+}
+
+/// Whether a response status indicates a transient failure
+fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::REQUEST_TIMEOUT
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Whether `err` says the API rejected a request for good, such as a result it can't accept, which
+/// would fail the same way however often it's sent. Authentication failures aren't, as they pass
+/// once the credentials are fixed.
+pub fn is_rejected(err: &color_eyre::Report) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        .filter_map(reqwest::Error::status)
+        .any(|status| {
+            matches!(
+                status,
+                StatusCode::BAD_REQUEST
+                    | StatusCode::GONE
+                    | StatusCode::PAYLOAD_TOO_LARGE
+                    | StatusCode::UNSUPPORTED_MEDIA_TYPE
+                    | StatusCode::UNPROCESSABLE_ENTITY
+            )
+        })
+}
+
+/// Whether a failed request is worth retrying. Connection problems, timeouts, and transient
+/// statuses are; client errors and undecodable responses aren't, as they'd fail the same way again.
+fn is_retryable(err: &reqwest::Error) -> bool {
+    match err.status() {
+        Some(status) => is_retryable_status(status),
+        None => err.is_connect() || err.is_timeout() || err.is_request() || err.is_body(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Policy;
+    use crate::server::{serve, Response};
+    use std::{
+        sync::atomic::{AtomicU32, Ordering},
+        sync::Arc,
+        time::Duration,
+    };
+
+    fn policy(max_attempts: u32) -> Policy {
+        Policy {
+            max_attempts,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            jitter: 0.0,
+        }
+    }
+
+    /// Serve `status` on every request, returning the request URL and the request counter
+    fn server(status: u16) -> (String, Arc<AtomicU32>) {
+        let hits = Arc::new(AtomicU32::new(0));
+        let counter = Arc::clone(&hits);
+        let addr = serve("127.0.0.1:0", move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Response::text(status, "")
+        })
+        .unwrap();
+
+        (format!("http://{addr}/"), hits)
+    }
+
+    fn get(url: &str) -> reqwest::Result<()> {
+        reqwest::blocking::get(url)?.error_for_status()?;
+        Ok(())
+    }
+
+    #[test]
+    fn retries_transient_failures() {
+        let (url, hits) = server(503);
+
+        assert!(policy(3).run("test", || get(&url)).is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn retries_unsent_requests_only() {
+        let (url, hits) = server(503);
+
+        assert!(policy(3)
+            .run_while("test", reqwest::Error::is_connect, || get(&url))
+            .is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn does_not_retry_client_errors() {
+        let (url, hits) = server(404);
+
+        assert!(policy(3).run("test", || get(&url)).is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_limit() {
+        let policy = Policy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            jitter: 0.5,
+        };
+
+        for (attempt, max) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (9, 1000)] {
+            let delay = policy.delay(attempt);
+            assert!(delay <= Duration::from_millis(max), "{attempt}: {delay:?}");
+            assert!(
+                delay >= Duration::from_millis(max / 2),
+                "{attempt}: {delay:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use color_eyre::Result;
+use tempfile::NamedTempFile;
+use tracing::{debug, warn};
+use yara::Rules;
+
+/// The file extension of compiled rulesets in the cache
+const EXTENSION: &str = "yarc";
+
+/// An on-disk cache of compiled rulesets, keyed by the commit hash of the ruleset.
+///
+/// Compiling the full ruleset is slow, and the same few commits tend to come up again and again
+/// (restarts, or jobs flip-flopping between two hashes during a rules deploy).
+///
+/// Loaded rules are trusted like the rules the API serves, so the directory is created private to
+/// the client, and on Unix entries are only loaded if the directory and the entry are owned by the
+/// client's user and writable by nobody else, see [`is_private`]. Loading an entry marks it as
+/// used, so the least recently used entries are evicted first.
+pub struct RulesCache {
+    dir: PathBuf,
+    capacity: usize,
+}
+
+impl RulesCache {
+    /// Create a cache in `dir`, which keeps at most `capacity` compiled rulesets
+    pub fn new(dir: impl Into<PathBuf>, capacity: usize) -> Self {
+        Self {
+            dir: dir.into(),
+            capacity,
+        }
+    }
+
+    /// The path of the cache entry for the given commit hash. Anything but ASCII alphanumerics is
+    /// stripped from the hash so it can't escape the cache directory.
+    fn entry_path(&self, hash: &str) -> PathBuf {
+        let key: String = hash.chars().filter(char::is_ascii_alphanumeric).collect();
+        self.dir.join(format!("{key}.{EXTENSION}"))
+    }
+
+    /// Load the compiled rules for `hash`, if they're cached.
+    ///
+    /// Entries that can't be loaded (corrupt, or saved by a different YARA version) are removed
+    /// from the cache.
+    pub fn load(&self, hash: &str) -> Option<Rules> {
+        let path = self.entry_path(hash);
+        let file = File::open(&path).ok()?;
+        if !is_private(&self.dir) || !is_private(&path) {
+            warn!(
+                "Not loading cached rules {}, others than the client's user can write to it",
+                path.display()
+            );
+            return None;
+        }
+
+        match Rules::load_from_stream(BufReader::new(&file)) {
+            Ok(rules) => {
+                debug!("Loaded compiled rules for {hash} from {}", path.display());
+                if let Err(err) = file.set_modified(SystemTime::now()) {
+                    debug!("Failed to mark {} as used: {err}", path.display());
+                }
+                Some(rules)
+            }
+            Err(err) => {
+                warn!("Discarding unusable cached rules {}: {err}", path.display());
+                if let Err(err) = fs::remove_file(&path) {
+                    warn!("Failed to remove {}: {err}", path.display());
+                }
+                None
+            }
+        }
+    }
+
+    /// Save the compiled `rules` for `hash` into the cache, evicting the least recently used
+    /// entries if the cache is over capacity.
+    pub fn store(&self, hash: &str, rules: &mut Rules) -> Result<()> {
+        create_private_dir(&self.dir)?;
+
+        // write to a temporary file first so a crash can never leave a half written entry behind
+        let file = NamedTempFile::new_in(&self.dir)?;
+        let mut writer = BufWriter::new(file.as_file());
+        rules.save_to_stream(&mut writer)?;
+        writer.flush()?;
+        drop(writer);
+        file.persist(self.entry_path(hash))?;
+
+        self.evict()
+    }
+
+    /// Remove the least recently used entries until at most `capacity` remain
+    fn evict(&self) -> Result<()> {
+        let mut entries = fs::read_dir(&self.dir)?
+            .filter_map(std::result::Result::ok)
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == EXTENSION))
+            .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+            .collect::<Vec<_>>();
+
+        if entries.len() <= self.capacity {
+            return Ok(());
+        }
+
+        entries.sort_unstable();
+        for (_, path) in &entries[..entries.len() - self.capacity] {
+            debug!("Evicting cached rules {}", path.display());
+            fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Create `dir` and its parents, readable only by the client's user
+#[cfg(unix)]
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+}
+
+/// Create `dir` and its parents
+#[cfg(not(unix))]
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)
+}
+
+/// Whether `path` is owned by the client's user, and neither its group nor others can write to it
+#[cfg(unix)]
+fn is_private(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    fs::metadata(path).is_ok_and(|metadata| {
+        metadata.uid() == rustix::process::geteuid().as_raw() && metadata.mode() & 0o022 == 0
+    })
+}
+
+/// Whether `path` is private to the client's user, which isn't checked on other platforms
+#[cfg(not(unix))]
+fn is_private(_path: &Path) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RulesCache;
+    use tempfile::tempdir;
+    use yara::Compiler;
+
+    fn compile(source: &str) -> yara::Rules {
+        Compiler::new()
+            .unwrap()
+            .add_rules_str(source)
+            .unwrap()
+            .compile_rules()
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trips_compiled_rules() {
+        let dir = tempdir().unwrap();
+        let cache = RulesCache::new(dir.path(), 2);
+        let mut rules = compile("rule a { strings: $a = \"abc\" condition: $a }");
+
+        assert!(cache.load("deadbeef").is_none());
+        cache.store("deadbeef", &mut rules).unwrap();
+
+        let loaded = cache.load("deadbeef").unwrap();
+        assert_eq!(loaded.scan_mem(b"xabcx", 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn discards_corrupt_entries() {
+        let dir = tempdir().unwrap();
+        let cache = RulesCache::new(dir.path(), 2);
+        let path = dir.path().join("deadbeef.yarc");
+        std::fs::write(&path, b"not a ruleset").unwrap();
+
+        assert!(cache.load("deadbeef").is_none());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn evicts_beyond_capacity() {
+        let dir = tempdir().unwrap();
+        let cache = RulesCache::new(dir.path(), 2);
+        let mut rules = compile("rule a { condition: true }");
+
+        for hash in ["a1", "b2", "c3"] {
+            cache.store(hash, &mut rules).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+        assert!(!dir.path().join("a1.yarc").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn does_not_load_entries_others_can_write() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let cache = RulesCache::new(dir.path(), 2);
+        cache
+            .store("deadbeef", &mut compile("rule a { condition: true }"))
+            .unwrap();
+        let path = dir.path().join("deadbeef.yarc");
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o666)).unwrap();
+
+        assert!(cache.load("deadbeef").is_none());
+    }
+
+    #[test]
+    fn sanitizes_hashes() {
+        let cache = RulesCache::new("/cache", 1);
+        assert_eq!(
+            cache.entry_path("../../etc/passwd"),
+            std::path::Path::new("/cache/etcpasswd.yarc")
+        );
+    }
+}
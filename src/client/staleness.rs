@@ -0,0 +1,77 @@
+//! Tracking whether the current ruleset may be stale.
+//!
+//! The API doesn't say how old a ruleset is, so a ruleset counts as current as long as jobs ask for
+//! it, or it was just fetched. Once a job asks for another ruleset and updating fails, the rules are
+//! outdated, and if that lasts for more than `max_rules_age` since they were last known to be
+//! current, the client stops accepting jobs rather than producing results the backend would
+//! attribute to the wrong ruleset.
+
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub struct Staleness {
+    max_age: Duration,
+    verified_at: Instant,
+    outdated: bool,
+}
+
+impl Staleness {
+    /// Rules that were just loaded. A `max_age` of 0 never expires them.
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            verified_at: Instant::now(),
+            outdated: false,
+        }
+    }
+
+    /// Record that the current rules are known to be the latest, as of `now`
+    pub fn verified(&mut self, now: Instant) {
+        self.verified_at = now;
+        self.outdated = false;
+    }
+
+    /// Record that newer rules exist but couldn't be installed
+    pub fn update_failed(&mut self) {
+        self.outdated = true;
+    }
+
+    /// How long ago the rules were last known to be the latest
+    pub fn age(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.verified_at)
+    }
+
+    /// Whether the rules are outdated and were last known to be the latest more than `max_age`
+    /// before `now`
+    pub fn is_expired(&self, now: Instant) -> bool {
+        !self.max_age.is_zero() && self.outdated && self.age(now) > self.max_age
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Staleness;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn expires_outdated_rules_after_max_age() {
+        let start = Instant::now();
+        let later = start + Duration::from_secs(3600);
+        let mut staleness = Staleness::new(Duration::from_secs(60));
+        staleness.verified(start);
+
+        // old, but nothing newer is known
+        assert!(!staleness.is_expired(later));
+
+        staleness.update_failed();
+        assert!(!staleness.is_expired(start + Duration::from_secs(30)));
+        assert!(staleness.is_expired(later));
+
+        staleness.verified(later);
+        assert!(!staleness.is_expired(later + Duration::from_secs(3600)));
+
+        let mut disabled = Staleness::new(Duration::ZERO);
+        disabled.update_failed();
+        assert!(!disabled.is_expired(later + Duration::from_secs(3600)));
+    }
+}
@@ -0,0 +1,638 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::Result;
+use once_cell::sync::Lazy;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use tracing::{debug, error, warn};
+
+use super::{retry::is_rejected, FileResultPart, ScanResult, ScanResultSerializer};
+
+/// How many keys of already submitted results are remembered to reject duplicates
+const SUBMITTED_HISTORY: usize = 1024;
+
+/// A random ID of this run of the client
+static RUN_ID: Lazy<String> = Lazy::new(|| format!("{:016x}", rand::thread_rng().gen::<u64>()));
+
+/// The idempotency key of the result queued under `key` (the hash, name and version of its job) in
+/// this run of the client, sent along with it so that the API can recognize a result it's sent
+/// twice, such as when a retry didn't see the response to an earlier attempt
+pub fn idempotency_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(format!("{key}\n{}", *RUN_ID)))
+}
+
+/// An idempotency key for the result queued under `key` that's never been sent before, for results
+/// that are meant to be submitted again
+fn fresh_idempotency_key(key: &str) -> String {
+    let nonce = rand::thread_rng().gen::<u64>();
+    idempotency_key(&format!("{key}\n{nonce:016x}"))
+}
+
+/// A result the API rejected for good, as it's written to the dead letter file, see
+/// [`SubmitQueue::drain`]
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    #[serde(flatten)]
+    result: &'a QueuedResult,
+    error: String,
+}
+
+/// Which chunk of the streamed file results of a package a queued entry is, see
+/// [`FileResultsStream`]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct FilesChunk {
+    name: String,
+    version: String,
+    chunk: usize,
+}
+
+/// A serialized scan result waiting to be submitted
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct QueuedResult {
+    key: String,
+    body: Value,
+
+    /// Sent with every attempt to submit the result, see [`idempotency_key`]. Empty for results
+    /// queued by older clients.
+    #[serde(default)]
+    idempotency_key: String,
+
+    /// Set if this is a chunk of file results rather than a result, `body` then holds its newline
+    /// delimited file results as a string
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    files: Option<FilesChunk>,
+}
+
+impl QueuedResult {
+    fn idempotency_key(&self) -> String {
+        if self.idempotency_key.is_empty() {
+            idempotency_key(&self.key)
+        } else {
+            self.idempotency_key.clone()
+        }
+    }
+
+    fn submission(&self) -> Submission<'_> {
+        match &self.files {
+            None => Submission::Result(&self.body),
+            Some(files) => Submission::FileResults {
+                name: &files.name,
+                version: &files.version,
+                chunk: files.chunk,
+                body: self.body.as_str().unwrap_or_default(),
+            },
+        }
+    }
+}
+
+/// What a queued entry is submitted as, see [`SubmitQueue::drain`]
+#[derive(Debug, PartialEq)]
+pub enum Submission<'a> {
+    /// A scan result, for the result sink
+    Result(&'a Value),
+
+    /// A chunk of the newline delimited file results of the release `version` of `name`, for the
+    /// API. `chunk` is the 0-based index of the chunk for the package.
+    FileResults {
+        name: &'a str,
+        version: &'a str,
+        chunk: usize,
+        body: &'a str,
+    },
+}
+
+/// The on-disk representation of the queue
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QueueState {
+    pending: VecDeque<QueuedResult>,
+    submitted: VecDeque<String>,
+
+    /// The most recently submitted results, oldest first, kept for [`SubmitQueue::resend`]
+    #[serde(default)]
+    history: VecDeque<QueuedResult>,
+}
+
+/// A persistent FIFO queue of scan results waiting to be submitted to the result sinks.
+///
+/// Results are submitted strictly in the order they were pushed, and each key is submitted at
+/// most once: pushing a result whose key is already pending, or was recently submitted, is a
+/// no-op. The queue is written to disk after every change so results survive restarts, along with
+/// their idempotency keys, so a result that was submitted right before a crash is recognized as a
+/// duplicate when it's submitted again.
+///
+/// The last few submitted results can also be kept, see [`SubmitQueue::with_history`]. Results the
+/// API rejects for good are moved to a dead letter file next to the queue, see
+/// [`SubmitQueue::dead_letter_path`].
+///
+/// The file results of a package can be queued ahead of its result as it's scanned, see
+/// [`FileResultsStream`]. They're submitted, deduplicated and dead lettered like results, but
+/// aren't kept in the history.
+pub struct SubmitQueue {
+    state: QueueState,
+    capacity: usize,
+    history_size: usize,
+    path: PathBuf,
+}
+
+impl SubmitQueue {
+    /// Open the queue persisted at `path`, or create an empty one if it doesn't exist yet.
+    ///
+    /// `capacity` is the depth at which [`SubmitQueue::is_full`] starts applying backpressure.
+    pub fn open(path: impl Into<PathBuf>, capacity: usize) -> Result<Self> {
+        let path = path.into();
+        let state = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|err| {
+                warn!(
+                    "Discarding unreadable submission queue {}: {err}",
+                    path.display()
+                );
+                QueueState::default()
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => QueueState::default(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self {
+            state,
+            capacity,
+            history_size: 0,
+            path,
+        })
+    }
+
+    /// Keep the last `size` submitted results so they can be resent with [`SubmitQueue::resend`]
+    pub fn with_history(mut self, size: usize) -> Self {
+        self.history_size = size;
+        while self.state.history.len() > size {
+            self.state.history.pop_front();
+        }
+        self
+    }
+
+    /// The amount of results waiting to be submitted
+    pub fn len(&self) -> usize {
+        self.state.pending.len()
+    }
+
+    /// Whether the queue is at (or over) capacity, in which case no new jobs should be fetched
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity
+    }
+
+    /// Queue a scan result for submission under the given `key`.
+    ///
+    /// Returns `false` if a result with the same key is already pending or was already
+    /// submitted, in which case the result is dropped.
+    pub fn push(&mut self, key: String, result: ScanResult) -> Result<bool> {
+        let body = serde_json::to_value(ScanResultSerializer::from(result))?;
+        self.enqueue(key, body, None, false)
+    }
+
+    /// Queue the result of a forced rescan under the given `key`.
+    ///
+    /// Unlike [`SubmitQueue::push`] the result is never dropped: an earlier submission with the same
+    /// key is forgotten, and a pending result with the same key is replaced. It gets an idempotency
+    /// key of its own, so it isn't taken for a duplicate of the earlier submission.
+    pub fn push_rescan(&mut self, key: String, result: ScanResult) -> Result<()> {
+        let body = serde_json::to_value(ScanResultSerializer::from(result))?;
+        self.enqueue(key, body, None, true).map(drop)
+    }
+
+    /// Queue `body` under `key`, as a chunk of file results if `files` is set. With `rescan`, like
+    /// [`SubmitQueue::push_rescan`], otherwise like [`SubmitQueue::push`].
+    fn enqueue(
+        &mut self,
+        key: String,
+        body: Value,
+        files: Option<FilesChunk>,
+        rescan: bool,
+    ) -> Result<bool> {
+        if rescan {
+            self.state.submitted.retain(|submitted| *submitted != key);
+            self.state.pending.retain(|queued| queued.key != key);
+        }
+
+        let is_duplicate = self.state.submitted.contains(&key)
+            || self.state.pending.iter().any(|queued| queued.key == key);
+        if is_duplicate {
+            warn!("Dropping duplicate result for {key}");
+            return Ok(false);
+        }
+
+        let idempotency_key = if rescan {
+            fresh_idempotency_key(&key)
+        } else {
+            idempotency_key(&key)
+        };
+        self.state.pending.push_back(QueuedResult {
+            key,
+            body,
+            idempotency_key,
+            files,
+        });
+        self.persist()?;
+
+        Ok(true)
+    }
+
+    /// Submit pending results and file results in order with `send`, along with their idempotency
+    /// keys, until the queue is empty or a submission fails. A failed result stays at the front of the queue to be
+    /// retried next time, with the same idempotency key, unless the API rejected it for good (see
+    /// [`is_rejected`]): then it's moved to the dead letter file, so it doesn't hold up the results
+    /// behind it.
+    ///
+    /// Returns the amount of results submitted, not counting file results.
+    pub fn drain<F>(&mut self, mut send: F) -> Result<usize>
+    where
+        F: FnMut(Submission, &str) -> Result<()>,
+    {
+        let mut submitted = 0;
+        while let Some(queued) = self.state.pending.front() {
+            match send(queued.submission(), &queued.idempotency_key()) {
+                Ok(()) => {}
+                Err(err) if is_rejected(&err) => {
+                    let queued = self.state.pending.pop_front().unwrap();
+                    self.dead_letter(&queued, &err)?;
+                    self.persist()?;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
+
+            let queued = self.state.pending.pop_front().unwrap();
+            debug!("Submitted result for {}", queued.key);
+            let is_result = queued.files.is_none();
+            if self.history_size > 0 && is_result {
+                self.state.history.push_back(queued.clone());
+                if self.state.history.len() > self.history_size {
+                    self.state.history.pop_front();
+                }
+            }
+            self.state.submitted.push_back(queued.key);
+            if self.state.submitted.len() > SUBMITTED_HISTORY {
+                self.state.submitted.pop_front();
+            }
+
+            self.persist()?;
+            submitted += usize::from(is_result);
+        }
+
+        Ok(submitted)
+    }
+
+    /// Submit the `last` most recently submitted results again with `send`, oldest first, stopping
+    /// at the first failure. Only results kept in the history are resent. They're resent with new
+    /// idempotency keys, so they aren't taken for duplicates of the first submission.
+    ///
+    /// Returns the amount of results resent.
+    pub fn resend<F>(&self, last: usize, mut send: F) -> Result<usize>
+    where
+        F: FnMut(&Value, &str) -> Result<()>,
+    {
+        let history = &self.state.history;
+        let mut resent = 0;
+        for queued in history.iter().skip(history.len().saturating_sub(last)) {
+            send(&queued.body, &fresh_idempotency_key(&queued.key))?;
+            debug!("Resent result for {}", queued.key);
+            resent += 1;
+        }
+
+        Ok(resent)
+    }
+
+    /// The file results rejected by the API are appended to, one JSON object per line with the
+    /// error they were rejected with
+    pub fn dead_letter_path(&self) -> PathBuf {
+        self.path.with_extension("dead.jsonl")
+    }
+
+    /// Append `queued`, rejected with `err`, to the dead letter file
+    fn dead_letter(&self, queued: &QueuedResult, err: &color_eyre::Report) -> Result<()> {
+        let path = self.dead_letter_path();
+        error!(
+            "The result for {} was rejected, moving it to {}: {err:#}",
+            queued.key,
+            path.display()
+        );
+
+        let mut line = serde_json::to_vec(&DeadLetter {
+            result: queued,
+            error: format!("{err:#}"),
+        })?;
+        line.push(b'\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(&line)?;
+
+        Ok(())
+    }
+
+    /// Atomically write the queue to disk
+    fn persist(&self) -> Result<()> {
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(dir)?;
+
+        let mut file = NamedTempFile::new_in(dir)?;
+        serde_json::to_writer(&mut file, &self.state)?;
+        file.flush()?;
+        file.persist(&self.path)?;
+
+        Ok(())
+    }
+}
+
+/// Queues the file results of a package as its distributions are scanned, in chunks of at most
+/// `chunk_size` files, ahead of its result.
+///
+/// Each chunk is queued under the key of the result with the index of the chunk appended, so
+/// chunks are deduplicated and get idempotency keys the same way the result does.
+pub struct FileResultsStream {
+    key: String,
+    name: String,
+    version: String,
+    rescan: bool,
+    chunk_size: usize,
+    chunks: usize,
+}
+
+impl FileResultsStream {
+    /// Stream the file results of the release `version` of `name`, whose result is queued under
+    /// `key`. Chunks of a forced `rescan` are queued like [`SubmitQueue::push_rescan`] does.
+    pub fn new(key: &str, name: &str, version: &str, rescan: bool, chunk_size: usize) -> Self {
+        Self {
+            key: key.to_owned(),
+            name: name.to_owned(),
+            version: version.to_owned(),
+            rescan,
+            chunk_size: chunk_size.max(1),
+            chunks: 0,
+        }
+    }
+
+    /// Queue the file results of a distribution that was just scanned
+    pub fn push<'a>(
+        &mut self,
+        queue: &mut SubmitQueue,
+        parts: impl Iterator<Item = FileResultPart<'a>>,
+    ) -> Result<()> {
+        let mut lines = String::new();
+        let mut count = 0;
+        for part in parts {
+            lines.push_str(&serde_json::to_string(&part)?);
+            lines.push('\n');
+            count += 1;
+
+            if count == self.chunk_size {
+                self.queue_chunk(queue, std::mem::take(&mut lines))?;
+                count = 0;
+            }
+        }
+
+        if count > 0 {
+            self.queue_chunk(queue, lines)?;
+        }
+
+        Ok(())
+    }
+
+    fn queue_chunk(&mut self, queue: &mut SubmitQueue, lines: String) -> Result<()> {
+        let files = FilesChunk {
+            name: self.name.clone(),
+            version: self.version.clone(),
+            chunk: self.chunks,
+        };
+        queue.enqueue(
+            format!("{}#files{}", self.key, self.chunks),
+            Value::String(lines),
+            Some(files),
+            self.rescan,
+        )?;
+        self.chunks += 1;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{idempotency_key, FileResultsStream, Submission, SubmitQueue};
+    use crate::{
+        client::{FileResultPart, SubmitJobResultsError, SubmitJobResultsSuccess},
+        scanner::Verdict,
+        server::{serve, Response},
+    };
+    use std::{collections::BTreeMap, fs};
+    use tempfile::tempdir;
+
+    #[allow(clippy::result_large_err)]
+    fn error(name: &str) -> crate::client::ScanResult {
+        Err(SubmitJobResultsError {
+            name: name.into(),
+            version: "1.0.0".into(),
+            reason: "reason".into(),
+            resource_limit: None,
+            client_version: String::new(),
+            worker_id: None,
+        })
+    }
+
+    /// The name of the package a submitted result is for
+    fn name(submission: &Submission) -> String {
+        match submission {
+            Submission::Result(body) => body["name"].as_str().unwrap().to_owned(),
+            Submission::FileResults { .. } => panic!("not a result"),
+        }
+    }
+
+    fn part(path: &str) -> FileResultPart<'static> {
+        FileResultPart {
+            distribution: Some("pkg-1.0.0.tar.gz"),
+            path: path.into(),
+            score: 1,
+            rules_matched: vec!["rule"],
+            sha256: "",
+            ssdeep: None,
+            snippets: &[],
+        }
+    }
+
+    #[test]
+    fn submits_in_order_and_at_most_once() {
+        let dir = tempdir().unwrap();
+        let mut queue = SubmitQueue::open(dir.path().join("queue.json"), 8).unwrap();
+
+        assert!(queue.push("a".into(), error("a")).unwrap());
+        assert!(queue.push("b".into(), error("b")).unwrap());
+        assert!(!queue.push("a".into(), error("a")).unwrap());
+        assert_eq!(queue.len(), 2);
+
+        let mut sent = Vec::new();
+        let mut keys = Vec::new();
+        let count = queue
+            .drain(|submission, idempotency_key| {
+                sent.push(name(&submission));
+                keys.push(idempotency_key.to_owned());
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(count, 2);
+        assert_eq!(sent, vec!["a", "b"]);
+        assert_eq!(keys, vec![idempotency_key("a"), idempotency_key("b")]);
+        assert_eq!(keys[0].len(), 64);
+        assert!(!queue.push("b".into(), error("b")).unwrap());
+
+        queue.push_rescan("b".into(), error("b")).unwrap();
+        assert_eq!(queue.len(), 1);
+        queue
+            .drain(|_, idempotency_key| {
+                assert_ne!(idempotency_key, keys[1]);
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn persists_pending_results() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("queue.json");
+
+        let mut queue = SubmitQueue::open(&path, 1).unwrap();
+        let success = SubmitJobResultsSuccess {
+            name: "pkg".into(),
+            version: "1.0.0".into(),
+            score: 0,
+            confidence: 0,
+            inspector_url: None,
+            rules_matched: Vec::new(),
+            commit: "abc".into(),
+            findings: Vec::new(),
+            verdict: Verdict::Clean,
+            oversized_files: Vec::new(),
+            files_skipped: Vec::new(),
+            partial_scans: Vec::new(),
+            mirror_hosts: Vec::new(),
+            typosquat_candidate: None,
+            iocs: Vec::new(),
+            telemetry: None,
+            artifacts: Vec::new(),
+            namespace_scores: BTreeMap::new(),
+            host: None,
+            client_version: String::new(),
+            worker_id: None,
+            pypi_metadata: None,
+        };
+        queue.push("pkg".into(), Ok(success)).unwrap();
+        assert!(queue.is_full());
+
+        let reopened = SubmitQueue::open(&path, 1).unwrap();
+        assert_eq!(reopened.len(), 1);
+        assert_eq!(reopened.state.pending[0].body["commit"], "abc");
+    }
+
+    #[test]
+    fn keeps_the_last_submitted_results_for_resending() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("queue.json");
+
+        let mut queue = SubmitQueue::open(&path, 8).unwrap().with_history(2);
+        for name in ["a", "b", "c"] {
+            queue.push(name.into(), error(name)).unwrap();
+        }
+        queue.drain(|_, _| Ok(())).unwrap();
+
+        let reopened = SubmitQueue::open(&path, 8).unwrap().with_history(2);
+        let mut resent = Vec::new();
+        let count = reopened
+            .resend(5, |body, _| {
+                resent.push(body["name"].as_str().unwrap().to_owned());
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(count, 2);
+        assert_eq!(resent, vec!["b", "c"]);
+    }
+
+    #[test]
+    fn moves_rejected_results_to_the_dead_letter_file() {
+        let dir = tempdir().unwrap();
+        let mut queue = SubmitQueue::open(dir.path().join("queue.json"), 8).unwrap();
+        for name in ["a", "b"] {
+            queue.push(name.into(), error(name)).unwrap();
+        }
+        let addr = serve("127.0.0.1:0", |_| Response::text(422, "")).unwrap();
+
+        let submitted = queue
+            .drain(|submission, _| {
+                if name(&submission) == "a" {
+                    reqwest::blocking::get(format!("http://{addr}/"))?.error_for_status()?;
+                }
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(submitted, 1);
+        assert_eq!(queue.len(), 0);
+        let dead = fs::read_to_string(queue.dead_letter_path()).unwrap();
+        assert_eq!(dead.lines().count(), 1);
+        let dead: serde_json::Value = serde_json::from_str(dead.trim()).unwrap();
+        assert_eq!(dead["key"], "a");
+        assert!(dead["error"].as_str().unwrap().contains("422"));
+    }
+
+    #[test]
+    fn queues_file_results_ahead_of_the_result() {
+        let dir = tempdir().unwrap();
+        let mut queue = SubmitQueue::open(dir.path().join("queue.json"), 8).unwrap();
+
+        let mut stream = FileResultsStream::new("pkg", "pkg", "1.0.0", false, 2);
+        stream
+            .push(&mut queue, ["a.py", "b.py", "c.py"].into_iter().map(part))
+            .unwrap();
+        stream.push(&mut queue, std::iter::empty()).unwrap();
+        queue.push("pkg".into(), error("pkg")).unwrap();
+        assert_eq!(queue.len(), 3);
+
+        let mut sent = Vec::new();
+        let submitted = queue
+            .drain(|submission, idempotency_key| {
+                sent.push(match submission {
+                    Submission::Result(body) => body["name"].as_str().unwrap().to_owned(),
+                    Submission::FileResults {
+                        name,
+                        version,
+                        chunk,
+                        body,
+                    } => {
+                        assert_eq!((name, version), ("pkg", "1.0.0"));
+                        assert_eq!(
+                            idempotency_key,
+                            super::idempotency_key(&format!("pkg#files{chunk}"))
+                        );
+                        format!("{chunk}: {}", body.lines().count())
+                    }
+                });
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(submitted, 1);
+        assert_eq!(sent, vec!["0: 2", "1: 1", "pkg"]);
+
+        // the results of a job handed out twice are only sent once
+        let mut stream = FileResultsStream::new("pkg", "pkg", "1.0.0", false, 2);
+        stream.push(&mut queue, [part("a.py")].into_iter()).unwrap();
+        assert_eq!(queue.len(), 0);
+    }
+}
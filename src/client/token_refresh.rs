@@ -0,0 +1,115 @@
+//! Renewing the access token in the background, before it expires.
+//!
+//! [`DragonflyClient::reauthenticate`](super::DragonflyClient::reauthenticate) only fetches a new
+//! token once the current one has expired, which stalls whatever request needed it. The refresher
+//! fetches the next token `auth_refresh_margin` before the current one expires (but no sooner than
+//! half way through its lifetime), and leaves it for the client to pick up on its next request.
+
+use std::{sync::Arc, thread, time::Duration};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use parking_lot::Mutex;
+use reqwest::blocking::Client;
+use tracing::{debug, info, warn};
+
+use super::{fetch_access_token, AuthState};
+use crate::app_config::Backend;
+
+/// How long to wait before trying again after failing to fetch a token
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// When to renew a token obtained at `obtained_at` and expiring at `expires_at`
+pub fn refresh_at(
+    obtained_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    margin: TimeDelta,
+) -> DateTime<Utc> {
+    let half_way = obtained_at + (expires_at - obtained_at) / 2;
+    expires_at
+        .checked_sub_signed(margin)
+        .map_or(half_way, |ahead| ahead.max(half_way))
+}
+
+/// A background thread renewing the access token
+pub struct TokenRefresher {
+    /// The token fetched last, until the client takes it
+    renewed: Arc<Mutex<Option<AuthState>>>,
+}
+
+impl TokenRefresher {
+    /// Start renewing the token for `backend` that expires at `expires_at`, `margin` before it does
+    pub fn spawn(
+        http_client: Client,
+        backend: Backend,
+        expires_at: DateTime<Utc>,
+        margin: Duration,
+    ) -> Self {
+        let renewed = Arc::new(Mutex::new(None));
+        let margin = TimeDelta::from_std(margin).unwrap_or(TimeDelta::max_value());
+
+        let slot = Arc::clone(&renewed);
+        let spawned = thread::Builder::new()
+            .name(String::from("token-refresh"))
+            .spawn(move || {
+                let mut obtained_at = Utc::now();
+                let mut expires_at = expires_at;
+                loop {
+                    let wait = refresh_at(obtained_at, expires_at, margin) - Utc::now();
+                    thread::sleep(wait.to_std().unwrap_or(Duration::ZERO));
+
+                    match fetch_access_token(&http_client, &backend) {
+                        Ok(response) => {
+                            obtained_at = Utc::now();
+                            expires_at =
+                                obtained_at + TimeDelta::seconds(response.expires_in.into());
+                            debug!("Renewed the access token, it expires at {expires_at}");
+                            *slot.lock() = Some(AuthState {
+                                access_token: response.access_token,
+                                expires_at,
+                            });
+                        }
+                        Err(err) => {
+                            warn!(
+                                "Failed to renew the access token ahead of its expiry at {expires_at}: {err}"
+                            );
+                            thread::sleep(RETRY_DELAY);
+                        }
+                    }
+                }
+            });
+        if let Err(err) = spawned {
+            warn!("Failed to start renewing the access token in the background: {err}");
+        } else {
+            info!("Renewing the access token in the background");
+        }
+
+        Self { renewed }
+    }
+
+    /// The token renewed since the last call, if any
+    pub fn take(&self) -> Option<AuthState> {
+        self.renewed.lock().take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::refresh_at;
+    use chrono::{TimeDelta, TimeZone, Utc};
+
+    #[test]
+    fn refreshes_ahead_of_expiry() {
+        let obtained_at = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let expires_at = obtained_at + TimeDelta::hours(1);
+
+        assert_eq!(
+            refresh_at(obtained_at, expires_at, TimeDelta::minutes(5)),
+            obtained_at + TimeDelta::minutes(55)
+        );
+        // short lived tokens are still used for half of their lifetime
+        assert_eq!(
+            refresh_at(obtained_at, expires_at, TimeDelta::hours(2)),
+            obtained_at + TimeDelta::minutes(30)
+        );
+    }
+}
@@ -0,0 +1,276 @@
+//! Fetching the Auth0 client credentials from `HashiCorp` Vault, for deployments that forbid
+//! static secrets.
+//!
+//! With a `vault_addr`, the `client_id` and `client_secret` are read from the secret at
+//! `vault_path` (a KV version 1 or 2 path, such as `secret/data/dragonfly`) with the `vault_token`,
+//! instead of from the configuration. They're read when the first access token is fetched, and
+//! read again when less than a third of the lease of the secret is left. Renewable leases are
+//! renewed then instead, ahead of their expiry since Vault revokes expired leases, and the secret
+//! is only read again if the renewal fails.
+
+use std::time::{Duration, Instant};
+
+use color_eyre::{eyre::eyre, Result};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use reqwest::blocking::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::{
+    app_config::{AppConfig, Backend},
+    APP_CONFIG,
+};
+
+/// The Auth0 client credentials
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+/// A response of the Vault API carrying a lease
+#[derive(Debug, Deserialize)]
+struct LeaseResponse {
+    #[serde(default)]
+    lease_id: String,
+
+    /// In seconds, 0 for secrets that don't expire
+    #[serde(default)]
+    lease_duration: u64,
+
+    #[serde(default)]
+    renewable: bool,
+}
+
+/// A response of the Vault API reading a secret
+#[derive(Debug, Deserialize)]
+struct SecretResponse {
+    #[serde(flatten)]
+    lease: LeaseResponse,
+    data: Value,
+}
+
+/// The lease of the secret the credentials were read from
+#[derive(Debug)]
+struct Lease {
+    id: String,
+    renewable: bool,
+    duration: Duration,
+
+    /// `None` if it never expires
+    expires_at: Option<Instant>,
+}
+
+impl From<LeaseResponse> for Lease {
+    fn from(response: LeaseResponse) -> Self {
+        let duration = Duration::from_secs(response.lease_duration);
+        Self {
+            id: response.lease_id,
+            renewable: response.renewable,
+            duration,
+            expires_at: (!duration.is_zero()).then(|| Instant::now() + duration),
+        }
+    }
+}
+
+impl Lease {
+    /// Whether less than a third of the lease is left at `now`
+    fn needs_renewal(&self, now: Instant) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at.saturating_duration_since(now) < self.duration / 3)
+    }
+}
+
+impl SecretResponse {
+    /// The credentials in the secret, under `data.data` for KV version 2 and `data` for version 1
+    fn credentials(&self) -> Result<Credentials> {
+        let data = self
+            .data
+            .get("data")
+            .filter(|data| data.is_object())
+            .unwrap_or(&self.data);
+        let field = |name: &str| {
+            data.get(name)
+                .and_then(Value::as_str)
+                .map(ToOwned::to_owned)
+                .ok_or_else(|| eyre!("The Vault secret has no {name} string"))
+        };
+
+        Ok(Credentials {
+            client_id: field("client_id")?,
+            client_secret: field("client_secret")?,
+        })
+    }
+}
+
+/// The credentials read from Vault, and their lease
+struct Vault {
+    addr: String,
+    token: String,
+    path: String,
+    current: Option<(Credentials, Lease)>,
+}
+
+impl Vault {
+    fn from_config(config: &AppConfig) -> Option<Result<Self>> {
+        let addr = config.vault_addr.as_deref()?;
+        let Some(token) = config.vault_token.clone() else {
+            return Some(Err(eyre!(
+                "vault_token must be set to read credentials from Vault"
+            )));
+        };
+
+        Some(Ok(Self {
+            addr: addr.trim_end_matches('/').to_owned(),
+            token,
+            path: config.vault_path.trim_matches('/').to_owned(),
+            current: None,
+        }))
+    }
+
+    /// The current credentials, renewed or read again if their lease is running out
+    fn credentials(&mut self, http_client: &Client) -> Result<Credentials> {
+        let current = match self.current.take() {
+            Some((_, lease)) if lease.needs_renewal(Instant::now()) && !lease.renewable => {
+                self.read(http_client)?
+            }
+            Some((credentials, lease)) if lease.needs_renewal(Instant::now()) => {
+                match self.renew(http_client, &lease) {
+                    Ok(lease) => (credentials, lease),
+                    Err(err) => {
+                        warn!("Failed to renew the Vault lease {}: {err}", lease.id);
+                        self.read(http_client)?
+                    }
+                }
+            }
+            Some(current) => current,
+            None => self.read(http_client)?,
+        };
+
+        let credentials = current.0.clone();
+        self.current = Some(current);
+        Ok(credentials)
+    }
+
+    /// Read the credentials from the secret
+    fn read(&self, http_client: &Client) -> Result<(Credentials, Lease)> {
+        let response: SecretResponse = http_client
+            .get(format!("{}/v1/{}", self.addr, self.path))
+            .header("X-Vault-Token", &self.token)
+            .send()?
+            .error_for_status()?
+            .json()?;
+        let credentials = response.credentials()?;
+        info!("Read the client credentials from Vault at {}", self.path);
+
+        Ok((credentials, response.lease.into()))
+    }
+
+    /// Extend `lease` by its original duration, if it's renewable
+    fn renew(&self, http_client: &Client, lease: &Lease) -> Result<Lease> {
+        if !lease.renewable || lease.id.is_empty() {
+            return Err(eyre!("the lease isn't renewable"));
+        }
+
+        let response: LeaseResponse = http_client
+            .put(format!("{}/v1/sys/leases/renew", self.addr))
+            .header("X-Vault-Token", &self.token)
+            .json(&json!({
+                "lease_id": lease.id,
+                "increment": lease.duration.as_secs(),
+            }))
+            .send()?
+            .error_for_status()?
+            .json()?;
+        info!("Renewed the Vault lease {}", lease.id);
+
+        Ok(response.into())
+    }
+}
+
+/// The Vault credentials are read from, `None` until they're first needed
+static VAULT: Lazy<Mutex<Option<Vault>>> = Lazy::new(Mutex::default);
+
+/// The Auth0 client credentials of `backend`: its own if it has any, otherwise from Vault if
+/// `vault_addr` is set, otherwise from the configuration
+pub fn client_credentials(http_client: &Client, backend: &Backend) -> Result<Credentials> {
+    if let (Some(client_id), Some(client_secret)) = (&backend.client_id, &backend.client_secret) {
+        return Ok(Credentials {
+            client_id: client_id.clone(),
+            client_secret: client_secret.clone(),
+        });
+    }
+
+    let mut vault = VAULT.lock();
+    if vault.is_none() {
+        match Vault::from_config(&APP_CONFIG.load()) {
+            None => {
+                return Ok(Credentials {
+                    client_id: APP_CONFIG.load().client_id.clone(),
+                    client_secret: APP_CONFIG.load().client_secret.clone(),
+                })
+            }
+            Some(opened) => *vault = Some(opened?),
+        }
+    }
+
+    vault.as_mut().unwrap().credentials(http_client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Credentials, Lease, LeaseResponse, SecretResponse};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn reads_credentials_from_kv_secrets() {
+        let expected = Credentials {
+            client_id: String::from("id"),
+            client_secret: String::from("secret"),
+        };
+
+        let v2: SecretResponse = serde_json::from_str(
+            r#"{"lease_id": "", "lease_duration": 0, "renewable": false,
+                "data": {"data": {"client_id": "id", "client_secret": "secret"},
+                         "metadata": {"version": 3}}}"#,
+        )
+        .unwrap();
+        assert_eq!(v2.credentials().unwrap(), expected);
+        assert_eq!(v2.lease.lease_duration, 0);
+
+        let v1: SecretResponse = serde_json::from_str(
+            r#"{"lease_id": "kv/dragonfly/abc", "lease_duration": 2764800, "renewable": true,
+                "data": {"client_id": "id", "client_secret": "secret"}}"#,
+        )
+        .unwrap();
+        assert_eq!(v1.credentials().unwrap(), expected);
+        assert!(v1.lease.renewable);
+
+        let missing: SecretResponse =
+            serde_json::from_str(r#"{"data": {"data": {"client_id": "id"}}}"#).unwrap();
+        assert!(missing.credentials().is_err());
+    }
+
+    #[test]
+    fn renews_leases_ahead_of_their_expiry() {
+        let lease = |lease_duration| {
+            Lease::from(LeaseResponse {
+                lease_id: String::from("kv/dragonfly/abc"),
+                lease_duration,
+                renewable: true,
+            })
+        };
+        let now = Instant::now();
+
+        let hourly = lease(3600);
+        assert!(!hourly.needs_renewal(now));
+        assert!(!hourly.needs_renewal(now + Duration::from_secs(2399)));
+        assert!(hourly.needs_renewal(now + Duration::from_secs(2401)));
+        assert!(hourly.needs_renewal(now + Duration::from_secs(7200)));
+
+        let forever = lease(0);
+        assert!(!forever.needs_renewal(now + Duration::from_secs(86400 * 365)));
+    }
+}
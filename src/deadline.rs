@@ -0,0 +1,106 @@
+//! An overall deadline for an iteration of the job loop.
+//!
+//! Every single step of an iteration is bounded, HTTP requests time out and so do the rules on
+//! every file, but enough slow steps in a row (a trickling download, thousands of files that each
+//! take almost the YARA timeout) could still hold up the client indefinitely. So every iteration
+//! gets a deadline of `iteration_timeout` seconds, which is checked cooperatively: on every read
+//! of a downloaded distribution, and before every file is scanned. Past the deadline these fail
+//! with a [`io::ErrorKind::TimedOut`] error, so the job is reported as failed like any other
+//! error and the loop moves on.
+
+use std::{
+    io::{self, Read},
+    time::{Duration, Instant},
+};
+
+/// The deadline of an iteration, if any
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    at: Option<Instant>,
+    timeout: Duration,
+}
+
+impl Deadline {
+    /// A deadline that never passes
+    pub fn none() -> Self {
+        Self {
+            at: None,
+            timeout: Duration::ZERO,
+        }
+    }
+
+    /// A deadline `timeout` from now, or none if `timeout` is zero
+    pub fn after(timeout: Duration) -> Self {
+        Self {
+            at: (!timeout.is_zero()).then(|| Instant::now() + timeout),
+            timeout,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.at.is_some_and(|at| Instant::now() >= at)
+    }
+
+    /// Fail if the deadline has passed
+    pub fn check(&self) -> io::Result<()> {
+        if self.is_expired() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("iteration deadline of {}s exceeded", self.timeout.as_secs()),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Wrap `inner` so reading from it fails once the deadline has passed
+    pub fn reader<R: Read>(self, inner: R) -> Reader<R> {
+        Reader {
+            inner,
+            deadline: self,
+        }
+    }
+}
+
+/// A reader that checks a [`Deadline`] on every read
+pub struct Reader<R> {
+    inner: R,
+    deadline: Deadline,
+}
+
+impl<R: Read> Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.deadline.check()?;
+        self.inner.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Deadline;
+    use std::{
+        io::{ErrorKind, Read},
+        time::Duration,
+    };
+
+    #[test]
+    fn fails_reads_past_the_deadline() {
+        let mut buf = Vec::new();
+        Deadline::none()
+            .reader(&b"contents"[..])
+            .read_to_end(&mut buf)
+            .unwrap();
+        assert_eq!(buf, b"contents");
+        assert!(!Deadline::after(Duration::ZERO).is_expired());
+        assert!(!Deadline::after(Duration::from_secs(60)).is_expired());
+
+        let expired = Deadline::after(Duration::from_nanos(1));
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(expired.is_expired());
+        let err = expired
+            .reader(&b"contents"[..])
+            .read_to_end(&mut buf)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+}
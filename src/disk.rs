@@ -0,0 +1,237 @@
+//! Managing the disk space distributions are extracted to, so concurrent scans of big sdists can't
+//! fill it up.
+//!
+//! Distributions are extracted into temporary directories in the `dragonfly-scratch` directory of
+//! `scratch_dir`, the system's temporary directory if it's unset. Before a distribution is extracted, the space its files are estimated
+//! to take is reserved from what's available there, less `min_free_disk` bytes that are always
+//! left free and what other jobs already reserved. A distribution that doesn't fit fails its job
+//! right away with a "disk exhausted" [`ResourceLimit`], and so does running out of space halfway
+//! through an extraction. The temporary directories left there by a client that crashed are removed
+//! at startup, see [`clean_orphans`]. Nothing else in `scratch_dir` is touched, as the other files
+//! of the client, like the submit queue and the stats, may live there too.
+
+use std::{
+    fs::{self, File},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use tempfile::TempDir;
+use tracing::warn;
+
+use crate::{
+    memory::{ResourceLimit, EXPANSION},
+    APP_CONFIG,
+};
+
+/// The directory of `scratch_dir` the temporary directories are created in
+const SUBDIR: &str = "dragonfly-scratch";
+
+/// The prefix of the names of the temporary directories, see [`tempdir`]
+const PREFIX: &str = "dragonfly-";
+
+/// The OS error of a write to a full disk
+#[cfg(unix)]
+const DISK_FULL: i32 = 28; // ENOSPC
+#[cfg(windows)]
+const DISK_FULL: i32 = 112; // ERROR_DISK_FULL
+
+/// The directory distributions are extracted into
+pub fn scratch_dir() -> PathBuf {
+    APP_CONFIG
+        .load()
+        .scratch_dir
+        .clone()
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+/// The directory of the [`scratch_dir`] that only holds the temporary directories and files of the
+/// client, see [`clean_orphans`]
+pub fn temp_dir() -> PathBuf {
+    scratch_dir().join(SUBDIR)
+}
+
+/// A new temporary directory in the [`temp_dir`]
+pub fn tempdir() -> io::Result<TempDir> {
+    let dir = temp_dir();
+    fs::create_dir_all(&dir)?;
+    tempfile::Builder::new().prefix(PREFIX).tempdir_in(dir)
+}
+
+/// A new temporary file in the [`temp_dir`], deleted once it's closed
+pub fn tempfile() -> io::Result<File> {
+    let dir = temp_dir();
+    fs::create_dir_all(&dir)?;
+    tempfile::tempfile_in(dir)
+}
+
+/// The bytes of the file system of `dir` that are available to the client
+#[cfg(unix)]
+fn available_space(dir: &Path) -> io::Result<u64> {
+    let stat = rustix::fs::statvfs(dir)?;
+    Ok(stat.f_bavail.saturating_mul(stat.f_frsize))
+}
+
+/// The bytes of the file system of `dir` that are available to the client, which are never known
+/// to run out on other platforms
+#[cfg(not(unix))]
+fn available_space(_dir: &Path) -> io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+/// The disk space extracting an archive of `archive_size` bytes is estimated to take. Archives of
+/// unknown size are assumed to expand to `max_extracted_size`.
+pub fn estimate(archive_size: Option<u64>) -> u64 {
+    let max = APP_CONFIG.load().max_extracted_size;
+    archive_size.map_or(max, |size| size.saturating_mul(EXPANSION).min(max))
+}
+
+/// The disk space reserved by all jobs, see the module docs
+#[derive(Debug, Default)]
+pub struct Budget {
+    reserved: Mutex<u64>,
+}
+
+/// A part of the disk space, given back to the [`Budget`] when dropped
+#[derive(Debug)]
+pub struct Reservation<'a> {
+    budget: &'a Budget,
+    bytes: u64,
+}
+
+impl Budget {
+    /// Reserve `bytes` of the space available in the [`scratch_dir`]
+    pub fn reserve(&self, bytes: u64) -> color_eyre::Result<Reservation<'_>> {
+        let dir = scratch_dir();
+        fs::create_dir_all(&dir)?;
+        let available = available_space(&dir)?;
+
+        Ok(self.reserve_from(bytes, available, APP_CONFIG.load().min_free_disk)?)
+    }
+
+    /// Reserve `bytes` of `available` bytes, leaving `min_free` bytes and what's already reserved
+    fn reserve_from(
+        &self,
+        bytes: u64,
+        available: u64,
+        min_free: u64,
+    ) -> Result<Reservation<'_>, ResourceLimit> {
+        let mut reserved = self.reserved.lock();
+        let free = available.saturating_sub(min_free).saturating_sub(*reserved);
+        if bytes > free {
+            return Err(ResourceLimit {
+                resource: "disk",
+                required: bytes,
+                budget: free,
+            });
+        }
+        *reserved += bytes;
+
+        Ok(Reservation {
+            budget: self,
+            bytes,
+        })
+    }
+}
+
+impl Reservation<'_> {
+    /// `err` as a "disk exhausted" [`ResourceLimit`] if it was caused by the disk filling up
+    pub fn exhausted(&self, err: color_eyre::Report) -> color_eyre::Report {
+        let disk_full = err
+            .chain()
+            .filter_map(|cause| cause.downcast_ref::<io::Error>())
+            .any(|cause| cause.raw_os_error() == Some(DISK_FULL));
+        if !disk_full {
+            return err;
+        }
+
+        ResourceLimit {
+            resource: "disk",
+            required: self.bytes,
+            budget: available_space(&scratch_dir()).unwrap_or(0),
+        }
+        .into()
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        *self.budget.reserved.lock() -= self.bytes;
+    }
+}
+
+/// The disk space of the client
+pub static DISK: Lazy<Budget> = Lazy::new(Budget::default);
+
+/// Remove the temporary directories created by [`tempdir`] in `dir`, the [`temp_dir`], that weren't
+/// modified in `max_age`, left behind by clients that crashed. Returns how many were removed.
+pub fn clean_orphans(dir: &Path, max_age: Duration) -> io::Result<usize> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_name().to_string_lossy().starts_with(PREFIX) || !entry.file_type()?.is_dir()
+        {
+            continue;
+        }
+        let age = entry.metadata()?.modified()?.elapsed().unwrap_or_default();
+        if age < max_age {
+            continue;
+        }
+
+        let path = entry.path();
+        match fs::remove_dir_all(&path) {
+            Ok(()) => removed += 1,
+            Err(err) => warn!("Failed to remove {}: {err}", path.display()),
+        }
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clean_orphans, Budget};
+    use std::{fs, time::Duration};
+    use tempfile::tempdir;
+
+    #[test]
+    fn reserves_what_fits_on_the_disk() {
+        let budget = Budget::default();
+        let first = budget.reserve_from(60, 100, 10).unwrap();
+
+        let err = budget.reserve_from(40, 100, 10).unwrap_err();
+        assert_eq!(err.resource, "disk");
+        assert_eq!(err.budget, 30);
+        assert!(err.to_string().starts_with("disk exhausted"));
+
+        drop(first);
+        assert!(budget.reserve_from(90, 100, 10).is_ok());
+    }
+
+    #[test]
+    fn cleans_orphaned_directories() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("dragonfly-abc123/pkg")).unwrap();
+        fs::write(dir.path().join("dragonfly-abc123/pkg/setup.py"), "").unwrap();
+        fs::create_dir(dir.path().join("unrelated")).unwrap();
+        fs::write(dir.path().join("dragonfly-submit-queue.json"), "[]").unwrap();
+
+        assert_eq!(
+            clean_orphans(dir.path(), Duration::from_secs(3600)).unwrap(),
+            0
+        );
+        assert_eq!(clean_orphans(dir.path(), Duration::ZERO).unwrap(), 1);
+        assert!(!dir.path().join("dragonfly-abc123").exists());
+        assert!(dir.path().join("unrelated").exists());
+        assert!(dir.path().join("dragonfly-submit-queue.json").exists());
+    }
+}
@@ -0,0 +1,257 @@
+//! A machine-readable stream of what the client is doing.
+//!
+//! External orchestrators and local tooling shouldn't have to parse the logs to follow the job
+//! loop. With `event_stream` set, every step of a job is emitted as one JSON object per line
+//! (NDJSON), with the kind of event in `event` and when it happened in `timestamp`:
+//!
+//! - `job_started` when a job is fetched
+//! - `distribution_scanned` once each of its distributions is scanned
+//! - `job_completed` once its results are queued for submission
+//! - `error` when fetching or scanning a job fails
+//! - `hot_rules` with the slowest rule files, see `rule_profiling_sample_rate`
+//! - `circuit` when the circuit breaker of a backend opens or closes, see [`crate::breaker`]
+//!
+//! The events of a job carry the name of the backend it was taken from in `backend`.
+//!
+//! The stream goes either to stdout (the logs go to stderr then), or to every client connected to
+//! the UNIX socket at `event_socket_path`. Subscribers that can't keep up, or disconnect, are
+//! dropped, they never hold up the job loop.
+
+use std::{io::Write, path::Path};
+
+use chrono::{DateTime, Utc};
+use color_eyre::Result;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{
+    app_config::EventStream,
+    breaker::Transition,
+    scanner::{HotRule, Verdict},
+    APP_CONFIG,
+};
+
+/// Something that happened in the job loop
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    JobStarted {
+        backend: &'a str,
+        name: &'a str,
+        version: &'a str,
+        rules_hash: &'a str,
+    },
+    DistributionScanned {
+        name: &'a str,
+        version: &'a str,
+        distribution: &'a str,
+        files: usize,
+        score: i64,
+    },
+    JobCompleted {
+        backend: &'a str,
+        name: &'a str,
+        version: &'a str,
+        score: i64,
+        verdict: Verdict,
+        duration_ms: u128,
+    },
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        backend: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        version: Option<&'a str>,
+        reason: &'a str,
+    },
+    HotRules {
+        rules_hash: &'a str,
+        rules: &'a [HotRule],
+    },
+    Circuit {
+        backend: &'a str,
+        #[serde(flatten)]
+        transition: Transition,
+    },
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// Render `event` as a line of the stream
+fn line(event: &Event) -> Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(&Line {
+        timestamp: Utc::now(),
+        event,
+    })?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+enum Output {
+    Stdout,
+    #[cfg(unix)]
+    Socket(socket::Subscribers),
+}
+
+static OUTPUT: OnceCell<Output> = OnceCell::new();
+
+/// Open the configured event stream. Until then, and if there's none, events go nowhere.
+pub fn init() -> Result<()> {
+    let output = match APP_CONFIG.load().event_stream {
+        EventStream::None => return Ok(()),
+        EventStream::Stdout => Output::Stdout,
+        EventStream::Socket => open_socket(&APP_CONFIG.load().event_socket_path)?,
+    };
+    // only ever called once, on startup
+    let _ = OUTPUT.set(output);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn open_socket(path: &Path) -> Result<Output> {
+    Ok(Output::Socket(socket::Subscribers::listen(path)?))
+}
+
+#[cfg(not(unix))]
+fn open_socket(_path: &Path) -> Result<Output> {
+    Err(color_eyre::eyre::eyre!(
+        "the socket event stream is only available on UNIX"
+    ))
+}
+
+/// Emit `event` to the event stream, if there's one
+pub fn emit(event: &Event) {
+    let Some(output) = OUTPUT.get() else {
+        return;
+    };
+    let line = match line(event) {
+        Ok(line) => line,
+        Err(err) => {
+            warn!("Failed to serialize event {event:?}: {err}");
+            return;
+        }
+    };
+
+    match output {
+        Output::Stdout => {
+            let mut stdout = std::io::stdout().lock();
+            if let Err(err) = stdout.write_all(&line).and_then(|()| stdout.flush()) {
+                warn!("Failed to write event to stdout: {err}");
+            }
+        }
+        #[cfg(unix)]
+        Output::Socket(subscribers) => subscribers.send(&line),
+    }
+}
+
+#[cfg(unix)]
+mod socket {
+    use std::{
+        io::Write,
+        os::unix::net::{UnixListener, UnixStream},
+        path::Path,
+        sync::Arc,
+        time::Duration,
+    };
+
+    use color_eyre::Result;
+    use parking_lot::Mutex;
+    use tracing::{debug, info, warn};
+
+    /// How long a subscriber may block a write before it's dropped
+    const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// The clients connected to the event socket
+    pub struct Subscribers(Arc<Mutex<Vec<UnixStream>>>);
+
+    impl Subscribers {
+        /// Listen on a UNIX socket at `path`, replacing whatever is left of a previous one, and
+        /// accept subscribers in the background
+        pub fn listen(path: &Path) -> Result<Self> {
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+            let listener = UnixListener::bind(path)?;
+            info!("Streaming events to {}", path.display());
+
+            let subscribers = Arc::new(Mutex::new(Vec::new()));
+            let accepted = Arc::clone(&subscribers);
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = stream.and_then(|stream| {
+                        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                        Ok(stream)
+                    });
+                    match stream {
+                        Ok(stream) => {
+                            debug!("Event subscriber connected");
+                            accepted.lock().push(stream);
+                        }
+                        Err(err) => warn!("Failed to accept event subscriber: {err}"),
+                    }
+                }
+            });
+
+            Ok(Self(subscribers))
+        }
+
+        /// Write `line` to every subscriber, dropping the ones that fail
+        pub fn send(&self, line: &[u8]) {
+            self.0.lock().retain_mut(|stream| {
+                let sent = stream.write_all(line).is_ok();
+                if !sent {
+                    debug!("Event subscriber disconnected");
+                }
+                sent
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{line, Event};
+    use crate::scanner::Verdict;
+
+    #[test]
+    fn renders_events_as_lines() {
+        let completed = line(&Event::JobCompleted {
+            backend: "default",
+            name: "pkg",
+            version: "1.0",
+            score: 7,
+            verdict: Verdict::Suspicious,
+            duration_ms: 1200,
+        })
+        .unwrap();
+        assert!(completed.ends_with(b"}\n"));
+        let completed: serde_json::Value = serde_json::from_slice(&completed).unwrap();
+        assert_eq!(completed["event"], "job_completed");
+        assert_eq!(completed["backend"], "default");
+        assert_eq!(completed["verdict"], "suspicious");
+        assert!(completed["timestamp"].is_string());
+
+        let error: serde_json::Value = serde_json::from_slice(
+            &line(&Event::Error {
+                backend: None,
+                name: None,
+                version: None,
+                reason: "oops",
+            })
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            error.as_object().unwrap().keys().collect::<Vec<_>>(),
+            ["event", "reason", "timestamp"]
+        );
+    }
+}
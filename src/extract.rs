@@ -0,0 +1,526 @@
+//! Reading the files out of distribution archives.
+//!
+//! Distributions are untrusted uploads, so every archive format is read through the same safety
+//! layer, [`for_each_file`], regardless of the backend: entry paths are sanitized so nothing can
+//! be written outside of the extraction directory, and the number of entries and the total amount
+//! of decompressed bytes are capped to defuse archive bombs. Backends only have to implement
+//! [`Extractor`], which yields the raw entries of an archive.
+//!
+//! On Windows, archives are unpacked below a verbatim (`\\?\`) path so deep trees aren't cut
+//! off by `MAX_PATH`, and file names Windows rejects (like `con.py`) are escaped on disk and
+//! mapped back to their archive paths, see [`disk_path`].
+//!
+//! This module deliberately doesn't depend on the rest of the crate, so the fuzz targets in `fuzz/`
+//! can include it as is.
+
+use std::{
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek},
+    path::{Component, Path, PathBuf},
+};
+
+use flate2::read::GzDecoder;
+use tracing::warn;
+
+/// The kind of archive a distribution is packaged as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    /// A gzipped tarball, used for source distributions
+    TarGz,
+
+    /// A zstd compressed tarball
+    TarZst,
+
+    /// A zip file, used for wheels (and some legacy source distributions)
+    Zip,
+}
+
+impl ArchiveKind {
+    /// Guess the kind of archive from its file name. Anything that isn't a `.tar.gz` or
+    /// `.tar.zst` is assumed to be a zip file.
+    pub fn from_file_name(file_name: &str) -> Self {
+        if file_name.ends_with(".tar.gz") {
+            Self::TarGz
+        } else if file_name.ends_with(".tar.zst") {
+            Self::TarZst
+        } else {
+            Self::Zip
+        }
+    }
+
+    /// Recognize an archive by the extension of its file name, `None` if it's not an archive
+    #[allow(clippy::case_sensitive_file_extension_comparisons)] // lowercased first
+    pub fn from_extension(file_name: &str) -> Option<Self> {
+        let file_name = file_name.to_ascii_lowercase();
+        if file_name.ends_with(".tar.gz") || file_name.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else if file_name.ends_with(".tar.zst") {
+            Some(Self::TarZst)
+        } else if [".zip", ".whl", ".egg"]
+            .iter()
+            .any(|extension| file_name.ends_with(extension))
+        {
+            Some(Self::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// Caps on what a single archive may expand to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum number of regular files in the archive
+    pub max_entries: usize,
+
+    /// The maximum number of decompressed bytes read from all files of the archive combined
+    pub max_total_size: u64,
+}
+
+/// A backend for one archive format.
+///
+/// Implementations yield every regular file of the archive in archive order, with the path as
+/// stored in the archive. Directories, links and other special entries are left out. Callers
+/// should go through [`for_each_file`] instead of using this directly.
+pub trait Extractor {
+    /// Call `visit` with the raw path, the declared size and the contents of every regular file
+    fn for_each_entry<E, F>(&mut self, visit: F) -> Result<(), E>
+    where
+        E: From<io::Error>,
+        F: FnMut(&Path, u64, &mut dyn Read) -> Result<(), E>;
+}
+
+/// A tarball, compressed with any codec
+pub struct Tar<R: Read>(tar::Archive<R>);
+
+impl<R: Read> Tar<GzDecoder<R>> {
+    /// A gzipped tarball
+    pub fn gz(reader: R) -> Self {
+        Self(tar::Archive::new(GzDecoder::new(reader)))
+    }
+}
+
+impl<R: Read> Tar<zstd::Decoder<'static, BufReader<R>>> {
+    /// A zstd compressed tarball
+    pub fn zst(reader: R) -> io::Result<Self> {
+        Ok(Self(tar::Archive::new(zstd::Decoder::new(reader)?)))
+    }
+}
+
+impl<R: Read> Extractor for Tar<R> {
+    fn for_each_entry<E, F>(&mut self, mut visit: F) -> Result<(), E>
+    where
+        E: From<io::Error>,
+        F: FnMut(&Path, u64, &mut dyn Read) -> Result<(), E>,
+    {
+        for entry in self.0.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+
+            let path = entry.path()?.into_owned();
+            let size = entry.size();
+            visit(&path, size, &mut entry)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A zip file
+pub struct Zip<R: Read + Seek>(zip::ZipArchive<R>);
+
+impl<R: Read + Seek> Zip<R> {
+    pub fn new(reader: R) -> io::Result<Self> {
+        Ok(Self(zip::ZipArchive::new(reader)?))
+    }
+}
+
+impl<R: Read + Seek> Extractor for Zip<R> {
+    fn for_each_entry<E, F>(&mut self, mut visit: F) -> Result<(), E>
+    where
+        E: From<io::Error>,
+        F: FnMut(&Path, u64, &mut dyn Read) -> Result<(), E>,
+    {
+        for index in 0..self.0.len() {
+            let mut file = self.0.by_index(index).map_err(io::Error::from)?;
+            if !file.is_file() || file.is_symlink() {
+                continue;
+            }
+
+            let path = PathBuf::from(file.name());
+            let size = file.size();
+            visit(&path, size, &mut file)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Make a path from an archive safe to join onto the extraction directory.
+///
+/// Returns `None` for paths that are absolute, that contain `..`, or that are empty. `.`
+/// components are dropped.
+pub fn sanitize(path: &Path) -> Option<PathBuf> {
+    let mut sanitized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) if !part.as_encoded_bytes().contains(&0) => {
+                sanitized.push(part);
+            }
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    (!sanitized.as_os_str().is_empty()).then_some(sanitized)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A reader that fails once more than the remaining budget of bytes has been read through it
+struct Budgeted<'a> {
+    inner: &'a mut dyn Read,
+    remaining: &'a mut u64,
+    limit: u64,
+}
+
+impl Read for Budgeted<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        *self.remaining = self.remaining.checked_sub(read as u64).ok_or_else(|| {
+            invalid_data(format!("archive expands to more than {} bytes", self.limit))
+        })?;
+        Ok(read)
+    }
+}
+
+/// Call `visit` with the sanitized path, the declared size and the contents of every regular file
+/// of the archive in `extractor`, enforcing `limits`.
+///
+/// Files with unsafe paths (see [`sanitize`]) are skipped with a warning. Going over either limit
+/// aborts the extraction with an error.
+pub fn for_each_file<X, E, F>(extractor: &mut X, limits: Limits, mut visit: F) -> Result<(), E>
+where
+    X: Extractor,
+    E: From<io::Error>,
+    F: FnMut(&Path, u64, &mut dyn Read) -> Result<(), E>,
+{
+    let mut entries = 0;
+    let mut remaining = limits.max_total_size;
+
+    extractor.for_each_entry(|raw_path, size, reader| {
+        entries += 1;
+        if entries > limits.max_entries {
+            return Err(invalid_data(format!(
+                "archive has more than {} files",
+                limits.max_entries
+            ))
+            .into());
+        }
+
+        let Some(path) = sanitize(raw_path) else {
+            warn!(
+                "Skipping archive entry with unsafe path {}",
+                raw_path.display()
+            );
+            return Ok(());
+        };
+
+        let mut reader = Budgeted {
+            inner: reader,
+            remaining: &mut remaining,
+            limit: limits.max_total_size,
+        };
+        visit(&path, size, &mut reader)
+    })
+}
+
+/// Like [`for_each_file`], picking the backend from `kind`
+pub fn for_each_file_in<R, E, F>(
+    reader: R,
+    kind: ArchiveKind,
+    limits: Limits,
+    visit: F,
+) -> Result<(), E>
+where
+    R: Read + Seek,
+    E: From<io::Error>,
+    F: FnMut(&Path, u64, &mut dyn Read) -> Result<(), E>,
+{
+    match kind {
+        ArchiveKind::TarGz => for_each_file(&mut Tar::gz(reader), limits, visit),
+        ArchiveKind::TarZst => for_each_file(&mut Tar::zst(reader)?, limits, visit),
+        ArchiveKind::Zip => for_each_file(&mut Zip::new(reader)?, limits, visit),
+    }
+}
+
+/// Names Windows reserves for devices, with or without an extension
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Characters Windows doesn't accept in file names, besides control characters
+const WINDOWS_INVALID_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*', '\\'];
+
+fn percent_encode(c: char, escaped: &mut String) {
+    let mut buf = [0; 4];
+    for byte in c.encode_utf8(&mut buf).bytes() {
+        let _ = write!(escaped, "%{byte:02X}");
+    }
+}
+
+/// Escape a path component so Windows accepts it as a file name: `%`, invalid characters and
+/// trailing dots and spaces are percent-encoded, and so is the first character of reserved names
+/// like `con.py`. [`unescape_component`] undoes it.
+fn escape_component(component: &str) -> String {
+    let stem = component.split('.').next().unwrap_or_default().trim_end();
+    let reserved = WINDOWS_RESERVED_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(stem));
+    let trailing = component.len() - component.trim_end_matches(['.', ' ']).len();
+
+    let mut escaped = String::with_capacity(component.len());
+    for (index, c) in component.char_indices() {
+        let escape = c == '%'
+            || c.is_control()
+            || WINDOWS_INVALID_CHARS.contains(&c)
+            || (reserved && index == 0)
+            || index >= component.len() - trailing;
+        if escape {
+            percent_encode(c, &mut escaped);
+        } else {
+            escaped.push(c);
+        }
+    }
+
+    escaped
+}
+
+/// Undo [`escape_component`]
+fn unescape_component(escaped: &str) -> String {
+    let bytes = escaped.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match hex {
+            Some(byte) if bytes[i] == b'%' => {
+                unescaped.push(byte);
+                i += 3;
+            }
+            _ => {
+                unescaped.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+/// Turn an absolute Windows path into a verbatim (`\\?\`) one, which may be longer than
+/// `MAX_PATH`
+fn verbatim(path: &str) -> String {
+    let has_drive =
+        path.as_bytes().get(1) == Some(&b':') && path.as_bytes()[0].is_ascii_alphabetic();
+    if path.starts_with(r"\\?\") {
+        path.to_owned()
+    } else if let Some(unc) = path.strip_prefix(r"\\") {
+        format!(r"\\?\UNC\{}", unc.replace('/', r"\"))
+    } else if has_drive {
+        format!(r"\\?\{}", path.replace('/', r"\"))
+    } else {
+        path.to_owned()
+    }
+}
+
+/// On Windows, the verbatim form of `path` so paths below it aren't limited to `MAX_PATH`.
+/// Anywhere else, `path` itself.
+pub fn long_path(path: &Path) -> PathBuf {
+    if cfg!(windows) {
+        PathBuf::from(verbatim(&path.to_string_lossy()))
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// Where the file at the sanitized archive `path` is unpacked to inside `dir`. On Windows, the
+/// path is a [`long_path`] and every component is escaped so names like `con.py` or `a:b.py` can
+/// be written; [`archive_path`] maps it back.
+pub fn disk_path(dir: &Path, path: &Path) -> PathBuf {
+    if !cfg!(windows) {
+        return dir.join(path);
+    }
+
+    let mut target = long_path(dir);
+    for component in path.components() {
+        target.push(escape_component(&component.as_os_str().to_string_lossy()));
+    }
+    target
+}
+
+/// The archive path of a file unpacked by [`unpack`], from its path relative to the directory
+pub fn archive_path(relative: &Path) -> PathBuf {
+    if !cfg!(windows) {
+        return relative.to_path_buf();
+    }
+
+    relative
+        .components()
+        .map(|component| unescape_component(&component.as_os_str().to_string_lossy()))
+        .collect()
+}
+
+/// Write the files of the archive in `extractor` into `dir`, enforcing `limits`. See
+/// [`disk_path`] for where they end up on Windows.
+pub fn unpack(extractor: &mut impl Extractor, limits: Limits, dir: &Path) -> io::Result<()> {
+    for_each_file(extractor, limits, |path, _, reader| {
+        let target = disk_path(dir, path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        io::copy(reader, &mut File::create(target)?)?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        escape_component, for_each_file_in, sanitize, unescape_component, unpack, verbatim,
+        ArchiveKind, Limits, Tar,
+    };
+    use std::{
+        io::{self, Cursor, Read, Write},
+        path::{Path, PathBuf},
+    };
+
+    const LIMITS: Limits = Limits {
+        max_entries: 16,
+        max_total_size: 1024,
+    };
+
+    /// A tarball with the given entries, written without the path checks of `tar::Builder`
+    fn tarball(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, *contents).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn zstd_tarball(files: &[(&str, &[u8])]) -> Vec<u8> {
+        zstd::encode_all(&tarball(files)[..], 0).unwrap()
+    }
+
+    fn paths(bytes: &[u8], kind: ArchiveKind, limits: Limits) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for_each_file_in(Cursor::new(bytes), kind, limits, |path, _, reader| {
+            io::copy(reader, &mut io::sink())?;
+            paths.push(path.to_path_buf());
+            Ok::<_, io::Error>(())
+        })?;
+        Ok(paths)
+    }
+
+    #[test]
+    fn sanitizes_paths() {
+        assert_eq!(
+            sanitize(Path::new("./pkg/./__init__.py")),
+            Some(PathBuf::from("pkg/__init__.py"))
+        );
+        assert_eq!(sanitize(Path::new("pkg/../../etc/passwd")), None);
+        assert_eq!(sanitize(Path::new("/etc/passwd")), None);
+        assert_eq!(sanitize(Path::new("./")), None);
+    }
+
+    #[test]
+    fn escapes_names_windows_rejects() {
+        for (name, escaped) in [
+            ("__init__.py", "__init__.py"),
+            ("con.py", "%63on.py"),
+            ("NUL", "%4EUL"),
+            ("aux .tar.gz", "%61ux .tar.gz"),
+            ("console.py", "console.py"),
+            ("a:b?.py", "a%3Ab%3F.py"),
+            ("100%.txt", "100%25.txt"),
+            ("trailing. ", "trailing%2E%20"),
+        ] {
+            assert_eq!(escape_component(name), escaped);
+            assert_eq!(unescape_component(escaped), name);
+        }
+
+        assert_eq!(verbatim(r"C:\Temp\.tmpX"), r"\\?\C:\Temp\.tmpX");
+        assert_eq!(verbatim(r"\\server\share\dir"), r"\\?\UNC\server\share\dir");
+        assert_eq!(verbatim(r"\\?\C:\Temp"), r"\\?\C:\Temp");
+        assert_eq!(verbatim("relative/dir"), "relative/dir");
+    }
+
+    #[test]
+    fn skips_unsafe_entries() {
+        let bytes = zstd_tarball(&[("../evil.py", b"evil"), ("pkg/ok.py", b"ok")]);
+        assert_eq!(
+            paths(&bytes, ArchiveKind::TarZst, LIMITS).unwrap(),
+            vec![PathBuf::from("pkg/ok.py")]
+        );
+
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for name in ["/abs.py", "pkg/ok.py"] {
+            zip.start_file(name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(b"ok").unwrap();
+        }
+        let bytes = zip.finish().unwrap().into_inner();
+        assert_eq!(
+            paths(&bytes, ArchiveKind::Zip, LIMITS).unwrap(),
+            vec![PathBuf::from("pkg/ok.py")]
+        );
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let files = [("a", &b"a"[..]), ("b", b"b"), ("c", b"c")];
+        let limits = Limits {
+            max_entries: 2,
+            ..LIMITS
+        };
+        assert!(paths(&zstd_tarball(&files), ArchiveKind::TarZst, limits).is_err());
+
+        let bomb = [0; 4096];
+        let limits = Limits {
+            max_total_size: 4095,
+            ..LIMITS
+        };
+        let bytes = zstd_tarball(&[("bomb", &bomb)]);
+        assert!(paths(&bytes, ArchiveKind::TarZst, limits).is_err());
+    }
+
+    #[test]
+    fn unpacks_into_the_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let bytes = zstd_tarball(&[("pkg/a.py", b"a"), ("../escape.py", b"evil")]);
+        unpack(&mut Tar::zst(&bytes[..]).unwrap(), LIMITS, dir.path()).unwrap();
+
+        let mut contents = String::new();
+        std::fs::File::open(dir.path().join("pkg/a.py"))
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "a");
+        assert!(!dir.path().parent().unwrap().join("escape.py").exists());
+    }
+}
@@ -1,27 +1,45 @@
-use yara::{MetadataValue, Rule};
+use yara::{MetadataValue, Rule, Rules};
 
-use crate::scanner::RuleScore;
+use crate::{
+    client::bundles::DEFAULT_NAMESPACE,
+    scanner::{RuleScore, Verdict, CANARY_NAMESPACE},
+    APP_CONFIG,
+};
 
+/// The metadata conventions of the rules, for both the rules that matched a file and the rules of
+/// a compiled ruleset (see [`ruleset_rules`])
 pub trait RuleExt<'a> {
+    /// The name of this rule as it's reported: `namespace:identifier` for rules of bundles, see
+    /// [`crate::client::bundles`]
+    fn full_name(&'a self) -> String;
+
     /// Get the value of a metadata by key. `None` if that key/value pair doesn't exist
-    fn get_metadata_value(&'a self, key: &str) -> Option<&'a MetadataValue>;
+    fn get_metadata_value(&'a self, key: &str) -> Option<&'a MetadataValue<'a>>;
 
-    /// Get the weight of this rule. `0` if no weight is defined.
-    fn get_rule_weight(&'a self) -> i64;
+    /// Get the value of an integer metadata by key. `None` if not defined or not an integer.
+    fn get_integer_metadata(&'a self, key: &str) -> Option<i64> {
+        if let Some(MetadataValue::Integer(integer)) = self.get_metadata_value(key) {
+            Some(*integer)
+        } else {
+            None
+        }
+    }
 
-    /// Get a vector over the `filetype` metadata value. An empty Vec if not defined.
-    fn get_filetypes(&'a self) -> Vec<&'a str>;
-}
+    /// Get the weight of this rule. `None` if no integer weight is defined.
+    fn get_rule_weight(&'a self) -> Option<i64> {
+        self.get_integer_metadata("weight")
+    }
 
-impl RuleExt<'_> for Rule<'_> {
-    fn get_metadata_value(&self, key: &str) -> Option<&'_ MetadataValue> {
-        self.metadatas
-            .iter()
-            .find(|metadata| metadata.identifier == key)
-            .map(|metadata| &metadata.value)
+    /// Get the size of the largest files this rule is evaluated against, in bytes, from the
+    /// `max_filesize` metadata value. `None` if not defined or not a positive integer.
+    fn get_max_filesize(&'a self) -> Option<u64> {
+        self.get_integer_metadata("max_filesize")
+            .and_then(|size| u64::try_from(size).ok())
+            .filter(|&size| size > 0)
     }
 
-    fn get_filetypes(&'_ self) -> Vec<&'_ str> {
+    /// Get a vector over the `filetype` metadata value. An empty Vec if not defined.
+    fn get_filetypes(&'a self) -> Vec<&'a str> {
         if let Some(MetadataValue::String(string)) = self.get_metadata_value("filetype") {
             string.split(' ').collect()
         } else {
@@ -29,20 +47,73 @@ impl RuleExt<'_> for Rule<'_> {
         }
     }
 
-    fn get_rule_weight(&self) -> i64 {
-        if let Some(MetadataValue::Integer(integer)) = self.get_metadata_value("weight") {
-            *integer
+    /// Get the verdict implied by the `severity` metadata value. `None` if not defined or unknown.
+    fn get_severity(&'a self) -> Option<Verdict> {
+        if let Some(MetadataValue::String(string)) = self.get_metadata_value("severity") {
+            Verdict::from_severity(string)
         } else {
-            0
+            None
         }
     }
+
+    /// Whether the files this rule matches should be quarantined, from the `quarantine` metadata
+    /// value. `false` if not defined.
+    fn quarantines(&'a self) -> bool {
+        matches!(
+            self.get_metadata_value("quarantine"),
+            Some(MetadataValue::Boolean(true))
+        )
+    }
+}
+
+/// The name of the rule `identifier` of `namespace` as it's reported, see [`RuleExt::full_name`]
+fn full_name(namespace: &str, identifier: &str) -> String {
+    if namespace == DEFAULT_NAMESPACE {
+        identifier.to_owned()
+    } else {
+        format!("{namespace}:{identifier}")
+    }
+}
+
+impl<'a> RuleExt<'a> for Rule<'a> {
+    fn full_name(&self) -> String {
+        full_name(self.namespace, self.identifier)
+    }
+
+    fn get_metadata_value(&'a self, key: &str) -> Option<&'a MetadataValue<'a>> {
+        self.metadatas
+            .iter()
+            .find(|metadata| metadata.identifier == key)
+            .map(|metadata| &metadata.value)
+    }
+}
+
+/// The rules of a compiled ruleset, as [`Rule`]s without strings, leaving out the built-in canary
+/// rule. yara doesn't export the type of the rules of a ruleset, so [`RuleExt`] can't be
+/// implemented for it.
+pub fn ruleset_rules(rules: &Rules) -> Vec<Rule<'_>> {
+    rules
+        .get_rules()
+        .into_iter()
+        .filter(|rule| rule.namespace != CANARY_NAMESPACE)
+        .map(|rule| Rule {
+            identifier: rule.identifier,
+            namespace: rule.namespace,
+            metadatas: rule.metadatas,
+            tags: rule.tags,
+            strings: Vec::new(),
+        })
+        .collect()
 }
 
 impl From<Rule<'_>> for RuleScore {
     fn from(rule: Rule) -> Self {
         Self {
-            name: rule.identifier.to_owned(),
-            score: rule.get_rule_weight(),
+            name: rule.full_name(),
+            score: rule
+                .get_rule_weight()
+                .unwrap_or(APP_CONFIG.load().default_rule_weight),
+            severity: rule.get_severity(),
         }
     }
 }
@@ -0,0 +1,133 @@
+//! Liveness and readiness probes.
+
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::{
+    build_info::BUILD_INFO,
+    server::{self, Response},
+    APP_CONFIG,
+};
+
+/// The health of the client, updated by the main loop and read by the probes
+pub struct Health {
+    authenticated: AtomicBool,
+    rules_loaded: AtomicBool,
+    rules_expired: AtomicBool,
+    last_poll: Mutex<Option<Instant>>,
+}
+
+impl Health {
+    fn new() -> Self {
+        Self {
+            authenticated: AtomicBool::new(false),
+            rules_loaded: AtomicBool::new(false),
+            rules_expired: AtomicBool::new(false),
+            last_poll: Mutex::new(None),
+        }
+    }
+
+    pub fn set_authenticated(&self, authenticated: bool) {
+        self.authenticated.store(authenticated, Ordering::Relaxed);
+    }
+
+    pub fn set_rules_loaded(&self, rules_loaded: bool) {
+        self.rules_loaded.store(rules_loaded, Ordering::Relaxed);
+    }
+
+    /// Set whether the rules are too old to accept jobs with, see [`crate::client::Staleness`]
+    pub fn set_rules_expired(&self, rules_expired: bool) {
+        self.rules_expired.store(rules_expired, Ordering::Relaxed);
+    }
+
+    /// Record a successful job poll, whether or not it returned a job
+    pub fn record_poll(&self) {
+        *self.last_poll.lock() = Some(Instant::now());
+    }
+
+    /// Check whether the client is ready to scan: it's authenticated, has rules loaded that
+    /// haven't expired, and successfully polled for jobs within the last `max_poll_age`.
+    ///
+    /// Returns the reason the client isn't ready otherwise.
+    pub fn readiness(&self, max_poll_age: Duration) -> Result<(), String> {
+        if !self.authenticated.load(Ordering::Relaxed) {
+            return Err(String::from("not authenticated"));
+        }
+
+        if !self.rules_loaded.load(Ordering::Relaxed) {
+            return Err(String::from("rules not loaded"));
+        }
+
+        if self.rules_expired.load(Ordering::Relaxed) {
+            return Err(String::from("rules expired, updates are failing"));
+        }
+
+        match *self.last_poll.lock() {
+            None => Err(String::from("no successful job poll yet")),
+            Some(last_poll) if last_poll.elapsed() > max_poll_age => Err(format!(
+                "last successful job poll was {}s ago",
+                last_poll.elapsed().as_secs()
+            )),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+/// The global health state of the client
+pub static HEALTH: Lazy<Health> = Lazy::new(Health::new);
+
+/// Serve `/healthz`, `/readyz`, and the build information on `/version` on `addr` in the
+/// background
+pub fn serve(addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+    server::serve(addr, |request| match request.path.as_str() {
+        "/healthz" => Response::text(200, "ok"),
+        "/version" => match serde_json::to_string(&*BUILD_INFO) {
+            Ok(body) => Response::json(200, body),
+            Err(err) => Response::text(500, err.to_string()),
+        },
+        "/readyz" => {
+            let max_poll_age = Duration::from_secs(APP_CONFIG.load().readiness_max_poll_age);
+            match HEALTH.readiness(max_poll_age) {
+                Ok(()) => Response::text(200, "ready"),
+                Err(reason) => Response::text(503, reason),
+            }
+        }
+        _ => Response::not_found(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Health;
+    use std::time::Duration;
+
+    #[test]
+    fn ready_once_authenticated_with_rules_and_polled() {
+        let health = Health::new();
+        let max_poll_age = Duration::from_secs(60);
+
+        assert!(health.readiness(max_poll_age).is_err());
+
+        health.set_authenticated(true);
+        health.set_rules_loaded(true);
+        assert_eq!(
+            health.readiness(max_poll_age),
+            Err(String::from("no successful job poll yet"))
+        );
+
+        health.record_poll();
+        assert_eq!(health.readiness(max_poll_age), Ok(()));
+
+        health.set_rules_expired(true);
+        assert!(health.readiness(max_poll_age).is_err());
+        health.set_rules_expired(false);
+        assert!(health.readiness(Duration::ZERO).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+//! An anonymized fingerprint of the host the client runs on.
+//!
+//! Attached to results when enabled, so the mainframe can tell when outliers all come from the
+//! same machine (a corrupted ruleset cache, a bad disk) without learning the machine's name. The
+//! host name is hashed with an HMAC keyed with `host_fingerprint_key`, a secret of the deployment,
+//! so the hashes of guessable host names can't be looked up.
+
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sha2::Sha256;
+use tracing::warn;
+
+use crate::APP_CONFIG;
+
+/// Identifies the host that produced a result
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Fingerprint {
+    /// The hex encoded HMAC-SHA256 of the host name, keyed with `host_fingerprint_key`
+    pub hostname_hash: String,
+
+    /// The region label from the configuration, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+}
+
+impl Fingerprint {
+    fn new(hostname: &str, key: &str, region: Option<String>) -> Self {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any size");
+        mac.update(hostname.as_bytes());
+
+        Self {
+            hostname_hash: format!("{:x}", mac.finalize().into_bytes()),
+            region,
+        }
+    }
+}
+
+/// The fingerprint of this host, or `None` if fingerprinting is disabled or there's no
+/// `host_fingerprint_key` to hash the host name with
+pub static FINGERPRINT: Lazy<Option<Fingerprint>> = Lazy::new(|| {
+    let config = APP_CONFIG.load();
+    if !config.host_fingerprint {
+        return None;
+    }
+    let Some(key) = config
+        .host_fingerprint_key
+        .as_deref()
+        .filter(|key| !key.is_empty())
+    else {
+        warn!("host_fingerprint is enabled without a host_fingerprint_key, not fingerprinting");
+        return None;
+    };
+
+    let hostname = gethostname::gethostname();
+    Some(Fingerprint::new(
+        &hostname.to_string_lossy(),
+        key,
+        config.region.clone(),
+    ))
+});
+
+#[cfg(test)]
+mod tests {
+    use super::Fingerprint;
+
+    #[test]
+    fn hashes_the_hostname() {
+        let fingerprint = Fingerprint::new("worker-1", "key", Some(String::from("eu-west")));
+
+        assert_eq!(fingerprint.hostname_hash.len(), 64);
+        assert!(!fingerprint.hostname_hash.contains("worker"));
+        assert_eq!(
+            fingerprint,
+            Fingerprint::new("worker-1", "key", Some(String::from("eu-west")))
+        );
+        assert_ne!(
+            fingerprint.hostname_hash,
+            Fingerprint::new("worker-2", "key", None).hostname_hash
+        );
+        assert_ne!(
+            fingerprint.hostname_hash,
+            Fingerprint::new("worker-1", "other key", None).hostname_hash
+        );
+    }
+}
@@ -0,0 +1,566 @@
+//! Where the job loop gets its jobs from.
+//!
+//! Besides the Dragonfly API, jobs can be read from disk so that a scanning host doesn't need to
+//! reach the API for them, and jobs can be moved there by hand. Local jobs are in the same JSON
+//! format as the API's, and their distributions are either URLs (including `file://` URLs) or paths
+//! relative to the file they're read from.
+//!
+//! - [`Directory`] scans every `*.json` file of `job_source_path` in file name order, and moves it
+//!   to `done/` once its result is queued (or to `failed/` if it's not a job).
+//! - [`QueueFile`] scans the jobs appended to the JSON Lines file `job_source_path`, one per line.
+//!   The file is never rewritten, so jobs can safely be appended while the client is running; the
+//!   offset of the first job that isn't done is kept next to it, in `<job_source_path>.cursor`.
+//! - [`Archives`] turns every distribution archive (`.tar.gz`, `.whl`, ...) dropped into
+//!   `job_source_path` into a job of its own, scanned with whatever rules are current, and moves it
+//!   to `done/` once its result is queued. Files that aren't distributions are moved to `failed/`.
+//! - [`Listener`] takes jobs pushed over HTTP, see [`listener`].
+
+mod listener;
+mod queue;
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{BufRead, BufReader, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime},
+};
+
+use color_eyre::{eyre::eyre, Result};
+pub use listener::Listener;
+use queue::JobQueue;
+use reqwest::Url;
+use tracing::{debug, error, info, trace, warn};
+
+use crate::{
+    app_config::{AppConfig, JobSourceKind},
+    client::{DragonflyClient, Job, Prefetched},
+    offline::parse_distribution_file_name,
+    APP_CONFIG,
+};
+
+/// A source of jobs for the job loop
+pub trait JobSource {
+    /// Get the next job, `None` if there are none right now
+    fn next_job(&mut self, client: &mut DragonflyClient) -> Result<Option<Job>>;
+
+    /// Called once the rules for `job` are in place, right before it's scanned
+    fn scan_started(&mut self, _client: &mut DragonflyClient) {}
+
+    /// Called once the result of `job` is queued for submission, so it's not handed out again
+    fn complete(&mut self, _job: &Job) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether [`JobSource::next_job`] already waits for jobs to come in, so the job loop shouldn't
+    /// back off when there are none
+    fn waits_for_jobs(&self) -> bool {
+        false
+    }
+}
+
+/// Open the job source configured in `config`
+pub fn open(config: &AppConfig) -> Result<Box<dyn JobSource>> {
+    Ok(match config.job_source {
+        JobSourceKind::Api => Box::new(Api::default()),
+        JobSourceKind::Directory => Box::new(Directory::new(&config.job_source_path)?),
+        JobSourceKind::QueueFile => Box::new(QueueFile::open(&config.job_source_path)?),
+        JobSourceKind::Archives => Box::new(Archives::new(&config.job_source_path)?),
+        JobSourceKind::Listener => Box::new(Listener::start(config)?),
+    })
+}
+
+/// Jobs fetched from the Dragonfly API in batches of `bulk_size`, kept in memory until they're
+/// scanned, highest priority first (see [`queue`]). The next batch is fetched in the background
+/// while the last job of the current one is scanned (except in `low_resource` mode).
+///
+/// With a `priority_poll_interval`, another batch is fetched that often while less than a batch
+/// is left, so jobs with a higher priority don't wait for the current batch to be done.
+#[derive(Default)]
+pub struct Api {
+    pending: JobQueue,
+    prefetched: Option<JoinHandle<Prefetched>>,
+
+    /// When jobs were last fetched
+    last_fetch: Option<Instant>,
+}
+
+/// The amount of jobs fetched at once
+fn batch_size() -> usize {
+    APP_CONFIG.load().bulk_size.max(1)
+}
+
+impl Api {
+    /// Whether to fetch more jobs before the current ones are done, see `priority_poll_interval`
+    fn refill_due(&self) -> bool {
+        let interval = Duration::from_secs(APP_CONFIG.load().priority_poll_interval);
+        !interval.is_zero()
+            && self.pending.len() < batch_size()
+            && !self
+                .last_fetch
+                .is_some_and(|last_fetch| last_fetch.elapsed() < interval)
+    }
+}
+
+impl JobSource for Api {
+    fn next_job(&mut self, client: &mut DragonflyClient) -> Result<Option<Job>> {
+        if self.pending.is_empty() {
+            let jobs = match self.prefetched.take().map(JoinHandle::join) {
+                Some(Ok(Prefetched { jobs, rules })) => {
+                    trace!("Using prefetched jobs");
+                    if let Some(rules) = rules {
+                        info!("Installing rules {} prepared in the background", rules.hash);
+                        if let Err(err) = client.install_rules(rules) {
+                            error!("{err:#}");
+                        }
+                    }
+                    jobs
+                }
+                Some(Err(_)) => {
+                    error!("Job prefetching thread panicked, fetching jobs again");
+                    client.bulk_get_job(batch_size())
+                }
+                None => {
+                    info!("Fetching jobs");
+                    client.bulk_get_job(batch_size())
+                }
+            };
+            self.pending.extend(jobs?);
+            self.last_fetch = Some(Instant::now());
+            if self.pending.len() > 1 {
+                info!("Fetched {} jobs", self.pending.len());
+            }
+        } else if self.refill_due() {
+            match client.bulk_get_job(batch_size()) {
+                Ok(jobs) => {
+                    debug!(
+                        "Fetched {} more jobs ahead of the current batch",
+                        jobs.len()
+                    );
+                    self.pending.extend(jobs);
+                }
+                Err(err) => warn!("Failed to fetch more jobs ahead of the current batch: {err}"),
+            }
+            self.last_fetch = Some(Instant::now());
+        }
+
+        let job = self.pending.pop();
+        if let Some(job) = job.as_ref().filter(|job| job.priority != 0) {
+            info!(
+                "Scanning {} v{} with priority {}",
+                job.name, job.version, job.priority
+            );
+        }
+        Ok(job)
+    }
+
+    fn scan_started(&mut self, client: &mut DragonflyClient) {
+        // a prefetched batch may come with a second compiled ruleset, held in memory alongside the
+        // current one
+        if self.pending.is_empty() && !APP_CONFIG.load().low_resource {
+            self.prefetched = Some(client.prefetch_jobs(batch_size()));
+        }
+    }
+}
+
+/// Turn the distributions of `job` that are relative paths into `file://` URLs, relative to `base`
+fn resolve_distributions(job: &mut Job, base: &Path) -> Result<()> {
+    for distribution in &mut job.distributions {
+        if Url::parse(distribution).is_err() {
+            let path = base.join(&*distribution);
+            *distribution = Url::from_file_path(&path)
+                .map_err(|()| eyre!("Can't turn {} into a URL", path.display()))?
+                .into();
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the jobs `a` and `b` are for the same release and rules
+fn same_job(a: &Job, b: &Job) -> bool {
+    (&a.name, &a.version, &a.hash) == (&b.name, &b.version, &b.hash)
+}
+
+/// Move `path` into the subdirectory `subdir` of its directory
+fn move_into(path: &Path, subdir: &str) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new(".")).join(subdir);
+    fs::create_dir_all(&dir)?;
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| eyre!("{} has no file name", path.display()))?;
+    fs::rename(path, dir.join(file_name))?;
+
+    Ok(())
+}
+
+/// Jobs read from a directory of JSON files, one job per file
+pub struct Directory {
+    dir: PathBuf,
+    in_flight: Vec<(Job, PathBuf)>,
+}
+
+impl Directory {
+    pub fn new(dir: &Path) -> Result<Self> {
+        Ok(Self {
+            dir: dir.canonicalize()?,
+            in_flight: Vec::new(),
+        })
+    }
+
+    /// The job files that haven't been handed out yet, in file name order
+    fn pending(&self) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let is_json = path
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+            let in_flight = self.in_flight.iter().any(|(_, other)| *other == path);
+            if is_json && path.is_file() && !in_flight {
+                paths.push(path);
+            }
+        }
+        paths.sort_unstable();
+
+        Ok(paths)
+    }
+
+    /// The next job file that's a job, see [`JobSource::next_job`]
+    fn next(&mut self) -> Result<Option<Job>> {
+        for path in self.pending()? {
+            let mut job: Job = match serde_json::from_slice(&fs::read(&path)?) {
+                Ok(job) => job,
+                Err(err) => {
+                    warn!("Moving {} to failed/, not a job: {err}", path.display());
+                    move_into(&path, "failed")?;
+                    continue;
+                }
+            };
+            resolve_distributions(&mut job, &self.dir)?;
+
+            self.in_flight.push((job.clone(), path));
+            return Ok(Some(job));
+        }
+
+        Ok(None)
+    }
+}
+
+impl JobSource for Directory {
+    fn next_job(&mut self, _client: &mut DragonflyClient) -> Result<Option<Job>> {
+        self.next()
+    }
+
+    fn complete(&mut self, job: &Job) -> Result<()> {
+        if let Some(index) = self
+            .in_flight
+            .iter()
+            .position(|(other, _)| same_job(job, other))
+        {
+            let (_, path) = self.in_flight.swap_remove(index);
+            move_into(&path, "done")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Jobs read from a JSON Lines file that's only ever appended to
+pub struct QueueFile {
+    path: PathBuf,
+    cursor_path: PathBuf,
+
+    /// The offset of the next line to read
+    next: u64,
+
+    /// The offsets right after the jobs handed out but not completed yet, oldest first
+    in_flight: VecDeque<u64>,
+}
+
+impl QueueFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut cursor_path = path.as_os_str().to_owned();
+        cursor_path.push(".cursor");
+        let cursor_path = PathBuf::from(cursor_path);
+
+        let next = match fs::read_to_string(&cursor_path) {
+            Ok(cursor) => cursor.trim().parse()?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self {
+            path: path.to_owned(),
+            cursor_path,
+            next,
+            in_flight: VecDeque::new(),
+        })
+    }
+
+    /// The next complete line of the file that's a job, see [`JobSource::next_job`]
+    fn next(&mut self) -> Result<Option<Job>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(self.next))?;
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            // a line without a newline may still be being written
+            if read == 0 || !line.ends_with('\n') {
+                return Ok(None);
+            }
+            self.next += read as u64;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut job: Job = match serde_json::from_str(&line) {
+                Ok(job) => job,
+                Err(err) => {
+                    warn!("Skipping line of {}, not a job: {err}", self.path.display());
+                    continue;
+                }
+            };
+            resolve_distributions(&mut job, self.path.parent().unwrap_or(Path::new(".")))?;
+
+            self.in_flight.push_back(self.next);
+            return Ok(Some(job));
+        }
+    }
+}
+
+impl JobSource for QueueFile {
+    fn next_job(&mut self, _client: &mut DragonflyClient) -> Result<Option<Job>> {
+        self.next()
+    }
+
+    fn complete(&mut self, _job: &Job) -> Result<()> {
+        // jobs are scanned one after the other, so they're completed in the order they're read
+        if let Some(cursor) = self.in_flight.pop_front() {
+            fs::write(&self.cursor_path, cursor.to_string())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// How long an archive must go unmodified before it's picked up, so archives that are still being
+/// copied into the directory aren't scanned half-written
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// Jobs made of the distribution archives dropped into a directory, one job per archive
+pub struct Archives {
+    dir: PathBuf,
+    settle_time: Duration,
+    in_flight: Vec<(Job, PathBuf)>,
+}
+
+impl Archives {
+    pub fn new(dir: &Path) -> Result<Self> {
+        Ok(Self {
+            dir: dir.canonicalize()?,
+            settle_time: SETTLE_TIME,
+            in_flight: Vec::new(),
+        })
+    }
+
+    /// Whether `path` hasn't been modified for at least `settle_time`
+    fn is_settled(&self, path: &Path) -> Result<bool> {
+        let modified = fs::metadata(path)?.modified()?;
+        Ok(SystemTime::now()
+            .duration_since(modified)
+            .is_ok_and(|age| age >= self.settle_time))
+    }
+
+    /// The archives that haven't been handed out yet, in file name order
+    fn pending(&self) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let in_flight = self.in_flight.iter().any(|(_, other)| *other == path);
+            if path.is_file() && !in_flight && self.is_settled(&path)? {
+                paths.push(path);
+            }
+        }
+        paths.sort_unstable();
+
+        Ok(paths)
+    }
+
+    /// A job for the next archive, scanned with the rules `rules_hash`, see [`JobSource::next_job`]
+    fn next(&mut self, rules_hash: &str) -> Result<Option<Job>> {
+        for path in self.pending()? {
+            let file_name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let Some((name, version)) = parse_distribution_file_name(&file_name) else {
+                warn!("Moving {} to failed/, not a distribution", path.display());
+                move_into(&path, "failed")?;
+                continue;
+            };
+            let url = Url::from_file_path(&path)
+                .map_err(|()| eyre!("Can't turn {} into a URL", path.display()))?;
+
+            let job = Job {
+                hash: rules_hash.to_owned(),
+                name,
+                version,
+                distributions: vec![url.into()],
+                digests: Default::default(),
+                rescan: false,
+                force_rules_hash: None,
+                priority: 0,
+                queued_at: None,
+            };
+            self.in_flight.push((job.clone(), path));
+            return Ok(Some(job));
+        }
+
+        Ok(None)
+    }
+}
+
+impl JobSource for Archives {
+    fn next_job(&mut self, client: &mut DragonflyClient) -> Result<Option<Job>> {
+        // the current rules, so dropping an archive never triggers a rules update
+        let rules_hash = client.rules().hash.clone();
+        self.next(&rules_hash)
+    }
+
+    fn complete(&mut self, job: &Job) -> Result<()> {
+        // two archives of the same release are two jobs, so match on the archive too
+        if let Some(index) = self
+            .in_flight
+            .iter()
+            .position(|(other, _)| same_job(job, other) && job.distributions == other.distributions)
+        {
+            let (_, path) = self.in_flight.swap_remove(index);
+            move_into(&path, "done")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve_distributions, Archives, Directory, JobSource, QueueFile};
+    use crate::client::Job;
+    use reqwest::Url;
+    use std::{fs, io::Write, path::Path, time::Duration};
+    use tempfile::tempdir;
+
+    const JOB: &str =
+        r#"{"hash": "h", "name": "a", "version": "1.0", "distributions": ["a-1.0.tar.gz"]}"#;
+
+    #[test]
+    fn resolves_relative_distributions() {
+        let mut job: Job = serde_json::from_str(JOB).unwrap();
+        job.distributions
+            .push(String::from("https://files.pythonhosted.org/a-1.0.whl"));
+
+        resolve_distributions(&mut job, Path::new("/jobs")).unwrap();
+        assert_eq!(
+            job.distributions,
+            [
+                "file:///jobs/a-1.0.tar.gz",
+                "https://files.pythonhosted.org/a-1.0.whl"
+            ]
+        );
+    }
+
+    #[test]
+    fn hands_out_directory_jobs_until_completed() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("2.json"), JOB.replace(r#""a""#, r#""b""#)).unwrap();
+        fs::write(dir.path().join("1.json"), JOB).unwrap();
+        fs::write(dir.path().join("broken.json"), "{").unwrap();
+        fs::write(dir.path().join("notes.txt"), "").unwrap();
+
+        let mut source = Directory::new(dir.path()).unwrap();
+        let first = source.next().unwrap().unwrap();
+        assert_eq!(first.name, "a");
+        assert_eq!(source.next().unwrap().unwrap().name, "b");
+        assert!(source.next().unwrap().is_none());
+        assert!(dir.path().join("failed/broken.json").is_file());
+
+        source.complete(&first).unwrap();
+        assert!(dir.path().join("done/1.json").is_file());
+        assert!(Directory::new(dir.path())
+            .unwrap()
+            .next()
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn resumes_queue_files_after_the_last_completed_job() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("jobs.jsonl");
+        let mut file = fs::File::create(&path).unwrap();
+        writeln!(file, "{JOB}\nnot a job\n").unwrap();
+        write!(file, "{}", JOB.replace(r#""a""#, r#""b""#)).unwrap();
+
+        let mut source = QueueFile::open(&path).unwrap();
+        let first = source.next().unwrap().unwrap();
+        assert_eq!(first.name, "a");
+        // the last line isn't complete yet
+        assert!(source.next().unwrap().is_none());
+        source.complete(&first).unwrap();
+
+        writeln!(file).unwrap();
+        let mut source = QueueFile::open(&path).unwrap();
+        let second = source.next().unwrap().unwrap();
+        assert_eq!(second.name, "b");
+        assert_eq!(
+            second.distributions,
+            [Url::from_file_path(dir.path().join("a-1.0.tar.gz"))
+                .unwrap()
+                .as_str()]
+        );
+        assert!(source.next().unwrap().is_none());
+    }
+
+    #[test]
+    fn turns_dropped_archives_into_jobs() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("b-2.0-py3-none-any.whl"), "").unwrap();
+        fs::write(dir.path().join("a-1.0.tar.gz"), "").unwrap();
+        fs::write(dir.path().join("notes.txt"), "").unwrap();
+
+        let mut source = Archives::new(dir.path()).unwrap();
+        // nothing has had the time to settle yet
+        assert!(source.next("h").unwrap().is_none());
+
+        source.settle_time = Duration::ZERO;
+        let first = source.next("h").unwrap().unwrap();
+        assert_eq!(
+            (&*first.name, &*first.version, &*first.hash),
+            ("a", "1.0", "h")
+        );
+        assert_eq!(
+            first.distributions,
+            [
+                Url::from_file_path(dir.path().canonicalize().unwrap().join("a-1.0.tar.gz"))
+                    .unwrap()
+                    .as_str()
+            ]
+        );
+        assert_eq!(source.next("h").unwrap().unwrap().name, "b");
+        assert!(source.next("h").unwrap().is_none());
+        assert!(dir.path().join("failed/notes.txt").is_file());
+
+        source.complete(&first).unwrap();
+        assert!(dir.path().join("done/a-1.0.tar.gz").is_file());
+        assert!(dir.path().join("b-2.0-py3-none-any.whl").is_file());
+    }
+}
@@ -0,0 +1,157 @@
+//! Jobs pushed to the client over HTTP, for a self-hosted mirror that wants new releases scanned
+//! as soon as they're published instead of whenever the client polls.
+//!
+//! `POST /jobs` takes a job in the same JSON format as the API's, whose distributions must be
+//! HTTP(S) URLs. Jobs are queued in memory, up to `listener_queue_size` of them: a job is answered
+//! with `202 Accepted` once it's queued, and with `429 Too Many Requests` while the queue is full,
+//! so the mirror can push it again later. Queued jobs are lost if the client stops.
+//!
+//! Like the admin endpoint, jobs are only taken on a loopback address, unless `listener_token` is
+//! set, in which case every request must carry it as an `Authorization: Bearer <token>` header.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+    time::Duration,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use reqwest::Url;
+use tracing::{debug, info};
+
+use super::JobSource;
+use crate::{
+    app_config::AppConfig,
+    client::{DragonflyClient, Job},
+    server::{self, Request, Response},
+};
+
+/// How long to wait for a job to be pushed before letting the job loop do its other work
+const WAIT: Duration = Duration::from_secs(5);
+
+/// Jobs pushed to the HTTP listener, see the module docs
+pub struct Listener {
+    jobs: Receiver<Job>,
+}
+
+impl Listener {
+    /// Start taking jobs on `listener_bind:listener_port` in the background
+    pub fn start(config: &AppConfig) -> Result<Self> {
+        let (sender, jobs) = mpsc::sync_channel(config.listener_queue_size.max(1));
+        let addr = serve(
+            config.listener_bind,
+            config.listener_port,
+            config.listener_token.clone(),
+            sender,
+        )?;
+        info!("Taking pushed jobs on {addr}");
+
+        Ok(Self { jobs })
+    }
+}
+
+/// Serve `/jobs` on `bind:port`, queueing the jobs on `jobs`. Binding to anything but a loopback
+/// address requires a `token`.
+fn serve(
+    bind: IpAddr,
+    port: u16,
+    token: Option<String>,
+    jobs: SyncSender<Job>,
+) -> Result<SocketAddr> {
+    if !bind.is_loopback() && token.is_none() {
+        return Err(eyre!("listener_token must be set to take jobs on {bind}"));
+    }
+
+    Ok(server::serve((bind, port), move |request| {
+        handle(request, token.as_deref(), &jobs)
+    })?)
+}
+
+/// Queue the job pushed with `request` on `jobs`
+fn handle(request: &Request, token: Option<&str>, jobs: &SyncSender<Job>) -> Response {
+    if !server::authorized(request, token) {
+        return Response::text(401, "unauthorized");
+    }
+    if request.path != "/jobs" {
+        return Response::not_found();
+    }
+    if request.method != "POST" {
+        return Response::text(405, "method not allowed");
+    }
+
+    let job = match parse(&request.body) {
+        Ok(job) => job,
+        Err(err) => return Response::text(400, format!("not a job: {err}")),
+    };
+    let release = format!("{} v{}", job.name, job.version);
+    match jobs.try_send(job) {
+        Ok(()) => {
+            debug!("Queued pushed job {release}");
+            Response::text(202, "queued")
+        }
+        Err(TrySendError::Full(_)) => Response::text(429, "queue full"),
+        Err(TrySendError::Disconnected(_)) => Response::text(503, "not taking jobs"),
+    }
+}
+
+/// Parse a pushed job, whose distributions must all be HTTP(S) URLs
+fn parse(body: &[u8]) -> Result<Job> {
+    let job: Job = serde_json::from_slice(body)?;
+    for distribution in &job.distributions {
+        let url = Url::parse(distribution)?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(eyre!("{distribution} isn't an HTTP(S) URL"));
+        }
+    }
+
+    Ok(job)
+}
+
+impl JobSource for Listener {
+    fn next_job(&mut self, _client: &mut DragonflyClient) -> Result<Option<Job>> {
+        match self.jobs.recv_timeout(WAIT) {
+            Ok(job) => Ok(Some(job)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(eyre!("The job listener stopped")),
+        }
+    }
+
+    fn waits_for_jobs(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::handle;
+    use crate::server::Request;
+    use std::sync::mpsc;
+
+    const JOB: &str = r#"{"hash": "h", "name": "a", "version": "1.0", "distributions": ["https://files.pythonhosted.org/a-1.0.tar.gz"]}"#;
+
+    fn post(path: &str, token: Option<&str>, body: &str) -> Request {
+        Request {
+            method: String::from("POST"),
+            path: path.to_owned(),
+            authorization: token.map(|token| format!("Bearer {token}")),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn queues_pushed_jobs_up_to_the_limit() {
+        let (sender, jobs) = mpsc::sync_channel(1);
+        let status = |request: Request| handle(&request, Some("secret"), &sender).status;
+
+        assert_eq!(status(post("/jobs", None, JOB)), 401);
+        assert_eq!(status(post("/other", Some("secret"), JOB)), 404);
+        assert_eq!(status(post("/jobs", Some("secret"), "{")), 400);
+        let local = JOB.replace("https://files.pythonhosted.org", "file:///etc");
+        assert_eq!(status(post("/jobs", Some("secret"), &local)), 400);
+
+        assert_eq!(status(post("/jobs", Some("secret"), JOB)), 202);
+        assert_eq!(status(post("/jobs", Some("secret"), JOB)), 429);
+        assert_eq!(jobs.try_recv().unwrap().name, "a");
+        assert_eq!(status(post("/jobs", Some("secret"), JOB)), 202);
+    }
+}
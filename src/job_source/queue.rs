@@ -0,0 +1,130 @@
+//! The jobs fetched from the API that are waiting to be scanned.
+//!
+//! Jobs are scanned highest `priority` first, so packages that were reported jump ahead of the
+//! bulk of the jobs. Jobs of the same priority are scanned oldest `queued_at` first (the ones
+//! without one last), and otherwise in the order they were fetched in.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::client::Job;
+
+/// The priority of a job, then whether it has a queue time, its queue time and the order it was
+/// queued in, reversed so that the earliest come first
+type Key = (i32, Reverse<(bool, Option<DateTime<Utc>>, u64)>);
+
+/// A job waiting in a [`JobQueue`]
+struct Queued {
+    job: Job,
+
+    /// How many jobs were queued before this one
+    seq: u64,
+}
+
+impl Queued {
+    /// Greater for the jobs to scan first
+    fn key(&self) -> Key {
+        (
+            self.job.priority,
+            Reverse((self.job.queued_at.is_none(), self.job.queued_at, self.seq)),
+        )
+    }
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// The jobs waiting to be scanned, in the order to scan them in
+#[derive(Default)]
+pub struct JobQueue {
+    jobs: BinaryHeap<Queued>,
+    queued: u64,
+}
+
+impl JobQueue {
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    /// Take the job to scan next
+    pub fn pop(&mut self) -> Option<Job> {
+        self.jobs.pop().map(|queued| queued.job)
+    }
+}
+
+impl Extend<Job> for JobQueue {
+    fn extend<T: IntoIterator<Item = Job>>(&mut self, jobs: T) {
+        for job in jobs {
+            self.jobs.push(Queued {
+                job,
+                seq: self.queued,
+            });
+            self.queued += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JobQueue;
+    use crate::client::Job;
+
+    fn job(name: &str, priority: i32, queued_at: Option<&str>) -> Job {
+        let mut job: Job = serde_json::from_value(serde_json::json!({
+            "hash": "abc",
+            "name": name,
+            "version": "1.0",
+            "distributions": [],
+            "priority": priority,
+        }))
+        .unwrap();
+        job.queued_at = queued_at.map(|queued_at| queued_at.parse().unwrap());
+        job
+    }
+
+    #[test]
+    fn scans_high_priority_jobs_first() {
+        let mut queue = JobQueue::default();
+        queue.extend([
+            job("bulk", 0, None),
+            job("older", 0, Some("2024-06-01T11:00:00Z")),
+            job("newer", 0, Some("2024-06-01T12:00:00Z")),
+        ]);
+        assert_eq!(queue.pop().unwrap().name, "older");
+
+        // fetched later, but reported
+        queue.extend([job("bulk2", 0, None), job("reported", 10, None)]);
+        assert_eq!(queue.len(), 4);
+
+        let order = std::iter::from_fn(|| queue.pop())
+            .map(|job| job.name)
+            .collect::<Vec<_>>();
+        assert_eq!(order, ["reported", "newer", "bulk", "bulk2"]);
+        assert!(queue.is_empty());
+    }
+}
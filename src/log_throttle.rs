@@ -0,0 +1,159 @@
+//! Throttling of repeated identical warnings and errors.
+//!
+//! During an outage every iteration of the loop fails the same way, which buries everything else in
+//! the logs. The [`Throttle`] layer lets the first occurrence of a warning or error through, drops
+//! identical ones (same call site, same fields) for `log_throttle_window` seconds, and then logs
+//! how often the message was repeated the next time it comes up.
+
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Write},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use tracing::{
+    error,
+    field::{Field, Visit},
+    warn, Event, Level, Subscriber,
+};
+use tracing_subscriber::layer::{Context, Layer};
+
+/// The number of distinct messages tracked before expired ones are pruned
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// What to do with an occurrence of a message
+#[derive(Debug, PartialEq, Eq)]
+enum Decision {
+    /// Log it
+    Emit,
+
+    /// Log it, after a summary of the `n` occurrences suppressed since it was last logged
+    EmitAfterRepeats(usize),
+
+    /// Drop it
+    Suppress,
+}
+
+struct Seen {
+    logged_at: Instant,
+    repeats: usize,
+}
+
+/// A [`Layer`] that drops repeated identical warnings and errors, see the module docs
+pub struct Throttle {
+    window: Duration,
+    seen: Mutex<HashMap<(&'static str, String), Seen>>,
+}
+
+impl Throttle {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record an occurrence of `message` from the call site `name` at `now`
+    fn check(&self, name: &'static str, message: String, now: Instant) -> Decision {
+        let mut seen = self.seen.lock();
+        if seen.len() >= PRUNE_THRESHOLD {
+            seen.retain(|_, entry| now.duration_since(entry.logged_at) < self.window);
+        }
+
+        let Some(entry) = seen.get_mut(&(name, message.clone())) else {
+            seen.insert(
+                (name, message),
+                Seen {
+                    logged_at: now,
+                    repeats: 0,
+                },
+            );
+            return Decision::Emit;
+        };
+
+        if now.duration_since(entry.logged_at) < self.window {
+            entry.repeats += 1;
+            return Decision::Suppress;
+        }
+
+        let repeats = std::mem::take(&mut entry.repeats);
+        entry.logged_at = now;
+        if repeats == 0 {
+            Decision::Emit
+        } else {
+            Decision::EmitAfterRepeats(repeats)
+        }
+    }
+}
+
+/// Flattens the fields of an event into a single string
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        let _ = write!(self.0, "{}={value:?};", field.name());
+    }
+}
+
+impl<S: Subscriber> Layer<S> for Throttle {
+    fn event_enabled(&self, event: &Event<'_>, _ctx: Context<'_, S>) -> bool {
+        let metadata = event.metadata();
+        // summaries are never throttled, they're unique anyway
+        if *metadata.level() > Level::WARN || metadata.target() == module_path!() {
+            return true;
+        }
+
+        let mut message = Message(String::new());
+        event.record(&mut message);
+
+        match self.check(metadata.name(), message.0, Instant::now()) {
+            Decision::Emit => true,
+            Decision::Suppress => false,
+            Decision::EmitAfterRepeats(repeats) => {
+                let minutes = self.window.as_secs().div_ceil(60);
+                if *metadata.level() == Level::ERROR {
+                    error!("The next message was repeated {repeats} times in the last {minutes} minutes");
+                } else {
+                    warn!("The next message was repeated {repeats} times in the last {minutes} minutes");
+                }
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Decision, Throttle};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn suppresses_repeats_within_the_window() {
+        let throttle = Throttle::new(Duration::from_secs(60));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(throttle.check("a", "down".into(), at(0)), Decision::Emit);
+        assert_eq!(
+            throttle.check("a", "down".into(), at(1)),
+            Decision::Suppress
+        );
+        assert_eq!(throttle.check("a", "other".into(), at(2)), Decision::Emit);
+        assert_eq!(throttle.check("b", "down".into(), at(3)), Decision::Emit);
+        assert_eq!(
+            throttle.check("a", "down".into(), at(30)),
+            Decision::Suppress
+        );
+
+        assert_eq!(
+            throttle.check("a", "down".into(), at(61)),
+            Decision::EmitAfterRepeats(2)
+        );
+        assert_eq!(
+            throttle.check("a", "down".into(), at(62)),
+            Decision::Suppress
+        );
+        assert_eq!(throttle.check("b", "down".into(), at(200)), Decision::Emit);
+    }
+}
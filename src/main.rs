@@ -1,87 +1,1015 @@
+mod admin;
+mod analyzers;
 mod app_config;
+mod audit;
+mod breaker;
+mod build_info;
+mod cli;
 mod client;
+mod deadline;
+mod disk;
+mod events;
+mod extract;
 mod exts;
+mod health;
+mod host;
+mod job_source;
+mod log_throttle;
+mod memory;
+mod offline;
+mod polling;
+mod pypi;
+mod quarantine;
+mod result_sink;
 mod scanner;
+mod server;
+mod stats;
+mod typosquat;
 mod utils;
 
-use std::time::Duration;
+use std::{
+    fs::File,
+    io::{Read, Seek},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use chrono::Utc;
+use clap::Parser;
 use client::DragonflyClient;
-use color_eyre::eyre::Result;
-use tracing::{error, info, span, trace, Level};
-use tracing_subscriber::EnvFilter;
+use color_eyre::eyre::{bail, eyre, Report, Result};
+use reqwest::Url;
+use tracing::{error, info, span, trace, warn, Level};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use yara::MetadataValue;
 
 use crate::{
-    app_config::APP_CONFIG,
-    client::{Job, ScanResult, SubmitJobResultsError},
-    scanner::{scan_all_distributions, PackageScanResults},
+    admin::STATE,
+    app_config::{AppConfig, Backend, ConfigWatcher, EventStream, LogFormat, APP_CONFIG},
+    audit::{AuditLog, Entry},
+    breaker::{Breaker, Transition},
+    build_info::BUILD_INFO,
+    cli::{Cli, Command, ConfigCommand, RulesCommand},
+    client::{
+        FileResultsStream, Job, RulesResponse, RulesState, ScanResult, ScanResultSerializer,
+        Submission, SubmitJobResultsError, SubmitQueue,
+    },
+    deadline::Deadline,
+    events::Event,
+    extract::ArchiveKind,
+    exts::ruleset_rules,
+    health::HEALTH,
+    job_source::JobSource,
+    log_throttle::Throttle,
+    memory::ResourceLimit,
+    polling::Schedule,
+    result_sink::ResultSink,
+    scanner::{
+        lint_rules, needs_published_digests, report_hot_rules, scan_all_distributions,
+        scan_archive, scan_archive_bytes, unknown_rules, validate_metadata,
+        DistributionScanResults, PackageScanResults,
+    },
+    stats::Stats,
 };
 
-fn scan_package(client: &DragonflyClient, job: Job) -> ScanResult {
-    let span = span!(Level::INFO, "Job", name = job.name, version = job.version);
+#[allow(clippy::result_large_err)] // the error is the result submitted for the job
+fn scan_package(
+    client: &mut DragonflyClient,
+    job: Job,
+    deadline: Deadline,
+    scanned: impl FnMut(&DistributionScanResults),
+) -> ScanResult {
+    let span = span!(
+        Level::INFO,
+        "Job",
+        name = job.name,
+        version = job.version,
+        commit = job.hash
+    );
     let _enter = span.enter();
 
-    match scan_all_distributions(client.get_http_client(), &client.rules_state.rules, &job) {
+    // hold on to this snapshot for the whole scan, so the results are attributed to the ruleset
+    // that actually produced them even if the rules are updated in the meantime
+    let rules = client.rules();
+    let release = pypi_release(client, &job);
+    match scan_all_distributions(
+        client.get_download_client(),
+        &rules,
+        &job,
+        release.as_ref(),
+        deadline,
+        scanned,
+    ) {
         Ok(results) => {
             let package_scan_results =
-                PackageScanResults::new(job.name, job.version, results, job.hash);
-            let body = package_scan_results.build_body();
+                PackageScanResults::new(job.name, job.version, results, rules.hash.clone());
+            let mut body = package_scan_results.build_body();
+            body.worker_id.clone_from(&client.worker_id);
+            body.pypi_metadata = release.and_then(|release| pypi_metadata(client, &release));
 
             Ok(body)
         }
-        Err(err) => Err(SubmitJobResultsError {
-            name: job.name,
-            version: job.version,
-            reason: format!("{err}"),
-        }),
+        Err(err) => Err(job_error(client, job.name, job.version, &err)),
     }
 }
 
-fn main() -> Result<()> {
-    color_eyre::install()?;
+/// The error submitted for the job of `name` `version`, which failed with `err`
+fn job_error(
+    client: &DragonflyClient,
+    name: String,
+    version: String,
+    err: &Report,
+) -> SubmitJobResultsError {
+    SubmitJobResultsError {
+        name,
+        version,
+        reason: format!("{err}"),
+        resource_limit: err.downcast_ref::<ResourceLimit>().cloned(),
+        client_version: BUILD_INFO.client_version(),
+        worker_id: client.worker_id.clone(),
+    }
+}
 
-    let default_env_filter = EnvFilter::builder()
-        .parse("warn,dragonfly_client_rs=info")
-        .unwrap();
-    let env_filter = EnvFilter::try_from_default_env().unwrap_or(default_env_filter);
+/// The release of `job` on the index, fetched once for both its published digests (see
+/// [`needs_published_digests`]) and its metadata (see [`pypi_metadata`]). `None` if neither is
+/// needed, or if it can't be fetched.
+fn pypi_release(client: &DragonflyClient, job: &Job) -> Option<pypi::Release> {
+    if !APP_CONFIG.load().pypi_enrichment && !needs_published_digests(job) {
+        return None;
+    }
 
-    tracing_subscriber::fmt().with_env_filter(env_filter).init();
-    let mut client = DragonflyClient::new()?;
+    Url::parse(&APP_CONFIG.load().pypi_url)
+        .map_err(Into::into)
+        .and_then(|base| {
+            pypi::release(client.get_download_client(), &base, &job.name, &job.version)
+        })
+        .inspect_err(|err| {
+            warn!(
+                "Failed to fetch {} v{} from the index: {err}",
+                job.name, job.version
+            );
+        })
+        .ok()
+}
 
-    loop {
-        info!("Fetching job");
-        match client.get_job() {
-            Ok(Some(job)) => {
-                trace!("Successfully fetched job");
+/// What the index knows about `release`, with `pypi_enrichment`
+fn pypi_metadata(client: &DragonflyClient, release: &pypi::Release) -> Option<pypi::Metadata> {
+    if !APP_CONFIG.load().pypi_enrichment {
+        return None;
+    }
+
+    let base = Url::parse(&APP_CONFIG.load().pypi_url).ok()?;
+    Some(pypi::metadata(client.get_download_client(), &base, release))
+}
+
+/// Submit as many queued results of `lane` as possible, logging the remaining queue depth. Nothing
+/// is submitted in a dry run, the queue is left for the next real one.
+///
+/// Returns how long to leave the backend alone if submitting failed and opened its circuit, see
+/// [`record_request`].
+fn flush_queue(lane: &mut Lane) -> Option<Duration> {
+    if APP_CONFIG.load().dry_run {
+        return None;
+    }
+    if lane.queue.len() == 0 {
+        STATE.set_queue_depth(0);
+        return None;
+    }
+    let Lane {
+        client,
+        sink,
+        queue,
+        breaker,
+        ..
+    } = lane;
+
+    let drained = queue.drain(|submission, key| match submission {
+        Submission::Result(body) => sink.send(client, body, key),
+        Submission::FileResults {
+            name,
+            version,
+            chunk,
+            body,
+        } => client.send_file_results(name, version, chunk, body, key),
+    });
+    let succeeded = match drained {
+        Ok(submitted) => {
+            info!("Submitted {submitted} results");
+            true
+        }
+        Err(err) => {
+            // the circuit opening again is logged instead
+            if !breaker.is_open() {
+                error!("Error while submitting result to {}: {err}", sink.name());
+            }
+            STATE.record_error(format!("failed to submit result to {}: {err}", sink.name()));
+            false
+        }
+    };
+
+    STATE.set_queue_depth(queue.len());
+    if queue.len() > 0 {
+        info!("{} results waiting to be submitted", queue.len());
+    }
+    record_request(lane, succeeded)
+}
+
+/// Record whether a request to the backend of `lane` succeeded in its circuit breaker, logging and
+/// emitting its transitions. Returns how long to leave the backend alone if the circuit opened.
+fn record_request(lane: &mut Lane, succeeded: bool) -> Option<Duration> {
+    let transition = if succeeded {
+        lane.breaker.succeeded()
+    } else {
+        lane.breaker.failed(&APP_CONFIG.load())
+    }?;
+
+    let backend = &lane.client.backend.name;
+    events::emit(&Event::Circuit {
+        backend,
+        transition,
+    });
+    match transition {
+        Transition::Opened { failures, wait } => {
+            warn!(
+                "{failures} requests to {backend} failed in a row, leaving it alone for {}s",
+                wait.as_secs()
+            );
+            Some(wait)
+        }
+        Transition::Closed => {
+            info!("Requests to {backend} succeed again");
+            None
+        }
+    }
+}
+
+/// Submit the `last` most recently submitted results of every backend again, instead of running
+/// the job loop
+fn resend(last: usize) -> Result<()> {
+    for backend in APP_CONFIG.load().api_backends()? {
+        let queue = SubmitQueue::open(
+            APP_CONFIG
+                .load()
+                .backend_path(&APP_CONFIG.load().submit_queue_path, &backend),
+            APP_CONFIG.load().submit_queue_capacity,
+        )?
+        .with_history(APP_CONFIG.load().result_history_size);
+        let mut client = DragonflyClient::for_backend(backend)?;
+        let mut sink = result_sink::open(&APP_CONFIG.load())?;
+
+        let resent = queue.resend(last, |body, key| sink.send(&mut client, body, key))?;
+        info!("Resent {resent} results to {}", client.backend.name);
+    }
+
+    Ok(())
+}
+
+/// The current ruleset: compiled from `rules_path` if it's set, fetched from the API otherwise
+fn current_rules() -> Result<Arc<RulesState>> {
+    match &APP_CONFIG.load().rules_path {
+        Some(path) => Ok(Arc::new(offline::load_rules(path)?)),
+        None => Ok(DragonflyClient::new()?.rules()),
+    }
+}
 
-                info!("Starting scan of {} v{}", job.name, job.version);
-                if job.hash != client.rules_state.hash {
-                    info!(
-                        "Must update rules, updating from {} to {}",
-                        client.rules_state.hash, job.hash
-                    );
+/// Scan a single distribution archive at `target`, or piped into stdin for `-`, against the
+/// current ruleset and print the results, instead of running the job loop. Without a `kind`, it's
+/// guessed from the file name. In `low_resource` mode an archive piped into stdin is spooled to a
+/// temporary file instead of being held in memory.
+fn scan_local(target: &Path, kind: Option<ArchiveKind>) -> Result<()> {
+    let rules = current_rules()?;
 
-                    if let Err(err) = client.update_rules() {
-                        error!("Error while updating rules: {err}");
+    let (name, version, results) = if target == Path::new("-") {
+        let kind = kind.unwrap_or(ArchiveKind::TarGz);
+        let inspector_url = Url::parse("file:///dev/stdin/")?;
+        let results = if APP_CONFIG.load().low_resource {
+            let mut archive = tempfile::tempfile()?;
+            std::io::copy(&mut std::io::stdin().lock(), &mut archive)?;
+            archive.rewind()?;
+            scan_archive(archive, kind, &rules.rules, inspector_url)?
+        } else {
+            let mut archive = Vec::new();
+            std::io::stdin().lock().read_to_end(&mut archive)?;
+            scan_archive_bytes(&archive, kind, &rules.rules, inspector_url)?
+        };
+        (String::from("stdin"), String::new(), results)
+    } else {
+        let path = target.canonicalize()?;
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let kind = kind.unwrap_or_else(|| ArchiveKind::from_file_name(&file_name));
+        let mut inspector_url = Url::from_file_path(&path)
+            .map_err(|()| eyre!("Can't turn {} into a URL", path.display()))?;
+        inspector_url.set_path(&format!("{}/", inspector_url.path()));
+
+        let results = scan_archive(File::open(&path)?, kind, &rules.rules, inspector_url)?;
+        let (name, version) =
+            offline::parse_distribution_file_name(&file_name).unwrap_or((file_name, String::new()));
+        (name, version, results)
+    };
+    let package_scan_results =
+        PackageScanResults::new(name, version, vec![results], rules.hash.clone());
+
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&package_scan_results.build_body())?
+    );
+
+    Ok(())
+}
+
+/// Run one of the `rules` commands
+fn rules_command(command: &RulesCommand) -> Result<()> {
+    match command {
+        RulesCommand::Pull => {
+            let mut client = DragonflyClient::new()?;
+            client.refresh_rules()?;
+            let rules = client.rules();
+            println!(
+                "Pulled ruleset {}, {} rules",
+                rules.hash,
+                ruleset_rules(&rules.rules).len()
+            );
+        }
+        RulesCommand::Show => {
+            let rules = current_rules()?;
+            println!("Ruleset {}", rules.hash);
+            for rule in ruleset_rules(&rules.rules) {
+                let weight = rule.metadatas.iter().find_map(|metadata| {
+                    match (metadata.identifier, &metadata.value) {
+                        ("weight", MetadataValue::Integer(weight)) => Some(weight.to_string()),
+                        _ => None,
                     }
+                });
+                println!(
+                    "{}\t{}",
+                    rule.identifier,
+                    weight.as_deref().unwrap_or("no weight")
+                );
+            }
+        }
+        RulesCommand::Lint => {
+            let ruleset = match &APP_CONFIG.load().rules_path {
+                Some(path) => offline::read_ruleset(path)?,
+                None => {
+                    let rules = DragonflyClient::new()?.rules();
+                    RulesResponse {
+                        hash: rules.hash.clone(),
+                        rules: (*rules.sources).clone(),
+                        etag: None,
+                    }
+                }
+            };
+
+            let issues = lint_rules(&ruleset.rules, APP_CONFIG.load().default_rule_weight);
+            for issue in &issues {
+                println!("{issue}");
+            }
+            let mut problems = issues.len();
+
+            // the lints already cover `weight`, and a ruleset that doesn't compile can't be
+            // checked any further
+            if let Ok(rules) = ruleset.compile() {
+                let required = APP_CONFIG
+                    .load()
+                    .required_rule_metadata
+                    .iter()
+                    .filter(|key| *key != "weight")
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let missing = validate_metadata(&rules, &required);
+                for rule in &missing {
+                    println!("{}: missing {}", rule.rule, rule.missing.join(", "));
+                }
+                let unknown = unknown_rules(&rules);
+                for rule in &unknown {
+                    println!("{rule}: included or excluded, but not in the ruleset");
+                }
+                problems += missing.len() + unknown.len();
+            }
+
+            if problems > 0 {
+                bail!("{problems} problems in ruleset {}", ruleset.hash);
+            }
+            println!("No problems in ruleset {}", ruleset.hash);
+        }
+    }
+
+    Ok(())
+}
+
+/// Scan the release `version` of the package `name` (or its latest release) straight from the
+/// package index against the current ruleset and print the results, instead of running the job
+/// loop. With `submit`, the results are also sent to the configured result sink.
+fn fetch_and_scan(name: &str, version: Option<&str>, submit: bool) -> Result<()> {
+    let mut client = DragonflyClient::new()?;
+    let job = pypi::resolve(
+        client.get_download_client(),
+        &Url::parse(&APP_CONFIG.load().pypi_url)?,
+        name,
+        version,
+        client.rules().hash.clone(),
+    )?;
+    info!(
+        "Scanning {} v{}, {} distributions",
+        job.name,
+        job.version,
+        job.distributions.len()
+    );
+
+    let key = client::idempotency_key(&format!("{}=={}@{}", job.name, job.version, job.hash));
+    let deadline = Deadline::after(Duration::from_secs(APP_CONFIG.load().iteration_timeout));
+    let body = serde_json::to_value(ScanResultSerializer::from(scan_package(
+        &mut client,
+        job,
+        deadline,
+        |_| {},
+    )))?;
+    println!("{}", serde_json::to_string_pretty(&body)?);
+
+    if submit {
+        let mut sink = result_sink::open(&APP_CONFIG.load())?;
+        sink.send(&mut client, &body, &key)?;
+        info!("Submitted results to {}", sink.name());
+    }
+
+    Ok(())
+}
+
+/// Make sure the current ruleset is the one `job` asks for, updating it if necessary.
+///
+/// Forced rescans always refresh the rules from source, bypassing the hash check and the cache of
+/// compiled rules. The API only serves its current ruleset, so a rescan forced with another one
+/// fails, see [`Job::accepts_rules`].
+fn prepare_rules_for(client: &mut DragonflyClient, job: &Job) -> Result<()> {
+    let current_hash = client.rules().hash.clone();
+    if job.is_forced() {
+        info!("Rescan requested, refreshing rules");
+        if let Err(err) = client.refresh_rules() {
+            error!("Error while refreshing rules: {err}");
+        }
+    } else if job.hash != current_hash {
+        info!(
+            "Must update rules, updating from {current_hash} to {}",
+            job.hash
+        );
+
+        if let Err(err) = client.update_rules() {
+            error!("Error while updating rules: {err}");
+        }
+    }
+
+    let rules_hash = &client.rules().hash;
+    if *rules_hash == job.hash {
+        client.staleness.verified(Instant::now());
+    }
+    if !job.accepts_rules(rules_hash) {
+        bail!(
+            "the rescan asked for rules {}, but only rules {rules_hash} could be fetched",
+            job.rules_hash()
+        );
+    }
+    if rules_hash != job.rules_hash() {
+        warn!(
+            "Scanning with rules {rules_hash}, the job asked for {}",
+            job.rules_hash()
+        );
+    }
+
+    Ok(())
+}
+
+/// Whether the rules have expired (see [`client::Staleness`]), in which case no jobs should be
+/// accepted until they can be updated
+fn rules_expired(client: &mut DragonflyClient) -> bool {
+    if !client.staleness.is_expired(Instant::now()) {
+        return false;
+    }
+
+    if client.update_rules().is_ok() {
+        info!("Updated expired rules to {}", client.rules().hash);
+        HEALTH.set_rules_expired(false);
+        return false;
+    }
+
+    error!(
+        "Rules {} are outdated and updates have been failing for {} hours, not accepting jobs",
+        client.rules().hash,
+        client.staleness.age(Instant::now()).as_secs() / 3600
+    );
+    HEALTH.set_rules_expired(true);
+    true
+}
+
+/// Upload the statistics if `stats_upload_interval` has passed since `last_upload`, unless in a
+/// dry run
+fn upload_stats(client: &mut DragonflyClient, stats: &Stats, last_upload: &mut Instant) {
+    let interval = Duration::from_secs(APP_CONFIG.load().stats_upload_interval);
+    if APP_CONFIG.load().dry_run || interval.is_zero() || last_upload.elapsed() < interval {
+        return;
+    }
+
+    match client.send_stats(&stats.report()) {
+        Ok(()) => trace!("Uploaded statistics"),
+        Err(err) => warn!("Failed to upload statistics: {err}"),
+    }
+    *last_upload = Instant::now();
+}
+
+/// Send a keepalive if the client registered and `keepalive_interval` has passed since
+/// `last_keepalive`
+fn send_keepalive(client: &mut DragonflyClient, last_keepalive: &mut Instant) {
+    let interval = Duration::from_secs(APP_CONFIG.load().keepalive_interval);
+    if client.worker_id.is_none() || interval.is_zero() || last_keepalive.elapsed() < interval {
+        return;
+    }
+
+    match client.send_keepalive() {
+        Ok(()) => trace!("Sent keepalive"),
+        Err(err) => warn!("Failed to send keepalive: {err}"),
+    }
+    *last_keepalive = Instant::now();
+}
+
+/// Refresh the list of top packages if `top_packages_refresh_interval` has passed since
+/// `last_refresh`, or it was never refreshed
+fn refresh_top_packages(client: &DragonflyClient, last_refresh: &mut Option<Instant>) {
+    let interval = Duration::from_secs(APP_CONFIG.load().top_packages_refresh_interval);
+    let Some(url) = &APP_CONFIG.load().top_packages_url else {
+        return;
+    };
+    if interval.is_zero() || last_refresh.is_some_and(|last| last.elapsed() < interval) {
+        return;
+    }
+
+    match typosquat::refresh(
+        client.get_download_client(),
+        url,
+        APP_CONFIG.load().top_packages_count,
+    ) {
+        Ok(count) => info!("Refreshed the list of top packages, {count} packages"),
+        Err(err) => warn!("Failed to refresh the list of top packages: {err}"),
+    }
+    *last_refresh = Some(Instant::now());
+}
+
+/// Reload the configuration if its files changed since the last iteration
+fn reload_config(watcher: &mut ConfigWatcher) {
+    if !watcher.changed() {
+        return;
+    }
+
+    match APP_CONFIG.reload() {
+        Ok((changed, _)) if changed.is_empty() => {}
+        Ok((changed, startup_only)) => {
+            info!("Reloaded configuration, changed {}", changed.join(", "));
+            if !startup_only.is_empty() {
+                warn!(
+                    "Changes to {} only take effect after a restart",
+                    startup_only.join(", ")
+                );
+            }
+        }
+        Err(err) => error!("Failed to reload configuration, keeping the current one: {err}"),
+    }
+}
+
+/// Log the result of `job` instead of submitting it, and mark the job as skipped if
+/// `dry_run_skip_jobs` is set
+fn dry_run(client: &mut DragonflyClient, job: &Job, scan_result: ScanResult) {
+    match serde_json::to_string(&ScanResultSerializer::from(scan_result)) {
+        Ok(body) => info!("Dry run, not submitting {body}"),
+        Err(err) => error!("Error while serializing result: {err}"),
+    }
+
+    if APP_CONFIG.load().dry_run_skip_jobs {
+        if let Err(err) = client.skip_job(&job.name, &job.version) {
+            error!("Error while marking job as skipped: {err}");
+        }
+    }
+}
+
+/// A backend the job loop serves, with its own source of jobs, result sink, submission queue and
+/// statistics
+struct Lane {
+    client: DragonflyClient,
+    source: Box<dyn JobSource>,
+    sink: Box<dyn ResultSink>,
+    queue: SubmitQueue,
+    stats: Stats,
+    last_stats_upload: Instant,
+    last_keepalive: Instant,
+
+    /// Stops requests to the backend while it keeps failing
+    breaker: Breaker,
+}
+
+impl Lane {
+    /// Connect to `backend`, with its own submission queue and statistics, see
+    /// [`AppConfig::backend_path`]
+    fn open(backend: Backend) -> Result<Self> {
+        let queue = SubmitQueue::open(
+            APP_CONFIG
+                .load()
+                .backend_path(&APP_CONFIG.load().submit_queue_path, &backend),
+            APP_CONFIG.load().submit_queue_capacity,
+        )?
+        .with_history(APP_CONFIG.load().result_history_size);
+        let stats = Stats::open(
+            APP_CONFIG
+                .load()
+                .backend_path(&APP_CONFIG.load().stats_path, &backend),
+            APP_CONFIG.load().stats_retention_days,
+        )?;
+
+        info!(
+            "Taking jobs from the {} backend at {}, weight {}",
+            backend.name, backend.base_url, backend.weight
+        );
+        let mut client = DragonflyClient::for_backend(backend)?;
+        client.start_token_refresh();
+        client.register();
+
+        Ok(Self {
+            client,
+            source: job_source::open(&APP_CONFIG.load())?,
+            sink: result_sink::open(&APP_CONFIG.load())?,
+            queue,
+            stats,
+            last_stats_upload: Instant::now(),
+            last_keepalive: Instant::now(),
+            breaker: Breaker::default(),
+        })
+    }
+}
+
+/// Scan a freshly fetched `job` and queue its results for submission, or only log them in a dry
+/// run
+fn process_job(lane: &mut Lane, audit: Option<&AuditLog>, job: &Job, deadline: Deadline) {
+    let Lane {
+        client,
+        source,
+        queue,
+        stats,
+        ..
+    } = lane;
+    trace!("Successfully fetched job");
+    HEALTH.record_poll();
+
+    info!("Starting scan of {} v{}", job.name, job.version);
+    let prepared = prepare_rules_for(client, job);
+    source.scan_started(client);
+    STATE.job_started(&job.name, &job.version);
+
+    let key = format!("{}=={}@{}", job.name, job.version, job.hash);
+    let is_forced = job.is_forced();
+    let rules_hash = client.rules().hash.clone();
+    let backend = client.backend.name.clone();
+    events::emit(&Event::JobStarted {
+        backend: &backend,
+        name: &job.name,
+        version: &job.version,
+        rules_hash: &rules_hash,
+    });
+    let scan_start = Instant::now();
+    // the file results are queued as each distribution is scanned, ahead of the result
+    let mut stream =
+        (APP_CONFIG.load().stream_file_results && !APP_CONFIG.load().dry_run).then(|| {
+            FileResultsStream::new(
+                &key,
+                &job.name,
+                &job.version,
+                is_forced,
+                APP_CONFIG.load().stream_chunk_size,
+            )
+        });
+    // a job that can't be scanned with the rules it asked for fails without being scanned
+    let scan_result = match prepared {
+        Ok(()) => scan_package(client, job.clone(), deadline, |distribution| {
+            if let Some(stream) = &mut stream {
+                if let Err(err) = stream.push(queue, distribution.file_result_parts()) {
+                    error!("Error while queueing file results: {err}");
                 }
+            }
+        }),
+        Err(err) => {
+            error!("Not scanning {} v{}: {err}", job.name, job.version);
+            Err(job_error(
+                client,
+                job.name.clone(),
+                job.version.clone(),
+                &err,
+            ))
+        }
+    };
+    let outcome = scan_result
+        .as_ref()
+        .map(|body| (body.score, body.verdict))
+        .map_err(|err| err.reason.clone());
+    STATE.job_finished(&job.name, &job.version);
+    if let Err(reason) = &outcome {
+        events::emit(&Event::Error {
+            backend: Some(&backend),
+            name: Some(&job.name),
+            version: Some(&job.version),
+            reason,
+        });
+        STATE.record_error(format!(
+            "failed to scan {} v{}: {reason}",
+            job.name, job.version
+        ));
+    }
+    if deadline.is_expired() {
+        error!(
+            "Scan of {} v{} ran past the iteration deadline of {}s, giving up on it",
+            job.name,
+            job.version,
+            APP_CONFIG.load().iteration_timeout
+        );
+    }
+    if let Err(err) = stats.record(Utc::now().date_naive(), scan_result.is_ok(), &rules_hash) {
+        warn!("Failed to record statistics: {err}");
+    }
+    if let Some(audit) = audit {
+        let entry = Entry::new(
+            job,
+            &backend,
+            &rules_hash,
+            &scan_result,
+            scan_start.elapsed(),
+        );
+        if let Err(err) = audit.append(&entry) {
+            warn!("Failed to append to the audit log: {err}");
+        }
+    }
+    let queued = if APP_CONFIG.load().dry_run {
+        dry_run(client, job, scan_result);
+        Ok(())
+    } else if is_forced {
+        queue.push_rescan(key, scan_result)
+    } else {
+        queue.push(key, scan_result).map(|_| ())
+    };
+    match queued {
+        Ok(()) => {
+            if let Ok((score, verdict)) = outcome {
+                events::emit(&Event::JobCompleted {
+                    backend: &backend,
+                    name: &job.name,
+                    version: &job.version,
+                    score,
+                    verdict,
+                    duration_ms: scan_start.elapsed().as_millis(),
+                });
+            }
+            if let Err(err) = source.complete(job) {
+                error!("Error while marking job as done: {err}");
+            }
+        }
+        Err(err) => error!("Error while queueing result: {err}"),
+    }
+}
 
-                let scan_result = scan_package(&client, job);
-                let http_result = client.send_result(scan_result);
-                if let Err(err) = http_result {
-                    error!("Error while sending response to API: {err}");
+/// Fetch, scan, and submit jobs forever, taking turns between the backends of `lanes`, see
+/// [`Schedule`]
+fn run(lanes: &mut [Lane], audit: Option<&AuditLog>) -> ! {
+    let mut last_hot_rules_report = Instant::now();
+    let mut last_top_packages_refresh = None;
+    let mut config_watcher = ConfigWatcher::new();
+    let mut schedule = Schedule::new(lanes.iter().map(|lane| lane.client.backend.weight));
+
+    loop {
+        // settings only change between iterations, never in the middle of a scan
+        reload_config(&mut config_watcher);
+        let index = match schedule.next(Instant::now()) {
+            Ok(index) => index,
+            Err(due) => {
+                std::thread::sleep(due.saturating_duration_since(Instant::now()));
+                continue;
+            }
+        };
+        let lane = &mut lanes[index];
+        let span = span!(Level::INFO, "Backend", backend = lane.client.backend.name);
+        let _enter = span.enter();
+
+        let iteration_start = Instant::now();
+        let load_duration = Duration::from_secs(APP_CONFIG.load().load_duration);
+        let deadline = Deadline::after(Duration::from_secs(APP_CONFIG.load().iteration_timeout));
+        STATE.set_client(
+            &lane.client.rules().hash,
+            lane.client.authentication_state.expires_at,
+        );
+        upload_stats(&mut lane.client, &lane.stats, &mut lane.last_stats_upload);
+        send_keepalive(&mut lane.client, &mut lane.last_keepalive);
+        report_hot_rules(&mut last_hot_rules_report);
+        refresh_top_packages(&lane.client, &mut last_top_packages_refresh);
+
+        if let Some(wait) = flush_queue(lane) {
+            schedule.delay(index, iteration_start, wait);
+            continue;
+        }
+        if lane.queue.is_full() {
+            warn!(
+                "Submission queue is full ({} results), not fetching new jobs",
+                lane.queue.len()
+            );
+            schedule.delay(index, iteration_start, load_duration);
+            continue;
+        }
+
+        if rules_expired(&mut lane.client) {
+            schedule.delay(index, iteration_start, load_duration);
+            continue;
+        }
+
+        let fetched = lane.source.next_job(&mut lane.client);
+        let was_open = lane.breaker.is_open();
+        let open_for = record_request(lane, fetched.is_ok());
+        match fetched {
+            Ok(Some(job)) => {
+                schedule.found_jobs(index);
+                process_job(lane, audit, &job, deadline);
+                if let Some(wait) = flush_queue(lane) {
+                    schedule.delay(index, Instant::now(), wait);
                 }
             }
 
+            // the source already waited for a job, so poll it again right away
+            Ok(None) if lane.source.waits_for_jobs() => HEALTH.record_poll(),
+
             Ok(None) => {
-                info!("No job found");
-                std::thread::sleep(Duration::from_secs(APP_CONFIG.load_duration));
+                let wait = schedule.found_none(index, &APP_CONFIG.load(), iteration_start);
+                info!("No job found, polling again in {}s", wait.as_secs());
+                HEALTH.record_poll();
             }
 
             Err(err) => {
-                error!("Unexpected HTTP error: {err}");
-                std::thread::sleep(Duration::from_secs(APP_CONFIG.load_duration));
+                // the circuit opening again is logged instead
+                if !was_open {
+                    error!("Error while fetching job: {err}");
+                }
+                STATE.record_error(format!(
+                    "failed to fetch job from {}: {err}",
+                    lane.client.backend.name
+                ));
+                events::emit(&Event::Error {
+                    backend: Some(&lane.client.backend.name),
+                    name: None,
+                    version: None,
+                    reason: &format!("failed to fetch job: {err}"),
+                });
+                schedule.delay(index, iteration_start, open_for.unwrap_or(load_duration));
+            }
+        }
+    }
+}
+
+/// Install the global tracing subscriber, in the configured log format
+fn init_logging() {
+    let default_env_filter = EnvFilter::builder()
+        .parse("warn,dragonfly_client_rs=info")
+        .unwrap();
+    let env_filter = EnvFilter::try_from_default_env().unwrap_or(default_env_filter);
+
+    // a window of 0 disables throttling
+    let throttle = (APP_CONFIG.load().log_throttle_window > 0)
+        .then(|| Throttle::new(Duration::from_secs(APP_CONFIG.load().log_throttle_window)));
+
+    // stdout is taken by the event stream then
+    let to_stderr = APP_CONFIG.load().event_stream == EventStream::Stdout;
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(env_filter)
+        .with_writer(move || -> Box<dyn std::io::Write> {
+            if to_stderr {
+                Box::new(std::io::stderr())
+            } else {
+                Box::new(std::io::stdout())
+            }
+        });
+    match APP_CONFIG.load().log_format {
+        LogFormat::Pretty => subscriber.finish().with(throttle).init(),
+        LogFormat::Json => subscriber
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .finish()
+            .with(throttle)
+            .init(),
+    }
+}
+
+/// Remove the temporary directories left in the scratch directory by clients that crashed, see
+/// [`disk::clean_orphans`]
+fn clean_scratch_dir() {
+    let scratch_dir = disk::temp_dir();
+    let max_age = Duration::from_secs(APP_CONFIG.load().scratch_orphan_age);
+    match disk::clean_orphans(&scratch_dir, max_age) {
+        Ok(0) => {}
+        Ok(removed) => info!(
+            "Removed {removed} orphaned temporary directories from {}",
+            scratch_dir.display()
+        ),
+        Err(err) => warn!("Failed to clean up {}: {err}", scratch_dir.display()),
+    }
+}
+
+/// Fetch, scan, and submit jobs with the API
+fn start_job_loop() -> Result<()> {
+    if let Some(port) = APP_CONFIG.load().health_port {
+        let addr = health::serve(("0.0.0.0", port))?;
+        info!("Serving health endpoints on {addr}");
+    }
+    if let Some(port) = APP_CONFIG.load().admin_port {
+        let addr = admin::serve(
+            APP_CONFIG.load().admin_bind,
+            port,
+            APP_CONFIG.load().admin_token.clone(),
+        )?;
+        info!("Serving the admin endpoint on {addr}");
+    }
+
+    events::init()?;
+    let mut lanes = APP_CONFIG
+        .load()
+        .api_backends()?
+        .into_iter()
+        .map(Lane::open)
+        .collect::<Result<Vec<_>>>()?;
+    HEALTH.set_authenticated(true);
+    HEALTH.set_rules_loaded(true);
+
+    let audit = AuditLog::from_config(&APP_CONFIG.load());
+    run(&mut lanes, audit.as_ref())
+}
+
+fn main() -> Result<()> {
+    color_eyre::install()?;
+
+    let cli = Cli::parse();
+    if cli.version {
+        if cli.verbose {
+            println!("{}", BUILD_INFO.verbose());
+        } else {
+            println!("{}", *BUILD_INFO);
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Run { dry_run: true, .. }) = cli.command {
+        // before the global configuration is read, so it's the same as setting it there
+        std::env::set_var("DRAGONFLY_DRY_RUN", "true");
+    }
+
+    // before anything reads the global configuration, which panics if it's invalid
+    if let Some(Command::Config(ConfigCommand::Validate)) = cli.command {
+        print!("{}", AppConfig::build()?.redacted()?);
+        return Ok(());
+    }
+
+    init_logging();
+    info!(
+        "Starting {}, built {} against yara {}",
+        *BUILD_INFO, BUILD_INFO.build_timestamp, BUILD_INFO.yara_version
+    );
+
+    let command = cli.command.unwrap_or(Command::Run {
+        offline: false,
+        dry_run: false,
+    });
+    if matches!(command, Command::Run { .. }) {
+        clean_scratch_dir();
+    }
+    match command {
+        Command::Run { offline, .. } if offline || cli.offline => offline::run(),
+        Command::Run { .. } => start_job_loop(),
+        Command::Scan { target, kind } => scan_local(&target, kind.map(ArchiveKind::from)),
+        Command::ScanStdin { kind } => scan_local(Path::new("-"), Some(kind.into())),
+        Command::FetchAndScan {
+            name,
+            version,
+            submit,
+        } => fetch_and_scan(&name, version.as_deref(), submit),
+        Command::Resend { last } => resend(last),
+        Command::Stats => {
+            let backends = APP_CONFIG.load().api_backends()?;
+            for backend in &backends {
+                let stats = Stats::open(
+                    APP_CONFIG
+                        .load()
+                        .backend_path(&APP_CONFIG.load().stats_path, backend),
+                    APP_CONFIG.load().stats_retention_days,
+                )?;
+                if backends.len() > 1 {
+                    println!("{}:", backend.name);
+                }
+                print!("{}", stats.summary());
             }
+            Ok(())
         }
+        Command::Rules(command) => rules_command(&command),
+        Command::Config(ConfigCommand::Validate) => unreachable!("handled above"),
     }
 }
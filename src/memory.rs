@@ -0,0 +1,177 @@
+//! A memory budget for scanning distributions, so a single huge job can't get the client killed
+//! for running out of memory.
+//!
+//! Distributions are extracted to disk, so what a scan holds in memory is mostly the file being
+//! scanned (at most `max_file_size` bytes, more for files a compressed archive expands to) and the
+//! archives it's nested in, or with `scan_threads`, every file in flight between the scanner
+//! threads. Before a distribution is extracted, that amount is estimated from the size of the
+//! archive and reserved from `memory_budget` bytes shared by all jobs, until the distribution is
+//! scanned. A distribution that's estimated to need more than the whole budget fails its job right
+//! away with a [`ResourceLimit`] error; otherwise the reservation waits for other jobs to release
+//! enough of the budget. A budget of 0 doesn't limit anything.
+
+use std::{
+    fmt::{self, Display},
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::{Condvar, Mutex};
+use serde::Serialize;
+
+use crate::{deadline::Deadline, scanner::files_in_flight, APP_CONFIG};
+
+/// What scanning a distribution takes on top of the files it holds, for YARA and the results
+const SCAN_OVERHEAD: u64 = 16 * 1024 * 1024;
+
+/// How much larger than its archive a file is assumed to get when it's decompressed
+pub const EXPANSION: u64 = 10;
+
+/// How long to wait for the budget between two checks of the deadline
+const WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A job that would need more of a resource than the client has
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ResourceLimit {
+    pub resource: &'static str,
+
+    /// The estimated amount it needs, in bytes
+    pub required: u64,
+
+    /// The most there is, in bytes
+    pub budget: u64,
+}
+
+impl Display for ResourceLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.resource == "disk" {
+            return write!(
+                f,
+                "disk exhausted: needs an estimated {} bytes of disk space, {} bytes are available",
+                self.required, self.budget
+            );
+        }
+        write!(
+            f,
+            "resource limit exceeded: needs an estimated {} bytes of {}, the budget is {} bytes",
+            self.required, self.resource, self.budget
+        )
+    }
+}
+
+impl std::error::Error for ResourceLimit {}
+
+/// The memory budget shared by all jobs, see the module docs
+#[derive(Debug)]
+pub struct Budget {
+    /// The budget in bytes, unlimited if 0
+    limit: u64,
+    reserved: Mutex<u64>,
+    released: Condvar,
+}
+
+/// A part of the [`Budget`], given back when dropped
+#[derive(Debug)]
+pub struct Reservation<'a> {
+    budget: &'a Budget,
+    bytes: u64,
+}
+
+impl Budget {
+    pub fn new(budget: u64) -> Self {
+        Self {
+            limit: budget,
+            reserved: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Reserve `bytes` of the budget, waiting for other reservations to be released until
+    /// `deadline` passes. Fails right away if `bytes` is more than the whole budget.
+    pub fn reserve(&self, bytes: u64, deadline: Deadline) -> color_eyre::Result<Reservation<'_>> {
+        if self.limit == 0 {
+            return Ok(Reservation {
+                budget: self,
+                bytes: 0,
+            });
+        }
+        if bytes > self.limit {
+            return Err(ResourceLimit {
+                resource: "memory",
+                required: bytes,
+                budget: self.limit,
+            }
+            .into());
+        }
+
+        let mut reserved = self.reserved.lock();
+        while *reserved + bytes > self.limit {
+            deadline.check()?;
+            self.released.wait_for(&mut reserved, WAIT_INTERVAL);
+        }
+        *reserved += bytes;
+
+        Ok(Reservation {
+            budget: self,
+            bytes,
+        })
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if self.bytes == 0 {
+            return;
+        }
+        *self.budget.reserved.lock() -= self.bytes;
+        self.budget.released.notify_all();
+    }
+}
+
+/// The memory scanning a distribution with an archive of `archive_size` bytes is estimated to
+/// take, see the module docs. Archives of unknown size are assumed to hold files of the largest
+/// size that's scanned.
+pub fn estimate(archive_size: Option<u64>) -> u64 {
+    let largest_file = archive_size.map_or(APP_CONFIG.load().max_file_size, |size| {
+        size.saturating_mul(EXPANSION)
+            .min(APP_CONFIG.load().max_file_size)
+    });
+    // nested archives are only scanned by the thread merging the files of the distribution
+    let files = files_in_flight(APP_CONFIG.load().scan_threads) as u64
+        + APP_CONFIG.load().max_archive_depth as u64;
+
+    SCAN_OVERHEAD.saturating_add(largest_file.saturating_mul(files))
+}
+
+/// The memory budget of the client, of `memory_budget` bytes
+pub static MEMORY: Lazy<Budget> = Lazy::new(|| Budget::new(APP_CONFIG.load().memory_budget));
+
+#[cfg(test)]
+mod tests {
+    use super::{Budget, ResourceLimit};
+    use crate::deadline::Deadline;
+    use std::time::Duration;
+
+    #[test]
+    fn rejects_what_exceeds_the_budget() {
+        let budget = Budget::new(100);
+        let err = budget.reserve(101, Deadline::none()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ResourceLimit>(),
+            Some(&ResourceLimit {
+                resource: "memory",
+                required: 101,
+                budget: 100,
+            })
+        );
+
+        let first = budget.reserve(60, Deadline::none()).unwrap();
+        // waits for `first` until the deadline passes
+        let deadline = Deadline::after(Duration::from_millis(50));
+        assert!(budget.reserve(60, deadline).is_err());
+        drop(first);
+        assert!(budget.reserve(60, Deadline::none()).is_ok());
+
+        assert!(Budget::new(0).reserve(u64::MAX, Deadline::none()).is_ok());
+    }
+}
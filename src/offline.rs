@@ -0,0 +1,293 @@
+//! Running the scanner without the Dragonfly API, for air-gapped hosts.
+//!
+//! Rules are compiled from `rules_path`. Jobs are read from `offline_jobs_path`, which is either a
+//! JSON file in the same format as the API's jobs (with local paths instead of download URLs), or
+//! a directory of distribution archives, each of which is scanned as its own package. Results are
+//! written to `offline_results_dir`, one JSON file per package.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use reqwest::{blocking::Client, Url};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{error, info, span, warn, Level};
+use walkdir::WalkDir;
+
+use crate::{
+    build_info::BUILD_INFO,
+    client::{
+        bundles, RulesResponse, RulesState, ScanResult, ScanResultSerializer, SubmitJobResultsError,
+    },
+    extract::ArchiveKind,
+    scanner::{scan_archive, PackageScanResults},
+    APP_CONFIG,
+};
+
+/// A job read from disk
+#[derive(Debug, Deserialize, PartialEq)]
+struct LocalJob {
+    name: String,
+    version: String,
+
+    /// Paths of the distribution archives, relative to the jobs file, or `file://` URLs
+    distributions: Vec<PathBuf>,
+}
+
+/// Read every `.yar` and `.yara` file under `dir`, by their path relative to `dir`
+pub fn read_rule_files(dir: &Path) -> Result<HashMap<String, String>> {
+    let mut rules = HashMap::new();
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry?;
+        let is_rule_file = entry
+            .path()
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("yar") || ext.eq_ignore_ascii_case("yara"));
+        if entry.file_type().is_file() && is_rule_file {
+            let name = entry
+                .path()
+                .strip_prefix(dir)?
+                .to_string_lossy()
+                .into_owned();
+            rules.insert(name, fs::read_to_string(entry.path())?);
+        }
+    }
+
+    Ok(rules)
+}
+
+/// Compile every `.yar` and `.yara` file under `dir` into a ruleset, along with the
+/// `rule_bundles`. See [`read_ruleset`].
+pub fn load_rules(dir: &Path) -> Result<RulesState> {
+    let response = read_ruleset(dir)?;
+    let bundles = bundles::load_all(&Client::new(), &APP_CONFIG.load().rule_bundles)?;
+    let mut state = RulesState::new(response.compile_with(&bundles)?, response.hash);
+    state.sources = Arc::new(response.rules);
+    Ok(state)
+}
+
+/// Read every `.yar` and `.yara` file under `dir` as a ruleset.
+///
+/// The hash of the ruleset is derived from the names and contents of the rule files, so results
+/// can still be attributed to a specific version of the rules.
+pub fn read_ruleset(dir: &Path) -> Result<RulesResponse> {
+    let rules = read_rule_files(dir)?;
+    if rules.is_empty() {
+        return Err(eyre!("No rule files found in {}", dir.display()));
+    }
+
+    let mut names = rules.keys().collect::<Vec<_>>();
+    names.sort_unstable();
+    let mut hasher = Sha256::new();
+    for name in names {
+        hasher.update(name.as_bytes());
+        hasher.update([0]);
+        hasher.update(rules[name].as_bytes());
+        hasher.update([0]);
+    }
+    let hash = format!("local-{:x}", hasher.finalize());
+
+    Ok(RulesResponse {
+        hash,
+        rules,
+        etag: None,
+    })
+}
+
+/// Guess the name and version of a package from the file name of one of its distributions, such
+/// as `name-1.0.tar.gz` or `name-1.0-py3-none-any.whl`
+pub fn parse_distribution_file_name(file_name: &str) -> Option<(String, String)> {
+    if let Some(stem) = file_name.strip_suffix(".whl") {
+        let mut parts = stem.splitn(3, '-');
+        return Some((parts.next()?.to_owned(), parts.next()?.to_owned()));
+    }
+
+    let stem = file_name
+        .strip_suffix(".tar.gz")
+        .or_else(|| file_name.strip_suffix(".tar.zst"))
+        .or_else(|| file_name.strip_suffix(".zip"))?;
+    let (name, version) = stem.rsplit_once('-')?;
+    Some((name.to_owned(), version.to_owned()))
+}
+
+/// Read the jobs at `path`, a JSON file of jobs or a directory of distribution archives
+fn read_jobs(path: &Path) -> Result<Vec<LocalJob>> {
+    if path.is_file() {
+        let base = path.parent().unwrap_or(Path::new("."));
+        let mut jobs: Vec<LocalJob> = serde_json::from_slice(&fs::read(path)?)?;
+        for job in &mut jobs {
+            for distribution in &mut job.distributions {
+                let url = Url::parse(&distribution.to_string_lossy()).ok();
+                *distribution = match url.and_then(|url| url.to_file_path().ok()) {
+                    Some(path) => path,
+                    None => base.join(&*distribution),
+                };
+            }
+        }
+        return Ok(jobs);
+    }
+
+    let mut jobs = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        if let Some((name, version)) = parse_distribution_file_name(&file_name.to_string_lossy()) {
+            jobs.push(LocalJob {
+                name,
+                version,
+                distributions: vec![entry.path()],
+            });
+        } else {
+            warn!("Skipping {}, not a distribution", entry.path().display());
+        }
+    }
+    jobs.sort_unstable_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+
+    Ok(jobs)
+}
+
+/// Scan every distribution of a local job
+fn scan_job(job: &LocalJob, rules: &RulesState) -> Result<PackageScanResults> {
+    let mut distribution_scan_results = Vec::with_capacity(job.distributions.len());
+    for path in &job.distributions {
+        let path = path.canonicalize()?;
+        let kind = ArchiveKind::from_file_name(&path.to_string_lossy());
+        let mut inspector_url = Url::from_file_path(&path)
+            .map_err(|()| eyre!("Can't turn {} into a URL", path.display()))?;
+        inspector_url.set_path(&format!("{}/", inspector_url.path()));
+
+        distribution_scan_results.push(scan_archive(
+            File::open(&path)?,
+            kind,
+            &rules.rules,
+            inspector_url,
+        )?);
+    }
+
+    Ok(PackageScanResults::new(
+        job.name.clone(),
+        job.version.clone(),
+        distribution_scan_results,
+        rules.hash.clone(),
+    ))
+}
+
+/// Scan all local jobs against the local rules and write the results to disk
+pub fn run() -> Result<()> {
+    let rules_path = APP_CONFIG
+        .load()
+        .rules_path
+        .clone()
+        .ok_or_else(|| eyre!("rules_path must be set in offline mode"))?;
+    let rules = load_rules(&rules_path)?;
+    info!("Compiled local rules {}", rules.hash);
+
+    let jobs = read_jobs(&APP_CONFIG.load().offline_jobs_path)?;
+    fs::create_dir_all(&APP_CONFIG.load().offline_results_dir)?;
+
+    for job in jobs {
+        let span = span!(Level::INFO, "Job", name = job.name, version = job.version);
+        let _enter = span.enter();
+
+        let result: ScanResult = match scan_job(&job, &rules) {
+            Ok(results) => Ok(results.build_body()),
+            Err(err) => {
+                error!("Error while scanning: {err}");
+                Err(SubmitJobResultsError {
+                    name: job.name.clone(),
+                    version: job.version.clone(),
+                    reason: format!("{err}"),
+                    resource_limit: None,
+                    client_version: BUILD_INFO.client_version(),
+                    worker_id: None,
+                })
+            }
+        };
+
+        let path = APP_CONFIG
+            .load()
+            .offline_results_dir
+            .join(format!("{}-{}.json", job.name, job.version));
+        fs::write(
+            &path,
+            serde_json::to_vec_pretty(&ScanResultSerializer::from(result))?,
+        )?;
+        info!("Wrote results to {}", path.display());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_rules, parse_distribution_file_name, read_jobs, scan_job, LocalJob};
+    use std::{fs, path::PathBuf};
+    use tempfile::tempdir;
+
+    #[test]
+    fn parses_distribution_file_names() {
+        assert_eq!(
+            parse_distribution_file_name("requests-2.19.1-py2.py3-none-any.whl"),
+            Some((String::from("requests"), String::from("2.19.1")))
+        );
+        assert_eq!(
+            parse_distribution_file_name("discord.py-2.2.3.tar.gz"),
+            Some((String::from("discord.py"), String::from("2.2.3")))
+        );
+        assert_eq!(parse_distribution_file_name("README.md"), None);
+    }
+
+    #[test]
+    fn reads_jobs_files_relative_to_the_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("jobs.json");
+        fs::write(
+            &path,
+            r#"[{"name": "a", "version": "1.0", "distributions": ["dists/a-1.0.tar.gz"]}]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            read_jobs(&path).unwrap(),
+            vec![LocalJob {
+                name: String::from("a"),
+                version: String::from("1.0"),
+                distributions: vec![dir.path().join("dists/a-1.0.tar.gz")],
+            }]
+        );
+    }
+
+    #[test]
+    fn scans_local_archives_with_local_rules() {
+        let dir = tempdir().unwrap();
+        let rules_dir = dir.path().join("rules");
+        fs::create_dir(&rules_dir).unwrap();
+        fs::write(
+            rules_dir.join("evil.yar"),
+            "rule evil { meta: weight = 2 strings: $a = \"evil\" condition: $a }",
+        )
+        .unwrap();
+        let rules = load_rules(&rules_dir).unwrap();
+        assert_eq!(rules.hash, load_rules(&rules_dir).unwrap().hash);
+
+        let archive = dir.path().join("pkg-1.0-py3-none-any.whl");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&archive).unwrap());
+        zip.start_file("pkg/evil.py", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        std::io::Write::write_all(&mut zip, b"evil").unwrap();
+        zip.finish().unwrap();
+
+        let jobs = read_jobs(dir.path()).unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].distributions, vec![PathBuf::from(&archive)]);
+
+        let body = scan_job(&jobs[0], &rules).unwrap().build_body();
+        assert_eq!(body.name, "pkg");
+        assert_eq!(body.score, 2);
+    }
+}
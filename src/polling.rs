@@ -0,0 +1,192 @@
+//! How long to wait between two job requests.
+//!
+//! When a request returns jobs, the next one is made as soon as they're scanned, since there are
+//! probably more. Every request that comes back empty doubles the wait, from `poll_min_interval`
+//! up to `load_duration`. Each wait is shortened by a random fraction of up to `poll_jitter`, so a
+//! fleet of clients started together drifts apart instead of polling in lockstep.
+//!
+//! With several `backends`, each one backs off on its own. Of the backends that are due, the one
+//! furthest behind its share of the polls is polled next (smooth weighted round robin), so with
+//! weights of 3 and 1 the first one is polled three times for every time the second one is.
+
+use std::{
+    cmp::Reverse,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use crate::app_config::AppConfig;
+
+/// The state of the polling backoff
+#[derive(Debug, Default)]
+pub struct Polling {
+    /// How many requests in a row came back empty
+    empty_polls: u32,
+}
+
+impl Polling {
+    /// Record a request that returned jobs
+    pub fn found_jobs(&mut self) {
+        self.empty_polls = 0;
+    }
+
+    /// Record a request that came back empty, returning how long to wait before the next one
+    pub fn found_none(&mut self, config: &AppConfig) -> Duration {
+        self.empty_polls = self.empty_polls.saturating_add(1);
+        let delay = self.delay(config);
+        let jitter = rand::thread_rng().gen_range(0.0..=config.poll_jitter.clamp(0.0, 1.0));
+        delay.mul_f64(1.0 - jitter)
+    }
+
+    /// The wait after the current streak of empty requests, before jitter
+    fn delay(&self, config: &AppConfig) -> Duration {
+        let max = Duration::from_secs(config.load_duration);
+        let min = Duration::from_secs(config.poll_min_interval).min(max);
+        min.saturating_mul(2_u32.saturating_pow(self.empty_polls.saturating_sub(1)))
+            .min(max)
+    }
+}
+
+/// When each of the backends is polled
+#[derive(Debug)]
+pub struct Schedule {
+    turns: Vec<Turn>,
+}
+
+#[derive(Debug)]
+struct Turn {
+    weight: i64,
+
+    /// How far ahead of its share of the polls the backend is
+    credit: i64,
+    polling: Polling,
+    next_poll: Instant,
+}
+
+impl Schedule {
+    /// Schedule backends with `weights`, all due right away
+    pub fn new(weights: impl IntoIterator<Item = u32>) -> Self {
+        let now = Instant::now();
+        Self {
+            turns: weights
+                .into_iter()
+                .map(|weight| Turn {
+                    weight: weight.into(),
+                    credit: 0,
+                    polling: Polling::default(),
+                    next_poll: now,
+                })
+                .collect(),
+        }
+    }
+
+    /// The index of the backend to poll next, or when the next one is due if none is at `now`
+    pub fn next(&mut self, now: Instant) -> Result<usize, Instant> {
+        let due_weight = self
+            .turns
+            .iter()
+            .filter(|turn| turn.next_poll <= now)
+            .map(|turn| turn.weight)
+            .sum::<i64>();
+        if due_weight == 0 {
+            return Err(self
+                .turns
+                .iter()
+                .map(|turn| turn.next_poll)
+                .min()
+                .unwrap_or(now));
+        }
+
+        for turn in self.turns.iter_mut().filter(|turn| turn.next_poll <= now) {
+            turn.credit += turn.weight;
+        }
+        let (index, turn) = self
+            .turns
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, turn)| turn.next_poll <= now)
+            .min_by_key(|(_, turn)| Reverse(turn.credit))
+            .expect("a backend is due");
+        turn.credit -= due_weight;
+
+        Ok(index)
+    }
+
+    /// Record that polling the backend `index` returned jobs, so it's due again right away
+    pub fn found_jobs(&mut self, index: usize) {
+        self.turns[index].polling.found_jobs();
+    }
+
+    /// Record that polling the backend `index` at `polled_at` came back empty, returning how long
+    /// to wait before polling it again, see [`Polling::found_none`]
+    pub fn found_none(&mut self, index: usize, config: &AppConfig, polled_at: Instant) -> Duration {
+        let turn = &mut self.turns[index];
+        let wait = turn.polling.found_none(config);
+        turn.next_poll = polled_at + wait;
+        wait
+    }
+
+    /// Don't poll the backend `index` until `wait` after `polled_at`
+    pub fn delay(&mut self, index: usize, polled_at: Instant, wait: Duration) {
+        self.turns[index].next_poll = polled_at + wait;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Polling, Schedule};
+    use crate::app_config::AppConfig;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn backs_off_while_there_are_no_jobs() {
+        let config = AppConfig {
+            poll_min_interval: 5,
+            load_duration: 60,
+            poll_jitter: 0.0,
+            ..AppConfig::default()
+        };
+        let mut polling = Polling::default();
+
+        let delays = (0..6)
+            .map(|_| polling.found_none(&config).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [5, 10, 20, 40, 60, 60]);
+
+        polling.found_jobs();
+        assert_eq!(polling.found_none(&config), Duration::from_secs(5));
+
+        let config = AppConfig {
+            poll_jitter: 0.5,
+            ..config
+        };
+        let jittered = polling.found_none(&config);
+        assert!(jittered >= Duration::from_secs(5) && jittered <= Duration::from_secs(10));
+    }
+
+    #[test]
+    fn polls_backends_by_weight() {
+        let mut schedule = Schedule::new([3, 1]);
+        let now = Instant::now();
+
+        let polled = (0..8)
+            .map(|_| schedule.next(now).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(polled, [0, 0, 1, 0, 0, 0, 1, 0]);
+
+        // the other backend is polled alone while one backs off
+        let config = AppConfig {
+            poll_min_interval: 5,
+            poll_jitter: 0.0,
+            ..AppConfig::default()
+        };
+        assert_eq!(schedule.found_none(0, &config, now), Duration::from_secs(5));
+        assert_eq!(schedule.next(now), Ok(1));
+        assert_eq!(schedule.next(now), Ok(1));
+
+        schedule.delay(1, now, Duration::from_secs(10));
+        assert_eq!(schedule.next(now), Err(now + Duration::from_secs(5)));
+        assert_eq!(schedule.next(now + Duration::from_secs(5)), Ok(0));
+    }
+}
@@ -0,0 +1,306 @@
+//! Resolving releases through the JSON API of the Python Package Index, so a package can be
+//! scanned by name without waiting for the mainframe to queue a job for it.
+//!
+//! With `pypi_enrichment`, results are also sent with some [`Metadata`] of their release, which
+//! helps triaging: a brand-new project from an unknown domain that publishes obfuscated code
+//! deserves a closer look than the hundredth release of a known one. The release is fetched once
+//! per job, for both its metadata and the digests of its distributions, and the releases of the
+//! project are counted with the versions the JSON simple API lists, rather than by downloading
+//! every release of the project.
+
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Utc};
+use color_eyre::{eyre::eyre, Result};
+use reqwest::{blocking::Client, header::ACCEPT, Url};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Deserialize, Serialize,
+};
+use tracing::debug;
+
+use crate::client::Job;
+
+/// The content type of the JSON simple API, see PEP 691
+const SIMPLE_JSON: &str = "application/vnd.pypi.simple.v1+json";
+
+/// A release, as returned by `/pypi/<name>/json` or `/pypi/<name>/<version>/json`
+#[derive(Debug, Deserialize)]
+pub struct Release {
+    info: Info,
+    urls: Vec<ReleaseFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Info {
+    name: String,
+    version: String,
+
+    #[serde(default)]
+    author_email: Option<String>,
+
+    #[serde(default)]
+    project_urls: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReleaseFile {
+    url: String,
+
+    /// The digests of the file, by algorithm
+    #[serde(default)]
+    digests: HashMap<String, String>,
+
+    #[serde(default)]
+    upload_time_iso_8601: Option<DateTime<Utc>>,
+}
+
+/// A project, as returned by the JSON simple API at `/simple/<name>/`, with only its versions
+#[derive(Debug, Deserialize)]
+struct Project {
+    #[serde(default)]
+    versions: Vec<IgnoredAny>,
+}
+
+/// What the index knows about a release, see the module docs
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Metadata {
+    /// When its first distribution was uploaded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uploaded_at: Option<DateTime<Utc>>,
+
+    /// The domain of the email address of its author
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author_email_domain: Option<String>,
+
+    /// The links of the project, by label
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub project_urls: BTreeMap<String, String>,
+
+    /// How many releases the project has, if they could be listed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release_count: Option<usize>,
+}
+
+/// The domain of the first address of an `author_email` such as `Jane <jane@example.com>`
+fn email_domain(author_email: &str) -> Option<String> {
+    let address = author_email.split(',').next()?;
+    let (_, domain) = address.rsplit_once('@')?;
+    let domain = domain.trim().trim_end_matches('>').trim();
+
+    (!domain.is_empty()).then(|| domain.to_ascii_lowercase())
+}
+
+/// The JSON API URL of the release `version` of `name` on the index at `base`, or of its latest
+/// release without a `version`
+fn release_url(base: &Url, name: &str, version: Option<&str>) -> Result<Url> {
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .map_err(|()| eyre!("{base} can't be a base URL"))?
+        .pop_if_empty()
+        .extend(["pypi", name].into_iter().chain(version).chain(["json"]));
+
+    Ok(url)
+}
+
+/// The JSON simple API URL of the project `name` on the index at `base`
+fn project_url(base: &Url, name: &str) -> Result<Url> {
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .map_err(|()| eyre!("{base} can't be a base URL"))?
+        .pop_if_empty()
+        .extend(["simple", name, ""]);
+
+    Ok(url)
+}
+
+impl Release {
+    /// The SHA-256 digests of the distributions of this release, by URL
+    pub fn sha256_digests(&self) -> HashMap<String, String> {
+        self.urls
+            .iter()
+            .filter_map(|file| Some((file.url.clone(), file.digests.get("sha256")?.clone())))
+            .collect()
+    }
+
+    /// The [`Metadata`] of this release, for a project with `release_count` releases
+    fn metadata(&self, release_count: Option<usize>) -> Metadata {
+        Metadata {
+            uploaded_at: self
+                .urls
+                .iter()
+                .filter_map(|file| file.upload_time_iso_8601)
+                .min(),
+            author_email_domain: self.info.author_email.as_deref().and_then(email_domain),
+            project_urls: self.info.project_urls.clone().unwrap_or_default(),
+            release_count,
+        }
+    }
+
+    /// A job scanning every distribution of this release with the ruleset `hash`
+    fn into_job(self, hash: String) -> Result<Job> {
+        if self.urls.is_empty() {
+            return Err(eyre!(
+                "{} v{} has no distributions",
+                self.info.name,
+                self.info.version
+            ));
+        }
+
+        Ok(Job {
+            digests: self.sha256_digests(),
+            hash,
+            name: self.info.name,
+            version: self.info.version,
+            distributions: self.urls.into_iter().map(|file| file.url).collect(),
+            rescan: false,
+            force_rules_hash: None,
+            priority: 0,
+            queued_at: None,
+        })
+    }
+}
+
+/// Fetch the release `version` of `name` from the index at `base`, or its latest release without
+/// a `version`
+fn fetch<T: DeserializeOwned>(
+    http_client: &Client,
+    base: &Url,
+    name: &str,
+    version: Option<&str>,
+) -> Result<T> {
+    Ok(http_client
+        .get(release_url(base, name, version)?)
+        .send()?
+        .error_for_status()?
+        .json()?)
+}
+
+/// Resolve the release `version` of `name` (or its latest release) on the index at `base` into a
+/// job to scan with the ruleset `hash`
+pub fn resolve(
+    http_client: &Client,
+    base: &Url,
+    name: &str,
+    version: Option<&str>,
+    hash: String,
+) -> Result<Job> {
+    fetch::<Release>(http_client, base, name, version)?.into_job(hash)
+}
+
+/// Fetch the release `version` of `name` from the index at `base`
+pub fn release(http_client: &Client, base: &Url, name: &str, version: &str) -> Result<Release> {
+    fetch(http_client, base, name, Some(version))
+}
+
+/// The [`Metadata`] of `release`, a release on the index at `base`. It's left without a release
+/// count if the versions of the project can't be listed.
+pub fn metadata(http_client: &Client, base: &Url, release: &Release) -> Metadata {
+    let name = &release.info.name;
+    let release_count = project_url(base, name)
+        .and_then(|url| {
+            Ok(http_client
+                .get(url)
+                .header(ACCEPT, SIMPLE_JSON)
+                .send()?
+                .error_for_status()?
+                .json::<Project>()?)
+        })
+        .map(|project| project.versions.len())
+        .inspect_err(|err| debug!("Failed to list the versions of {name}: {err}"))
+        .ok();
+
+    release.metadata(release_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{email_domain, project_url, release_url, Project, Release};
+    use reqwest::Url;
+
+    #[test]
+    fn resolves_releases_into_jobs() {
+        let base = Url::parse("https://pypi.org").unwrap();
+        assert_eq!(
+            release_url(&base, "requests", None).unwrap().as_str(),
+            "https://pypi.org/pypi/requests/json"
+        );
+        assert_eq!(
+            release_url(&base, "requests", Some("2.32.3"))
+                .unwrap()
+                .as_str(),
+            "https://pypi.org/pypi/requests/2.32.3/json"
+        );
+
+        let release: Release = serde_json::from_str(
+            r#"{
+                "info": {"name": "Flask", "version": "3.0.3", "summary": "A web framework"},
+                "urls": [
+                    {"filename": "flask-3.0.3-py3-none-any.whl", "url": "https://files.pythonhosted.org/packages/61/flask-3.0.3-py3-none-any.whl", "digests": {"md5": "1d2f", "sha256": "8a11"}},
+                    {"filename": "flask-3.0.3.tar.gz", "url": "https://files.pythonhosted.org/packages/41/flask-3.0.3.tar.gz"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let job = release.into_job(String::from("abc")).unwrap();
+        assert_eq!(
+            (job.name.as_str(), job.version.as_str()),
+            ("Flask", "3.0.3")
+        );
+        assert_eq!(job.distributions.len(), 2);
+        assert_eq!(job.hash, "abc");
+        assert_eq!(
+            job.digests
+                .get("https://files.pythonhosted.org/packages/61/flask-3.0.3-py3-none-any.whl"),
+            Some(&String::from("8a11"))
+        );
+        assert_eq!(job.digests.len(), 1);
+
+        let empty: Release =
+            serde_json::from_str(r#"{"info": {"name": "x", "version": "1"}, "urls": []}"#).unwrap();
+        assert!(empty.into_job(String::new()).is_err());
+    }
+
+    #[test]
+    fn collects_the_metadata_of_releases() {
+        let release: Release = serde_json::from_str(
+            r#"{
+                "info": {
+                    "name": "reqeusts",
+                    "version": "0.1.0",
+                    "author_email": "Someone <someone@Example.COM>, other@example.org",
+                    "project_urls": {"Homepage": "https://example.com/reqeusts"}
+                },
+                "urls": [
+                    {"url": "https://files.pythonhosted.org/reqeusts-0.1.0.tar.gz", "upload_time_iso_8601": "2024-11-02T10:15:00.000000Z"},
+                    {"url": "https://files.pythonhosted.org/reqeusts-0.1.0-py3-none-any.whl", "upload_time_iso_8601": "2024-11-02T10:14:30.000000Z"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let project: Project = serde_json::from_str(
+            r#"{"meta": {"api-version": "1.1"}, "name": "reqeusts", "versions": ["0.0.1", "0.1.0"], "files": []}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            project_url(&Url::parse("https://pypi.org").unwrap(), "reqeusts")
+                .unwrap()
+                .as_str(),
+            "https://pypi.org/simple/reqeusts/"
+        );
+
+        let metadata = release.metadata(Some(project.versions.len()));
+        assert_eq!(
+            serde_json::to_value(&metadata).unwrap(),
+            serde_json::json!({
+                "uploaded_at": "2024-11-02T10:14:30Z",
+                "author_email_domain": "example.com",
+                "project_urls": {"Homepage": "https://example.com/reqeusts"},
+                "release_count": 2
+            })
+        );
+
+        assert_eq!(email_domain("nobody"), None);
+        assert_eq!(email_domain("a@b.io"), Some(String::from("b.io")));
+    }
+}
@@ -0,0 +1,242 @@
+//! Keeping the files that matched rules with `quarantine = true` metadata.
+//!
+//! `PyPI` removes malicious releases quickly, often before anyone got to look at the payload. So
+//! with a `quarantine_target`, the first `quarantine_max_size` bytes of every file (up to
+//! `quarantine_max_files` per distribution) matched by such a rule are kept while scanning, and
+//! uploaded once the distribution is scanned. Each upload is reported as an [`Artifact`] in the
+//! results. Uploads are content addressed, at `{quarantine_prefix}{name}/{version}/{sha256}`, so
+//! rescans don't store the same file twice.
+
+use chrono::Utc;
+use color_eyre::{eyre::eyre, Result};
+use reqwest::{blocking::Client, Url};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::{
+    app_config::{AppConfig, QuarantineTarget},
+    result_sink::S3,
+    APP_CONFIG,
+};
+
+/// The start of a file that matched a quarantine rule, kept until it's uploaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Excerpt {
+    /// The path of the file, relative to the distribution's archive root
+    pub path: String,
+
+    /// The quarantine rules the file matched
+    pub rules: Vec<String>,
+
+    /// The size of the whole file, in bytes
+    pub size: u64,
+    pub contents: Vec<u8>,
+}
+
+impl Excerpt {
+    /// Keep the first `max_size` bytes of `contents`
+    pub fn new(path: String, rules: Vec<String>, contents: &[u8], max_size: u64) -> Self {
+        let kept = usize::try_from(max_size).map_or(contents.len(), |max| max.min(contents.len()));
+        Self {
+            path,
+            rules,
+            size: contents.len() as u64,
+            contents: contents[..kept].to_vec(),
+        }
+    }
+}
+
+/// A quarantined file, as reported in the results
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Artifact {
+    /// The file name of the distribution containing the file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distribution: Option<String>,
+
+    /// The path of the file, relative to the distribution's archive root
+    pub path: String,
+
+    /// The quarantine rules the file matched
+    pub rules: Vec<String>,
+
+    /// The size of the whole file, in bytes
+    pub size: u64,
+
+    /// Whether only the start of the file was uploaded
+    pub truncated: bool,
+
+    /// The hex SHA-256 digest of what was uploaded
+    pub sha256: String,
+
+    /// Where the upload is stored: an `s3://` URI or an HTTP URL
+    pub reference: String,
+}
+
+/// Where quarantined files are uploaded to
+enum Store {
+    S3(S3),
+    Http(Url),
+}
+
+impl Store {
+    fn from_config(config: &AppConfig) -> Result<Option<Self>> {
+        Ok(match config.quarantine_target {
+            QuarantineTarget::None => None,
+            QuarantineTarget::S3 => Some(Self::S3(S3::from_config(config)?)),
+            QuarantineTarget::Http => {
+                let url = config
+                    .quarantine_url
+                    .as_deref()
+                    .ok_or_else(|| eyre!("quarantine_url must be set to quarantine over http"))?;
+                let mut url: Url = url.parse()?;
+                if !url.path().ends_with('/') {
+                    url.set_path(&format!("{}/", url.path()));
+                }
+                Some(Self::Http(url))
+            }
+        })
+    }
+
+    /// Upload `contents` to `key`, returning where it's stored
+    fn put(&self, http_client: &Client, key: &str, contents: Vec<u8>) -> Result<String> {
+        match self {
+            Self::S3(s3) => {
+                s3.put(
+                    http_client,
+                    key,
+                    contents,
+                    "application/octet-stream",
+                    Utc::now(),
+                )?;
+                Ok(s3.reference(key))
+            }
+            Self::Http(base) => {
+                let url = base.join(key)?;
+                http_client
+                    .put(url.clone())
+                    .header("Content-Type", "application/octet-stream")
+                    .body(contents)
+                    .send()?
+                    .error_for_status()?;
+                Ok(url.to_string())
+            }
+        }
+    }
+}
+
+/// Whether files matched by quarantine rules should be kept at all
+pub fn enabled() -> bool {
+    APP_CONFIG.load().quarantine_target != QuarantineTarget::None
+}
+
+/// Upload the `excerpts` of the distribution `distribution` of `name` `version`.
+///
+/// Excerpts that fail to upload are logged and left out, they don't fail the job.
+pub fn upload(
+    http_client: &Client,
+    name: &str,
+    version: &str,
+    distribution: Option<&str>,
+    excerpts: Vec<Excerpt>,
+) -> Vec<Artifact> {
+    if excerpts.is_empty() {
+        return Vec::new();
+    }
+    let store = match Store::from_config(&APP_CONFIG.load()) {
+        Ok(Some(store)) => store,
+        Ok(None) => return Vec::new(),
+        Err(err) => {
+            warn!("Not quarantining the files of {name} v{version}: {err}");
+            return Vec::new();
+        }
+    };
+
+    excerpts
+        .into_iter()
+        .filter_map(|excerpt| {
+            let sha256 = format!("{:x}", Sha256::digest(&excerpt.contents));
+            let key = format!(
+                "{}{}/{}/{sha256}",
+                APP_CONFIG.load().quarantine_prefix,
+                name.replace('/', "_"),
+                version.replace('/', "_")
+            );
+            let truncated = (excerpt.contents.len() as u64) < excerpt.size;
+
+            match store.put(http_client, &key, excerpt.contents) {
+                Ok(reference) => {
+                    info!(
+                        "Quarantined {} of {name} v{version} at {reference}",
+                        excerpt.path
+                    );
+                    Some(Artifact {
+                        distribution: distribution.map(ToOwned::to_owned),
+                        path: excerpt.path,
+                        rules: excerpt.rules,
+                        size: excerpt.size,
+                        truncated,
+                        sha256,
+                        reference,
+                    })
+                }
+                Err(err) => {
+                    warn!(
+                        "Failed to quarantine {} of {name} v{version}: {err}",
+                        excerpt.path
+                    );
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Excerpt, Store};
+    use crate::{
+        app_config::{AppConfig, QuarantineTarget},
+        server::{serve, Response},
+    };
+    use reqwest::blocking::Client;
+
+    #[test]
+    fn uploads_excerpts_over_http() {
+        let excerpt = Excerpt::new(String::from("setup.py"), Vec::new(), b"import os", 6);
+        assert_eq!(excerpt.contents, b"import");
+        assert_eq!(excerpt.size, 9);
+
+        let addr = serve("127.0.0.1:0", |request| {
+            if request.method == "PUT" && request.path == "/store/quarantine/pkg/1.0/abc" {
+                Response::text(200, "")
+            } else {
+                Response::not_found()
+            }
+        })
+        .unwrap();
+        let config = AppConfig {
+            quarantine_target: QuarantineTarget::Http,
+            quarantine_url: Some(format!("http://{addr}/store")),
+            ..AppConfig::default()
+        };
+        let store = Store::from_config(&config).unwrap().unwrap();
+
+        let reference = store
+            .put(&Client::new(), "quarantine/pkg/1.0/abc", excerpt.contents)
+            .unwrap();
+        assert_eq!(
+            reference,
+            format!("http://{addr}/store/quarantine/pkg/1.0/abc")
+        );
+        assert!(store
+            .put(&Client::new(), "quarantine/pkg/1.0/def", Vec::new())
+            .is_err());
+
+        let unconfigured = AppConfig {
+            quarantine_target: QuarantineTarget::Http,
+            ..AppConfig::default()
+        };
+        assert!(Store::from_config(&unconfigured).is_err());
+    }
+}
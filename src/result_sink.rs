@@ -0,0 +1,276 @@
+//! Where scan results are submitted to.
+//!
+//! `result_sinks` lists the sinks every result goes to, in order. The first one is the primary
+//! sink: a result stays in the submission queue until it's accepted there. The others archive a
+//! copy of the result on a best effort basis, so a failure is logged but doesn't hold up the queue
+//! (retrying would duplicate the result in the sinks that did accept it).
+//!
+//! - `http` submits to the Dragonfly API.
+//! - `file` appends one JSON result per line to `results_file_path`.
+//! - `s3` uploads each result as its own object to an S3 (compatible) bucket, see [`S3`].
+//! - `webhook` posts each result to `webhook_url`, see [`Webhook`].
+
+mod s3;
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{eyre::eyre, Result};
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use serde_json::Value;
+use sha2::Sha256;
+use tracing::error;
+
+pub use s3::S3;
+
+use crate::{
+    app_config::{AppConfig, ResultSinkKind},
+    client::DragonflyClient,
+};
+
+/// A destination for serialized scan results, such as a
+/// [`crate::client::ScanResultSerializer`]
+pub trait ResultSink {
+    /// A short name of the sink, for logs
+    fn name(&self) -> &'static str;
+
+    /// Deliver `body`. Sinks that can pass `idempotency_key` on to the receiver do, so it can tell
+    /// a result it already has from a new one, see [`crate::client::idempotency_key`].
+    fn send(
+        &mut self,
+        client: &mut DragonflyClient,
+        body: &Value,
+        idempotency_key: &str,
+    ) -> Result<()>;
+}
+
+/// Open the result sinks configured in `config`
+pub fn open(config: &AppConfig) -> Result<Box<dyn ResultSink>> {
+    let mut sinks = config
+        .result_sinks
+        .iter()
+        .map(|kind| -> Result<Box<dyn ResultSink>> {
+            Ok(match kind {
+                ResultSinkKind::Http => Box::new(Http),
+                ResultSinkKind::File => Box::new(File::new(&config.results_file_path)),
+                ResultSinkKind::S3 => Box::new(S3::from_config(config)?),
+                ResultSinkKind::Webhook => Box::new(Webhook::from_config(config)?),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if sinks.is_empty() {
+        return Err(eyre!("At least one result sink must be configured"));
+    }
+    let primary = sinks.remove(0);
+
+    Ok(if sinks.is_empty() {
+        primary
+    } else {
+        Box::new(Fanout {
+            primary,
+            archives: sinks,
+        })
+    })
+}
+
+/// Results submitted to the Dragonfly API
+pub struct Http;
+
+impl ResultSink for Http {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    fn send(
+        &mut self,
+        client: &mut DragonflyClient,
+        body: &Value,
+        idempotency_key: &str,
+    ) -> Result<()> {
+        client.send_result(body, idempotency_key)
+    }
+}
+
+/// Results appended to a JSON Lines file
+pub struct File {
+    path: PathBuf,
+}
+
+impl File {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+        }
+    }
+
+    /// Append `body` to the file, see [`ResultSink::send`]
+    fn append(&self, body: &Value) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut line = serde_json::to_vec(body)?;
+        line.push(b'\n');
+        // a single write, so concurrent readers never see half a line
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&line)?;
+
+        Ok(())
+    }
+}
+
+impl ResultSink for File {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn send(
+        &mut self,
+        _client: &mut DragonflyClient,
+        body: &Value,
+        _idempotency_key: &str,
+    ) -> Result<()> {
+        self.append(body)
+    }
+}
+
+/// Results posted as JSON to a webhook.
+///
+/// With a `webhook_secret`, every request carries an `X-Dragonfly-Signature: sha256=<hex>` header,
+/// the HMAC-SHA256 of the body keyed with the secret, so the receiver can check where it came from.
+pub struct Webhook {
+    url: Url,
+    secret: Option<String>,
+}
+
+impl Webhook {
+    pub fn from_config(config: &AppConfig) -> Result<Self> {
+        let url = config
+            .webhook_url
+            .as_deref()
+            .ok_or_else(|| eyre!("webhook_url must be set to use the webhook result sink"))?;
+
+        Ok(Self {
+            url: url.parse()?,
+            secret: config.webhook_secret.clone(),
+        })
+    }
+
+    /// The value of the signature header for `body`, if there's a secret to sign it with
+    fn signature(&self, body: &[u8]) -> Option<String> {
+        let secret = self.secret.as_deref()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC accepts keys of any size");
+        mac.update(body);
+        Some(format!("sha256={:x}", mac.finalize().into_bytes()))
+    }
+}
+
+impl ResultSink for Webhook {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn send(
+        &mut self,
+        client: &mut DragonflyClient,
+        body: &Value,
+        idempotency_key: &str,
+    ) -> Result<()> {
+        let body = serde_json::to_vec(body)?;
+        let mut request = client
+            .get_http_client()
+            .post(self.url.clone())
+            .header("Content-Type", "application/json")
+            .header("Idempotency-Key", idempotency_key);
+        if let Some(signature) = self.signature(&body) {
+            request = request.header("X-Dragonfly-Signature", signature);
+        }
+        request.body(body).send()?.error_for_status()?;
+
+        Ok(())
+    }
+}
+
+/// Results sent to a primary sink, and archived to the others, see the module docs
+pub struct Fanout {
+    primary: Box<dyn ResultSink>,
+    archives: Vec<Box<dyn ResultSink>>,
+}
+
+impl ResultSink for Fanout {
+    fn name(&self) -> &'static str {
+        self.primary.name()
+    }
+
+    fn send(
+        &mut self,
+        client: &mut DragonflyClient,
+        body: &Value,
+        idempotency_key: &str,
+    ) -> Result<()> {
+        self.primary.send(client, body, idempotency_key)?;
+
+        for archive in &mut self.archives {
+            if let Err(err) = archive.send(client, body, idempotency_key) {
+                error!("Failed to archive result to {}: {err}", archive.name());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{File, Webhook};
+    use crate::app_config::AppConfig;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    #[test]
+    fn appends_results_to_files() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("archive/results.jsonl");
+
+        let sink = File::new(&path);
+        sink.append(&json!({"name": "a"})).unwrap();
+        sink.append(&json!({"name": "b"})).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "{\"name\":\"a\"}\n{\"name\":\"b\"}\n"
+        );
+    }
+
+    #[test]
+    fn signs_webhook_bodies_with_the_secret() {
+        let mut config = AppConfig {
+            webhook_url: Some(String::from("https://example.com/hook")),
+            ..AppConfig::default()
+        };
+        assert!(Webhook::from_config(&config)
+            .unwrap()
+            .signature(b"{}")
+            .is_none());
+
+        config.webhook_secret = Some(String::from("key"));
+        // the HMAC-SHA256 example from Wikipedia
+        assert_eq!(
+            Webhook::from_config(&config)
+                .unwrap()
+                .signature(b"The quick brown fox jumps over the lazy dog")
+                .unwrap(),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+        assert!(Webhook::from_config(&AppConfig::default()).is_err());
+    }
+}
@@ -0,0 +1,250 @@
+//! Uploading results to an S3 compatible bucket.
+//!
+//! Requests are signed with AWS Signature Version 4 and use path-style URLs
+//! (`{endpoint}/{bucket}/{key}`), which AWS as well as self-hosted implementations like `MinIO`
+//! accept. Each result is uploaded to
+//! `{s3_prefix}{name}/{version}/{timestamp}.json`, so results of rescans don't overwrite each
+//! other.
+
+use std::fmt::Write as _;
+
+use chrono::{DateTime, Utc};
+use color_eyre::{eyre::eyre, Result};
+use hmac::{Hmac, Mac};
+use reqwest::{blocking::Client, Url};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use super::ResultSink;
+use crate::{app_config::AppConfig, client::DragonflyClient};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Results uploaded to an S3 bucket, one object per result
+pub struct S3 {
+    endpoint: Url,
+    bucket: String,
+    region: String,
+    prefix: String,
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+/// The key requests made on `date` (`YYYYMMDD`) are signed with
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac(format!("AWS4{secret_access_key}").as_bytes(), date);
+    let key = hmac(&key, region);
+    let key = hmac(&key, service);
+    hmac(&key, "aws4_request")
+}
+
+/// Percent-encode an object key for the canonical request, keeping the `/` separators
+fn encode_key(key: &str) -> String {
+    key.bytes().fold(String::new(), |mut encoded, byte| {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~' | b'/') {
+            encoded.push(char::from(byte));
+        } else {
+            let _ = write!(encoded, "%{byte:02X}");
+        }
+        encoded
+    })
+}
+
+impl S3 {
+    pub fn from_config(config: &AppConfig) -> Result<Self> {
+        let missing = |name: &str| eyre!("{name} must be set to use the s3 result sink");
+        let endpoint = match &config.s3_endpoint {
+            Some(endpoint) => endpoint.parse()?,
+            None => format!("https://s3.{}.amazonaws.com", config.s3_region).parse()?,
+        };
+
+        Ok(Self {
+            endpoint,
+            bucket: config
+                .s3_bucket
+                .clone()
+                .ok_or_else(|| missing("s3_bucket"))?,
+            region: config.s3_region.clone(),
+            prefix: config.s3_prefix.clone(),
+            access_key_id: config
+                .s3_access_key_id
+                .clone()
+                .ok_or_else(|| missing("s3_access_key_id"))?,
+            secret_access_key: config
+                .s3_secret_access_key
+                .clone()
+                .ok_or_else(|| missing("s3_secret_access_key"))?,
+        })
+    }
+
+    /// The object key a result is uploaded to
+    fn key_for(&self, body: &Value, now: DateTime<Utc>) -> String {
+        let field = |name: &str| body[name].as_str().unwrap_or("unknown").replace('/', "_");
+        format!(
+            "{}{}/{}/{}.json",
+            self.prefix,
+            field("name"),
+            field("version"),
+            now.format("%Y%m%dT%H%M%S%.3fZ")
+        )
+    }
+
+    /// Where the object `key` is stored, as an `s3://{bucket}/{key}` URI
+    pub fn reference(&self, key: &str) -> String {
+        format!("s3://{}/{key}", self.bucket)
+    }
+
+    /// Upload `contents` of type `content_type` to the object `key`, signing the request as of
+    /// `now`
+    pub fn put(
+        &self,
+        http_client: &Client,
+        key: &str,
+        contents: Vec<u8>,
+        content_type: &str,
+        now: DateTime<Utc>,
+    ) -> Result<()> {
+        let path = format!("/{}/{}", self.bucket, encode_key(key));
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_owned(),
+            (None, _) => return Err(eyre!("{} has no host", self.endpoint)),
+        };
+
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex(&Sha256::digest(&contents));
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "PUT\n{path}\n\nhost:{host}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{timestamp}\n\n{signed_headers}\n{payload_hash}"
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signature = hex(&hmac(
+            &signing_key(&self.secret_access_key, &date, &self.region, "s3"),
+            &string_to_sign,
+        ));
+
+        http_client
+            .put(url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", timestamp)
+            .header(
+                "Authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                    self.access_key_id
+                ),
+            )
+            .header("Content-Type", content_type)
+            .body(contents)
+            .send()?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+impl ResultSink for S3 {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    fn send(
+        &mut self,
+        client: &mut DragonflyClient,
+        body: &Value,
+        _idempotency_key: &str,
+    ) -> Result<()> {
+        let now = Utc::now();
+        self.put(
+            client.get_http_client(),
+            &self.key_for(body, now),
+            serde_json::to_vec(body)?,
+            "application/json",
+            now,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_key, hex, signing_key, S3};
+    use crate::{
+        app_config::AppConfig,
+        server::{serve, Response},
+    };
+    use chrono::{TimeZone, Utc};
+    use reqwest::blocking::Client;
+    use serde_json::json;
+
+    #[test]
+    fn derives_signing_keys() {
+        // the example from the AWS documentation on deriving signing keys
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex(&key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+        assert_eq!(encode_key("a b/c+d.json"), "a%20b/c%2Bd.json");
+    }
+
+    #[test]
+    fn uploads_results_to_path_style_urls() {
+        let addr = serve("127.0.0.1:0", |request| {
+            if request.method == "PUT"
+                && request.path == "/results/dragonfly/pkg/1.0/20240501T120000.000Z.json"
+            {
+                Response::text(200, "")
+            } else {
+                Response::not_found()
+            }
+        })
+        .unwrap();
+        let config = AppConfig {
+            s3_endpoint: Some(format!("http://{addr}")),
+            s3_bucket: Some(String::from("results")),
+            s3_prefix: String::from("dragonfly/"),
+            s3_access_key_id: Some(String::from("AKIDEXAMPLE")),
+            s3_secret_access_key: Some(String::from("secret")),
+            ..AppConfig::default()
+        };
+        let sink = S3::from_config(&config).unwrap();
+        assert!(S3::from_config(&AppConfig::default()).is_err());
+
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let key = sink.key_for(&json!({"name": "pkg", "version": "1.0"}), now);
+        sink.put(
+            &Client::new(),
+            &key,
+            b"{}".to_vec(),
+            "application/json",
+            now,
+        )
+        .unwrap();
+    }
+}
@@ -1,74 +1,478 @@
+mod cache;
+mod canary;
+mod confidence;
+mod correlation;
+mod dedup;
+mod embedded;
+mod filter;
+mod fuzzy;
+mod iocs;
+mod lint;
+mod matching;
+mod pipeline;
+mod profiling;
+mod selection;
+mod shadow;
+mod snippets;
+mod telemetry;
+mod validation;
+mod verdict;
+mod wheel;
+
+use std::fmt::Write as _;
+use std::io::{Cursor, Read, Seek};
 use std::path::PathBuf;
-use std::{collections::HashSet, path::Path};
+use std::time::Instant;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    path::Path,
+    sync::Arc,
+};
 
-use color_eyre::Result;
+use color_eyre::{eyre::eyre, Result};
 use reqwest::{blocking::Client, Url};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use tempfile::TempDir;
-use walkdir::WalkDir;
+use tracing::{debug, warn};
+use walkdir::{DirEntry, WalkDir};
 use yara::Rules;
 
+pub use canary::{
+    bundle as canary_bundle, self_test as canary_self_test, NAMESPACE as CANARY_NAMESPACE,
+};
+use correlation::Digests;
+use dedup::Duplicates;
+use filter::{within_size_gate, FileTypes, Filter, IgnoreList};
+pub use iocs::Ioc;
+use iocs::Iocs;
+pub use lint::lint as lint_rules;
+use matching::Outcome;
+pub use pipeline::files_in_flight;
+pub use profiling::{profile_ruleset, report_hot_rules, HotRule};
+use selection::Selection;
+pub use selection::{report_unknown_rules, unknown_rules};
+pub use shadow::Shadow;
+pub use snippets::Snippet;
+use telemetry::Measurements;
+pub use telemetry::Telemetry;
+pub use validation::{report_missing_metadata, validate as validate_metadata};
+pub use verdict::Verdict;
+use wheel::Contents;
+
 use crate::{
-    client::{download_distribution, Job, SubmitJobResultsSuccess},
+    analyzers::{self, Finding, FindingKind},
+    app_config::{OversizedFilePolicy, ScoringStrategy, TwoPassFallback},
+    build_info::BUILD_INFO,
+    client::{
+        bundles::DEFAULT_NAMESPACE, download_distribution, FileResultPart, Job, RulesState,
+        SubmitJobResultsSuccess,
+    },
+    deadline::Deadline,
+    events::{self, Event},
+    extract::{self, ArchiveKind},
     exts::RuleExt,
+    host, pypi,
+    quarantine::{self, Artifact, Excerpt},
+    typosquat,
     utils::create_inspector_url,
+    APP_CONFIG,
 };
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
 pub struct RuleScore {
     pub name: String,
     pub score: i64,
+
+    /// The verdict a match of this rule implies on its own, from the `severity` metadata
+    pub severity: Option<Verdict>,
 }
 
-/// The results of scanning a single file. Contains the file path and the rules it matched
-#[derive(Debug)]
+impl RuleScore {
+    /// The namespace of the rule: the namespace of its bundle, see [`crate::client::bundles`]. The
+    /// names of rules of bundles are `namespace:identifier`.
+    pub fn namespace(&self) -> &str {
+        self.name
+            .split_once(':')
+            .map_or(DEFAULT_NAMESPACE, |(namespace, _)| namespace)
+    }
+}
+
+/// The results of scanning a single file. Contains the file path, its hashes and the rules it
+/// matched
+#[derive(Debug, Clone)]
 pub struct FileScanResult {
     pub path: PathBuf,
     pub rules: Vec<RuleScore>,
+
+    /// The hex encoded SHA-256 hash of the file
+    pub sha256: String,
+
+    /// The ssdeep fuzzy hash of the file, only with `fuzzy_hashes` and if it matched any rule
+    pub ssdeep: Option<String>,
+
+    /// The context of the strings the rules matched, see `match_context`
+    pub snippets: Vec<Snippet>,
 }
 
 impl FileScanResult {
-    fn new(path: PathBuf, rules: Vec<RuleScore>) -> Self {
-        Self { path, rules }
+    fn new(path: PathBuf, rules: Vec<RuleScore>, digest: &[u8]) -> Self {
+        let sha256 = digest
+            .iter()
+            .fold(String::with_capacity(64), |mut hex, byte| {
+                let _ = write!(hex, "{byte:02x}");
+                hex
+            });
+
+        Self {
+            path,
+            rules,
+            sha256,
+            ssdeep: None,
+            snippets: Vec::new(),
+        }
+    }
+
+    /// Compute the fuzzy hash of the file from its `contents`, if enabled and the file matched
+    fn with_fuzzy_hash(mut self, contents: &[u8]) -> Self {
+        if APP_CONFIG.load().fuzzy_hashes && !self.rules.is_empty() {
+            self.ssdeep = Some(fuzzy::ssdeep(contents));
+        }
+        self
+    }
+
+    fn with_snippets(mut self, snippets: Vec<Snippet>) -> Self {
+        self.snippets = snippets;
+        self
     }
 
     /// Returns the total score of all matched rules.
     fn calculate_score(&self) -> i64 {
         self.rules.iter().map(|i| i.score).sum()
     }
+
+    /// The multiplier of the scores of matches in this file, from the extension of the file.
+    /// Units nested inside a file (`config!locator`) take the extension of the file containing
+    /// them.
+    fn filetype_weight(&self, weights: &HashMap<String, f64>) -> f64 {
+        let path = self.path.to_string_lossy();
+        let outer = path
+            .split_once('!')
+            .map_or(path.as_ref(), |(outer, _)| outer);
+        Path::new(outer)
+            .extension()
+            .and_then(|ext| weights.get(&ext.to_string_lossy().to_ascii_lowercase()))
+            .copied()
+            .unwrap_or(1.0)
+    }
 }
 
-/// A distribution consisting of an archive and an inspector url.
-struct Distribution {
-    dir: TempDir,
-    inspector_url: Url,
+/// A file that was larger than the `max_file_size` limit
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OversizedFile {
+    /// The file name of the distribution containing the file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distribution: Option<String>,
+
+    /// The path of the file, relative to the distribution's archive root
+    pub path: String,
+
+    /// The size of the file, in bytes
+    pub size: u64,
+
+    /// Whether the start of the file was scanned, instead of skipping it entirely
+    pub truncated: bool,
 }
 
-impl Distribution {
-    fn scan(&mut self, rules: &Rules) -> Result<DistributionScanResults> {
-        let mut file_scan_results: Vec<FileScanResult> = Vec::new();
-        for entry in WalkDir::new(self.dir.path())
-            .into_iter()
-            .filter_map(|dirent| dirent.into_iter().find(|de| de.file_type().is_file()))
-        {
-            let file_scan_result = self.scan_file(entry.path(), rules)?;
-            file_scan_results.push(file_scan_result);
+/// A file that couldn't be read or scanned, and was left out of the results
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SkippedFile {
+    /// The file name of the distribution containing the file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distribution: Option<String>,
+
+    /// The path of the file, relative to the distribution's archive root
+    pub path: String,
+
+    /// Why the file couldn't be scanned
+    pub reason: String,
+}
+
+/// A distribution that wasn't scanned completely because it has more than
+/// `max_files_per_distribution` files
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PartialScan {
+    /// The file name of the distribution
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distribution: Option<String>,
+
+    /// How many files were scanned, nested ones included
+    pub scanned_files: usize,
+
+    /// How many files were left out once the limit was reached
+    pub unscanned_files: usize,
+}
+
+/// Accumulates the results of scanning the files of a single distribution, wherever they're read
+/// from.
+struct DistributionScan<'a> {
+    rules: &'a Rules,
+    filter: Filter,
+
+    /// The size of the largest files the rules are evaluated against, see [`filter::size_gate`]
+    size_gate: Option<u64>,
+    selection: Selection,
+    file_scan_results: Vec<FileScanResult>,
+    findings: Vec<Finding>,
+    skipped_files: usize,
+    unscanned_files: usize,
+    oversized_files: Vec<OversizedFile>,
+    files_skipped: Vec<SkippedFile>,
+    digests: Digests,
+    wheel: Contents,
+    iocs: Iocs,
+    measurements: Measurements,
+    quarantined: Vec<Excerpt>,
+    snippet_limits: snippets::Limits,
+    match_settings: matching::Settings,
+
+    /// How many bytes of match data may still be reported, see [`snippets`]
+    match_data_budget: usize,
+
+    /// How many files may be quarantined, 0 without a `quarantine_target`
+    max_quarantined_files: usize,
+    max_file_size: u64,
+    oversized_file_policy: OversizedFilePolicy,
+
+    /// How many files may be scanned, nested ones included, 0 for no limit
+    max_files: usize,
+
+    /// How many archives deep the file being scanned is, 0 for files of the distribution itself
+    depth: usize,
+    max_archive_depth: usize,
+
+    /// The amount of bytes that may still be read out of nested archives
+    nested_budget: u64,
+
+    /// Checked before every file is scanned
+    deadline: Deadline,
+
+    /// Whether files are matched against the rules, or only analyzed, see [`filter::FileTypes`]
+    match_rules: bool,
+
+    /// The files matched earlier in the job, with `deduplicate_files`, see [`dedup`]
+    duplicates: Option<&'a Duplicates>,
+
+    /// The ruleset compiled with yara-x, with `shadow_scan`, see [`shadow`]
+    shadow: Option<&'a Shadow>,
+}
+
+impl<'a> DistributionScan<'a> {
+    fn new(rules: &'a Rules) -> Self {
+        Self {
+            rules,
+            filter: Filter::from_config(),
+            size_gate: filter::size_gate(rules),
+            selection: Selection::from_config(),
+            file_scan_results: Vec::new(),
+            findings: Vec::new(),
+            skipped_files: 0,
+            unscanned_files: 0,
+            oversized_files: Vec::new(),
+            files_skipped: Vec::new(),
+            digests: Digests::new(),
+            wheel: Contents::default(),
+            iocs: Iocs::new(APP_CONFIG.load().max_iocs),
+            measurements: Measurements::default(),
+            quarantined: Vec::new(),
+            snippet_limits: snippets::Limits::from_config(),
+            match_settings: matching::Settings::from_config(),
+            match_data_budget: APP_CONFIG.load().match_data_budget,
+            max_quarantined_files: if quarantine::enabled() {
+                APP_CONFIG.load().quarantine_max_files
+            } else {
+                0
+            },
+            max_file_size: APP_CONFIG.load().max_file_size,
+            oversized_file_policy: APP_CONFIG.load().oversized_file_policy,
+            max_files: APP_CONFIG.load().max_files_per_distribution,
+            depth: 0,
+            max_archive_depth: APP_CONFIG.load().max_archive_depth,
+            nested_budget: APP_CONFIG.load().max_nested_extracted_size,
+            deadline: Deadline::none(),
+            match_rules: true,
+            duplicates: None,
+            shadow: None,
         }
+    }
 
-        Ok(DistributionScanResults::new(
-            file_scan_results,
-            self.inspector_url.clone(),
-        ))
+    /// Read a file of the distribution from `reader` and scan it.
+    ///
+    /// Files larger than `max_file_size` are recorded, and then either skipped or only scanned up
+    /// to the limit depending on the configured [`OversizedFilePolicy`].
+    ///
+    /// # Arguments
+    /// * `path` - The path of the file, relative to the archive root
+    /// * `size` - The size of the file, in bytes
+    /// * `reader` - The contents of the file
+    fn scan_reader(&mut self, path: &Path, size: u64, reader: impl Read) -> Result<()> {
+        if !self.admit(path, size)? {
+            return Ok(());
+        }
+
+        let mut contents = Vec::new();
+        reader.take(self.max_file_size).read_to_end(&mut contents)?;
+        self.scan_file(path, &contents)
+    }
+
+    /// Open the extracted file at `path` and scan it as `relative`, see [`Self::scan_reader`]
+    fn scan_path(&mut self, path: &Path, relative: &Path) -> Result<()> {
+        let file = std::fs::File::open(path)?;
+        let size = file.metadata()?.len();
+        self.scan_reader(relative, size, file)
     }
 
-    /// Scan a file given it's path, and compiled rules.
+    /// Record that the file at `path` (relative to the archive root) couldn't be scanned because
+    /// of `err`, and carry on with the others. Errors past the deadline are returned instead.
+    fn skip_failed(&mut self, path: &Path, err: color_eyre::Report) -> Result<()> {
+        if self.deadline.is_expired() {
+            return Err(err);
+        }
+        warn!("Failed to scan {}, skipping it: {err:#}", path.display());
+        self.files_skipped.push(SkippedFile {
+            distribution: None,
+            path: path.to_string_lossy().into_owned(),
+            reason: format!("{err:#}"),
+        });
+        Ok(())
+    }
+
+    /// Whether the file at `path` of `size` bytes should be read and scanned, recording it if it's
+    /// oversized or left out. Fails once the deadline passed.
+    fn admit(&mut self, path: &Path, size: u64) -> Result<bool> {
+        self.deadline.check()?;
+        if self.is_full() {
+            self.leave_out(path);
+            return Ok(false);
+        }
+        if size > self.max_file_size {
+            let truncated = self.oversized_file_policy == OversizedFilePolicy::Truncate;
+            self.oversized_files.push(OversizedFile {
+                distribution: None,
+                path: path.to_string_lossy().into_owned(),
+                size,
+                truncated,
+            });
+
+            if !truncated {
+                return Ok(false);
+            }
+            if self.depth == 0 {
+                self.wheel.truncated(path);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Whether any file matched a rule so far
+    fn has_matches(&self) -> bool {
+        self.file_scan_results
+            .iter()
+            .any(|file| !file.rules.is_empty())
+    }
+
+    /// Whether `max_files` files were scanned already, so the others are left out
+    fn is_full(&self) -> bool {
+        self.max_files > 0 && self.measurements.files >= self.max_files
+    }
+
+    /// Count the file at `path` as left out, see [`DistributionScan::is_full`]
+    fn leave_out(&mut self, path: &Path) {
+        if self.unscanned_files == 0 {
+            warn!(
+                "Scanned {} files already, leaving out {} and the rest of the distribution",
+                self.measurements.files,
+                path.display()
+            );
+        }
+        self.unscanned_files += 1;
+    }
+
+    /// Scan a single file of the distribution.
+    ///
+    /// Files rejected by the [`Filter`] or larger than the `max_filesize` of every rule, and every
+    /// file while `match_rules` is unset, aren't matched against the rules, only analyzed. Matches
+    /// of rules left out by the [`Selection`], or gated by a smaller `max_filesize`, are dropped.
     ///
     /// # Arguments
-    /// * `path` - The path of the file to scan.
-    /// * `rules` - The compiled rule set to scan this file against
-    fn scan_file(&self, path: &Path, rules: &Rules) -> Result<FileScanResult> {
-        let rules = rules
-            .scan_file(path, 10)?
+    /// * `path` - The path of the file, relative to the archive root
+    /// * `contents` - The raw contents of the file
+    fn scan_file(&mut self, path: &Path, contents: &[u8]) -> Result<()> {
+        if let Some(duplicate) = self.duplicate_of(path, contents) {
+            return self.record_duplicate(path, contents, &duplicate);
+        }
+
+        let matches = match_file(
+            self.rules,
+            &self.filter,
+            self.size_gate,
+            self.match_rules,
+            self.match_settings,
+            path,
+            contents,
+        )?;
+        self.record_file(path, contents, matches)
+    }
+
+    /// The results of a file with the same path and `contents` matched earlier in the job, while
+    /// files are matched against the rules, see [`dedup`]
+    fn duplicate_of(&self, path: &Path, contents: &[u8]) -> Option<Arc<dedup::Entry>> {
+        if !self.match_rules {
+            return None;
+        }
+        self.duplicates?.get(path, contents)
+    }
+
+    /// Record a file scanned by [`match_file`], see [`DistributionScan::scan_file`]
+    fn record_file(
+        &mut self,
+        path: &Path,
+        contents: &[u8],
+        matches: Option<Outcome<'a>>,
+    ) -> Result<()> {
+        let digest = self.record_contents(path, contents);
+
+        let timed_out = matches!(matches, Some(Outcome::TimedOut));
+        let matches = match matches {
+            None => {
+                self.skipped_files += 1;
+                return Ok(());
+            }
+            Some(Outcome::Matched(matches)) => matches,
+            Some(Outcome::TimedOut) => {
+                self.timed_out(path);
+                Vec::new()
+            }
+        };
+        if let Some(difference) = self
+            .shadow
+            .filter(|_| !timed_out)
+            .and_then(|shadow| shadow.compare(contents, &matches))
+        {
+            warn!(
+                "yara-x disagrees with yara on {}: only yara matched [{}], only yara-x matched [{}], scoring {} and {}",
+                path.display(),
+                difference.only_yara.join(", "),
+                difference.only_yara_x.join(", "),
+                difference.yara_score,
+                difference.yara_x_score
+            );
+        }
+        let matches = matches
             .into_iter()
+            .filter(|rule| self.selection.keeps(rule.identifier))
+            .filter(|rule| within_size_gate(rule, contents.len()))
             .filter(|rule| {
                 let filetypes = rule.get_filetypes();
                 filetypes.is_empty()
@@ -76,41 +480,597 @@ impl Distribution {
                         .iter()
                         .any(|filetype| path.to_string_lossy().ends_with(filetype))
             })
-            .map(RuleScore::from)
-            .collect();
+            .collect::<Vec<_>>();
+
+        let quarantine_rules = matches
+            .iter()
+            .filter(|rule| rule.quarantines())
+            .map(|rule| rule.identifier.to_owned())
+            .collect::<Vec<_>>();
+        if !quarantine_rules.is_empty() {
+            self.quarantine(path, quarantine_rules.clone(), contents);
+        }
+
+        let snippets = snippets::capture(
+            &matches,
+            contents,
+            self.snippet_limits,
+            &mut self.match_data_budget,
+        );
+        let rules = matches.into_iter().map(RuleScore::from).collect();
+        let result = FileScanResult::new(path.to_path_buf(), rules, &digest)
+            .with_fuzzy_hash(contents)
+            .with_snippets(snippets);
+        // a file that timed out could still match, so its duplicates are matched again
+        if let Some(duplicates) = self.duplicates.filter(|_| !timed_out) {
+            duplicates.insert(
+                path,
+                digest,
+                dedup::Entry {
+                    result: result.clone(),
+                    quarantine_rules,
+                },
+            );
+        }
+        self.file_scan_results.push(result);
+
+        self.scan_contents(path, contents)
+    }
+
+    /// Record a file whose `duplicate` was matched earlier in the job, reusing its results
+    fn record_duplicate(
+        &mut self,
+        path: &Path,
+        contents: &[u8],
+        duplicate: &dedup::Entry,
+    ) -> Result<()> {
+        self.record_contents(path, contents);
+        self.measurements.duplicate_files += 1;
+        if !duplicate.quarantine_rules.is_empty() {
+            self.quarantine(path, duplicate.quarantine_rules.clone(), contents);
+        }
+        self.file_scan_results.push(duplicate.result.clone());
+
+        self.scan_contents(path, contents)
+    }
+
+    /// Analyze a file and count it, whether it's matched against the rules or not. Returns the
+    /// SHA-256 digest of its `contents`.
+    fn record_contents(&mut self, path: &Path, contents: &[u8]) -> [u8; 32] {
+        self.findings
+            .extend(analyzers::analyze_file(path, contents));
+        self.iocs.add(path, contents);
+        self.measurements.files += 1;
+        self.measurements.bytes += contents.len() as u64;
+        // nested modules aren't installed, so there's nothing to correlate them with
+        if self.depth == 0 {
+            let digest = self.wheel.add(path, contents);
+            self.digests.insert(path.to_path_buf(), digest);
+            digest
+        } else {
+            Sha256::digest(contents).into()
+        }
+    }
+
+    /// Scan the scripts embedded in the file at `path` and the files of the archive it is, if
+    /// it's either
+    fn scan_contents(&mut self, path: &Path, contents: &[u8]) -> Result<()> {
+        if embedded::is_config_file(path) {
+            self.scan_embedded_scripts(path, contents)?;
+        }
+
+        if let Some(kind) = ArchiveKind::from_extension(&path.to_string_lossy()) {
+            self.scan_nested_archive(path, kind, contents);
+        }
+
+        Ok(())
+    }
+
+    /// Report that matching the file at `path` against the rules took too long, see [`matching`]
+    fn timed_out(&mut self, path: &Path) {
+        warn!(
+            "Matching {} took longer than {}s, leaving it unmatched",
+            path.display(),
+            self.match_settings.timeout
+        );
+        self.findings.push(Finding::new(
+            path,
+            FindingKind::ScanTimeout {
+                timeout_secs: self.match_settings.timeout,
+            },
+        ));
+    }
+
+    /// Keep the start of a file that matched the quarantine `rules`, see [`crate::quarantine`]
+    fn quarantine(&mut self, path: &Path, rules: Vec<String>, contents: &[u8]) {
+        if self.quarantined.len() >= self.max_quarantined_files {
+            if self.max_quarantined_files > 0 {
+                debug!(
+                    "Not quarantining {}, already kept {} files of this distribution",
+                    path.display(),
+                    self.quarantined.len()
+                );
+            }
+            return;
+        }
 
-        Ok(FileScanResult::new(
-            self.relative_to_archive_root(path)?,
+        self.quarantined.push(Excerpt::new(
+            path.to_string_lossy().into_owned(),
             rules,
-        ))
+            contents,
+            APP_CONFIG.load().quarantine_max_size,
+        ));
+    }
+
+    /// Scan the files of an archive found inside the distribution, such as a zip file bundled in
+    /// an sdist.
+    ///
+    /// Each file is reported with a path of the form `path/to/outer.zip!inner/file.py`, and
+    /// archives inside it are scanned in turn up to `max_archive_depth` levels deep. All nested
+    /// archives of the distribution share a budget of `max_nested_extracted_size` bytes. A nested
+    /// archive that can't be read or goes over the budget is only scanned as far as it got, it
+    /// doesn't fail the whole distribution.
+    fn scan_nested_archive(&mut self, path: &Path, kind: ArchiveKind, contents: &[u8]) {
+        if self.depth >= self.max_archive_depth {
+            debug!(
+                "Not scanning {}, nested more than {} archives deep",
+                path.display(),
+                self.max_archive_depth
+            );
+            return;
+        }
+
+        let limits = extract::Limits {
+            max_entries: APP_CONFIG.load().max_archive_entries,
+            max_total_size: self.nested_budget,
+        };
+
+        self.depth += 1;
+        let result = extract::for_each_file_in(
+            Cursor::new(contents),
+            kind,
+            limits,
+            |inner, size, reader| {
+                if self.nested_budget == 0 {
+                    return Err(eyre!("nested archive size budget exhausted"));
+                }
+
+                let mut unit_path = path.as_os_str().to_owned();
+                unit_path.push("!");
+                unit_path.push(inner);
+
+                let mut counted = reader.take(u64::MAX);
+                let result = self.scan_reader(Path::new(&unit_path), size, &mut counted);
+                self.nested_budget = self
+                    .nested_budget
+                    .saturating_sub(u64::MAX - counted.limit());
+                result
+            },
+        );
+        self.depth -= 1;
+
+        if let Err(err) = result {
+            warn!("Stopped scanning nested archive {}: {err}", path.display());
+        }
+    }
+
+    /// Scan the inline scripts of a packaging or CI config file as standalone units.
+    ///
+    /// Each script is reported as its own [`FileScanResult`] with a path of the form
+    /// `path/to/config!locator`. The language of an embedded script can't be inferred from an
+    /// extension, so it's evaluated against every rule regardless of `filetype` metadata.
+    fn scan_embedded_scripts(&mut self, path: &Path, contents: &[u8]) -> Result<()> {
+        let contents = String::from_utf8_lossy(contents);
+
+        for script in embedded::extract_scripts(path, &contents) {
+            let source = script.source.as_bytes();
+            let mut unit_path = path.to_path_buf().into_os_string();
+            unit_path.push(format!("!{}", script.locator));
+            let unit_path = PathBuf::from(unit_path);
+
+            let matches = match matching::scan(self.rules, source, self.match_settings)? {
+                Outcome::Matched(matches) => matches,
+                Outcome::TimedOut => {
+                    self.timed_out(&unit_path);
+                    Vec::new()
+                }
+            };
+            let matches = matches
+                .into_iter()
+                .filter(|rule| self.selection.keeps(rule.identifier))
+                .filter(|rule| within_size_gate(rule, source.len()))
+                .collect::<Vec<_>>();
+            let snippets = snippets::capture(
+                &matches,
+                source,
+                self.snippet_limits,
+                &mut self.match_data_budget,
+            );
+            let rules = matches.into_iter().map(RuleScore::from).collect();
+
+            let digest = Sha256::digest(source);
+            self.file_scan_results.push(
+                FileScanResult::new(unit_path, rules, &digest)
+                    .with_fuzzy_hash(source)
+                    .with_snippets(snippets),
+            );
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self, inspector_url: Url) -> DistributionScanResults {
+        let inconsistencies = self.wheel.check(&self.findings);
+        self.findings.extend(inconsistencies);
+        let mut results =
+            DistributionScanResults::new(self.file_scan_results, self.findings, inspector_url);
+        results.skipped_files = self.skipped_files;
+        results.partial_scan = (self.unscanned_files > 0).then_some(PartialScan {
+            distribution: None,
+            scanned_files: self.measurements.files,
+            unscanned_files: self.unscanned_files,
+        });
+        results.oversized_files = self.oversized_files;
+        results.files_skipped = self.files_skipped;
+        results.digests = self.digests;
+        results.iocs = self.iocs.into_vec();
+        results.measurements = self.measurements;
+        results.quarantined = self.quarantined;
+        results
+    }
+}
+
+/// The matches of `rules` in the file at `path`, or `None` if it's skipped: because the
+/// [`Filter`] rejects it, because it's larger than the `size_gate`, or because files are only
+/// analyzed, see [`DistributionScan::scan_file`].
+fn match_file<'r>(
+    rules: &'r Rules,
+    filter: &Filter,
+    size_gate: Option<u64>,
+    match_rules: bool,
+    settings: matching::Settings,
+    path: &Path,
+    contents: &[u8],
+) -> Result<Option<Outcome<'r>>> {
+    let gated = size_gate.is_some_and(|max| contents.len() as u64 > max);
+    if !match_rules || gated || filter.skips(path, contents) {
+        return Ok(None);
+    }
+    profiling::sample(path, contents);
+
+    Ok(Some(matching::scan(rules, contents, settings)?))
+}
+
+/// What [`Distribution::walk`] decided to do with an extracted file
+enum Walked {
+    Scan(PathBuf),
+    Defer(PathBuf),
+    Ignore,
+}
+
+/// A distribution consisting of an archive and an inspector url.
+struct Distribution {
+    dir: TempDir,
+    inspector_url: Url,
+}
+
+impl Distribution {
+    /// Scan every file of the distribution. With `two_pass_scan`, the files of the types the rules
+    /// are restricted to are scanned first, see [`filter::FileTypes`]. With more than one
+    /// `scan_threads`, they're scanned by a [`pipeline`].
+    ///
+    /// Files that can't be read or scanned are recorded as skipped, and only fail the scan if none
+    /// of the files could be scanned at all. Files matched earlier in the job are looked up in
+    /// `duplicates`, see [`dedup`], and files matched against the rules are also matched with the
+    /// `shadow` ruleset, see [`shadow`].
+    fn scan(
+        &self,
+        rules: &Rules,
+        deadline: Deadline,
+        duplicates: Option<&Duplicates>,
+        shadow: Option<&Shadow>,
+    ) -> Result<DistributionScanResults> {
+        let mut scan = DistributionScan::new(rules);
+        scan.deadline = deadline;
+        scan.duplicates = duplicates;
+        scan.shadow = shadow;
+        let filetypes = APP_CONFIG
+            .load()
+            .two_pass_scan
+            .then(|| FileTypes::of(rules));
+        let ignored = IgnoreList::from_config();
+
+        let deferred = if APP_CONFIG.load().scan_threads > 1 {
+            pipeline::scan(
+                self,
+                &mut scan,
+                &ignored,
+                filetypes.as_ref(),
+                APP_CONFIG.load().scan_threads,
+            )?
+        } else {
+            let mut deferred = Vec::new();
+            for entry in self.files() {
+                match self.walk(&ignored, filetypes.as_ref(), entry)? {
+                    Walked::Scan(path) => self.scan_entry(&mut scan, &path)?,
+                    Walked::Defer(path) => deferred.push(path),
+                    Walked::Ignore => scan.measurements.ignored_files += 1,
+                }
+            }
+            deferred
+        };
+
+        if !deferred.is_empty() {
+            scan.match_rules = APP_CONFIG.load().two_pass_fallback == TwoPassFallback::Always
+                || !scan.has_matches();
+            if !scan.match_rules {
+                debug!(
+                    "Files of the rules' types matched, only analyzing the other {} files",
+                    deferred.len()
+                );
+            }
+            for path in deferred {
+                self.scan_entry(&mut scan, &path)?;
+            }
+        }
+
+        if scan.measurements.files == 0 {
+            if let Some(failed) = scan.files_skipped.first() {
+                return Err(eyre!(
+                    "None of the {} files of the distribution could be scanned, {} failed with: {}",
+                    scan.files_skipped.len(),
+                    failed.path,
+                    failed.reason
+                ));
+            }
+        }
+
+        Ok(scan.finish(self.inspector_url.clone()))
+    }
+
+    /// The extracted files of the distribution, in no particular order
+    fn files(&self) -> impl Iterator<Item = DirEntry> {
+        WalkDir::new(extract::long_path(self.dir.path()))
+            .into_iter()
+            .filter_map(|dirent| dirent.into_iter().find(|de| de.file_type().is_file()))
+    }
+
+    /// What to do with the extracted file `entry`: leave it out if it's `ignored`, and defer it
+    /// with `filetypes` if it isn't of one of them
+    fn walk(
+        &self,
+        ignored: &IgnoreList,
+        filetypes: Option<&FileTypes>,
+        entry: DirEntry,
+    ) -> Result<Walked> {
+        let path = self.relative_to_archive_root(entry.path())?;
+        if ignored.ignores(&path) {
+            return Ok(Walked::Ignore);
+        }
+
+        Ok(match filetypes {
+            Some(filetypes) if !filetypes.is_empty() && !filetypes.matches(&path) => {
+                Walked::Defer(entry.into_path())
+            }
+            _ => Walked::Scan(entry.into_path()),
+        })
+    }
+
+    /// Scan the extracted file at `path`, recording it as skipped if it can't be
+    fn scan_entry(&self, scan: &mut DistributionScan, path: &Path) -> Result<()> {
+        let relative = self.relative_to_archive_root(path)?;
+        // past the limit, don't even open the files
+        if scan.is_full() {
+            scan.leave_out(&relative);
+            return Ok(());
+        }
+
+        match scan.scan_path(path, &relative) {
+            Ok(()) => Ok(()),
+            Err(err) => scan.skip_failed(&relative, err),
+        }
     }
 
     /// Make the path relative to the archive root
     fn relative_to_archive_root(&self, path: &Path) -> Result<PathBuf> {
-        Ok(path.strip_prefix(self.dir.path())?.to_path_buf())
+        let relative = path.strip_prefix(extract::long_path(self.dir.path()))?;
+        Ok(extract::archive_path(relative))
     }
 }
 
+/// Scan a distribution archive held in memory (or any other seekable source) without extracting
+/// it to disk.
+///
+/// # Arguments
+/// * `reader` - The raw archive
+/// * `kind` - What kind of archive `reader` contains
+/// * `rules` - The compiled rule set to scan the files against
+/// * `inspector_url` - The base URL that links to the files of this distribution
+pub fn scan_archive<R: Read + Seek>(
+    reader: R,
+    kind: ArchiveKind,
+    rules: &Rules,
+    inspector_url: Url,
+) -> Result<DistributionScanResults> {
+    let mut scan = DistributionScan::new(rules);
+    let ignored = IgnoreList::from_config();
+    extract::for_each_file_in(
+        reader,
+        kind,
+        APP_CONFIG.load().extraction_limits(),
+        |path, size, file| {
+            if ignored.ignores(path) {
+                scan.measurements.ignored_files += 1;
+                return Ok(());
+            }
+            scan.scan_reader(path, size, file)
+        },
+    )?;
+
+    Ok(scan.finish(inspector_url))
+}
+
+/// Scan a distribution archive from a byte slice. See [`scan_archive`].
+pub fn scan_archive_bytes(
+    bytes: &[u8],
+    kind: ArchiveKind,
+    rules: &Rules,
+    inspector_url: Url,
+) -> Result<DistributionScanResults> {
+    scan_archive(Cursor::new(bytes), kind, rules, inspector_url)
+}
+
 /// Struct representing the results of a scanned distribution
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DistributionScanResults {
     /// The scan results for each file in this distribution
     file_scan_results: Vec<FileScanResult>,
 
-    /// The inspector URL pointing to this distribution's base
+    /// The findings of the heuristic analyzers for the files in this distribution
+    findings: Vec<Finding>,
+
+    /// The inspector URL pointing to this distribution's base. For distributions the inspector
+    /// can't show, it's their download URL instead, see `inspectable`.
     inspector_url: Url,
+
+    /// Whether `inspector_url` can be linked to, `false` for distributions not hosted on `PyPI`
+    inspectable: bool,
+
+    /// The amount of files that weren't matched against the rules, see [`filter::Filter`]
+    skipped_files: usize,
+
+    /// Set if not every file was scanned
+    partial_scan: Option<PartialScan>,
+
+    /// The files that were over the size limit
+    oversized_files: Vec<OversizedFile>,
+
+    /// The files that couldn't be read or scanned
+    files_skipped: Vec<SkippedFile>,
+
+    /// The digests of the files, for correlating distributions
+    digests: Digests,
+
+    /// The network indicators found in the files of this distribution
+    iocs: Vec<Ioc>,
+
+    /// What downloading and scanning this distribution took
+    measurements: Measurements,
+
+    /// The files matched by quarantine rules, until they're uploaded
+    quarantined: Vec<Excerpt>,
+
+    /// The quarantined files of this distribution, once uploaded
+    artifacts: Vec<Artifact>,
 }
 
 impl DistributionScanResults {
-    /// Create a new `DistributionScanResults` based off the results of its files and the base
-    /// inspector URL for this distribution.
-    pub fn new(file_scan_results: Vec<FileScanResult>, inspector_url: Url) -> Self {
+    /// Create a new `DistributionScanResults` based off the results of its files, the analyzer
+    /// findings, and the base inspector URL for this distribution.
+    pub fn new(
+        file_scan_results: Vec<FileScanResult>,
+        findings: Vec<Finding>,
+        inspector_url: Url,
+    ) -> Self {
         Self {
             file_scan_results,
+            findings,
             inspector_url,
+            inspectable: true,
+            skipped_files: 0,
+            partial_scan: None,
+            oversized_files: Vec::new(),
+            files_skipped: Vec::new(),
+            digests: Digests::new(),
+            iocs: Vec::new(),
+            measurements: Measurements::default(),
+            quarantined: Vec::new(),
+            artifacts: Vec::new(),
         }
     }
 
+    /// The amount of files that weren't matched against the rules because of their type
+    pub fn skipped_files(&self) -> usize {
+        self.skipped_files
+    }
+
+    /// The host this distribution was downloaded from, if the inspector can't show it
+    fn mirror_host(&self) -> Option<&str> {
+        (!self.inspectable).then(|| {
+            self.inspector_url
+                .host_str()
+                .unwrap_or(self.inspector_url.scheme())
+        })
+    }
+
+    /// The file name of this distribution, taken from the last segment of the inspector URL
+    fn file_name(&self) -> Option<&str> {
+        self.inspector_url
+            .path_segments()?
+            .filter(|segment| !segment.is_empty())
+            .last()
+    }
+
+    /// The results of every file of this distribution that matched at least one rule, in a form
+    /// that can be streamed to the API
+    pub fn file_result_parts(&self) -> impl Iterator<Item = FileResultPart<'_>> {
+        self.file_scan_results
+            .iter()
+            .filter(|file| !file.rules.is_empty())
+            .map(|file| FileResultPart {
+                distribution: self.file_name(),
+                path: file.path.to_string_lossy().into_owned(),
+                score: file.calculate_score(),
+                rules_matched: file.rules.iter().map(|rule| rule.name.as_str()).collect(),
+                sha256: &file.sha256,
+                ssdeep: file.ssdeep.as_deref(),
+                snippets: &file.snippets,
+            })
+    }
+
+    /// Get the analyzer findings of this distribution, tagged with the distribution's file name
+    pub fn get_findings(&self) -> impl Iterator<Item = Finding> + '_ {
+        self.findings.iter().cloned().map(|mut finding| {
+            finding.distribution = self.file_name().map(ToOwned::to_owned);
+            finding
+        })
+    }
+
+    /// Get the network indicators found in this distribution, tagged with the distribution's file
+    /// name
+    pub fn get_iocs(&self) -> impl Iterator<Item = Ioc> + '_ {
+        self.iocs.iter().cloned().map(|mut ioc| {
+            ioc.distribution = self.file_name().map(ToOwned::to_owned);
+            ioc
+        })
+    }
+
+    /// Get the files of this distribution that were over the size limit, tagged with the
+    /// distribution's file name
+    pub fn get_oversized_files(&self) -> impl Iterator<Item = OversizedFile> + '_ {
+        self.oversized_files.iter().cloned().map(|mut file| {
+            file.distribution = self.file_name().map(ToOwned::to_owned);
+            file
+        })
+    }
+
+    /// The files that couldn't be scanned, tagged with the distribution's file name
+    pub fn get_skipped_files(&self) -> impl Iterator<Item = SkippedFile> + '_ {
+        self.files_skipped.iter().cloned().map(|mut file| {
+            file.distribution = self.file_name().map(ToOwned::to_owned);
+            file
+        })
+    }
+
+    /// How this distribution was only scanned in part, if it had too many files, tagged with the
+    /// distribution's file name
+    pub fn get_partial_scan(&self) -> Option<PartialScan> {
+        self.partial_scan.clone().map(|mut partial_scan| {
+            partial_scan.distribution = self.file_name().map(ToOwned::to_owned);
+            partial_scan
+        })
+    }
+
     /// Get the "most malicious file" in the distribution.
     ///
     /// This file with the greatest score is considered the most malicious. If multiple
@@ -133,9 +1093,44 @@ impl DistributionScanResults {
         rules
     }
 
-    /// Calculate the total score of this distribution, without counting duplicates twice
+    /// Calculate the total score of this distribution, without counting duplicates twice. The
+    /// weights of the analyzer findings are included.
     pub fn get_total_score(&self) -> i64 {
-        self.get_matched_rules().iter().map(|rule| rule.score).sum()
+        self.get_matched_rules()
+            .iter()
+            .map(|rule| rule.score)
+            .sum::<i64>()
+            + self.get_findings_score()
+    }
+
+    /// The sum of the weights of the analyzer findings of this distribution
+    fn get_findings_score(&self) -> i64 {
+        self.findings
+            .iter()
+            .map(|finding| finding.kind.weight())
+            .sum()
+    }
+
+    /// Like [`DistributionScanResults::get_total_score`], with the score of every rule multiplied
+    /// by the weight of the type of the file it matched in. A rule that matched in several files
+    /// counts with the highest of those weights.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+    fn get_weighted_score(&self, weights: &HashMap<String, f64>) -> i64 {
+        let mut rules: HashMap<&RuleScore, f64> = HashMap::new();
+        for file in &self.file_scan_results {
+            let weight = file.filetype_weight(weights);
+            for rule in &file.rules {
+                let entry = rules.entry(rule).or_insert(weight);
+                *entry = entry.max(weight);
+            }
+        }
+
+        rules
+            .into_iter()
+            .map(|(rule, weight)| rule.score as f64 * weight)
+            .sum::<f64>()
+            .round() as i64
+            + self.get_findings_score()
     }
 
     /// Get a vector of the **unique** rule identifiers this distribution matched
@@ -147,14 +1142,21 @@ impl DistributionScanResults {
     }
 
     /// Return the inspector URL of the most malicious file, or `None` if there is no most malicious
-    /// file
+    /// file or the distribution isn't [inspectable](Self::inspectable)
+    ///
+    /// Units nested inside a file (such as embedded scripts, `config!locator`) link to the file
+    /// containing them.
     pub fn inspector_url(&self) -> Option<String> {
+        if !self.inspectable {
+            return None;
+        }
+
         self.get_most_malicious_file().map(|file| {
-            format!(
-                "{}{}",
-                self.inspector_url.as_str(),
-                file.path.to_string_lossy().as_ref()
-            )
+            let path = file.path.to_string_lossy();
+            let path = path
+                .split_once('!')
+                .map_or(path.as_ref(), |(outer, _)| outer);
+            format!("{}{}", self.inspector_url.as_str(), path)
         })
     }
 }
@@ -181,6 +1183,57 @@ impl PackageScanResults {
         }
     }
 
+    /// The score of the package, combining the scores of its distributions with `strategy`
+    fn score(&self, strategy: ScoringStrategy, filetype_weights: &HashMap<String, f64>) -> i64 {
+        let distributions = self.distribution_scan_results.iter();
+        match strategy {
+            ScoringStrategy::Max => distributions
+                .map(DistributionScanResults::get_total_score)
+                .max()
+                .unwrap_or_default(),
+            ScoringStrategy::SumUnique => {
+                let findings = distributions
+                    .clone()
+                    .map(DistributionScanResults::get_findings_score)
+                    .sum::<i64>();
+                distributions
+                    .flat_map(DistributionScanResults::get_matched_rules)
+                    .collect::<HashSet<_>>()
+                    .into_iter()
+                    .map(|rule| rule.score)
+                    .sum::<i64>()
+                    + findings
+            }
+            ScoringStrategy::Mean => {
+                let count = i64::try_from(self.distribution_scan_results.len()).unwrap_or(i64::MAX);
+                distributions
+                    .map(DistributionScanResults::get_total_score)
+                    .sum::<i64>()
+                    .checked_div(count)
+                    .unwrap_or_default()
+            }
+            ScoringStrategy::WeightedByFiletype => distributions
+                .map(|distribution| distribution.get_weighted_score(filetype_weights))
+                .max()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The sum of the scores of the rules matched in any distribution, by namespace, without
+    /// counting duplicates twice
+    fn namespace_scores(&self) -> BTreeMap<String, i64> {
+        let mut scores = BTreeMap::new();
+        for rule in self
+            .distribution_scan_results
+            .iter()
+            .flat_map(DistributionScanResults::get_matched_rules)
+            .collect::<HashSet<_>>()
+        {
+            *scores.entry(rule.namespace().to_owned()).or_default() += rule.score;
+        }
+        scores
+    }
+
     /// Format the package scan results into something that can be sent over the API
     pub fn build_body(&self) -> SubmitJobResultsSuccess {
         let highest_score_distribution = self
@@ -188,9 +1241,13 @@ impl PackageScanResults {
             .iter()
             .max_by_key(|distrib| distrib.get_total_score());
 
-        let score = highest_score_distribution
-            .map(DistributionScanResults::get_total_score)
-            .unwrap_or_default();
+        let typosquat_candidate = typosquat::candidate(&self.name);
+        let score = self.score(
+            APP_CONFIG.load().scoring_strategy,
+            &APP_CONFIG.load().filetype_weights,
+        ) + typosquat_candidate
+            .as_ref()
+            .map_or(0, |_| APP_CONFIG.load().typosquat_score);
 
         let inspector_url =
             highest_score_distribution.and_then(DistributionScanResults::inspector_url);
@@ -205,34 +1262,242 @@ impl PackageScanResults {
             .into_iter()
             .collect();
 
+        let findings = self
+            .distribution_scan_results
+            .iter()
+            .flat_map(DistributionScanResults::get_findings)
+            .chain(correlation::correlate(&self.distribution_scan_results))
+            .collect();
+
+        let severities = self
+            .distribution_scan_results
+            .iter()
+            .flat_map(DistributionScanResults::get_matched_rules)
+            .filter_map(|rule| rule.severity);
+        let confidence = confidence::confidence(score, &APP_CONFIG.load().confidence_curve);
+        let verdict = Verdict::classify(confidence, severities);
+
+        let oversized_files = self
+            .distribution_scan_results
+            .iter()
+            .flat_map(DistributionScanResults::get_oversized_files)
+            .collect();
+
+        let files_skipped = self
+            .distribution_scan_results
+            .iter()
+            .flat_map(DistributionScanResults::get_skipped_files)
+            .collect();
+
+        let partial_scans = self
+            .distribution_scan_results
+            .iter()
+            .filter_map(DistributionScanResults::get_partial_scan)
+            .collect();
+
+        let iocs = self
+            .distribution_scan_results
+            .iter()
+            .flat_map(DistributionScanResults::get_iocs)
+            .take(APP_CONFIG.load().max_iocs)
+            .collect();
+
+        let telemetry = APP_CONFIG.load().report_telemetry.then(|| {
+            self.distribution_scan_results
+                .iter()
+                .map(|distribution| &distribution.measurements)
+                .sum::<Measurements>()
+                .into()
+        });
+
+        let namespace_scores = if APP_CONFIG.load().rule_bundles.is_empty() {
+            BTreeMap::new()
+        } else {
+            self.namespace_scores()
+        };
+
+        let artifacts = self
+            .distribution_scan_results
+            .iter()
+            .flat_map(|distribution| distribution.artifacts.iter().cloned())
+            .collect();
+
+        let mirror_hosts = self
+            .distribution_scan_results
+            .iter()
+            .filter_map(DistributionScanResults::mirror_host)
+            .map(ToOwned::to_owned)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+
         SubmitJobResultsSuccess {
             name: self.name.clone(),
             version: self.version.clone(),
             score,
+            confidence,
             inspector_url,
             rules_matched,
             commit: self.commit_hash.clone(),
+            findings,
+            verdict,
+            oversized_files,
+            files_skipped,
+            partial_scans,
+            mirror_hosts,
+            typosquat_candidate,
+            iocs,
+            telemetry,
+            artifacts,
+            namespace_scores,
+            host: host::FINGERPRINT.clone(),
+            client_version: BUILD_INFO.client_version(),
+            worker_id: None,
+            pypi_metadata: None,
+        }
+    }
+}
+
+/// Whether the digests `job` is verified against are those published on the index, see
+/// [`expected_digests`]
+pub fn needs_published_digests(job: &Job) -> bool {
+    let config = APP_CONFIG.load();
+    config.verify_digests && config.fetch_pypi_digests && job.digests.is_empty()
+}
+
+/// The SHA-256 digests to verify the distributions of `job` against, by URL: those of the job, or
+/// with `fetch_pypi_digests`, those published on the index in `release` if the job has none
+fn expected_digests(job: &Job, release: Option<&pypi::Release>) -> HashMap<String, String> {
+    if !APP_CONFIG.load().verify_digests {
+        return HashMap::new();
+    }
+    if !needs_published_digests(job) {
+        return job.digests.clone();
+    }
+
+    match release {
+        Some(release) => release.sha256_digests(),
+        None => {
+            warn!(
+                "The release {} v{} wasn't fetched from the index, not verifying its distributions",
+                job.name, job.version
+            );
+            HashMap::new()
         }
     }
 }
 
 /// Scan all the distributions of the given job against the given ruleset
 ///
-/// Uses the provided HTTP client to download each distribution.
+/// Uses the provided HTTP client to download each distribution. Fails once `deadline` has passed,
+/// see [`Deadline`]. Distributions that don't match their published digest fail the job, see
+/// [`download_distribution`]. Distributions recently scanned with the same ruleset aren't
+/// downloaded again, unless the job is a forced rescan, see [`cache`].
+///
+/// `release` is the release of the job fetched from the index, if it was, see
+/// [`needs_published_digests`]. `scanned` is called with the results of each distribution as soon
+/// as it's scanned.
 pub fn scan_all_distributions(
     http_client: &Client,
-    rules: &Rules,
+    rules: &RulesState,
     job: &Job,
+    release: Option<&pypi::Release>,
+    deadline: Deadline,
+    mut scanned: impl FnMut(&DistributionScanResults),
 ) -> Result<Vec<DistributionScanResults>> {
     let mut distribution_scan_results = Vec::with_capacity(job.distributions.len());
+    let digests = expected_digests(job, release);
+    let duplicates = APP_CONFIG
+        .load()
+        .deduplicate_files
+        .then(Duplicates::default);
+    let recent_scans = cache::for_job(job.is_forced());
     for distribution in &job.distributions {
+        let cached = recent_scans.and_then(|recent_scans| {
+            recent_scans
+                .lock()
+                .get(distribution, &rules.hash, Instant::now())
+        });
+        if let Some(mut cached) = cached {
+            debug!(
+                "Reusing the results of {distribution}, it was scanned with rules {} recently",
+                rules.hash
+            );
+            // nothing was downloaded or scanned this time
+            cached.measurements = Measurements::default();
+            scanned(&cached);
+            distribution_scan_results.push(cached);
+            continue;
+        }
+
         let download_url: Url = distribution.parse().unwrap();
-        let inspector_url = create_inspector_url(&job.name, &job.version, &download_url);
+        let (inspector_url, inspectable) = if let Some(inspector_url) =
+            create_inspector_url(&job.name, &job.version, &download_url)
+        {
+            (inspector_url, true)
+        } else {
+            debug!("{download_url} isn't hosted on PyPI, not linking to the inspector");
+            let mut base = download_url.clone();
+            base.set_path(&format!("{}/", download_url.path()));
+            (base, false)
+        };
 
-        let dir = download_distribution(http_client, download_url.clone())?;
+        let extracted = download_distribution(
+            http_client,
+            &download_url,
+            digests.get(distribution).map(String::as_str),
+            deadline,
+        )?;
 
-        let mut dist = Distribution { dir, inspector_url };
-        let distribution_scan_result = dist.scan(rules)?;
+        let dist = Distribution {
+            dir: extracted.dir,
+            inspector_url,
+        };
+        let start = Instant::now();
+        let mut distribution_scan_result = dist.scan(
+            &rules.rules,
+            deadline,
+            duplicates.as_ref(),
+            rules.shadow.as_deref(),
+        )?;
+        distribution_scan_result.inspectable = inspectable;
+        distribution_scan_result.measurements.download = extracted.download_time;
+        distribution_scan_result.measurements.extraction = extracted.extraction_time;
+        distribution_scan_result.measurements.scan = start.elapsed();
+        drop(extracted.memory);
+        // the contents are removed before the disk space reserved for them is handed back
+        drop(dist);
+        drop(extracted.disk);
+        let quarantined = std::mem::take(&mut distribution_scan_result.quarantined);
+        distribution_scan_result.artifacts = quarantine::upload(
+            http_client,
+            &job.name,
+            &job.version,
+            distribution_scan_result.file_name(),
+            quarantined,
+        );
+        events::emit(&Event::DistributionScanned {
+            name: &job.name,
+            version: &job.version,
+            distribution: download_url.as_str(),
+            files: distribution_scan_result.file_scan_results.len(),
+            score: distribution_scan_result.get_total_score(),
+        });
+        if distribution_scan_result.skipped_files() > 0 {
+            debug!(
+                "Skipped {} files of {download_url}",
+                distribution_scan_result.skipped_files()
+            );
+        }
+        if let Some(recent_scans) = recent_scans {
+            recent_scans.lock().insert(
+                distribution,
+                &rules.hash,
+                &distribution_scan_result,
+                Instant::now(),
+            );
+        }
+        scanned(&distribution_scan_result);
         distribution_scan_results.push(distribution_scan_result);
     }
 
@@ -241,13 +1506,22 @@ pub fn scan_all_distributions(
 
 #[cfg(test)]
 mod tests {
-    use super::{DistributionScanResults, PackageScanResults};
+    use super::{Digests, DistributionScanResults, Measurements, PackageScanResults};
     use crate::{
-        client::{ScanResultSerializer, SubmitJobResultsError, SubmitJobResultsSuccess},
-        scanner::{FileScanResult, RuleScore},
+        app_config::{OversizedFilePolicy, ScoringStrategy},
+        client::{
+            bundles::Bundle, RulesResponse, ScanResultSerializer, SubmitJobResultsError,
+            SubmitJobResultsSuccess,
+        },
+        deadline::Deadline,
+        scanner::{FileScanResult, RuleScore, Verdict},
     };
     use std::io::Write;
-    use std::{collections::HashSet, path::PathBuf};
+    use std::{
+        collections::{BTreeMap, HashMap, HashSet},
+        fs,
+        path::{Path, PathBuf},
+    };
     use tempfile::{tempdir, tempdir_in};
     use yara::Compiler;
 
@@ -257,14 +1531,30 @@ mod tests {
             name: "test".into(),
             version: "1.0.0".into(),
             score: 10,
+            confidence: 66,
             inspector_url: Some("inspector url".into()),
             rules_matched: vec!["abc".into(), "def".into()],
             commit: "commit hash".into(),
+            findings: Vec::new(),
+            verdict: Verdict::Clean,
+            oversized_files: Vec::new(),
+            files_skipped: Vec::new(),
+            partial_scans: Vec::new(),
+            mirror_hosts: Vec::new(),
+            typosquat_candidate: None,
+            iocs: Vec::new(),
+            telemetry: None,
+            artifacts: Vec::new(),
+            namespace_scores: BTreeMap::new(),
+            host: None,
+            client_version: String::from("0.1.0+abc1234"),
+            worker_id: None,
+            pypi_metadata: None,
         };
 
         let scan_result: ScanResultSerializer = Ok(success).into();
         let actual = serde_json::to_string(&scan_result).unwrap();
-        let expected = r#"{"name":"test","version":"1.0.0","score":10,"inspector_url":"inspector url","rules_matched":["abc","def"],"commit":"commit hash"}"#;
+        let expected = r#"{"name":"test","version":"1.0.0","score":10,"confidence":66,"inspector_url":"inspector url","rules_matched":["abc","def"],"commit":"commit hash","verdict":"clean","client_version":"0.1.0+abc1234"}"#;
 
         assert_eq!(actual, expected);
     }
@@ -275,11 +1565,14 @@ mod tests {
             name: "test".into(),
             version: "1.0.0".into(),
             reason: "Package too large".into(),
+            resource_limit: None,
+            client_version: String::from("0.1.0+abc1234"),
+            worker_id: None,
         };
 
         let scan_result: ScanResultSerializer = Err(error).into();
         let actual = serde_json::to_string(&scan_result).unwrap();
-        let expected = r#"{"name":"test","version":"1.0.0","reason":"Package too large"}"#;
+        let expected = r#"{"name":"test","version":"1.0.0","reason":"Package too large","client_version":"0.1.0+abc1234"}"#;
 
         assert_eq!(actual, expected);
     }
@@ -290,15 +1583,20 @@ mod tests {
             RuleScore {
                 name: String::from("rule1"),
                 score: 5,
+                severity: None,
             },
             RuleScore {
                 name: String::from("rule2"),
                 score: 7,
+                severity: None,
             },
         ];
 
         let file_scan_result = FileScanResult {
             path: PathBuf::default(),
+            sha256: String::new(),
+            ssdeep: None,
+            snippets: Vec::new(),
             rules,
         };
         assert_eq!(file_scan_result.calculate_score(), 12);
@@ -309,30 +1607,53 @@ mod tests {
         let file_scan_results = vec![
             FileScanResult {
                 path: PathBuf::default(),
+                sha256: String::new(),
+                ssdeep: None,
+                snippets: Vec::new(),
                 rules: vec![RuleScore {
                     name: String::from("rule1"),
                     score: 5,
+                    severity: None,
                 }],
             },
             FileScanResult {
                 path: PathBuf::default(),
+                sha256: String::new(),
+                ssdeep: None,
+                snippets: Vec::new(),
                 rules: vec![RuleScore {
                     name: String::from("rule2"),
                     score: 7,
+                    severity: None,
                 }],
             },
             FileScanResult {
                 path: PathBuf::default(),
+                sha256: String::new(),
+                ssdeep: None,
+                snippets: Vec::new(),
                 rules: vec![RuleScore {
                     name: String::from("rule3"),
                     score: 4,
+                    severity: None,
                 }],
             },
         ];
 
         let distribution_scan_results = DistributionScanResults {
             file_scan_results,
+            findings: Vec::new(),
             inspector_url: reqwest::Url::parse("https://example.net").unwrap(),
+            inspectable: true,
+            skipped_files: 0,
+            partial_scan: None,
+            oversized_files: Vec::new(),
+            files_skipped: Vec::new(),
+            digests: Digests::new(),
+            iocs: Vec::new(),
+            measurements: Measurements::default(),
+            quarantined: Vec::new(),
+            artifacts: Vec::new(),
         };
 
         assert_eq!(
@@ -350,40 +1671,55 @@ mod tests {
         let file_scan_results = vec![
             FileScanResult {
                 path: PathBuf::default(),
+                sha256: String::new(),
+                ssdeep: None,
+                snippets: Vec::new(),
                 rules: vec![
                     RuleScore {
                         name: String::from("rule1"),
                         score: 5,
+                        severity: None,
                     },
                     RuleScore {
                         name: String::from("rule2"),
                         score: 7,
+                        severity: None,
                     },
                 ],
             },
             FileScanResult {
                 path: PathBuf::default(),
+                sha256: String::new(),
+                ssdeep: None,
+                snippets: Vec::new(),
                 rules: vec![
                     RuleScore {
                         name: String::from("rule2"),
                         score: 7,
+                        severity: None,
                     },
                     RuleScore {
                         name: String::from("rule3"),
                         score: 9,
+                        severity: None,
                     },
                 ],
             },
             FileScanResult {
                 path: PathBuf::default(),
+                sha256: String::new(),
+                ssdeep: None,
+                snippets: Vec::new(),
                 rules: vec![
                     RuleScore {
                         name: String::from("rule3"),
                         score: 9,
+                        severity: None,
                     },
                     RuleScore {
                         name: String::from("rule4"),
                         score: 6,
+                        severity: None,
                     },
                 ],
             },
@@ -391,7 +1727,18 @@ mod tests {
 
         let distribution_scan_results = DistributionScanResults {
             file_scan_results,
+            findings: Vec::new(),
             inspector_url: reqwest::Url::parse("https://example.net").unwrap(),
+            inspectable: true,
+            skipped_files: 0,
+            partial_scan: None,
+            oversized_files: Vec::new(),
+            files_skipped: Vec::new(),
+            digests: Digests::new(),
+            iocs: Vec::new(),
+            measurements: Measurements::default(),
+            quarantined: Vec::new(),
+            artifacts: Vec::new(),
         };
 
         let matched_rules: HashSet<RuleScore> = distribution_scan_results
@@ -404,18 +1751,22 @@ mod tests {
             RuleScore {
                 name: String::from("rule1"),
                 score: 5,
+                severity: None,
             },
             RuleScore {
                 name: String::from("rule2"),
                 score: 7,
+                severity: None,
             },
             RuleScore {
                 name: String::from("rule3"),
                 score: 9,
+                severity: None,
             },
             RuleScore {
                 name: String::from("rule4"),
                 score: 6,
+                severity: None,
             },
         ]);
 
@@ -427,40 +1778,55 @@ mod tests {
         let file_scan_results = vec![
             FileScanResult {
                 path: PathBuf::default(),
+                sha256: String::new(),
+                ssdeep: None,
+                snippets: Vec::new(),
                 rules: vec![
                     RuleScore {
                         name: String::from("rule1"),
                         score: 5,
+                        severity: None,
                     },
                     RuleScore {
                         name: String::from("rule2"),
                         score: 7,
+                        severity: None,
                     },
                 ],
             },
             FileScanResult {
                 path: PathBuf::default(),
+                sha256: String::new(),
+                ssdeep: None,
+                snippets: Vec::new(),
                 rules: vec![
                     RuleScore {
                         name: String::from("rule2"),
                         score: 7,
+                        severity: None,
                     },
                     RuleScore {
                         name: String::from("rule3"),
                         score: 9,
+                        severity: None,
                     },
                 ],
             },
             FileScanResult {
                 path: PathBuf::default(),
+                sha256: String::new(),
+                ssdeep: None,
+                snippets: Vec::new(),
                 rules: vec![
                     RuleScore {
                         name: String::from("rule3"),
                         score: 9,
+                        severity: None,
                     },
                     RuleScore {
                         name: String::from("rule4"),
                         score: 6,
+                        severity: None,
                     },
                 ],
             },
@@ -468,7 +1834,18 @@ mod tests {
 
         let distribution_scan_results = DistributionScanResults {
             file_scan_results,
+            findings: Vec::new(),
             inspector_url: reqwest::Url::parse("https://example.net").unwrap(),
+            inspectable: true,
+            skipped_files: 0,
+            partial_scan: None,
+            oversized_files: Vec::new(),
+            files_skipped: Vec::new(),
+            digests: Digests::new(),
+            iocs: Vec::new(),
+            measurements: Measurements::default(),
+            quarantined: Vec::new(),
+            artifacts: Vec::new(),
         };
 
         let matched_rule_identifiers = distribution_scan_results.get_matched_rule_identifiers();
@@ -486,43 +1863,81 @@ mod tests {
         let file_scan_results1 = vec![
             FileScanResult {
                 path: PathBuf::default(),
+                sha256: String::new(),
+                ssdeep: None,
+                snippets: Vec::new(),
                 rules: vec![RuleScore {
                     name: String::from("rule1"),
                     score: 5,
+                    severity: None,
                 }],
             },
             FileScanResult {
                 path: PathBuf::default(),
+                sha256: String::new(),
+                ssdeep: None,
+                snippets: Vec::new(),
                 rules: vec![RuleScore {
                     name: String::from("rule2"),
                     score: 7,
+                    severity: None,
                 }],
             },
         ];
         let distribution_scan_results1 = DistributionScanResults {
             file_scan_results: file_scan_results1,
+            findings: Vec::new(),
             inspector_url: reqwest::Url::parse("https://example.net/distrib1.tar.gz").unwrap(),
+            inspectable: true,
+            skipped_files: 0,
+            partial_scan: None,
+            oversized_files: Vec::new(),
+            files_skipped: Vec::new(),
+            digests: Digests::new(),
+            iocs: Vec::new(),
+            measurements: Measurements::default(),
+            quarantined: Vec::new(),
+            artifacts: Vec::new(),
         };
 
         let file_scan_results2 = vec![
             FileScanResult {
                 path: PathBuf::default(),
+                sha256: String::new(),
+                ssdeep: None,
+                snippets: Vec::new(),
                 rules: vec![RuleScore {
                     name: String::from("rule3"),
                     score: 2,
+                    severity: None,
                 }],
             },
             FileScanResult {
                 path: PathBuf::default(),
+                sha256: String::new(),
+                ssdeep: None,
+                snippets: Vec::new(),
                 rules: vec![RuleScore {
                     name: String::from("rule4"),
                     score: 9,
+                    severity: None,
                 }],
             },
         ];
         let distribution_scan_results2 = DistributionScanResults {
             file_scan_results: file_scan_results2,
+            findings: Vec::new(),
             inspector_url: reqwest::Url::parse("https://example.net/distrib2.whl").unwrap(),
+            inspectable: true,
+            skipped_files: 0,
+            partial_scan: None,
+            oversized_files: Vec::new(),
+            files_skipped: Vec::new(),
+            digests: Digests::new(),
+            iocs: Vec::new(),
+            measurements: Measurements::default(),
+            quarantined: Vec::new(),
+            artifacts: Vec::new(),
         };
 
         let package_scan_results = PackageScanResults {
@@ -548,6 +1963,93 @@ mod tests {
             ]),
             HashSet::from_iter(body.rules_matched)
         );
+
+        let weights = HashMap::new();
+        let score = |strategy| package_scan_results.score(strategy, &weights);
+        assert_eq!(score(ScoringStrategy::Max), 12);
+        assert_eq!(score(ScoringStrategy::SumUnique), 23);
+        assert_eq!(score(ScoringStrategy::Mean), 11);
+        assert_eq!(score(ScoringStrategy::WeightedByFiletype), 12);
+    }
+
+    #[test]
+    fn does_not_link_mirrored_distributions() {
+        let distribution_scan_results = DistributionScanResults {
+            file_scan_results: vec![FileScanResult {
+                path: PathBuf::from("pkg/__init__.py"),
+                sha256: String::new(),
+                ssdeep: None,
+                snippets: Vec::new(),
+                rules: vec![RuleScore {
+                    name: String::from("rule1"),
+                    score: 5,
+                    severity: None,
+                }],
+            }],
+            findings: Vec::new(),
+            inspector_url: reqwest::Url::parse("https://pypi.mirror.example/pkg-1.0.tar.gz/")
+                .unwrap(),
+            inspectable: false,
+            skipped_files: 0,
+            partial_scan: None,
+            oversized_files: Vec::new(),
+            files_skipped: Vec::new(),
+            digests: Digests::new(),
+            iocs: Vec::new(),
+            measurements: Measurements::default(),
+            quarantined: Vec::new(),
+            artifacts: Vec::new(),
+        };
+        let package_scan_results = PackageScanResults {
+            name: String::from("pkg"),
+            version: String::from("1.0"),
+            distribution_scan_results: vec![distribution_scan_results],
+            commit_hash: String::from("abc"),
+        };
+
+        let body = package_scan_results.build_body();
+        assert_eq!(body.inspector_url, None);
+        assert_eq!(body.mirror_hosts, ["pypi.mirror.example"]);
+        assert_eq!(body.score, 5);
+    }
+
+    #[test]
+    fn weights_scores_by_filetype() {
+        let rule = |name: &str, score| RuleScore {
+            name: name.into(),
+            score,
+            severity: None,
+        };
+        let distribution = DistributionScanResults {
+            file_scan_results: vec![
+                FileScanResult::new("pkg/hook.pth".into(), vec![rule("a", 4)], &[]),
+                FileScanResult::new(
+                    "docs/usage.md".into(),
+                    vec![rule("a", 4), rule("b", 4)],
+                    &[],
+                ),
+                FileScanResult::new("setup.cfg!options".into(), vec![rule("c", 1)], &[]),
+            ],
+            findings: Vec::new(),
+            inspector_url: reqwest::Url::parse("https://example.net/distrib.tar.gz").unwrap(),
+            inspectable: true,
+            skipped_files: 0,
+            partial_scan: None,
+            oversized_files: Vec::new(),
+            files_skipped: Vec::new(),
+            digests: Digests::new(),
+            iocs: Vec::new(),
+            measurements: Measurements::default(),
+            quarantined: Vec::new(),
+            artifacts: Vec::new(),
+        };
+        let weights = HashMap::from([
+            (String::from("pth"), 2.0),
+            (String::from("md"), 0.5),
+            (String::from("cfg"), 3.0),
+        ]);
+
+        assert_eq!(distribution.get_weighted_score(&weights), 8 + 2 + 3);
     }
 
     #[test]
@@ -556,6 +2058,7 @@ mod tests {
             rule contains_rust {
                 meta:
                     weight = 5
+                    severity = "suspicious"
                 strings:
                     $rust = "rust" nocase
                 condition:
@@ -567,28 +2070,25 @@ mod tests {
 
         let rules = compiler.compile_rules().unwrap();
 
-        let tempdir = tempdir().unwrap();
-        let archive_root = tempfile::Builder::new().tempdir_in(tempdir.path()).unwrap();
-
-        let mut tmpfile = tempfile::NamedTempFile::new_in(archive_root.path()).unwrap();
-
-        writeln!(&mut tmpfile, "I hate Rust >:(").unwrap();
-
-        let distro = super::Distribution {
-            dir: tempdir,
-            inspector_url: "https://example.com".parse().unwrap(),
-        };
-
-        let result = distro.scan_file(tmpfile.path(), &rules).unwrap();
+        let mut scan = super::DistributionScan::new(&rules);
+        scan.scan_file(Path::new("src/lib.rs"), b"I hate Rust >:(\n")
+            .unwrap();
+        let result = &scan.file_scan_results[0];
 
         assert_eq!(
             result.rules[0],
             RuleScore {
                 name: "contains_rust".into(),
-                score: 5
+                score: 5,
+                severity: Some(Verdict::Suspicious),
             }
         );
         assert_eq!(result.calculate_score(), 5);
+        assert_eq!(
+            result.sha256,
+            "c0b98a1e5bef1b6eca290bb6f3469b97449a94871a22bd3674d41e04ff99ce26"
+        );
+        assert_eq!(result.ssdeep, None);
     }
 
     #[test]
@@ -614,6 +2114,7 @@ mod tests {
             rule contains_rust {
                 meta:
                     weight = 5
+                    severity = "suspicious"
                 strings:
                     $rust = "rust" nocase
                 condition:
@@ -629,13 +2130,470 @@ mod tests {
         let mut tempfile = tempfile::NamedTempFile::new_in(tempdir.path()).unwrap();
         writeln!(&mut tempfile, "rust").unwrap();
 
-        let mut distro = super::Distribution {
+        let distro = super::Distribution {
             dir: tempdir,
             inspector_url: "https://example.com".parse().unwrap(),
         };
 
-        let results = distro.scan(&rules).unwrap();
+        let results = distro.scan(&rules, Deadline::none(), None, None).unwrap();
 
         assert_eq!(results.file_scan_results.len(), 1);
     }
+
+    #[test]
+    fn reuses_the_results_of_duplicate_files() {
+        let rules = Compiler::new()
+            .unwrap()
+            .add_rules_str(
+                r#"rule exec { meta: weight = 3 strings: $exec = "exec(" condition: $exec }"#,
+            )
+            .unwrap()
+            .compile_rules()
+            .unwrap();
+        let duplicates = super::Duplicates::default();
+        let scan = |platform: &str| {
+            let dir = tempdir().unwrap();
+            fs::write(dir.path().join("core.py"), "exec(payload)").unwrap();
+            fs::write(dir.path().join("_speedups.so"), platform).unwrap();
+            let distro = super::Distribution {
+                dir,
+                inspector_url: "https://example.com".parse().unwrap(),
+            };
+            distro
+                .scan(&rules, Deadline::none(), Some(&duplicates), None)
+                .unwrap()
+        };
+
+        let manylinux = scan("manylinux");
+        let musllinux = scan("musllinux");
+
+        assert_eq!(manylinux.measurements.duplicate_files, 0);
+        assert_eq!(musllinux.measurements.files, 2);
+        assert_eq!(musllinux.measurements.duplicate_files, 1);
+        assert_eq!(musllinux.get_total_score(), manylinux.get_total_score());
+        assert_eq!(musllinux.get_matched_rule_identifiers(), ["exec"]);
+    }
+
+    #[test]
+    fn scan_includes_embedded_scripts() {
+        let rules = r#"
+            rule python_only {
+                meta:
+                    weight = 5
+                    filetype = ".py"
+                strings:
+                    $exec = "exec(" nocase
+                condition:
+                    $exec
+            }
+        "#;
+
+        let compiler = Compiler::new().unwrap().add_rules_str(rules).unwrap();
+
+        let rules = compiler.compile_rules().unwrap();
+        let tempdir = tempdir().unwrap();
+        std::fs::write(
+            tempdir.path().join("tox.ini"),
+            "[testenv]\ncommands = python -c 'exec(1)'\n",
+        )
+        .unwrap();
+
+        let distro = super::Distribution {
+            dir: tempdir,
+            inspector_url: "https://example.com/".parse().unwrap(),
+        };
+
+        let results = distro.scan(&rules, Deadline::none(), None, None).unwrap();
+
+        assert_eq!(results.file_scan_results.len(), 2);
+        assert_eq!(results.get_total_score(), 5);
+        assert_eq!(
+            results.inspector_url(),
+            Some(String::from("https://example.com/tox.ini"))
+        );
+
+        let expired = Deadline::after(std::time::Duration::from_nanos(1));
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        assert!(distro.scan(&rules, expired, None, None).is_err());
+    }
+
+    #[test]
+    fn scans_archives_in_memory() {
+        let rules = Compiler::new()
+            .unwrap()
+            .add_rules_str("rule evil { meta: weight = 3 strings: $a = \"evil\" condition: $a }")
+            .unwrap()
+            .compile_rules()
+            .unwrap();
+        let inspector_url: reqwest::Url = "file:///dev/stdin/".parse().unwrap();
+
+        let mut tarball = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ));
+        let contents = b"an evil payload";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_cksum();
+        tarball
+            .append_data(&mut header, "pkg-1.0/evil.py", &contents[..])
+            .unwrap();
+        let tarball = tarball.into_inner().unwrap().finish().unwrap();
+
+        let results = super::scan_archive_bytes(
+            &tarball,
+            super::ArchiveKind::TarGz,
+            &rules,
+            inspector_url.clone(),
+        )
+        .unwrap();
+        assert_eq!(results.get_total_score(), 3);
+        assert_eq!(
+            results.inspector_url(),
+            Some(String::from("file:///dev/stdin/pkg-1.0/evil.py"))
+        );
+
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        zip.start_file("pkg/evil.py", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(contents).unwrap();
+        let zip = zip.finish().unwrap().into_inner();
+
+        let results =
+            super::scan_archive_bytes(&zip, super::ArchiveKind::Zip, &rules, inspector_url)
+                .unwrap();
+        assert_eq!(results.get_total_score(), 3);
+    }
+
+    #[test]
+    fn scans_nested_archives() {
+        let rules = Compiler::new()
+            .unwrap()
+            .add_rules_str("rule evil { meta: weight = 3 strings: $a = \"evil\" condition: $a }")
+            .unwrap()
+            .compile_rules()
+            .unwrap();
+
+        let zip = |path: &str, contents: &[u8]| {
+            let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+            zip.start_file(path, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(contents).unwrap();
+            zip.finish().unwrap().into_inner()
+        };
+        let inner = zip("inner/evil.py", b"an evil payload");
+        let outer = zip("nested.zip", &inner);
+
+        let scanned = |scan: super::DistributionScan| {
+            scan.file_scan_results
+                .iter()
+                .map(|file| file.path.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let mut scan = super::DistributionScan::new(&rules);
+        scan.scan_file(Path::new("pkg/data/outer.zip"), &outer)
+            .unwrap();
+        // only the archive itself is installed, not the files nested in it
+        assert_eq!(
+            scan.digests.keys().collect::<Vec<_>>(),
+            [Path::new("pkg/data/outer.zip")]
+        );
+        assert_eq!(
+            scanned(scan),
+            [
+                "pkg/data/outer.zip",
+                "pkg/data/outer.zip!nested.zip",
+                "pkg/data/outer.zip!nested.zip!inner/evil.py"
+            ]
+        );
+
+        let mut scan = super::DistributionScan::new(&rules);
+        scan.max_archive_depth = 1;
+        scan.scan_file(Path::new("pkg/data/outer.zip"), &outer)
+            .unwrap();
+        assert_eq!(
+            scanned(scan),
+            ["pkg/data/outer.zip", "pkg/data/outer.zip!nested.zip"]
+        );
+
+        let mut scan = super::DistributionScan::new(&rules);
+        scan.nested_budget = 4;
+        scan.scan_file(Path::new("pkg/data/outer.zip"), &outer)
+            .unwrap();
+        assert_eq!(scanned(scan), ["pkg/data/outer.zip"]);
+    }
+
+    #[test]
+    fn limits_oversized_files() {
+        let rules = Compiler::new()
+            .unwrap()
+            .add_rules_str("rule evil { strings: $a = \"evil\" condition: $a }")
+            .unwrap()
+            .compile_rules()
+            .unwrap();
+        let path = Path::new("pkg/data.txt");
+        let contents = b"evil, then more evil";
+
+        let mut scan = super::DistributionScan::new(&rules);
+        scan.max_file_size = 8;
+        scan.oversized_file_policy = OversizedFilePolicy::Truncate;
+        scan.scan_reader(path, contents.len() as u64, &contents[..])
+            .unwrap();
+        assert_eq!(scan.file_scan_results[0].rules.len(), 1);
+        assert!(scan.oversized_files[0].truncated);
+
+        let mut scan = super::DistributionScan::new(&rules);
+        scan.max_file_size = 8;
+        scan.oversized_file_policy = OversizedFilePolicy::Skip;
+        scan.scan_reader(path, contents.len() as u64, &contents[..])
+            .unwrap();
+        assert!(scan.file_scan_results.is_empty());
+        assert_eq!(scan.oversized_files[0].size, 20);
+        assert!(!scan.oversized_files[0].truncated);
+    }
+
+    #[test]
+    fn records_files_that_cannot_be_scanned() {
+        let rules = Compiler::new()
+            .unwrap()
+            .add_rules_str("rule evil { strings: $a = \"evil\" condition: $a }")
+            .unwrap()
+            .compile_rules()
+            .unwrap();
+        let tempdir = tempdir().unwrap();
+        fs::create_dir(tempdir.path().join("pkg")).unwrap();
+        fs::write(tempdir.path().join("pkg/evil.py"), "evil").unwrap();
+        let distribution = super::Distribution {
+            dir: tempdir,
+            inspector_url: "https://example.com/".parse().unwrap(),
+        };
+
+        let mut scan = super::DistributionScan::new(&rules);
+        let root = distribution.dir.path();
+        distribution
+            .scan_entry(&mut scan, &root.join("pkg/missing.py"))
+            .unwrap();
+        distribution
+            .scan_entry(&mut scan, &root.join("pkg/evil.py"))
+            .unwrap();
+
+        assert_eq!(scan.files_skipped.len(), 1);
+        assert_eq!(scan.files_skipped[0].path, "pkg/missing.py");
+        assert_eq!(scan.file_scan_results.len(), 1);
+        assert_eq!(scan.measurements.files, 1);
+    }
+
+    #[test]
+    fn stops_scanning_past_the_file_limit() {
+        let rules = Compiler::new()
+            .unwrap()
+            .add_rules_str("rule evil { strings: $a = \"evil\" condition: $a }")
+            .unwrap()
+            .compile_rules()
+            .unwrap();
+
+        let mut scan = super::DistributionScan::new(&rules);
+        scan.max_files = 2;
+        for name in ["pkg/a.py", "pkg/b.py", "pkg/c.py", "pkg/d.py"] {
+            scan.scan_reader(Path::new(name), 4, &b"evil"[..]).unwrap();
+        }
+        assert_eq!(scan.file_scan_results.len(), 2);
+
+        let results =
+            scan.finish(reqwest::Url::parse("https://example.net/pkg-1.0.tar.gz/").unwrap());
+        let partial_scan = results.get_partial_scan().unwrap();
+        assert_eq!(partial_scan.distribution.as_deref(), Some("pkg-1.0.tar.gz"));
+        assert_eq!(
+            (partial_scan.scanned_files, partial_scan.unscanned_files),
+            (2, 2)
+        );
+    }
+
+    #[test]
+    fn reports_scores_by_namespace() {
+        let response = RulesResponse {
+            hash: String::from("abc"),
+            rules: HashMap::from([(
+                String::from("public.yar"),
+                String::from("rule evil { meta: weight = 3 strings: $a = \"evil\" condition: $a }"),
+            )]),
+            etag: None,
+        };
+        let bundle = Bundle {
+            namespace: String::from("acme"),
+            sources: BTreeMap::from([(
+                String::from("private.yar"),
+                String::from("rule evil { meta: weight = 5 strings: $a = \"evil\" condition: $a }"),
+            )]),
+        };
+        let rules = response.compile_with(&[bundle]).unwrap();
+
+        let mut scan = super::DistributionScan::new(&rules);
+        scan.scan_file(Path::new("setup.py"), b"evil").unwrap();
+        let mut names = scan.file_scan_results[0]
+            .rules
+            .iter()
+            .map(|rule| rule.name.as_str())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, ["acme:evil", "evil"]);
+
+        let url = reqwest::Url::parse("https://inspector.pypi.io/project/pkg/1.0/").unwrap();
+        let package = PackageScanResults::new(
+            String::from("pkg"),
+            String::from("1.0"),
+            vec![scan.finish(url)],
+            String::from("abc"),
+        );
+        assert_eq!(
+            package.namespace_scores(),
+            BTreeMap::from([(String::from("acme"), 5), (String::from("default"), 3)])
+        );
+    }
+
+    #[test]
+    fn quarantines_files_matched_by_quarantine_rules() {
+        let rules = Compiler::new()
+            .unwrap()
+            .add_rules_str(
+                r#"
+                rule dropper { meta: quarantine = true strings: $a = "exec" condition: $a }
+                rule noisy { strings: $a = "import" condition: $a }
+                "#,
+            )
+            .unwrap()
+            .compile_rules()
+            .unwrap();
+
+        let mut scan = super::DistributionScan::new(&rules);
+        scan.scan_file(Path::new("setup.py"), b"import os; exec(payload)")
+            .unwrap();
+        assert!(scan.quarantined.is_empty(), "quarantine is disabled");
+
+        let mut scan = super::DistributionScan::new(&rules);
+        scan.max_quarantined_files = 1;
+        scan.scan_file(Path::new("setup.py"), b"import os; exec(payload)")
+            .unwrap();
+        scan.scan_file(Path::new("lib.py"), b"import sys").unwrap();
+        scan.scan_file(Path::new("other.py"), b"exec(more)")
+            .unwrap();
+
+        assert_eq!(scan.quarantined.len(), 1);
+        assert_eq!(scan.quarantined[0].path, "setup.py");
+        assert_eq!(scan.quarantined[0].rules, ["dropper"]);
+        assert_eq!(scan.file_scan_results[0].rules.len(), 2);
+    }
+
+    #[test]
+    fn scan_reports_analyzer_findings() {
+        let rules = Compiler::new()
+            .unwrap()
+            .add_rules_str("rule never { condition: false }")
+            .unwrap()
+            .compile_rules()
+            .unwrap();
+
+        let tempdir = tempdir().unwrap();
+        std::fs::write(
+            tempdir.path().join("payload.py"),
+            "exec(marshal.loads(zlib.decompress(b'')))",
+        )
+        .unwrap();
+
+        let distro = super::Distribution {
+            dir: tempdir,
+            inspector_url: "https://example.com/packages/pkg-1.0.tar.gz/"
+                .parse()
+                .unwrap(),
+        };
+
+        let results = distro.scan(&rules, Deadline::none(), None, None).unwrap();
+        let findings: Vec<_> = results.get_findings().collect();
+
+        assert_eq!(findings.len(), 2);
+        assert!(findings.iter().all(|finding| finding.path == "payload.py"
+            && finding.distribution.as_deref() == Some("pkg-1.0.tar.gz")));
+        assert_eq!(results.get_total_score(), 0);
+    }
+
+    #[test]
+    fn weighted_findings_add_to_the_score() {
+        let rules = Compiler::new()
+            .unwrap()
+            .add_rules_str("rule never { condition: false }")
+            .unwrap()
+            .compile_rules()
+            .unwrap();
+
+        let mut scan = super::DistributionScan::new(&rules);
+        scan.scan_file(
+            Path::new("pkg-1.0/setup.py"),
+            b"from setuptools import setup\nsetup(cmdclass={'install': Install})\n",
+        )
+        .unwrap();
+        let results = scan.finish("https://example.com/pkg-1.0.tar.gz/".parse().unwrap());
+
+        assert_eq!(results.get_total_score(), 3);
+    }
+
+    #[test]
+    fn test_file_result_parts() {
+        let distribution_scan_results = DistributionScanResults {
+            file_scan_results: vec![
+                FileScanResult {
+                    path: PathBuf::from("pkg/clean.py"),
+                    sha256: String::new(),
+                    ssdeep: None,
+                    snippets: Vec::new(),
+                    rules: Vec::new(),
+                },
+                FileScanResult {
+                    path: PathBuf::from("pkg/evil.py"),
+                    sha256: String::new(),
+                    ssdeep: None,
+                    snippets: Vec::new(),
+                    rules: vec![
+                        RuleScore {
+                            name: String::from("rule1"),
+                            score: 5,
+                            severity: None,
+                        },
+                        RuleScore {
+                            name: String::from("rule2"),
+                            score: 2,
+                            severity: None,
+                        },
+                    ],
+                },
+            ],
+            findings: Vec::new(),
+            inspector_url: reqwest::Url::parse("https://example.net/pkg-1.0.tar.gz/").unwrap(),
+            inspectable: true,
+            skipped_files: 0,
+            partial_scan: None,
+            oversized_files: Vec::new(),
+            files_skipped: Vec::new(),
+            digests: Digests::new(),
+            iocs: Vec::new(),
+            measurements: Measurements::default(),
+            quarantined: Vec::new(),
+            artifacts: Vec::new(),
+        };
+
+        let parts: Vec<_> = distribution_scan_results.file_result_parts().collect();
+
+        assert_eq!(
+            parts,
+            vec![crate::client::FileResultPart {
+                distribution: Some("pkg-1.0.tar.gz"),
+                path: String::from("pkg/evil.py"),
+                score: 7,
+                rules_matched: vec!["rule1", "rule2"],
+                sha256: "",
+                ssdeep: None,
+                snippets: &[],
+            }]
+        );
+    }
 }
@@ -0,0 +1,178 @@
+//! Reusing the results of distributions that were scanned recently.
+//!
+//! Jobs are occasionally issued again for the same distributions. As long as the ruleset didn't
+//! change, scanning a distribution again gives the same results, so they're kept for
+//! `scan_cache_ttl` seconds by distribution URL and ruleset hash, and a repeat scan in that time
+//! skips downloading and scanning entirely. At most `scan_cache_size` results are kept, the least
+//! recently used ones are evicted first. Forced rescans neither use nor keep results, see
+//! [`for_job`].
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use super::DistributionScanResults;
+use crate::APP_CONFIG;
+
+struct Entry {
+    results: DistributionScanResults,
+    scanned_at: Instant,
+
+    /// When the entry was last used, in calls to the cache
+    last_used: u64,
+}
+
+/// Recent scan results by distribution URL and ruleset hash, see the module docs
+pub struct RecentScans {
+    entries: HashMap<(String, String), Entry>,
+    capacity: usize,
+    ttl: Duration,
+    tick: u64,
+}
+
+impl RecentScans {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+            ttl,
+            tick: 0,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.capacity > 0 && !self.ttl.is_zero()
+    }
+
+    /// The results of scanning `url` with the ruleset `rules_hash`, if it was scanned less than
+    /// `ttl` before `now`
+    pub fn get(
+        &mut self,
+        url: &str,
+        rules_hash: &str,
+        now: Instant,
+    ) -> Option<DistributionScanResults> {
+        let key = (url.to_owned(), rules_hash.to_owned());
+        let entry = self.entries.get_mut(&key)?;
+        if now.saturating_duration_since(entry.scanned_at) >= self.ttl {
+            self.entries.remove(&key);
+            return None;
+        }
+
+        self.tick += 1;
+        entry.last_used = self.tick;
+        Some(entry.results.clone())
+    }
+
+    /// Keep the `results` of scanning `url` with the ruleset `rules_hash` at `now`
+    pub fn insert(
+        &mut self,
+        url: &str,
+        rules_hash: &str,
+        results: &DistributionScanResults,
+        now: Instant,
+    ) {
+        if !self.enabled() {
+            return;
+        }
+
+        let ttl = self.ttl;
+        self.entries
+            .retain(|_, entry| now.saturating_duration_since(entry.scanned_at) < ttl);
+        if self.entries.len() >= self.capacity {
+            let least_recently_used = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(key) = least_recently_used {
+                self.entries.remove(&key);
+            }
+        }
+
+        self.tick += 1;
+        self.entries.insert(
+            (url.to_owned(), rules_hash.to_owned()),
+            Entry {
+                results: results.clone(),
+                scanned_at: now,
+                last_used: self.tick,
+            },
+        );
+    }
+}
+
+/// The scan results of recently scanned distributions
+pub static RECENT_SCANS: Lazy<Mutex<RecentScans>> = Lazy::new(|| {
+    Mutex::new(RecentScans::new(
+        APP_CONFIG.load().scan_cache_size,
+        Duration::from_secs(APP_CONFIG.load().scan_cache_ttl),
+    ))
+});
+
+/// The [`RECENT_SCANS`] a job may use, `None` for `forced` jobs, which bypass the result caches, see
+/// [`crate::client::Job::is_forced`]
+pub fn for_job(forced: bool) -> Option<&'static Mutex<RecentScans>> {
+    (!forced).then(|| &*RECENT_SCANS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{for_job, RecentScans};
+    use crate::scanner::DistributionScanResults;
+    use reqwest::Url;
+    use std::time::{Duration, Instant};
+
+    fn results() -> DistributionScanResults {
+        let url = Url::parse("https://inspector.pypi.io/project/pkg/1.0/").unwrap();
+        DistributionScanResults::new(Vec::new(), Vec::new(), url)
+    }
+
+    #[test]
+    fn expires_scan_results() {
+        let mut cache = RecentScans::new(8, Duration::from_secs(60));
+        let start = Instant::now();
+        cache.insert("https://files/pkg.whl", "abc", &results(), start);
+
+        assert!(cache.get("https://files/pkg.whl", "abc", start).is_some());
+        assert!(cache.get("https://files/pkg.whl", "def", start).is_none());
+        assert!(cache
+            .get(
+                "https://files/pkg.whl",
+                "abc",
+                start + Duration::from_secs(60)
+            )
+            .is_none());
+        assert!(cache.get("https://files/pkg.whl", "abc", start).is_none());
+
+        let mut disabled = RecentScans::new(8, Duration::ZERO);
+        disabled.insert("https://files/pkg.whl", "abc", &results(), start);
+        assert!(disabled
+            .get("https://files/pkg.whl", "abc", start)
+            .is_none());
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_results() {
+        let mut cache = RecentScans::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        cache.insert("a", "abc", &results(), now);
+        cache.insert("b", "abc", &results(), now);
+        assert!(cache.get("a", "abc", now).is_some());
+
+        cache.insert("c", "abc", &results(), now);
+        assert!(cache.get("a", "abc", now).is_some());
+        assert!(cache.get("b", "abc", now).is_none());
+        assert!(cache.get("c", "abc", now).is_some());
+    }
+
+    #[test]
+    fn forced_jobs_bypass_the_cache() {
+        assert!(for_job(true).is_none());
+        assert!(for_job(false).is_some());
+    }
+}
@@ -0,0 +1,134 @@
+//! A self-test of freshly fetched rulesets, so a broken one isn't used to clear packages.
+//!
+//! A ruleset that compiles can still be broken, by a bad merge or a truncated rule file, and then
+//! quietly match nothing. Every ruleset must match the built-in [`CANARY`] buffer with its
+//! `canary_rule` before it's used, much like antivirus engines are tested with the EICAR file: the
+//! API ships that rule as part of the ruleset, so a ruleset missing it or whose rules don't match
+//! anymore fails. Rulesets that fail are never installed, see
+//! [`crate::client::DragonflyClient::install_rules`].
+//!
+//! Every ruleset is also compiled with a built-in canary rule in its own [`NAMESPACE`], which must
+//! match the [`CANARY`] too. It only tests that the YARA engine matches at all, since it passes
+//! whatever the rules of the API are. Its matches are never reported, see
+//! [`super::matching::scan`].
+
+use std::collections::BTreeMap;
+
+use color_eyre::{eyre::eyre, Result};
+use yara::Rules;
+
+use crate::{
+    client::bundles::{Bundle, DEFAULT_NAMESPACE},
+    APP_CONFIG,
+};
+
+/// What the canary rule must match. Harmless, and unlikely to appear in a real package.
+pub const CANARY: &[u8] = b"DRAGONFLY-CANARY-7c2f9e41: this buffer must match the canary rule";
+
+/// The namespace the built-in canary rule is compiled into
+pub const NAMESPACE: &str = "dragonfly_canary";
+
+/// The identifier of the built-in canary rule
+const RULE: &str = "canary";
+
+/// The source of the built-in canary rule
+const SOURCE: &str =
+    r#"rule canary { strings: $canary = "DRAGONFLY-CANARY-7c2f9e41" condition: $canary }"#;
+
+/// How many seconds matching the canary may take
+const TIMEOUT: i32 = 5;
+
+/// The built-in canary rule, to be compiled into every ruleset like a bundle
+pub fn bundle() -> Bundle {
+    Bundle {
+        namespace: NAMESPACE.to_owned(),
+        sources: BTreeMap::from([(String::from("canary.yar"), SOURCE.to_owned())]),
+    }
+}
+
+/// Check that `rules` match the [`CANARY`] with the built-in canary rule, and with their
+/// `canary_rule`
+pub fn self_test(rules: &Rules) -> Result<()> {
+    check(rules, NAMESPACE, RULE)?;
+    check(rules, DEFAULT_NAMESPACE, &APP_CONFIG.load().canary_rule)
+}
+
+/// Check that `rules` match the [`CANARY`] with the rule `rule` of `namespace`
+fn check(rules: &Rules, namespace: &str, rule: &str) -> Result<()> {
+    let matches = rules.scan_mem(CANARY, TIMEOUT)?;
+    if matches
+        .iter()
+        .any(|matched| matched.namespace == namespace && matched.identifier == rule)
+    {
+        return Ok(());
+    }
+
+    Err(eyre!(
+        "the canary rule {rule} didn't match the canary ({} other rules did)",
+        matches.len()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bundle, check, self_test, NAMESPACE, RULE};
+    use crate::client::{bundles::DEFAULT_NAMESPACE, RulesResponse};
+    use std::collections::HashMap;
+    use yara::Compiler;
+
+    #[test]
+    fn requires_the_canary_rule_to_match() {
+        let rules = Compiler::new()
+            .unwrap()
+            .add_rules_str(
+                r#"
+                rule canary { strings: $canary = "DRAGONFLY-CANARY-7c2f9e41" condition: $canary }
+                rule broken { strings: $never = "never matched" condition: $never }
+                "#,
+            )
+            .unwrap()
+            .compile_rules()
+            .unwrap();
+
+        assert!(check(&rules, DEFAULT_NAMESPACE, "canary").is_ok());
+        assert!(check(&rules, DEFAULT_NAMESPACE, "broken").is_err());
+        assert!(check(&rules, DEFAULT_NAMESPACE, "missing").is_err());
+        assert!(check(&rules, NAMESPACE, "canary").is_err());
+    }
+
+    #[test]
+    fn compiles_the_built_in_rule_into_every_ruleset() {
+        let response = RulesResponse {
+            hash: String::from("abc"),
+            rules: HashMap::from([(
+                String::from("a.yar"),
+                String::from("rule a { condition: false }"),
+            )]),
+            etag: None,
+        };
+        let rules = response.compile_with(&[bundle()]).unwrap();
+
+        assert!(check(&rules, NAMESPACE, RULE).is_ok());
+        assert!(check(&response.compile().unwrap(), NAMESPACE, RULE).is_err());
+    }
+
+    #[test]
+    fn requires_a_rule_of_the_ruleset_to_match() {
+        let compile = |source: &str| {
+            RulesResponse {
+                hash: String::from("abc"),
+                rules: HashMap::from([(String::from("a.yar"), source.to_owned())]),
+                etag: None,
+            }
+            .compile_with(&[bundle()])
+            .unwrap()
+        };
+
+        // the built-in rule alone doesn't make a ruleset pass
+        assert!(self_test(&compile("rule a { condition: false }")).is_err());
+        assert!(self_test(&compile(
+            r#"rule canary { strings: $canary = "DRAGONFLY-CANARY-7c2f9e41" condition: $canary }"#
+        ))
+        .is_ok());
+    }
+}
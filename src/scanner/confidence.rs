@@ -0,0 +1,54 @@
+//! A 0 to 100 confidence score, comparable across rulesets.
+//!
+//! The score of a package is a sum of rule weights, so what it means depends on how the ruleset is
+//! weighted. The confidence maps it onto 0 to 100 along `confidence_curve`, a list of `[score,
+//! confidence]` points that's interpolated linearly between points, and flat before the first and
+//! after the last one. The verdict of a package is classified by its confidence, see
+//! [`super::Verdict::classify`].
+
+/// The confidence of `score` along `curve`. Without a curve, it's the score capped to 0 to 100.
+pub fn confidence(score: i64, curve: &[(i64, u8)]) -> u8 {
+    let mut points = curve.to_vec();
+    points.sort_unstable();
+
+    let interpolated = match (points.first(), points.last()) {
+        (Some(&(first, low)), _) if score <= first => i128::from(low),
+        (_, Some(&(last, high))) if score >= last => i128::from(high),
+        (Some(_), Some(_)) => points
+            .windows(2)
+            .find(|segment| segment[0].0 <= score && score <= segment[1].0)
+            .map_or(0, |segment| {
+                let ((x0, y0), (x1, y1)) = (segment[0], segment[1]);
+                let (x0, y0, x1, y1) = (
+                    i128::from(x0),
+                    i128::from(y0),
+                    i128::from(x1),
+                    i128::from(y1),
+                );
+                y0 + (y1 - y0) * (i128::from(score) - x0) / (x1 - x0).max(1)
+            }),
+        _ => i128::from(score),
+    };
+
+    u8::try_from(interpolated.clamp(0, 100)).unwrap_or(100)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::confidence;
+
+    #[test]
+    fn maps_scores_along_the_curve() {
+        let curve = [(15, 80), (0, 0), (5, 40), (30, 100)];
+
+        assert_eq!(confidence(-3, &curve), 0);
+        assert_eq!(confidence(0, &curve), 0);
+        assert_eq!(confidence(5, &curve), 40);
+        assert_eq!(confidence(10, &curve), 60);
+        assert_eq!(confidence(20, &curve), 86);
+        assert_eq!(confidence(1000, &curve), 100);
+
+        assert_eq!(confidence(42, &[]), 42);
+        assert_eq!(confidence(420, &[]), 100);
+    }
+}
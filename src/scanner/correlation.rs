@@ -0,0 +1,263 @@
+//! Comparing the source distribution of a package against its wheels.
+//!
+//! A wheel is supposed to be built from the sdist, so its Python modules should be found in the
+//! sdist with the same contents. Modules that only exist in the wheel, or differ from their sdist
+//! counterpart, are a classic sign of a payload injected at build time, where the sdist (which is
+//! what reviewers usually read) is clean.
+//!
+//! Every Python module that's missing or different is reported on its own. The other files of the
+//! wheel (data files, compiled extensions, ...) are only listed in a single `distribution_mismatch`
+//! finding per wheel, along with the modules, since they're often legitimately built rather than
+//! copied from the sdist. Paths are normalized to the path they're installed at: the
+//! `name-version/` root and a `src/` layout are stripped from sdist paths, and the `.dist-info`
+//! and non-library `.data` directories of wheels are ignored.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::analyzers::{Finding, FindingKind};
+
+use super::DistributionScanResults;
+
+/// The SHA-256 digests of the files of a distribution, by path in the archive
+pub type Digests = BTreeMap<PathBuf, [u8; 32]>;
+
+fn components(path: &Path) -> Vec<&str> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The module path a file of an sdist would be installed at
+fn sdist_module_path(path: &Path) -> Option<PathBuf> {
+    let parts = components(path);
+    let parts = parts.get(1..)?;
+    let parts = parts.strip_prefix(&["src"]).unwrap_or(parts);
+    (!parts.is_empty()).then(|| parts.iter().collect())
+}
+
+/// The module path a file of a wheel is installed at, `None` for metadata and data files
+fn wheel_module_path(path: &Path) -> Option<PathBuf> {
+    let parts = components(path);
+    let first = parts.first()?;
+    if first.ends_with(".dist-info") {
+        return None;
+    }
+
+    let parts = if has_extension(first, "data") {
+        match parts.get(1) {
+            Some(&"purelib" | &"platlib") => &parts[2..],
+            _ => return None,
+        }
+    } else {
+        &parts[..]
+    };
+
+    (!parts.is_empty()).then(|| parts.iter().collect())
+}
+
+fn has_extension(name: &str, extension: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case(extension))
+}
+
+fn finding(distribution: &str, path: &Path, kind: FindingKind) -> Finding {
+    let mut finding = Finding::new(path, kind);
+    finding.distribution = Some(distribution.to_owned());
+    finding
+}
+
+fn is_python_source(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "py")
+}
+
+/// Compare one sdist against one wheel
+fn compare(
+    (sdist_name, sdist): (&str, &Digests),
+    (wheel_name, wheel): (&str, &Digests),
+) -> Vec<Finding> {
+    let sdist_files = sdist
+        .iter()
+        .filter_map(|(path, digest)| Some((sdist_module_path(path)?, (path, digest))))
+        .collect::<BTreeMap<_, _>>();
+    let wheel_files = wheel
+        .iter()
+        .filter_map(|(path, digest)| Some((wheel_module_path(path)?, (path, digest))))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut findings = Vec::new();
+    let mut only_in_wheel = Vec::new();
+    let mut differs_from_sdist = Vec::new();
+    for (module, (path, digest)) in &wheel_files {
+        let kind = match sdist_files.get(module) {
+            None => {
+                only_in_wheel.push(path.to_string_lossy().into_owned());
+                FindingKind::OnlyInWheel {
+                    sdist: sdist_name.to_owned(),
+                }
+            }
+            Some((_, sdist_digest)) if sdist_digest != digest => {
+                differs_from_sdist.push(path.to_string_lossy().into_owned());
+                FindingKind::DiffersFromSdist {
+                    sdist: sdist_name.to_owned(),
+                }
+            }
+            Some(_) => continue,
+        };
+        if is_python_source(module) {
+            findings.push(finding(wheel_name, path, kind));
+        }
+    }
+
+    // sdists also ship tests, build scripts and the like, so only modules of packages the wheel
+    // installs are expected in the wheel
+    let top_level = wheel_files
+        .keys()
+        .filter(|module| is_python_source(module) && module.components().count() > 1)
+        .filter_map(|module| module.components().next())
+        .collect::<HashSet<_>>();
+    for (module, (path, _)) in &sdist_files {
+        let in_wheel_package = module
+            .components()
+            .next()
+            .is_some_and(|package| top_level.contains(&package));
+        if is_python_source(module) && in_wheel_package && !wheel_files.contains_key(module) {
+            findings.push(finding(
+                sdist_name,
+                path,
+                FindingKind::OnlyInSdist {
+                    wheel: wheel_name.to_owned(),
+                },
+            ));
+        }
+    }
+
+    if !only_in_wheel.is_empty() || !differs_from_sdist.is_empty() {
+        findings.push(finding(
+            wheel_name,
+            Path::new(""),
+            FindingKind::DistributionMismatch {
+                sdist: sdist_name.to_owned(),
+                only_in_wheel,
+                differs_from_sdist,
+            },
+        ));
+    }
+
+    findings
+}
+
+/// Compare every sdist of a package against every wheel
+pub fn correlate(distributions: &[DistributionScanResults]) -> Vec<Finding> {
+    let named = distributions
+        .iter()
+        .filter_map(|distribution| Some((distribution.file_name()?, &distribution.digests)))
+        .collect::<Vec<_>>();
+    let (wheels, sdists): (Vec<_>, Vec<_>) = named
+        .into_iter()
+        .partition(|(name, _)| has_extension(name, "whl"));
+
+    sdists
+        .iter()
+        .flat_map(|sdist| wheels.iter().flat_map(move |wheel| compare(*sdist, *wheel)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare, sdist_module_path, wheel_module_path, Digests};
+    use crate::analyzers::FindingKind;
+    use std::path::{Path, PathBuf};
+
+    fn digests(files: &[(&str, u8)]) -> Digests {
+        files
+            .iter()
+            .map(|(path, digest)| (PathBuf::from(path), [*digest; 32]))
+            .collect()
+    }
+
+    #[test]
+    fn normalizes_module_paths() {
+        assert_eq!(
+            sdist_module_path(Path::new("pkg-1.0/src/pkg/__init__.py")),
+            Some(PathBuf::from("pkg/__init__.py"))
+        );
+        assert_eq!(
+            wheel_module_path(Path::new("pkg-1.0.data/purelib/pkg/a.py")),
+            Some(PathBuf::from("pkg/a.py"))
+        );
+        assert_eq!(
+            wheel_module_path(Path::new("pkg-1.0.dist-info/METADATA")),
+            None
+        );
+    }
+
+    #[test]
+    fn flags_injected_and_modified_modules() {
+        let sdist = digests(&[
+            ("pkg-1.0/setup.py", 0),
+            ("pkg-1.0/tests/test_pkg.py", 0),
+            ("pkg-1.0/pkg/__init__.py", 1),
+            ("pkg-1.0/pkg/core.py", 2),
+            ("pkg-1.0/pkg/removed.py", 3),
+            ("pkg-1.0/pkg/data.json", 5),
+        ]);
+        let wheel = digests(&[
+            ("pkg/__init__.py", 1),
+            ("pkg/core.py", 9),
+            ("pkg/_hook.py", 4),
+            ("pkg/data.json", 5),
+            ("pkg/_speedups.so", 6),
+            ("pkg-1.0.dist-info/RECORD", 7),
+        ]);
+
+        let findings = compare(
+            ("pkg-1.0.tar.gz", &sdist),
+            ("pkg-1.0-py3-none-any.whl", &wheel),
+        )
+        .into_iter()
+        .map(|finding| (finding.path, finding.kind))
+        .collect::<Vec<_>>();
+        let sdist_name = String::from("pkg-1.0.tar.gz");
+        assert_eq!(
+            findings,
+            vec![
+                (
+                    String::from("pkg/_hook.py"),
+                    FindingKind::OnlyInWheel {
+                        sdist: sdist_name.clone()
+                    }
+                ),
+                (
+                    String::from("pkg/core.py"),
+                    FindingKind::DiffersFromSdist {
+                        sdist: sdist_name.clone()
+                    }
+                ),
+                (
+                    String::from("pkg-1.0/pkg/removed.py"),
+                    FindingKind::OnlyInSdist {
+                        wheel: String::from("pkg-1.0-py3-none-any.whl")
+                    }
+                ),
+                (
+                    String::new(),
+                    FindingKind::DistributionMismatch {
+                        sdist: sdist_name,
+                        only_in_wheel: vec![
+                            String::from("pkg/_hook.py"),
+                            String::from("pkg/_speedups.so")
+                        ],
+                        differs_from_sdist: vec![String::from("pkg/core.py")],
+                    }
+                ),
+            ]
+        );
+    }
+}
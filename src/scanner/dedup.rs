@@ -0,0 +1,51 @@
+//! Matching the files shared by the distributions of a job against the rules once.
+//!
+//! A release often ships a dozen platform wheels whose pure-Python files are identical. With
+//! `deduplicate_files`, the results of every file matched against the rules are kept for the rest
+//! of the job, and a file at the same path with the same contents in another distribution reuses
+//! them instead of being matched again. It's still analyzed and correlated like any other file,
+//! only the matching is skipped. Files that timed out or were only analyzed aren't kept.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+
+use super::FileScanResult;
+
+/// What matching a file against the rules gave, to be reused by its duplicates
+#[derive(Debug)]
+pub struct Entry {
+    pub result: FileScanResult,
+
+    /// The quarantine rules the file matched, so its duplicates are quarantined too
+    pub quarantine_rules: Vec<String>,
+}
+
+/// The path of a file and the SHA-256 digest of its contents
+type Key = (PathBuf, [u8; 32]);
+
+/// The files matched so far during a job, by path and SHA-256 digest of their contents
+#[derive(Debug, Default)]
+pub struct Duplicates {
+    entries: Mutex<HashMap<Key, Arc<Entry>>>,
+}
+
+impl Duplicates {
+    /// The results of a file at `path` with the same `contents` matched earlier, if there was one
+    pub fn get(&self, path: &Path, contents: &[u8]) -> Option<Arc<Entry>> {
+        let key = (path.to_path_buf(), Sha256::digest(contents).into());
+        self.entries.lock().get(&key).cloned()
+    }
+
+    /// Keep the results of matching the file at `path` with the given `digest`
+    pub fn insert(&self, path: &Path, digest: [u8; 32], entry: Entry) {
+        self.entries
+            .lock()
+            .insert((path.to_path_buf(), digest), Arc::new(entry));
+    }
+}
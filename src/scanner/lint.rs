@@ -0,0 +1,267 @@
+//! Checking rule files for the conventions scoring relies on, for `rules lint`.
+//!
+//! YARA accepts rules that this client can't make sense of: a rule without an integer `weight`
+//! silently scores `default_rule_weight`, and a malformed `filetype` restricts a rule to files it
+//! wasn't meant for. Rule identifiers defined in several files make the whole ruleset fail to
+//! compile, so they're found in the sources before the ruleset is compiled.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Display},
+};
+
+use color_eyre::Result;
+use yara::{Compiler, Metadata, MetadataValue, Rules};
+
+/// A problem with a rule file, or with the ruleset as a whole
+#[derive(Debug, PartialEq, Eq)]
+pub struct Issue {
+    /// The rule file the problem is in, if it's known
+    pub file: Option<String>,
+
+    /// The rule the problem is with, if it's about a single rule
+    pub rule: Option<String>,
+    pub message: String,
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{file}: ")?;
+        }
+        if let Some(rule) = &self.rule {
+            write!(f, "rule {rule}: ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// `source` with its comments and string literals blanked out, so the words in them aren't
+/// mistaken for rule declarations
+fn strip_comments_and_strings(source: &str) -> String {
+    let mut stripped = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '/' if chars.peek() == Some(&'/') => {
+                chars.find(|c| *c == '\n');
+                stripped.push('\n');
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = ' ';
+                chars.find(|c| std::mem::replace(&mut previous, *c) == '*' && *c == '/');
+                stripped.push(' ');
+            }
+            '"' => {
+                let mut escaped = false;
+                chars.find(|c| {
+                    let closing = !escaped && *c == '"';
+                    escaped = !escaped && *c == '\\';
+                    closing
+                });
+                stripped.push(' ');
+            }
+            c => stripped.push(c),
+        }
+    }
+    stripped
+}
+
+/// The identifiers of the rules declared in `source`
+fn rule_identifiers(source: &str) -> Vec<String> {
+    let stripped = strip_comments_and_strings(source);
+    let words = stripped
+        .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>();
+
+    words
+        .windows(2)
+        .filter(|pair| pair[0] == "rule")
+        .map(|pair| pair[1].to_owned())
+        .collect()
+}
+
+/// The value of the metadata `key` of a rule
+fn metadata_value<'a, 'r>(
+    metadatas: &'a [Metadata<'r>],
+    key: &str,
+) -> Option<&'a MetadataValue<'r>> {
+    metadatas
+        .iter()
+        .find(|metadata| metadata.identifier == key)
+        .map(|metadata| &metadata.value)
+}
+
+/// The problems with the `metadatas` of a rule
+fn lint_rule(metadatas: &[Metadata], default_weight: i64) -> Vec<String> {
+    let mut problems = Vec::new();
+    match metadata_value(metadatas, "weight") {
+        Some(MetadataValue::Integer(_)) => {}
+        Some(_) => problems.push(format!(
+            "`weight` isn't an integer, so it's ignored and the rule scores the default weight of {default_weight}"
+        )),
+        None => problems.push(format!(
+            "no `weight`, so the rule scores the default weight of {default_weight}"
+        )),
+    }
+
+    match metadata_value(metadatas, "filetype") {
+        None => {}
+        Some(MetadataValue::String(filetype)) => {
+            if filetype.split(' ').any(str::is_empty) {
+                problems.push(format!(
+                    "`filetype` {filetype:?} has empty entries, which match every file: separate file types with single spaces"
+                ));
+            }
+        }
+        Some(_) => problems.push(String::from(
+            "`filetype` isn't a string of space-separated file types, so it's ignored",
+        )),
+    }
+
+    match metadata_value(metadatas, "max_filesize") {
+        None | Some(MetadataValue::Integer(1..)) => {}
+        Some(_) => problems.push(String::from(
+            "`max_filesize` isn't a positive integer, so the rule is evaluated against every file",
+        )),
+    }
+
+    problems
+}
+
+fn compile(source: &str) -> Result<Rules> {
+    Ok(Compiler::new()?.add_rules_str(source)?.compile_rules()?)
+}
+
+/// Lint the rule files `sources`, by name. Rules without a valid `weight` score `default_weight`.
+pub fn lint(sources: &HashMap<String, String>, default_weight: i64) -> Vec<Issue> {
+    let mut files = sources.keys().collect::<Vec<_>>();
+    files.sort_unstable();
+
+    let mut declared: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for file in &files {
+        for identifier in rule_identifiers(&sources[*file]) {
+            declared.entry(identifier).or_default().push(file);
+        }
+    }
+
+    let mut issues = declared
+        .iter()
+        .filter(|(_, files)| files.len() > 1)
+        .map(|(identifier, files)| Issue {
+            file: None,
+            rule: Some(identifier.clone()),
+            message: format!("defined more than once, in {}", files.join(", ")),
+        })
+        .collect::<Vec<_>>();
+    if !issues.is_empty() {
+        return issues;
+    }
+
+    let source = files
+        .iter()
+        .map(|file| sources[*file].as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let rules = match compile(&source) {
+        Ok(rules) => rules,
+        Err(err) => {
+            issues.push(Issue {
+                file: None,
+                rule: None,
+                message: format!("the ruleset doesn't compile: {err}"),
+            });
+            return issues;
+        }
+    };
+
+    for rule in rules.get_rules() {
+        let file = declared
+            .get(rule.identifier)
+            .and_then(|files| files.first())
+            .map(|file| (*file).to_owned());
+        issues.extend(
+            lint_rule(&rule.metadatas, default_weight)
+                .into_iter()
+                .map(|message| Issue {
+                    file: file.clone(),
+                    rule: Some(rule.identifier.to_owned()),
+                    message,
+                }),
+        );
+    }
+
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lint, rule_identifiers};
+    use std::collections::HashMap;
+
+    #[test]
+    fn finds_rule_declarations() {
+        let source = r#"
+            // rule commented_out { condition: true }
+            private rule helper { condition: true }
+            /* rule also_commented_out */
+            rule main { meta: description = "a rule named \"rule fake\"" condition: helper }
+        "#;
+        assert_eq!(rule_identifiers(source), ["helper", "main"]);
+    }
+
+    #[test]
+    fn lints_rule_metadata() {
+        let sources = HashMap::from([
+            (
+                String::from("a.yar"),
+                String::from(
+                    r#"
+                    rule weighted { meta: weight = 1 filetype = ".py .pth" condition: true }
+                    rule unweighted { meta: filetype = ".py  .pth" condition: true }
+                    rule gated { meta: weight = 1 max_filesize = "1MB" condition: true }
+                    "#,
+                ),
+            ),
+            (
+                String::from("b.yar"),
+                String::from(
+                    r#"rule string_weight { meta: weight = "1" filetype = 3 condition: true }"#,
+                ),
+            ),
+        ]);
+
+        let issues = lint(&sources, 0)
+            .into_iter()
+            .map(|issue| (issue.file.unwrap(), issue.rule.unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            issues,
+            [
+                ("a.yar".into(), "unweighted".into()),
+                ("a.yar".into(), "unweighted".into()),
+                ("a.yar".into(), "gated".into()),
+                ("b.yar".into(), "string_weight".into()),
+                ("b.yar".into(), "string_weight".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_duplicate_identifiers() {
+        let rule = String::from("rule same { meta: weight = 1 condition: true }");
+        let sources = HashMap::from([
+            (String::from("a.yar"), rule.clone()),
+            (String::from("b.yar"), rule),
+        ]);
+
+        let issues = lint(&sources, 0);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(
+            issues[0].to_string(),
+            "rule same: defined more than once, in a.yar, b.yar"
+        );
+    }
+}
@@ -0,0 +1,135 @@
+//! Matching files against the rules with the scan settings of the configuration.
+//!
+//! Every file gets `yara_timeout` seconds to be matched. A file that takes longer is reported with
+//! a `scan_timeout` finding instead of failing the whole distribution, so a single pathological
+//! file (or rule) doesn't hide what the others matched. With `yara_fast_mode`, YARA stops looking
+//! for a string once it's found it.
+//!
+//! `max_matches_per_rule` caps how many matches of a rule are kept. The cap is applied as each rule
+//! is reported matching, before the next one is, so the matches the client keeps for a file are
+//! bounded by the number of rules it matched. YARA itself still records every match of a string
+//! (up to its own limit) until the file is scanned, which the cap doesn't bound.
+
+use yara::{errors::YaraErrorKind, CallbackMsg, CallbackReturn, Rule, Rules, ScanFlags, YaraError};
+
+use super::CANARY_NAMESPACE;
+use crate::APP_CONFIG;
+
+/// How files are matched against the rules
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    /// How many seconds matching a single file may take, 0 for no limit
+    pub timeout: u32,
+
+    /// Whether to stop looking for a string once it's found
+    pub fast_mode: bool,
+
+    /// How many matches of a rule are kept, over all of its strings, 0 for no limit
+    pub max_matches_per_rule: usize,
+}
+
+impl Settings {
+    pub fn from_config() -> Self {
+        Self {
+            timeout: APP_CONFIG.load().yara_timeout,
+            fast_mode: APP_CONFIG.load().yara_fast_mode,
+            max_matches_per_rule: APP_CONFIG.load().max_matches_per_rule,
+        }
+    }
+}
+
+/// What matching a file against the rules gave
+#[derive(Debug)]
+pub enum Outcome<'r> {
+    /// The rules that matched the file
+    Matched(Vec<Rule<'r>>),
+
+    /// Matching the file took longer than the timeout
+    TimedOut,
+}
+
+/// Match `contents` against `rules` with the given `settings`
+pub fn scan<'r>(
+    rules: &'r Rules,
+    contents: &[u8],
+    settings: Settings,
+) -> Result<Outcome<'r>, YaraError> {
+    let mut scanner = rules.scanner()?;
+    scanner.set_timeout(i32::try_from(settings.timeout).unwrap_or(i32::MAX));
+    if settings.fast_mode {
+        scanner.set_flags(ScanFlags::FAST_MODE);
+    }
+
+    let mut matches = Vec::new();
+    let scanned = scanner.scan_mem_callback(contents, |message| {
+        match message {
+            // the self-test rule isn't reported, see [`super::canary`]
+            CallbackMsg::RuleMatching(rule) if rule.namespace == CANARY_NAMESPACE => {}
+            CallbackMsg::RuleMatching(mut rule) => {
+                if settings.max_matches_per_rule > 0 {
+                    cap(&mut rule, settings.max_matches_per_rule);
+                }
+                matches.push(rule);
+            }
+            _ => {}
+        }
+        CallbackReturn::Continue
+    });
+
+    match scanned {
+        Ok(()) => Ok(Outcome::Matched(matches)),
+        Err(YaraError {
+            kind: YaraErrorKind::ScanTimeout,
+        }) => Ok(Outcome::TimedOut),
+        Err(err) => Err(err),
+    }
+}
+
+/// Keep the first `max` matches of `rule`, in the order of its strings
+fn cap(rule: &mut Rule, max: usize) {
+    let mut left = max;
+    for string in &mut rule.strings {
+        string.matches.truncate(left);
+        left -= string.matches.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{scan, Outcome, Settings};
+    use yara::Compiler;
+
+    #[test]
+    fn caps_the_matches_of_rules() {
+        let rules = Compiler::new()
+            .unwrap()
+            .add_rules_str(r#"rule a { strings: $a = "a" $b = "b" condition: any of them }"#)
+            .unwrap()
+            .compile_rules()
+            .unwrap();
+        let settings = Settings {
+            timeout: 10,
+            fast_mode: false,
+            max_matches_per_rule: 3,
+        };
+
+        let Outcome::Matched(matches) = scan(&rules, b"aabbb", settings).unwrap() else {
+            panic!("expected the rule to match");
+        };
+        let counts = matches[0]
+            .strings
+            .iter()
+            .map(|string| string.matches.len())
+            .collect::<Vec<_>>();
+        assert_eq!(counts, [2, 1]);
+
+        let unlimited = Settings {
+            max_matches_per_rule: 0,
+            ..settings
+        };
+        let Outcome::Matched(matches) = scan(&rules, b"aabbb", unlimited).unwrap() else {
+            panic!("expected the rule to match");
+        };
+        assert_eq!(matches[0].strings[1].matches.len(), 3);
+    }
+}
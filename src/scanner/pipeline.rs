@@ -0,0 +1,311 @@
+//! Scanning the files of a distribution on several threads, so a huge distribution isn't read and
+//! matched against the rules one file at a time.
+//!
+//! A walker thread lists the extracted files and hands them to `scan_threads` scanner threads,
+//! which read them and match them against the shared [`Rules`]. The calling thread merges each
+//! file into the [`DistributionScan`] as soon as it's matched, applying the limits and running the
+//! analyzers just like a single thread does. The files end up in the results in the order they
+//! were matched in. Files that can't be read or matched are recorded as skipped, like they are on a
+//! single thread. Files matched earlier in the job aren't matched again, see [`super::dedup`].
+//!
+//! Once `max_files` files are merged, the scanner threads stop reading files, and the rest are
+//! only counted as left out.
+
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use parking_lot::Mutex;
+use yara::Rules;
+
+use super::{
+    dedup::{Duplicates, Entry},
+    filter::{FileTypes, Filter, IgnoreList},
+    match_file, matching, Distribution, DistributionScan, Outcome, Walked,
+};
+use crate::app_config::OversizedFilePolicy;
+
+/// How many files each scanner thread may read ahead of the merging thread
+const QUEUE_DEPTH: usize = 2;
+
+/// The most files of a distribution held in memory at once with `threads` scanner threads: one
+/// being read by each thread, the ones queued for merging and the one being merged
+pub fn files_in_flight(threads: usize) -> usize {
+    if threads > 1 {
+        threads * (QUEUE_DEPTH + 1) + 1
+    } else {
+        1
+    }
+}
+
+/// A file read and matched against the rules by a scanner thread
+struct Scanned<'r> {
+    size: u64,
+    contents: Vec<u8>,
+    matches: Option<Outcome<'r>>,
+
+    /// The results of a duplicate matched earlier in the job, in which case it isn't matched again
+    duplicate: Option<Arc<Entry>>,
+}
+
+impl Scanned<'_> {
+    /// A file that wasn't read because the distribution is full, see [`DistributionScan::is_full`]
+    fn left_out() -> Self {
+        Self {
+            size: 0,
+            contents: Vec::new(),
+            matches: None,
+            duplicate: None,
+        }
+    }
+}
+
+/// How the scanner threads read and match files, taken from the [`DistributionScan`]
+struct Reader<'r> {
+    rules: &'r Rules,
+    filter: Filter,
+    size_gate: Option<u64>,
+    match_rules: bool,
+    settings: matching::Settings,
+    max_file_size: u64,
+    skip_oversized: bool,
+    duplicates: Option<&'r Duplicates>,
+}
+
+/// Scan the files of `distribution` into `scan` with `threads` scanner threads, see the module
+/// docs. Returns the files deferred by `filetypes`, which are left to the caller.
+pub(super) fn scan<'r>(
+    distribution: &Distribution,
+    scan: &mut DistributionScan<'r>,
+    ignored: &IgnoreList,
+    filetypes: Option<&FileTypes>,
+    threads: usize,
+) -> Result<Vec<PathBuf>> {
+    let (path_sender, path_receiver) = mpsc::sync_channel::<PathBuf>(threads * QUEUE_DEPTH);
+    // dropped along with the last scanner thread, so the walker stops if they all do
+    let path_receiver = Arc::new(Mutex::new(path_receiver));
+    let (file_sender, file_receiver) =
+        mpsc::sync_channel::<(PathBuf, Result<Scanned<'r>>)>(threads * QUEUE_DEPTH);
+    // set once `max_files` files are merged, so the rest aren't read
+    let full = AtomicBool::new(scan.is_full());
+    let full = &full;
+    let reader = Reader {
+        rules: scan.rules,
+        filter: Filter::from_config(),
+        size_gate: scan.size_gate,
+        match_rules: scan.match_rules,
+        settings: scan.match_settings,
+        max_file_size: scan.max_file_size,
+        skip_oversized: scan.oversized_file_policy == OversizedFilePolicy::Skip,
+        duplicates: scan.duplicates.filter(|_| scan.match_rules),
+    };
+    let reader = &reader;
+
+    thread::scope(|scope| -> Result<Vec<PathBuf>> {
+        let walker = scope.spawn(move || -> Result<(Vec<PathBuf>, usize)> {
+            let mut deferred = Vec::new();
+            let mut ignored_files = 0;
+            for entry in distribution.files() {
+                match distribution.walk(ignored, filetypes, entry)? {
+                    Walked::Scan(path) => {
+                        if path_sender.send(path).is_err() {
+                            break;
+                        }
+                    }
+                    Walked::Defer(path) => deferred.push(path),
+                    Walked::Ignore => ignored_files += 1,
+                }
+            }
+            Ok((deferred, ignored_files))
+        });
+
+        for _ in 0..threads {
+            let path_receiver = Arc::clone(&path_receiver);
+            let file_sender = file_sender.clone();
+            scope.spawn(move || loop {
+                let Ok(path) = path_receiver.lock().recv() else {
+                    break;
+                };
+                // the path of files that can't be made relative is still worth reporting
+                let relative = distribution
+                    .relative_to_archive_root(&path)
+                    .unwrap_or_else(|_| path.clone());
+                let scanned = if full.load(Ordering::Relaxed) {
+                    Ok(Scanned::left_out())
+                } else {
+                    reader.read_and_match(distribution, &path)
+                };
+                if file_sender.send((relative, scanned)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(path_receiver);
+        drop(file_sender);
+
+        let merged = file_receiver
+            .iter()
+            .try_for_each(|(path, scanned)| -> Result<()> {
+                let recorded = scanned.and_then(|scanned| {
+                    if !scan.admit(&path, scanned.size)? {
+                        return Ok(());
+                    }
+                    match scanned.duplicate {
+                        Some(duplicate) => {
+                            scan.record_duplicate(&path, &scanned.contents, &duplicate)?;
+                        }
+                        None => scan.record_file(&path, &scanned.contents, scanned.matches)?,
+                    }
+                    Ok(())
+                });
+                full.store(scan.is_full(), Ordering::Relaxed);
+                match recorded {
+                    Ok(()) => Ok(()),
+                    Err(err) => scan.skip_failed(&path, err),
+                }
+            });
+        // stops the scanner threads, and through them the walker, if merging failed
+        drop(file_receiver);
+        merged?;
+
+        let (deferred, ignored_files) = walker
+            .join()
+            .map_err(|_| eyre!("The walker thread panicked"))??;
+        scan.measurements.ignored_files += ignored_files;
+        Ok(deferred)
+    })
+}
+
+impl<'r> Reader<'r> {
+    /// Read the extracted file at `path` of `distribution` and match it against the rules, see
+    /// [`match_file`], unless a duplicate of it was. Oversized files that are skipped aren't read
+    /// at all.
+    fn read_and_match(&self, distribution: &Distribution, path: &Path) -> Result<Scanned<'r>> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let relative = distribution.relative_to_archive_root(path)?;
+
+        let mut contents = Vec::new();
+        if size <= self.max_file_size || !self.skip_oversized {
+            file.take(self.max_file_size).read_to_end(&mut contents)?;
+        }
+        let duplicate = self
+            .duplicates
+            .and_then(|duplicates| duplicates.get(&relative, &contents));
+        if duplicate.is_some() {
+            return Ok(Scanned {
+                size,
+                contents,
+                matches: None,
+                duplicate,
+            });
+        }
+        let matches = match_file(
+            self.rules,
+            &self.filter,
+            self.size_gate,
+            self.match_rules,
+            self.settings,
+            &relative,
+            &contents,
+        )?;
+
+        Ok(Scanned {
+            size,
+            contents,
+            matches,
+            duplicate: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::scan;
+    use crate::scanner::{filter::IgnoreList, Distribution, DistributionScan};
+    use std::fs;
+    use tempfile::tempdir;
+    use yara::Compiler;
+
+    #[test]
+    fn scans_files_on_several_threads() {
+        let rules = Compiler::new()
+            .unwrap()
+            .add_rules_str(
+                r#"rule exec { meta: weight = 2 strings: $exec = "exec(" condition: $exec }"#,
+            )
+            .unwrap()
+            .compile_rules()
+            .unwrap();
+        let dir = tempdir().unwrap();
+        for i in 0..40 {
+            let contents = if i % 4 == 0 {
+                "exec(payload)"
+            } else {
+                "print(1)"
+            };
+            fs::write(dir.path().join(format!("module_{i}.py")), contents).unwrap();
+        }
+        let distribution = Distribution {
+            dir,
+            inspector_url: "https://example.com/".parse().unwrap(),
+        };
+
+        let mut results = DistributionScan::new(&rules);
+        let deferred = scan(
+            &distribution,
+            &mut results,
+            &IgnoreList::from_config(),
+            None,
+            4,
+        )
+        .unwrap();
+
+        assert!(deferred.is_empty());
+        assert_eq!(results.measurements.files, 40);
+        assert_eq!(results.file_scan_results.len(), 40);
+        let results = results.finish("https://example.com/".parse().unwrap());
+        assert_eq!(results.get_total_score(), 2);
+    }
+
+    #[test]
+    fn stops_reading_files_once_full() {
+        let rules = Compiler::new()
+            .unwrap()
+            .add_rules_str("rule always { condition: true }")
+            .unwrap()
+            .compile_rules()
+            .unwrap();
+        let dir = tempdir().unwrap();
+        for i in 0..40 {
+            fs::write(dir.path().join(format!("module_{i}.py")), "print(1)").unwrap();
+        }
+        let distribution = Distribution {
+            dir,
+            inspector_url: "https://example.com/".parse().unwrap(),
+        };
+
+        let mut results = DistributionScan::new(&rules);
+        results.max_files = 5;
+        scan(
+            &distribution,
+            &mut results,
+            &IgnoreList::from_config(),
+            None,
+            4,
+        )
+        .unwrap();
+
+        assert_eq!(results.measurements.files, 5);
+        assert_eq!(results.file_scan_results.len(), 5);
+        assert_eq!(results.unscanned_files, 35);
+    }
+}
@@ -0,0 +1,226 @@
+//! Sampled per-rule scan timing, to find the rules that are pathologically slow on some files.
+//!
+//! YARA only reports what matched, not how long each rule took. So with a
+//! `rule_profiling_sample_rate` above 0, every rule file of the ruleset is also compiled on its own,
+//! and that fraction of the scanned files is scanned again against each of them separately. Every
+//! `rule_profiling_report_interval` seconds the `rule_profiling_top` rule files with the highest
+//! mean scan time are logged and emitted as a `hot_rules` event, and the timings start over.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use color_eyre::Result;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::Serialize;
+use tracing::{debug, info};
+use yara::{Compiler, Rules};
+
+use crate::{
+    client::RulesState,
+    events::{self, Event},
+    APP_CONFIG,
+};
+
+/// The scan timeout of a single rule file, in seconds
+const TIMEOUT: i32 = 10;
+
+#[derive(Debug, Default)]
+struct Timing {
+    scans: u32,
+    total: Duration,
+    slowest: Duration,
+    slowest_file: String,
+}
+
+/// A rule file that's slow to scan with
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct HotRule {
+    pub file: String,
+    pub scans: u32,
+    pub mean_us: u128,
+    pub slowest_ms: u128,
+    pub slowest_file: String,
+}
+
+struct Profiler {
+    hash: String,
+
+    /// Each rule file of the ruleset, compiled on its own
+    rule_files: Arc<Vec<(String, Rules)>>,
+    timings: HashMap<String, Timing>,
+}
+
+impl Profiler {
+    fn record(&mut self, rule_file: &str, path: &Path, elapsed: Duration) {
+        let timing = self.timings.entry(rule_file.to_owned()).or_default();
+        timing.scans += 1;
+        timing.total += elapsed;
+        if elapsed > timing.slowest {
+            timing.slowest = elapsed;
+            timing.slowest_file = path.to_string_lossy().into_owned();
+        }
+    }
+
+    /// The `n` rule files with the highest mean scan time, slowest first
+    fn hot_rules(&self, n: usize) -> Vec<HotRule> {
+        let mut hot_rules = self
+            .timings
+            .iter()
+            .map(|(file, timing)| HotRule {
+                file: file.clone(),
+                scans: timing.scans,
+                mean_us: (timing.total / timing.scans.max(1)).as_micros(),
+                slowest_ms: timing.slowest.as_millis(),
+                slowest_file: timing.slowest_file.clone(),
+            })
+            .collect::<Vec<_>>();
+        hot_rules.sort_unstable_by(|a, b| b.mean_us.cmp(&a.mean_us).then(a.file.cmp(&b.file)));
+        hot_rules.truncate(n);
+        hot_rules
+    }
+}
+
+fn compile(source: &str) -> Result<Rules> {
+    Ok(Compiler::new()?.add_rules_str(source)?.compile_rules()?)
+}
+
+static PROFILER: Lazy<Mutex<Option<Profiler>>> = Lazy::new(|| Mutex::new(None));
+
+/// Compile the rule files of a freshly installed ruleset one by one for profiling, if profiling
+/// is enabled. Rule files that don't compile on their own aren't profiled.
+pub fn profile_ruleset(state: &RulesState) {
+    if APP_CONFIG.load().rule_profiling_sample_rate <= 0.0 {
+        return;
+    }
+    if PROFILER
+        .lock()
+        .as_ref()
+        .is_some_and(|profiler| profiler.hash == state.hash)
+    {
+        return;
+    }
+
+    let rule_files = state
+        .sources
+        .iter()
+        .filter_map(|(name, source)| match compile(source) {
+            Ok(rules) => Some((name.clone(), rules)),
+            Err(err) => {
+                debug!("Not profiling {name}, it doesn't compile on its own: {err}");
+                None
+            }
+        })
+        .collect();
+
+    *PROFILER.lock() = Some(Profiler {
+        hash: state.hash.clone(),
+        rule_files: Arc::new(rule_files),
+        timings: HashMap::new(),
+    });
+}
+
+/// Scan a sample of the files against each rule file separately, timing every scan
+pub fn sample(path: &Path, contents: &[u8]) {
+    let rate = APP_CONFIG.load().rule_profiling_sample_rate;
+    if rate <= 0.0 || !rand::thread_rng().gen_bool(rate.min(1.0)) {
+        return;
+    }
+    let Some(rule_files) = PROFILER
+        .lock()
+        .as_ref()
+        .map(|profiler| Arc::clone(&profiler.rule_files))
+    else {
+        return;
+    };
+
+    let timings = rule_files
+        .iter()
+        .map(|(name, rules)| {
+            let start = Instant::now();
+            let _ = rules.scan_mem(contents, TIMEOUT);
+            (name, start.elapsed())
+        })
+        .collect::<Vec<_>>();
+
+    if let Some(profiler) = PROFILER.lock().as_mut() {
+        for (name, elapsed) in timings {
+            profiler.record(name, path, elapsed);
+        }
+    }
+}
+
+/// Log and emit the slowest rule files if `rule_profiling_report_interval` has passed since
+/// `last_report`, and start the timings over
+pub fn report_hot_rules(last_report: &mut Instant) {
+    let interval = Duration::from_secs(APP_CONFIG.load().rule_profiling_report_interval);
+    if interval.is_zero() || last_report.elapsed() < interval {
+        return;
+    }
+    *last_report = Instant::now();
+
+    let mut profiler = PROFILER.lock();
+    let Some(profiler) = profiler.as_mut() else {
+        return;
+    };
+    let hot_rules = profiler.hot_rules(APP_CONFIG.load().rule_profiling_top);
+    profiler.timings.clear();
+    if hot_rules.is_empty() {
+        return;
+    }
+
+    for rule in &hot_rules {
+        info!(
+            "Rule file {} took {}µs per file on average over {} files, at most {}ms on {}",
+            rule.file, rule.mean_us, rule.scans, rule.slowest_ms, rule.slowest_file
+        );
+    }
+    events::emit(&Event::HotRules {
+        rules_hash: &profiler.hash,
+        rules: &hot_rules,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HotRule, Profiler};
+    use std::{collections::HashMap, path::Path, sync::Arc, time::Duration};
+
+    #[test]
+    fn ranks_rule_files_by_mean_scan_time() {
+        let mut profiler = Profiler {
+            hash: String::from("abc"),
+            rule_files: Arc::new(Vec::new()),
+            timings: HashMap::new(),
+        };
+        profiler.record("fast.yar", Path::new("a.py"), Duration::from_micros(10));
+        profiler.record("slow.yar", Path::new("a.py"), Duration::from_micros(100));
+        profiler.record("slow.yar", Path::new("b.js"), Duration::from_micros(300));
+        profiler.record("medium.yar", Path::new("a.py"), Duration::from_micros(50));
+
+        assert_eq!(
+            profiler.hot_rules(2),
+            [
+                HotRule {
+                    file: String::from("slow.yar"),
+                    scans: 2,
+                    mean_us: 200,
+                    slowest_ms: 0,
+                    slowest_file: String::from("b.js"),
+                },
+                HotRule {
+                    file: String::from("medium.yar"),
+                    scans: 1,
+                    mean_us: 50,
+                    slowest_ms: 0,
+                    slowest_file: String::from("a.py"),
+                },
+            ]
+        );
+    }
+}
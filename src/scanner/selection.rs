@@ -0,0 +1,89 @@
+//! Deciding which rules of the ruleset count.
+//!
+//! A single noisy rule can flood the results until the ruleset is fixed. The matches of the
+//! rules listed in `rules_exclude` are dropped, and if `rules_include` isn't empty, only the
+//! matches of the rules listed there are kept. Rules are listed by their identifier, and the
+//! ruleset is still compiled as a whole, so this only applies to the matches.
+
+use tracing::warn;
+use yara::Rules;
+
+use crate::{exts::ruleset_rules, APP_CONFIG};
+
+/// Decides which rule matches are kept
+pub struct Selection {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl Selection {
+    /// The selection described by the global configuration
+    pub fn from_config() -> Self {
+        let config = APP_CONFIG.load();
+        Self {
+            include: config.rules_include.clone(),
+            exclude: config.rules_exclude.clone(),
+        }
+    }
+
+    /// Whether the matches of the rule `identifier` are kept
+    pub fn keeps(&self, identifier: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|rule| rule == identifier))
+            && !self.exclude.iter().any(|rule| rule == identifier)
+    }
+
+    /// The listed rules that aren't in `rules`, likely typos or rules since removed
+    fn unknown(&self, rules: &Rules) -> Vec<String> {
+        let known = ruleset_rules(rules);
+        self.include
+            .iter()
+            .chain(self.exclude.iter())
+            .filter(|rule| !known.iter().any(|known| known.identifier == rule.as_str()))
+            .cloned()
+            .collect()
+    }
+}
+
+/// The rules of `rules_include` and `rules_exclude` that aren't in `rules`
+pub fn unknown_rules(rules: &Rules) -> Vec<String> {
+    Selection::from_config().unknown(rules)
+}
+
+/// Log the rules of `rules_include` and `rules_exclude` that aren't in the ruleset `hash`
+pub fn report_unknown_rules(rules: &Rules, hash: &str) {
+    let unknown = unknown_rules(rules);
+    if !unknown.is_empty() {
+        warn!(
+            "Ruleset {hash} has none of the included or excluded rules {}",
+            unknown.join(", ")
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Selection;
+
+    #[test]
+    fn keeps_included_rules_that_arent_excluded() {
+        let include = vec![String::from("a"), String::from("b")];
+        let exclude = vec![String::from("b"), String::from("c")];
+
+        let everything = Selection {
+            include: Vec::new(),
+            exclude: Vec::new(),
+        };
+        assert!(everything.keeps("a") && everything.keeps("c"));
+
+        let excluding = Selection {
+            include: Vec::new(),
+            exclude: exclude.clone(),
+        };
+        assert!(excluding.keeps("a"));
+        assert!(!excluding.keeps("b") && !excluding.keeps("c"));
+
+        let both = Selection { include, exclude };
+        assert!(both.keeps("a"));
+        assert!(!both.keeps("b") && !both.keeps("c") && !both.keeps("d"));
+    }
+}
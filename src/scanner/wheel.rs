@@ -0,0 +1,470 @@
+//! Checking that the files of a wheel match its own metadata.
+//!
+//! Every wheel lists its files along with their SHA-256 digests in `*.dist-info/RECORD`, which
+//! installers verify. Tampering with a wheel after it's built (or building it by hand) tends to
+//! leave inconsistencies behind: files that aren't in the RECORD, digests that don't match, or a
+//! `METADATA` name and version that don't match the `.dist-info` directory. Console and GUI
+//! scripts in `entry_points.txt` are checked too, since they're what gets run after installing:
+//! one pointing at a module with an obfuscated name, or a module the analyzers found packed or
+//! encoded, is reported. So are native modules and binaries in a wheel whose `WHEEL` file tags it
+//! as pure Python, like `py3-none-any`, which installers put on any platform without a second look.
+//!
+//! Only wheels are checked, that is archives with a `*.dist-info/RECORD` at the root.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::analyzers::{binaries, Finding, FindingKind};
+
+/// The weight of a file missing from the RECORD
+const MISSING_FROM_RECORD_WEIGHT: i64 = 3;
+
+/// The weight of a file whose digest doesn't match the RECORD
+const HASH_MISMATCH_WEIGHT: i64 = 5;
+
+/// The weight of a `METADATA` that doesn't match the `.dist-info` directory
+const METADATA_MISMATCH_WEIGHT: i64 = 2;
+
+/// The weight of a script entry point pointing at an obfuscated module
+const SUSPICIOUS_ENTRY_POINT_WEIGHT: i64 = 4;
+
+/// The weight of native code in a wheel tagged as pure Python
+const NATIVE_CODE_IN_PURE_WHEEL_WEIGHT: i64 = 4;
+
+/// Files of the `.dist-info` directory that can't be in the RECORD
+const UNRECORDED_FILES: &[&str] = &["RECORD", "RECORD.jws", "RECORD.p7s"];
+
+/// Encode `bytes` as URL-safe base64 without padding, like digests in a RECORD
+fn urlsafe_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0_u32, |group, (i, byte)| {
+            group | u32::from(*byte) << (16 - 8 * i)
+        });
+        for i in 0..=chunk.len() {
+            encoded.push(char::from(
+                ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize],
+            ));
+        }
+    }
+
+    encoded
+}
+
+/// Normalize a distribution name the way wheel file names do
+fn normalize_name(name: &str) -> String {
+    name.to_ascii_lowercase().replace(['-', '.'], "_")
+}
+
+/// Whether a module name looks machine generated, like `lIlIIl` or `_0x1f3a`
+fn is_obfuscated_name(name: &str) -> bool {
+    let confusable = name.len() >= 4
+        && name
+            .chars()
+            .all(|c| matches!(c, 'l' | 'I' | '1' | 'O' | '0' | '_'));
+    confusable || name.starts_with("_0x")
+}
+
+/// The files of a single wheel, collected while scanning it
+#[derive(Debug, Default)]
+pub struct Contents {
+    digests: BTreeMap<PathBuf, [u8; 32]>,
+    truncated: BTreeSet<PathBuf>,
+    dist_info: Option<PathBuf>,
+    record: Option<String>,
+    metadata: Option<String>,
+    entry_points: Option<String>,
+    wheel: Option<String>,
+}
+
+impl Contents {
+    /// Record a file at the root of the distribution, returning its SHA-256 digest
+    pub fn add(&mut self, path: &Path, contents: &[u8]) -> [u8; 32] {
+        let digest = Sha256::digest(contents).into();
+        self.digests.insert(path.to_path_buf(), digest);
+
+        let mut components = path.iter();
+        let (Some(dir), Some(name), None) =
+            (components.next(), components.next(), components.next())
+        else {
+            return digest;
+        };
+        if !Path::new(dir)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("dist-info"))
+        {
+            return digest;
+        }
+
+        let slot = match name.to_str() {
+            Some("RECORD") => &mut self.record,
+            Some("METADATA") => &mut self.metadata,
+            Some("entry_points.txt") => &mut self.entry_points,
+            Some("WHEEL") => &mut self.wheel,
+            _ => return digest,
+        };
+        *slot = Some(String::from_utf8_lossy(contents).into_owned());
+        self.dist_info = Some(PathBuf::from(dir));
+
+        digest
+    }
+
+    /// Mark the file at `path` as only read in part, so its digest can't be checked
+    pub fn truncated(&mut self, path: &Path) {
+        self.truncated.insert(path.to_path_buf());
+    }
+
+    /// Check the wheel against its metadata. `findings` are the analyzer findings made in the
+    /// wheel, to tell whether entry points point at packed modules and which files are binaries.
+    pub fn check(&self, findings: &[Finding]) -> Vec<Finding> {
+        let (Some(dist_info), Some(record)) = (&self.dist_info, &self.record) else {
+            return Vec::new();
+        };
+
+        let mut results = self.check_record(dist_info, record);
+        if let Some(metadata) = &self.metadata {
+            results.extend(check_metadata(dist_info, metadata));
+        }
+        if let Some(entry_points) = &self.entry_points {
+            results.extend(check_entry_points(dist_info, entry_points, findings));
+        }
+        if self.wheel.as_deref().is_some_and(is_pure) {
+            results.extend(self.check_native_code(findings));
+        }
+
+        results
+    }
+
+    /// Report the native modules, and the binaries among the `findings`, of a pure Python wheel
+    fn check_native_code(&self, findings: &[Finding]) -> Vec<Finding> {
+        let flagged = findings
+            .iter()
+            .filter(|finding| matches!(finding.kind, FindingKind::NativeBinary { .. }))
+            .map(|finding| PathBuf::from(&finding.path))
+            .collect::<BTreeSet<_>>();
+
+        self.digests
+            .keys()
+            .filter(|path| binaries::has_native_extension(path) || flagged.contains(*path))
+            .map(|path| {
+                inconsistency(
+                    path,
+                    "native-code-in-pure-wheel",
+                    String::from("the wheel is tagged as pure Python but ships native code"),
+                    NATIVE_CODE_IN_PURE_WHEEL_WEIGHT,
+                )
+            })
+            .collect()
+    }
+
+    fn check_record(&self, dist_info: &Path, record: &str) -> Vec<Finding> {
+        let mut recorded = BTreeMap::new();
+        for line in record.lines().filter(|line| !line.trim().is_empty()) {
+            let mut fields = line.rsplitn(3, ',');
+            let (Some(_size), Some(hash), Some(path)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let path = path.trim_matches('"');
+            recorded.insert(PathBuf::from(path), hash.strip_prefix("sha256="));
+        }
+
+        let mut findings = Vec::new();
+        for (path, digest) in &self.digests {
+            let unrecorded = path.parent() == Some(dist_info)
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| UNRECORDED_FILES.contains(&name));
+            if unrecorded {
+                continue;
+            }
+
+            match recorded.get(path) {
+                None => findings.push(inconsistency(
+                    path,
+                    "missing-from-record",
+                    String::from("the file isn't listed in the RECORD"),
+                    MISSING_FROM_RECORD_WEIGHT,
+                )),
+                Some(Some(expected)) if !self.truncated.contains(path) => {
+                    let actual = urlsafe_base64(digest);
+                    if actual != *expected {
+                        findings.push(inconsistency(
+                            path,
+                            "hash-mismatch",
+                            format!(
+                                "the RECORD says sha256={expected}, the file is sha256={actual}"
+                            ),
+                            HASH_MISMATCH_WEIGHT,
+                        ));
+                    }
+                }
+                // other algorithms than sha256 aren't checked
+                Some(_) => {}
+            }
+        }
+
+        findings
+    }
+}
+
+/// Check that the scripts in `entry_points.txt` don't point at obfuscated modules, by name or
+/// by the `findings` of the analyzers
+fn check_entry_points(dist_info: &Path, entry_points: &str, findings: &[Finding]) -> Vec<Finding> {
+    let path = dist_info.join("entry_points.txt");
+    let mut results = Vec::new();
+    let mut section = "";
+
+    for line in entry_points.lines().map(str::trim) {
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            section = name.trim();
+            continue;
+        }
+        if !matches!(section, "console_scripts" | "gui_scripts") {
+            continue;
+        }
+        let Some((script, target)) = line.split_once('=') else {
+            continue;
+        };
+        let module = target.split(':').next().unwrap_or_default().trim();
+
+        let reason = if module.split('.').any(is_obfuscated_name) {
+            Some(String::from("an obfuscated module name"))
+        } else {
+            let base = module.replace('.', "/");
+            let candidates = [format!("{base}.py"), format!("{base}/__init__.py")];
+            findings
+                .iter()
+                .filter(|finding| candidates.contains(&finding.path))
+                .find_map(|finding| match finding.kind {
+                    FindingKind::PackedPython { .. } => Some("packed"),
+                    FindingKind::HighEntropyBlob { .. } => Some("high entropy"),
+                    FindingKind::InvalidPythonSyntax { .. } => Some("invalid Python"),
+                    _ => None,
+                })
+                .map(|what| format!("a module that's {what}"))
+        };
+
+        if let Some(reason) = reason {
+            results.push(inconsistency(
+                &path,
+                "suspicious-entry-point",
+                format!(
+                    "{section} `{}` points at {reason}: {}",
+                    script.trim(),
+                    target.trim()
+                ),
+                SUSPICIOUS_ENTRY_POINT_WEIGHT,
+            ));
+        }
+    }
+
+    results
+}
+
+/// Check that the name and version in `METADATA` match the `name-version.dist-info` directory
+fn check_metadata(dist_info: &Path, metadata: &str) -> Option<Finding> {
+    let field = |name: &str| {
+        metadata
+            .lines()
+            .take_while(|line| !line.is_empty())
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .map(str::trim)
+    };
+    let (name, version) = (field("Name")?, field("Version")?);
+
+    let stem = dist_info.file_stem()?.to_str()?;
+    let (dir_name, dir_version) = stem.rsplit_once('-')?;
+    if normalize_name(dir_name) == normalize_name(name) && dir_version == version {
+        return None;
+    }
+
+    let mut detail = String::new();
+    let _ = write!(
+        detail,
+        "METADATA is for {name} {version}, the wheel is for {dir_name} {dir_version}"
+    );
+    Some(inconsistency(
+        &dist_info.join("METADATA"),
+        "metadata-mismatch",
+        detail,
+        METADATA_MISMATCH_WEIGHT,
+    ))
+}
+
+/// Whether the `WHEEL` file of a wheel only tags it for any platform, like `py3-none-any`
+fn is_pure(wheel: &str) -> bool {
+    let mut tags = wheel
+        .lines()
+        .filter_map(|line| line.strip_prefix("Tag:"))
+        .map(str::trim)
+        .peekable();
+
+    tags.peek().is_some() && tags.all(|tag| tag.ends_with("-none-any"))
+}
+
+fn inconsistency(path: &Path, issue: &'static str, detail: String, weight: i64) -> Finding {
+    Finding::new(
+        path,
+        FindingKind::WheelInconsistency {
+            issue,
+            detail,
+            weight,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{urlsafe_base64, Contents};
+    use crate::analyzers::{Finding, FindingKind};
+    use sha2::{Digest, Sha256};
+    use std::path::Path;
+
+    fn issues(findings: &[Finding]) -> Vec<(&str, &'static str)> {
+        findings
+            .iter()
+            .map(|finding| match finding.kind {
+                FindingKind::WheelInconsistency { issue, .. } => (finding.path.as_str(), issue),
+                _ => panic!("unexpected finding {finding:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn encodes_record_digests() {
+        assert_eq!(urlsafe_base64(b""), "");
+        assert_eq!(urlsafe_base64(b"f"), "Zg");
+        assert_eq!(urlsafe_base64(b"fo"), "Zm8");
+        assert_eq!(urlsafe_base64(b"foo"), "Zm9v");
+        assert_eq!(urlsafe_base64(&[0xfb, 0xff]), "-_8");
+    }
+
+    #[test]
+    fn flags_files_inconsistent_with_the_record() {
+        let digest = |contents: &[u8]| urlsafe_base64(&Sha256::digest(contents));
+        let record = format!(
+            "pkg/__init__.py,sha256={},5\npkg/core.py,sha256={},4\npkg-1.0.dist-info/METADATA,,\npkg-1.0.dist-info/RECORD,,\n",
+            digest(b"hello"),
+            digest(b"core")
+        );
+
+        let mut wheel = Contents::default();
+        wheel.add(Path::new("pkg/__init__.py"), b"hello");
+        wheel.add(Path::new("pkg/core.py"), b"tampered");
+        wheel.add(Path::new("pkg/_hook.py"), b"payload");
+        wheel.add(
+            Path::new("pkg-1.0.dist-info/METADATA"),
+            b"Metadata-Version: 2.1\nName: other\nVersion: 1.0\n\nDescription",
+        );
+        wheel.add(
+            Path::new("pkg-1.0.dist-info/entry_points.txt"),
+            b"[console_scripts]\npkg = pkg.cli:main\nrun = pkg.lIlIlI:main\n",
+        );
+        wheel.add(Path::new("pkg-1.0.dist-info/RECORD"), record.as_bytes());
+
+        assert_eq!(
+            issues(&wheel.check(&[])),
+            [
+                ("pkg/_hook.py", "missing-from-record"),
+                ("pkg/core.py", "hash-mismatch"),
+                ("pkg-1.0.dist-info/entry_points.txt", "missing-from-record"),
+                ("pkg-1.0.dist-info/METADATA", "metadata-mismatch"),
+                (
+                    "pkg-1.0.dist-info/entry_points.txt",
+                    "suspicious-entry-point"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn flags_entry_points_into_packed_modules() {
+        let mut wheel = Contents::default();
+        wheel.add(
+            Path::new("pkg-1.0.dist-info/entry_points.txt"),
+            b"[console_scripts]\npkg = pkg.cli:main\n",
+        );
+        wheel.add(
+            Path::new("pkg-1.0.dist-info/RECORD"),
+            b"pkg-1.0.dist-info/entry_points.txt,,\n",
+        );
+        let packed = Finding::new(
+            Path::new("pkg/cli.py"),
+            FindingKind::PackedPython {
+                packer: "pyarmor",
+                evidence: String::new(),
+            },
+        );
+
+        let findings = wheel.check(&[packed]);
+        assert_eq!(
+            issues(&findings),
+            [(
+                "pkg-1.0.dist-info/entry_points.txt",
+                "suspicious-entry-point"
+            )]
+        );
+        assert!(Contents::default().check(&[]).is_empty());
+    }
+
+    #[test]
+    fn flags_native_code_in_pure_wheels() {
+        let mut wheel = Contents::default();
+        wheel.add(
+            Path::new("pkg-1.0.dist-info/WHEEL"),
+            b"Wheel-Version: 1.0\nRoot-Is-Purelib: true\nTag: py3-none-any\n",
+        );
+        wheel.add(Path::new("pkg-1.0.dist-info/RECORD"), b"");
+        wheel.add(Path::new("pkg/_core.cpython-312-x86_64-linux-gnu.so"), b"");
+        wheel.add(Path::new("pkg/data.bin"), b"");
+        wheel.add(Path::new("pkg/__init__.py"), b"");
+        let binary = Finding::new(
+            Path::new("pkg/data.bin"),
+            FindingKind::NativeBinary {
+                format: "elf",
+                arch: "x86_64",
+                imports_hash: None,
+                weight: 5,
+            },
+        );
+        let native_code = |findings: Vec<Finding>| {
+            findings
+                .into_iter()
+                .filter(|finding| {
+                    matches!(
+                        finding.kind,
+                        FindingKind::WheelInconsistency {
+                            issue: "native-code-in-pure-wheel",
+                            ..
+                        }
+                    )
+                })
+                .map(|finding| finding.path)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            native_code(wheel.check(&[binary])),
+            ["pkg/_core.cpython-312-x86_64-linux-gnu.so", "pkg/data.bin"]
+        );
+
+        wheel.add(
+            Path::new("pkg-1.0.dist-info/WHEEL"),
+            b"Tag: cp312-cp312-manylinux_2_17_x86_64\n",
+        );
+        assert!(native_code(wheel.check(&[])).is_empty());
+    }
+}
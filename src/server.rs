@@ -0,0 +1,307 @@
+//! A tiny blocking HTTP/1.1 server for the client's operational endpoints.
+//!
+//! It only implements what probes, local tooling and pushed jobs need: one request per connection,
+//! no keep-alive, no chunked bodies. Connections are handled on a few threads, so that a slow client
+//! doesn't hold up the others until it times out.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+/// How many connections are handled at once
+const HANDLER_THREADS: usize = 4;
+
+/// How many accepted connections can wait for a handler thread before accepting more blocks
+const BACKLOG: usize = 16;
+
+/// How long to wait on a client before giving up on the connection
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The largest request head (request line and headers) that will be read
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// The largest request body that will be read, larger ones make the request malformed
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// An incoming HTTP request
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+
+    /// The value of the `Authorization` header, if any
+    pub authorization: Option<String>,
+
+    /// The body, as long as the `Content-Length` header says
+    pub body: Vec<u8>,
+}
+
+/// An HTTP response to send back
+#[derive(Debug, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into(),
+        }
+    }
+
+    pub fn json(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.into(),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Self::text(404, "not found")
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            202 => "Accepted",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            429 => "Too Many Requests",
+            500 => "Internal Server Error",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+}
+
+/// Whether `request` carries `token` as an `Authorization: Bearer <token>` header, or there's no
+/// token to carry
+pub fn authorized(request: &Request, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    request
+        .authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| constant_time_eq(given.trim(), token))
+}
+
+/// Whether `a` and `b` are equal, in a time that doesn't depend on where they differ. Their digests
+/// are compared rather than themselves, so the time doesn't depend on their lengths either.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    Sha256::digest(a)
+        .iter()
+        .zip(Sha256::digest(b))
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+/// Bind to `addr` and serve requests with `handler` on background threads: one accepts
+/// connections, and [`HANDLER_THREADS`] handle them.
+///
+/// Returns the address the server is bound to, which is useful when binding to port 0.
+pub fn serve<A, F>(addr: A, handler: F) -> io::Result<SocketAddr>
+where
+    A: ToSocketAddrs,
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+
+    let handler = Arc::new(handler);
+    let (sender, connections) = mpsc::sync_channel::<TcpStream>(BACKLOG);
+    let connections = Arc::new(Mutex::new(connections));
+    for i in 0..HANDLER_THREADS {
+        let handler = Arc::clone(&handler);
+        let connections = Arc::clone(&connections);
+        thread::Builder::new()
+            .name(format!("http-{local_addr}-{i}"))
+            .spawn(move || loop {
+                // the lock is released as soon as a connection is received
+                let Ok(stream) = connections.lock().unwrap().recv() else {
+                    return;
+                };
+                if let Err(err) = handle_connection(stream, &*handler) {
+                    debug!("Error while handling HTTP connection: {err}");
+                }
+            })?;
+    }
+
+    thread::Builder::new()
+        .name(format!("http-{local_addr}"))
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if sender.send(stream).is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) => warn!("Failed to accept HTTP connection: {err}"),
+                }
+            }
+        })?;
+
+    Ok(local_addr)
+}
+
+fn handle_connection<F>(mut stream: TcpStream, handler: &F) -> io::Result<()>
+where
+    F: Fn(&Request) -> Response,
+{
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+
+    let response = match read_request(&stream)? {
+        Some(request) => handler(&request),
+        None => Response::text(400, "bad request"),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+    stream.flush()
+}
+
+/// Read the request from `stream`. Returns `None` if the request is malformed.
+fn read_request(stream: &TcpStream) -> io::Result<Option<Request>> {
+    let mut reader = BufReader::new(stream).take(MAX_HEAD_SIZE as u64);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // drain the headers, keeping only the ones handlers look at
+    let mut authorization = None;
+    let mut content_length = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_owned());
+            } else if name.trim().eq_ignore_ascii_case("content-length") {
+                match value.trim().parse() {
+                    Ok(length) if length <= MAX_BODY_SIZE => content_length = length,
+                    _ => return Ok(None),
+                }
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Ok(None);
+    };
+
+    let mut body = vec![0; content_length];
+    reader.set_limit(content_length as u64);
+    reader.read_exact(&mut body)?;
+
+    let path = target.split('?').next().unwrap_or(target);
+    Ok(Some(Request {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        authorization,
+        body,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{authorized, serve, Request, Response, CONNECTION_TIMEOUT};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Instant;
+
+    fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {path}?x=1 HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn routes_requests_to_handler() {
+        let addr = serve("127.0.0.1:0", |request| match request.path.as_str() {
+            "/hello" => Response::text(200, "hi"),
+            _ => Response::not_found(),
+        })
+        .unwrap();
+
+        let response = get(addr, "/hello");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nhi"));
+
+        assert!(get(addr, "/nope").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn reads_request_bodies() {
+        let addr = serve("127.0.0.1:0", |request| {
+            Response::text(200, String::from_utf8_lossy(&request.body))
+        })
+        .unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST /jobs HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello, and more"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn serves_requests_while_a_client_stalls() {
+        let addr = serve("127.0.0.1:0", |_| Response::text(200, "hi")).unwrap();
+
+        let _stalled = TcpStream::connect(addr).unwrap();
+        let start = Instant::now();
+        assert!(get(addr, "/").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(start.elapsed() < CONNECTION_TIMEOUT);
+    }
+
+    #[test]
+    fn checks_bearer_tokens() {
+        let request = |authorization: Option<&str>| Request {
+            method: String::from("GET"),
+            path: String::from("/"),
+            authorization: authorization.map(ToOwned::to_owned),
+            body: Vec::new(),
+        };
+
+        assert!(authorized(&request(None), None));
+        assert!(authorized(&request(Some("Bearer secret")), Some("secret")));
+        assert!(!authorized(&request(Some("Bearer secre")), Some("secret")));
+        assert!(!authorized(&request(Some("secret")), Some("secret")));
+        assert!(!authorized(&request(None), Some("secret")));
+    }
+}
@@ -0,0 +1,181 @@
+//! Rolling statistics of the work done by this client, for capacity planning.
+//!
+//! The amount of jobs scanned, how many of them failed, and the rulesets they were scanned with
+//! are tallied per (UTC) day and persisted to `stats_path` after every job, keeping the last
+//! `stats_retention_days` days. The `stats` command prints them, and they're optionally uploaded
+//! to the API every `stats_upload_interval` seconds.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use chrono::{NaiveDate, TimeDelta};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use tracing::warn;
+
+use crate::{build_info::BUILD_INFO, host};
+
+/// The tally of a single day
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Day {
+    /// The amount of jobs scanned, including failed ones
+    pub jobs_scanned: u64,
+
+    /// The amount of jobs that failed to scan
+    pub errors: u64,
+
+    /// The hashes of the rulesets jobs were scanned with
+    pub rulesets: BTreeSet<String>,
+}
+
+impl Day {
+    /// The fraction of jobs that failed, between 0 and 1
+    #[allow(clippy::cast_precision_loss)]
+    pub fn error_rate(&self) -> f64 {
+        if self.jobs_scanned == 0 {
+            0.0
+        } else {
+            self.errors as f64 / self.jobs_scanned as f64
+        }
+    }
+}
+
+/// The statistics as uploaded to the API
+#[derive(Debug, Serialize)]
+pub struct Report<'a> {
+    pub version: &'static str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<&'a host::Fingerprint>,
+
+    pub days: &'a BTreeMap<NaiveDate, Day>,
+}
+
+/// Per-day statistics persisted at a path
+pub struct Stats {
+    days: BTreeMap<NaiveDate, Day>,
+    retention_days: u32,
+    path: PathBuf,
+}
+
+impl Stats {
+    /// Open the statistics persisted at `path`, or start from scratch if there are none yet
+    pub fn open(path: impl Into<PathBuf>, retention_days: u32) -> Result<Self> {
+        let path = path.into();
+        let days = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|err| {
+                warn!("Discarding unreadable statistics {}: {err}", path.display());
+                BTreeMap::new()
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self {
+            days,
+            retention_days,
+            path,
+        })
+    }
+
+    /// Record a job scanned on `date` with the ruleset `rules_hash`, and persist the statistics
+    pub fn record(&mut self, date: NaiveDate, succeeded: bool, rules_hash: &str) -> Result<()> {
+        let day = self.days.entry(date).or_default();
+        day.jobs_scanned += 1;
+        if !succeeded {
+            day.errors += 1;
+        }
+        if !day.rulesets.contains(rules_hash) {
+            day.rulesets.insert(rules_hash.to_owned());
+        }
+
+        let oldest = date - TimeDelta::days(i64::from(self.retention_days.saturating_sub(1)));
+        self.days.retain(|day, _| *day >= oldest);
+
+        self.persist()
+    }
+
+    /// The statistics to upload to the API
+    pub fn report(&self) -> Report<'_> {
+        Report {
+            version: BUILD_INFO.version,
+            host: host::FINGERPRINT.as_ref(),
+            days: &self.days,
+        }
+    }
+
+    /// A human readable table of the statistics, one line per day
+    pub fn summary(&self) -> String {
+        if self.days.is_empty() {
+            return String::from("No jobs scanned yet\n");
+        }
+
+        self.days
+            .iter()
+            .fold(String::new(), |mut summary, (date, day)| {
+                let rulesets = day.rulesets.iter().cloned().collect::<Vec<_>>().join(", ");
+                let _ = writeln!(
+                    summary,
+                    "{date}  {:>6} jobs  {:>5} errors ({:.1}%)  rules: {rulesets}",
+                    day.jobs_scanned,
+                    day.errors,
+                    day.error_rate() * 100.0
+                );
+                summary
+            })
+    }
+
+    /// Atomically write the statistics to disk
+    fn persist(&self) -> Result<()> {
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(dir)?;
+
+        let mut file = NamedTempFile::new_in(dir)?;
+        serde_json::to_writer(&mut file, &self.days)?;
+        file.flush()?;
+        file.persist(&self.path)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Stats;
+    use chrono::NaiveDate;
+    use tempfile::tempdir;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 5, day).unwrap()
+    }
+
+    #[test]
+    fn tallies_and_persists_per_day() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("stats.json");
+
+        let mut stats = Stats::open(&path, 2).unwrap();
+        stats.record(date(1), true, "a").unwrap();
+        stats.record(date(2), true, "a").unwrap();
+        stats.record(date(2), false, "b").unwrap();
+
+        let mut stats = Stats::open(&path, 2).unwrap();
+        let day = &stats.days[&date(2)];
+        assert_eq!((day.jobs_scanned, day.errors), (2, 1));
+        assert!((day.error_rate() - 0.5).abs() < f64::EPSILON);
+        assert_eq!(day.rulesets.iter().collect::<Vec<_>>(), ["a", "b"]);
+
+        stats.record(date(3), true, "b").unwrap();
+        assert_eq!(
+            stats.days.keys().copied().collect::<Vec<_>>(),
+            [date(2), date(3)]
+        );
+        assert!(stats.summary().starts_with("2024-05-02"));
+    }
+}
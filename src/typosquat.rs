@@ -0,0 +1,275 @@
+//! Detection of typosquats of popular packages.
+//!
+//! A typosquat is a package named so that it's installed by mistake instead of a popular one:
+//! `reqeusts` for `requests`, or `python-dateutill` for `python-dateutil`. The name of every
+//! scanned package is compared against a list of the most downloaded packages, and a package
+//! one typo away from one of them (that isn't one of them itself) is flagged as a typosquat
+//! candidate, adding `typosquat_score` to its score.
+//!
+//! Names are compared without separators, so `pythondateutil` is one typo away from
+//! `python-dateutil`, and a typo swapping a letter for a key next to it on a QWERTY keyboard
+//! counts for half. Longer names may be a typo and a half away.
+//!
+//! The list is bundled with the client, and replaced every `top_packages_refresh_interval` by
+//! the first `top_packages_count` packages at `top_packages_url`.
+
+use color_eyre::{eyre::eyre, Result};
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use reqwest::blocking::Client;
+use serde::Deserialize;
+
+/// The list of top packages bundled with the client
+const BUNDLED: &str = include_str!("typosquat/top_packages.txt");
+
+/// The cost of an edit, in half typos
+const TYPO: usize = 2;
+
+/// The cost of substituting a character for one next to it on the keyboard
+const NEIGHBOUR_TYPO: usize = 1;
+
+/// Names shorter than this (without separators) are too short to tell typos from other names
+const MIN_NAME_LENGTH: usize = 5;
+
+/// Names at least this long (without separators) may be a typo and a half away
+const LONG_NAME_LENGTH: usize = 10;
+
+/// The rows of a QWERTY keyboard, with their offset from the left in quarter keys
+const KEYBOARD: [(&str, usize); 4] = [
+    ("1234567890", 0),
+    ("qwertyuiop", 2),
+    ("asdfghjkl", 3),
+    ("zxcvbnm", 5),
+];
+
+/// The position of a key, as its row and its offset from the left in quarter keys
+fn key_position(c: char) -> Option<(usize, usize)> {
+    KEYBOARD
+        .iter()
+        .enumerate()
+        .find_map(|(row, (keys, offset))| Some((row, offset + 4 * keys.find(c)?)))
+}
+
+/// Whether the keys of `a` and `b` touch on the keyboard
+fn are_neighbours(a: char, b: char) -> bool {
+    match (key_position(a), key_position(b)) {
+        (Some((row_a, x_a)), Some((row_b, x_b))) => {
+            row_a.abs_diff(row_b) <= 1 && x_a.abs_diff(x_b) <= 4
+        }
+        _ => false,
+    }
+}
+
+/// The edit distance between `a` and `b` in half typos, where insertions, deletions,
+/// substitutions and swaps of adjacent characters are a typo each
+fn distance(a: &[char], b: &[char]) -> usize {
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i * TYPO;
+    }
+    for j in 0..=b.len() {
+        rows[0][j] = j * TYPO;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let substitution = if a[i - 1] == b[j - 1] {
+                0
+            } else if are_neighbours(a[i - 1], b[j - 1]) {
+                NEIGHBOUR_TYPO
+            } else {
+                TYPO
+            };
+            let mut cost = (rows[i - 1][j] + TYPO)
+                .min(rows[i][j - 1] + TYPO)
+                .min(rows[i - 1][j - 1] + substitution);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                cost = cost.min(rows[i - 2][j - 2] + TYPO);
+            }
+            rows[i][j] = cost;
+        }
+    }
+
+    rows[a.len()][b.len()]
+}
+
+/// Normalize a package name like the package index does: lowercase, with runs of `-`, `_` and `.` as `-`
+fn normalize(name: &str) -> String {
+    let mut normalized = String::with_capacity(name.len());
+    for c in name.trim().chars() {
+        if matches!(c, '-' | '_' | '.') {
+            if !normalized.ends_with('-') {
+                normalized.push('-');
+            }
+        } else {
+            normalized.push(c.to_ascii_lowercase());
+        }
+    }
+
+    normalized
+}
+
+/// The characters of a normalized name, without separators
+fn letters(normalized: &str) -> Vec<char> {
+    normalized.chars().filter(|c| *c != '-').collect()
+}
+
+/// A list of popular packages, most popular first
+#[derive(Debug)]
+pub struct TopPackages {
+    /// The normalized names of the packages, and their letters
+    packages: Vec<(String, Vec<char>)>,
+}
+
+/// The list of packages at `top_packages_url`, in the format of
+/// <https://hugovk.github.io/top-pypi-packages/>
+#[derive(Deserialize)]
+struct Listing {
+    rows: Vec<ListingRow>,
+}
+
+#[derive(Deserialize)]
+struct ListingRow {
+    project: String,
+}
+
+impl TopPackages {
+    pub fn new<'a>(names: impl IntoIterator<Item = &'a str>) -> Self {
+        Self {
+            packages: names
+                .into_iter()
+                .map(normalize)
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    let letters = letters(&name);
+                    (name, letters)
+                })
+                .collect(),
+        }
+    }
+
+    /// The list bundled with the client
+    pub fn bundled() -> Self {
+        Self::new(
+            BUNDLED
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#')),
+        )
+    }
+
+    /// Parse the first `count` packages of a listing in the format of `top_packages_url`
+    pub fn from_listing(listing: &str, count: usize) -> Result<Self> {
+        let listing: Listing = serde_json::from_str(listing)?;
+        Ok(Self::new(
+            listing
+                .rows
+                .iter()
+                .take(count)
+                .map(|row| row.project.as_str()),
+        ))
+    }
+
+    pub fn len(&self) -> usize {
+        self.packages.len()
+    }
+
+    /// The popular package `name` could be a typosquat of, if any
+    pub fn candidate(&self, name: &str) -> Option<&str> {
+        let name = normalize(name);
+        let name_letters = letters(&name);
+        if name_letters.len() < MIN_NAME_LENGTH || self.packages.iter().any(|(top, _)| *top == name)
+        {
+            return None;
+        }
+        let max_distance = if name_letters.len() >= LONG_NAME_LENGTH {
+            TYPO + NEIGHBOUR_TYPO
+        } else {
+            TYPO
+        };
+
+        self.packages
+            .iter()
+            // the distance is at least the difference in length, don't bother with the others
+            .filter(|(_, letters)| {
+                letters.len().abs_diff(name_letters.len()) * TYPO <= max_distance
+            })
+            .map(|(top, letters)| (top, distance(&name_letters, letters)))
+            .filter(|(_, distance)| *distance <= max_distance)
+            // the first of the closest, so the most popular one
+            .min_by_key(|(_, distance)| *distance)
+            .map(|(top, _)| top.as_str())
+    }
+}
+
+/// The current list of top packages, see [`refresh`]
+static TOP_PACKAGES: Lazy<RwLock<TopPackages>> = Lazy::new(|| RwLock::new(TopPackages::bundled()));
+
+/// The popular package `name` could be a typosquat of, according to the current list
+pub fn candidate(name: &str) -> Option<String> {
+    TOP_PACKAGES.read().candidate(name).map(ToOwned::to_owned)
+}
+
+/// Replace the current list by the first `count` packages listed at `url`, returning how many
+/// packages it has now
+pub fn refresh(http_client: &Client, url: &str, count: usize) -> Result<usize> {
+    let listing = http_client.get(url).send()?.error_for_status()?.text()?;
+    let packages = TopPackages::from_listing(&listing, count)?;
+    let len = packages.len();
+    if len == 0 {
+        return Err(eyre!("{url} lists no packages"));
+    }
+    *TOP_PACKAGES.write() = packages;
+
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{are_neighbours, normalize, TopPackages};
+
+    #[test]
+    fn knows_neighbouring_keys() {
+        assert!(are_neighbours('q', 'w'));
+        assert!(are_neighbours('q', 'a'));
+        assert!(are_neighbours('s', 'w'));
+        assert!(are_neighbours('n', 'h'));
+        assert!(!are_neighbours('q', 's'));
+        assert!(!are_neighbours('a', 'l'));
+        assert!(!are_neighbours('-', 'a'));
+        assert_eq!(normalize("Python__DateUtil.x"), "python-dateutil-x");
+    }
+
+    #[test]
+    fn finds_typosquat_candidates() {
+        let top = TopPackages::new(["requests", "python-dateutil", "numpy", "six", "urllib3"]);
+
+        assert_eq!(top.candidate("reqeusts"), Some("requests"));
+        assert_eq!(top.candidate("requestss"), Some("requests"));
+        assert_eq!(top.candidate("requezts"), Some("requests"));
+        assert_eq!(top.candidate("pythondateutil"), Some("python-dateutil"));
+        assert_eq!(top.candidate("python-dateutils"), Some("python-dateutil"));
+        // a typo and a half is only allowed for long names
+        assert_eq!(top.candidate("pythom-dateutils"), Some("python-dateutil"));
+        assert_eq!(top.candidate("urlib4"), None);
+
+        assert_eq!(top.candidate("Requests"), None);
+        assert_eq!(top.candidate("python_dateutil"), None);
+        assert_eq!(top.candidate("sux"), None);
+        assert_eq!(top.candidate("flask"), None);
+    }
+
+    #[test]
+    fn parses_listings() {
+        let listing = r#"{"last_update": "2024-05-01 00:00:00", "rows": [
+            {"download_count": 3, "project": "boto3"},
+            {"download_count": 2, "project": "Typing_Extensions"},
+            {"download_count": 1, "project": "idna"}
+        ]}"#;
+        let top = TopPackages::from_listing(listing, 2).unwrap();
+
+        assert_eq!(top.len(), 2);
+        assert_eq!(top.candidate("typing-extension"), Some("typing-extensions"));
+        assert!(TopPackages::bundled().len() > 300);
+    }
+}
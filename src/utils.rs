@@ -1,8 +1,16 @@
 use reqwest::Url;
 
+/// The host the Python Package Index serves distributions from, the only one the inspector can show files of
+pub const PYPI_FILES_HOST: &str = "files.pythonhosted.org";
+
 #[allow(clippy::doc_markdown)] // Clippy thinks PyPI is a documentation item
-/// Turn a package `name`, `version`, and `download_url` into a PyPI Inspector URL
-pub fn create_inspector_url(name: &str, version: &str, download_url: &Url) -> Url {
+/// Turn a package `name`, `version`, and `download_url` into a PyPI Inspector URL, or `None` if
+/// the distribution isn't hosted on PyPI (such as on a mirror)
+pub fn create_inspector_url(name: &str, version: &str, download_url: &Url) -> Option<Url> {
+    if download_url.host_str() != Some(PYPI_FILES_HOST) {
+        return None;
+    }
+
     let mut download_url = download_url.clone();
     let new_path = format!(
         "project/{}/{}/{}/",
@@ -14,7 +22,7 @@ pub fn create_inspector_url(name: &str, version: &str, download_url: &Url) -> Ur
     download_url.set_host(Some("inspector.pypi.io")).unwrap();
     download_url.set_path(&new_path);
 
-    download_url
+    Some(download_url)
 }
 
 #[cfg(test)]
@@ -27,7 +35,7 @@ mod tests {
                 #[test]
                 fn $name() {
                     let ((n, version, download_url), exp) = $value;
-                    assert_eq!(exp, create_inspector_url(n, version, &download_url));
+                    assert_eq!(Some(exp), create_inspector_url(n, version, &download_url));
                 }
             )*
         }
@@ -51,4 +59,12 @@ mod tests {
             Url::parse("https://inspector.pypi.io/project/requests/2.19.1/packages/54/1f/782a5734931ddf2e1494e4cd615a51ff98e1879cbe9eecbdfeaf09aa75e9/requests-2.19.1.tar.gz/requests-2.19.1/LICENSE/").unwrap()
         ),
     }
+
+    #[test]
+    fn skips_distributions_hosted_elsewhere() {
+        let mirror =
+            Url::parse("https://pypi.mirror.example/packages/ab/cd/requests-2.19.1.tar.gz")
+                .unwrap();
+        assert_eq!(None, create_inspector_url("requests", "2.19.1", &mirror));
+    }
 }